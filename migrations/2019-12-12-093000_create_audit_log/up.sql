@@ -0,0 +1,11 @@
+CREATE TABLE audit_log (
+    id SERIAL PRIMARY KEY,
+    created_at TIMESTAMP NOT NULL DEFAULT now(),
+    action TEXT NOT NULL,
+    token_sub TEXT,
+    token_name TEXT,
+    token_claims TEXT,
+    params TEXT NOT NULL DEFAULT '{}'
+);
+
+CREATE INDEX audit_log_action_index ON audit_log (action);
@@ -0,0 +1,3 @@
+ALTER TABLE builds DROP COLUMN app_id;
+ALTER TABLE builds DROP COLUMN uploader;
+ALTER TABLE builds DROP COLUMN metadata;
@@ -0,0 +1,3 @@
+ALTER TABLE builds ADD app_id TEXT;
+ALTER TABLE builds ADD uploader TEXT;
+ALTER TABLE builds ADD metadata JSONB NOT NULL DEFAULT '{}';
@@ -0,0 +1,6 @@
+DROP INDEX published_refs_repo_ref_index;
+
+ALTER TABLE published_refs DROP COLUMN published_at;
+ALTER TABLE published_refs DROP COLUMN previous_commit;
+ALTER TABLE published_refs DROP COLUMN repo;
+ALTER TABLE published_refs DROP COLUMN job_id;
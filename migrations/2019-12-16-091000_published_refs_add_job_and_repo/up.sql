@@ -0,0 +1,8 @@
+ALTER TABLE published_refs ADD job_id INTEGER REFERENCES jobs (id);
+ALTER TABLE published_refs ADD repo TEXT;
+UPDATE published_refs SET repo = builds.repo FROM builds WHERE builds.id = published_refs.build_id;
+ALTER TABLE published_refs ALTER COLUMN repo SET NOT NULL;
+ALTER TABLE published_refs ADD previous_commit TEXT;
+ALTER TABLE published_refs ADD published_at TIMESTAMP NOT NULL DEFAULT now();
+
+CREATE INDEX published_refs_repo_ref_index ON published_refs (repo, ref_name);
@@ -0,0 +1,2 @@
+ALTER TABLE jobs DROP COLUMN ended_at;
+ALTER TABLE jobs DROP COLUMN started_at;
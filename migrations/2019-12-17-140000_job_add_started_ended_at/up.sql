@@ -0,0 +1,2 @@
+ALTER TABLE jobs ADD started_at TIMESTAMP;
+ALTER TABLE jobs ADD ended_at TIMESTAMP;
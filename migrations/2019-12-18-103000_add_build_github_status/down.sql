@@ -0,0 +1,2 @@
+ALTER TABLE builds DROP COLUMN github_sha;
+ALTER TABLE builds DROP COLUMN github_repository;
@@ -0,0 +1,2 @@
+ALTER TABLE builds ADD github_repository TEXT;
+ALTER TABLE builds ADD github_sha TEXT;
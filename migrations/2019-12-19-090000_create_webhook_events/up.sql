@@ -0,0 +1,11 @@
+CREATE TABLE webhook_events (
+    id SERIAL PRIMARY KEY,
+    created_at TIMESTAMP NOT NULL DEFAULT now(),
+    event TEXT NOT NULL,
+    payload TEXT NOT NULL,
+    attempts INTEGER NOT NULL DEFAULT 0,
+    delivered BOOLEAN NOT NULL DEFAULT false,
+    last_error TEXT
+);
+
+CREATE INDEX webhook_events_undelivered_index ON webhook_events (id) WHERE NOT delivered;
@@ -0,0 +1,9 @@
+CREATE TABLE build_comments (
+    id SERIAL PRIMARY KEY,
+    build_id INTEGER NOT NULL REFERENCES builds (id) ON DELETE CASCADE,
+    created_at TIMESTAMP NOT NULL DEFAULT now(),
+    author TEXT NOT NULL,
+    comment TEXT NOT NULL
+);
+
+CREATE INDEX build_comments_build_id_index ON build_comments (build_id);
@@ -0,0 +1 @@
+ALTER TABLE builds ADD deleted_at TIMESTAMP;
@@ -0,0 +1,3 @@
+ALTER TABLE build_refs DROP COLUMN size;
+ALTER TABLE builds DROP COLUMN uploaded_bytes;
+ALTER TABLE builds DROP COLUMN uploaded_objects;
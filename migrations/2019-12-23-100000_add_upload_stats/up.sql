@@ -0,0 +1,3 @@
+ALTER TABLE builds ADD uploaded_objects BIGINT NOT NULL DEFAULT 0;
+ALTER TABLE builds ADD uploaded_bytes BIGINT NOT NULL DEFAULT 0;
+ALTER TABLE build_refs ADD size BIGINT;
@@ -0,0 +1 @@
+ALTER TABLE builds ADD derived_from INTEGER REFERENCES builds (id);
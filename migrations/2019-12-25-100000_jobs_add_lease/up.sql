@@ -0,0 +1,2 @@
+ALTER TABLE jobs ADD lease_owner TEXT;
+ALTER TABLE jobs ADD lease_expires_at TIMESTAMP;
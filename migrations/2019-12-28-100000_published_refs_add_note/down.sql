@@ -0,0 +1 @@
+ALTER TABLE published_refs DROP COLUMN note;
@@ -0,0 +1,5 @@
+CREATE TABLE token_usage (
+    subject TEXT PRIMARY KEY,
+    uploaded_bytes BIGINT NOT NULL DEFAULT 0,
+    updated_at TIMESTAMP NOT NULL DEFAULT now()
+);
@@ -0,0 +1,11 @@
+CREATE TABLE ref_pins (
+    id SERIAL PRIMARY KEY,
+    repo TEXT NOT NULL,
+    ref_name TEXT NOT NULL,
+    commit TEXT, -- NULL pins the whole history of the ref
+    created_at TIMESTAMP NOT NULL DEFAULT now(),
+    author TEXT NOT NULL,
+    reason TEXT
+);
+
+CREATE INDEX ref_pins_repo_idx ON ref_pins (repo);
@@ -0,0 +1,11 @@
+CREATE TABLE ref_tombstones (
+    id SERIAL PRIMARY KEY,
+    repo TEXT NOT NULL,
+    ref_name TEXT NOT NULL,
+    reason TEXT NOT NULL,
+    rebase TEXT,
+    created_at TIMESTAMP NOT NULL DEFAULT now(),
+    author TEXT NOT NULL,
+    job_id INTEGER REFERENCES jobs(id),
+    UNIQUE (repo, ref_name)
+);
@@ -0,0 +1 @@
+ALTER TABLE published_refs ADD published_by TEXT;
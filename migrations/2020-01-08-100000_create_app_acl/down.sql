@@ -0,0 +1,2 @@
+DROP TABLE acl_group_members;
+DROP TABLE app_acl;
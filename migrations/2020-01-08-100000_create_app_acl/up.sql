@@ -0,0 +1,17 @@
+CREATE TABLE app_acl (
+    id SERIAL PRIMARY KEY,
+    app_id TEXT NOT NULL,
+    principal TEXT NOT NULL, -- A token name, or group:NAME
+    created_at TIMESTAMP NOT NULL DEFAULT now(),
+    author TEXT NOT NULL,
+    UNIQUE (app_id, principal)
+);
+
+CREATE TABLE acl_group_members (
+    id SERIAL PRIMARY KEY,
+    group_name TEXT NOT NULL,
+    member TEXT NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT now(),
+    author TEXT NOT NULL,
+    UNIQUE (group_name, member)
+);
@@ -0,0 +1,18 @@
+CREATE TABLE organizations (
+    id SERIAL PRIMARY KEY,
+    name TEXT NOT NULL UNIQUE,
+    upload_quota BIGINT, -- Bytes all members can upload together, NULL for no limit
+    created_at TIMESTAMP NOT NULL DEFAULT now(),
+    author TEXT NOT NULL
+);
+
+CREATE TABLE organization_members (
+    id SERIAL PRIMARY KEY,
+    organization_id INTEGER NOT NULL REFERENCES organizations ON DELETE CASCADE,
+    member TEXT NOT NULL, -- A token name
+    created_at TIMESTAMP NOT NULL DEFAULT now(),
+    author TEXT NOT NULL,
+    UNIQUE (organization_id, member)
+);
+
+CREATE INDEX organization_members_member_idx ON organization_members (member);
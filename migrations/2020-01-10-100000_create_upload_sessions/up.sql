@@ -0,0 +1,9 @@
+CREATE TABLE upload_sessions (
+    id SERIAL PRIMARY KEY,
+    build_id INTEGER NOT NULL UNIQUE REFERENCES builds,
+    created_at TIMESTAMP NOT NULL DEFAULT now(),
+    last_activity_at TIMESTAMP NOT NULL DEFAULT now(),
+    expired_at TIMESTAMP -- Set once the build was aborted for being idle
+);
+
+INSERT INTO upload_sessions (build_id, created_at, last_activity_at) SELECT id, created_at, now() FROM builds WHERE repo_state = 0;
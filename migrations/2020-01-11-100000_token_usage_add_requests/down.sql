@@ -0,0 +1,3 @@
+ALTER TABLE token_usage DROP COLUMN remote_addrs;
+ALTER TABLE token_usage DROP COLUMN last_used_at;
+ALTER TABLE token_usage DROP COLUMN request_count;
@@ -0,0 +1,3 @@
+ALTER TABLE token_usage ADD request_count BIGINT NOT NULL DEFAULT 0;
+ALTER TABLE token_usage ADD last_used_at TIMESTAMP;
+ALTER TABLE token_usage ADD remote_addrs TEXT[] NOT NULL DEFAULT '{}'; -- The most recent first
@@ -0,0 +1,10 @@
+CREATE TABLE job_failure_alerts (
+    id SERIAL PRIMARY KEY,
+    kind SMALLINT NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT now(),
+    failed INTEGER NOT NULL,
+    total INTEGER NOT NULL,
+    job_id INTEGER -- The failure that set off the alert
+);
+
+CREATE INDEX job_failure_alerts_kind_idx ON job_failure_alerts (kind, created_at);
@@ -0,0 +1,11 @@
+CREATE TABLE queued_mails (
+    id SERIAL PRIMARY KEY,
+    created_at TIMESTAMP NOT NULL DEFAULT now(),
+    subject TEXT NOT NULL,
+    body TEXT NOT NULL,
+    attempts INTEGER NOT NULL DEFAULT 0,
+    sent BOOLEAN NOT NULL DEFAULT false,
+    last_error TEXT
+);
+
+CREATE INDEX queued_mails_unsent_index ON queued_mails (id) WHERE NOT sent;
@@ -0,0 +1,2 @@
+DROP INDEX job_failure_alerts_window_idx;
+ALTER TABLE job_failure_alerts DROP COLUMN window_start;
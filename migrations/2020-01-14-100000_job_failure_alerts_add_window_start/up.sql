@@ -0,0 +1,8 @@
+ALTER TABLE job_failure_alerts ADD window_start TIMESTAMP;
+UPDATE job_failure_alerts SET window_start = created_at;
+DELETE FROM job_failure_alerts a USING job_failure_alerts b
+    WHERE a.kind = b.kind AND a.window_start = b.window_start AND a.id > b.id;
+ALTER TABLE job_failure_alerts ALTER window_start SET NOT NULL;
+
+-- At most one alert per kind and window
+CREATE UNIQUE INDEX job_failure_alerts_window_idx ON job_failure_alerts (kind, window_start);
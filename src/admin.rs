@@ -0,0 +1,216 @@
+use chrono::Utc;
+use futures;
+use futures::Future;
+use jwt;
+use ostree;
+use serde_json;
+use std::fs;
+use std::sync::Arc;
+
+use app::{Claims, Config};
+use db::Db;
+use errors::ApiError;
+use models::{RepoState, PublishedState, SignType};
+
+/* Operational tasks for the flat-manager-admin command, these go through
+ * the same database code as the api, so the running server picks up the
+ * changes on its next job queue poll.
+ */
+
+pub struct Admin {
+    config: Arc<Config>,
+    db: Db,
+}
+
+impl Admin {
+    pub fn new(config: &Arc<Config>) -> Admin {
+        Admin {
+            config: config.clone(),
+            db: Db(::connect_to_db(config)),
+        }
+    }
+
+    pub fn gentoken(&self,
+                    name: &str,
+                    sub: &str,
+                    scope: Vec<String>,
+                    prefixes: Vec<String>,
+                    repos: Vec<String>,
+                    duration: i64) -> Result<String, ApiError> {
+        let claims = Claims {
+            sub: sub.to_string(),
+            scope,
+            name: Some(name.to_string()),
+            prefixes,
+            repos,
+            exp: Utc::now().timestamp().saturating_add(duration),
+            parent_sub: None,
+        };
+
+        jwt::encode(&jwt::Header::default(), &claims, &self.config.secret)
+            .map_err(|e| ApiError::InternalServerError(e.to_string()))
+    }
+
+    pub fn list_builds(&self) -> impl Future<Item = (), Error = ApiError> {
+        self.db
+            .list_builds(None)
+            .map(|builds| {
+                for build in builds {
+                    let repo_state = RepoState::from_db(build.repo_state, &build.repo_state_reason);
+                    let published_state = PublishedState::from_db(build.published_state, &build.published_state_reason);
+                    println!("{}\t{}\t{}\t{:?}\t{:?}",
+                             build.id, build.repo, build.app_id.unwrap_or("-".to_string()),
+                             repo_state, published_state);
+                }
+            })
+    }
+
+    pub fn purge_build(&self, build_id: i32) -> impl Future<Item = (), Error = ApiError> {
+        let build_repo_path = self.config.build_repo_base.join(build_id.to_string());
+        let db = self.db.clone();
+        let config = self.config.clone();
+        self.db
+            .init_purge(self.config.clone(), build_id)
+            .and_then(move |_ok| {
+                let res = fs::remove_dir_all(&build_repo_path);
+                db.finish_purge(config,
+                                build_id,
+                                match res {
+                                    Ok(()) => None,
+                                    Err(e) => Some(e.to_string()),
+                                })
+            })
+            .map(|build| {
+                let repo_state = RepoState::from_db(build.repo_state, &build.repo_state_reason);
+                println!("Build {}: {:?}", build.id, repo_state);
+            })
+    }
+
+    pub fn retry_job(&self, job_id: i32) -> impl Future<Item = (), Error = ApiError> {
+        self.db
+            .retry_job(job_id)
+            .map(|job| println!("Job {} queued for retry", job.id))
+    }
+
+    pub fn update_repo(&self, repo: &str) -> impl Future<Item = (), Error = ApiError> {
+        futures::done(self.config.get_repoconfig(repo).map(|repoconfig| repoconfig.name.clone()))
+            .and_then({
+                let db = self.db.clone();
+                move |repo| db.queue_update_repo(repo)
+            })
+            .map(|job| println!("Queued update job {}", job.id))
+    }
+
+    pub fn prune(&self, repo: &str, depth: Option<i32>, dry_run: bool) -> impl Future<Item = (), Error = ApiError> {
+        futures::done(self.config.get_repoconfig(repo)
+                      .and_then(|repoconfig| {
+                          let depth = depth.or(repoconfig.prune.as_ref().map(|prune| prune.depth))
+                              .ok_or_else(|| ApiError::BadRequest(format!("No prune depth configured for repo {}", repoconfig.name)))?;
+                          Ok((repoconfig.name.clone(), depth))
+                      }))
+            .and_then({
+                let db = self.db.clone();
+                move |(repo, depth)| db.queue_prune(repo, depth, dry_run)
+            })
+            .map(|job| println!("Queued prune job {}", job.id))
+    }
+
+    pub fn reset_token_usage(&self, subject: &str) -> impl Future<Item = (), Error = ApiError> {
+        let subject = subject.to_string();
+        self.db.reset_token_usage(subject.clone())
+            .map(move |_| println!("Reset the upload usage of {}", subject))
+    }
+
+    pub fn export_metadata(&self, path: &str) -> impl Future<Item = (), Error = ApiError> {
+        let path = path.to_string();
+        self.db.export_metadata()
+            .and_then(move |dump| {
+                fs::write(&path, serde_json::to_string_pretty(&dump).unwrap_or_default())
+                    .map_err(|e| ApiError::InternalServerError(format!("Failed to write {}: {}", path, e)))?;
+                println!("Exported metadata to {}", path);
+                Ok(())
+            })
+    }
+
+    pub fn import_metadata(&self, path: &str) -> impl Future<Item = (), Error = ApiError> {
+        let db = self.db.clone();
+        futures::done(fs::read_to_string(path)
+                      .map_err(|e| ApiError::BadRequest(format!("Failed to read {}: {}", path, e)))
+                      .and_then(|data| serde_json::from_str(&data)
+                                .map_err(|e| ApiError::BadRequest(format!("Invalid metadata dump {}: {}", path, e)))))
+            .and_then(move |dump| db.import_metadata(dump))
+            .map(|counts| {
+                for (table, count) in counts {
+                    println!("Imported {} rows into {}", count, table);
+                }
+            })
+    }
+
+    pub fn import_repo(&self, repo: &str) -> impl Future<Item = (), Error = ApiError> {
+        futures::done(self.config.get_repoconfig(repo)
+                      .and_then(|repoconfig| {
+                          let mut refs = vec![];
+                          for prefix in &["app", "runtime"] {
+                              for ref_name in ostree::list_refs(&repoconfig.path, prefix) {
+                                  let commit = ostree::parse_ref(&repoconfig.path, &ref_name)?;
+                                  refs.push((ref_name, commit));
+                              }
+                          }
+                          refs.sort();
+                          Ok((repoconfig.name.clone(), refs))
+                      }))
+            .and_then({
+                let db = self.db.clone();
+                move |(repo, refs)| {
+                    let n_refs = refs.len();
+                    db.adopt_repo(repo, refs)
+                        .map(move |build| println!("Recorded {} refs as published by build {}", n_refs, build.id))
+                }
+            })
+    }
+
+    pub fn gc(&self, repo: &str, dry_run: bool) -> impl Future<Item = (), Error = ApiError> {
+        futures::done(self.config.get_repoconfig(repo).map(|repoconfig| repoconfig.name.clone()))
+            .and_then({
+                let db = self.db.clone();
+                move |repo| db.queue_gc(repo, dry_run)
+            })
+            .map(|job| println!("Queued gc job {}", job.id))
+    }
+
+    pub fn fsck(&self, repo: &str) -> impl Future<Item = (), Error = ApiError> {
+        futures::done(self.config.get_repoconfig(repo).map(|repoconfig| repoconfig.name.clone()))
+            .and_then({
+                let db = self.db.clone();
+                move |repo| db.queue_fsck(repo)
+            })
+            .map(|job| println!("Queued fsck job {}", job.id))
+    }
+
+    pub fn regenerate_deltas(&self, repo: &str) -> impl Future<Item = (), Error = ApiError> {
+        futures::done(self.config.get_repoconfig(repo).map(|repoconfig| repoconfig.name.clone()))
+            .and_then({
+                let db = self.db.clone();
+                move |repo| db.queue_regenerate_deltas(repo)
+            })
+            .map(|job| println!("Queued regenerate-deltas job {}", job.id))
+    }
+
+    pub fn resign(&self, repo: &str, sign_type: &str, key: &str, delete_key: Option<String>) -> impl Future<Item = (), Error = ApiError> {
+        let key = key.to_string();
+        futures::done(self.config.get_repoconfig(repo)
+                      .and_then(|repoconfig| {
+                          let sign_type = SignType::from_name(sign_type)
+                              .ok_or_else(|| ApiError::BadRequest(format!("Unsupported sign type {}", sign_type)))?;
+                          if sign_type != SignType::Gpg && delete_key.is_some() {
+                              return Err(ApiError::BadRequest("Only gpg signatures can be deleted".to_string()));
+                          }
+                          Ok((repoconfig.name.clone(), sign_type))
+                      }))
+            .and_then({
+                let db = self.db.clone();
+                move |(repo, sign_type)| db.queue_resign(repo, sign_type, key, delete_key)
+            })
+            .map(|job| println!("Queued resign job {}", job.id))
+    }
+}
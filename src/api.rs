@@ -5,33 +5,51 @@ use actix_web::web::{Json, Data, Path};
 use actix_web_actors::ws;
 use actix_multipart::Multipart;
 use actix_web::middleware::BodyEncoding;
+use actix_web::Responder;
+use actix_files::NamedFile;
 
 use futures::future;
 use futures::future::{Future};
 use std::cell::RefCell;
+use std::collections::HashMap;
 use std::clone::Clone;
 use std::env;
 use std::fs;
 use std::io;
-use std::io::Write;
+use std::iter;
+use std::io::{Read, Seek, SeekFrom, Write};
 use std::os::unix;
 use std::os::unix::fs::PermissionsExt;
 use std::path;
 use std::rc::Rc;
 use std::sync::Arc;
-use tempfile::NamedTempFile;
-use chrono::{Utc};
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use tempfile::{self, NamedTempFile};
+use tokio::timer::Delay;
+use chrono::{self, Utc};
 use jwt;
 use serde::Serialize;
+use serde_json;
 
-use app::{Claims,Config};
+use app::{Claims,CommitTimestamp,Config,RepoConfig};
 use errors::ApiError;
 use db::*;
-use models::{Job,JobStatus, JobKind,NewBuild,NewBuildRef};
+use models::{Build,Job,JobStatus, JobKind,BundleJob,RepoState,PublishedState,NewAuditLogEntry,NewBuild,NewBuildComment,NewBuildRef,NewRefPin,NewRefTombstone,NewAppAclEntry,NewAclGroupMember,NewOrganization,TypedJobResults};
 use tokens::{self, ClaimsValidator};
 use jobs::{ProcessJobs, JobQueue};
 use askama::Template;
 use deltas::{DeltaGenerator,RemoteWorker};
+use ostree;
+use diskspace;
+use objectpool;
+use throttle::Throttled;
+use health::Health;
+use metrics;
+use openssl::sha::Sha256;
+use hex;
+use tar;
+use zstd;
 
 fn init_ostree_repo(repo_path: &path::PathBuf, parent_repo_path: &path::PathBuf, build_id: i32, opt_collection_id: &Option<String>) -> io::Result<()> {
     let parent_repo_absolute_path = env::current_dir()?.join(parent_repo_path);
@@ -63,10 +81,62 @@ min-free-space-size=500MB
     Ok(())
 }
 
+/* Audit logging is best effort, we don't want to fail the actual
+ * operation (which has already happened) if the insert fails */
+fn audit_log(db: &Data<Db>, req: &HttpRequest, action: &str, params: serde_json::Value) {
+    let claims = req.get_claims();
+    let entry = NewAuditLogEntry {
+        action: action.to_string(),
+        token_sub: claims.as_ref().map(|c| c.sub.clone()),
+        token_name: claims.as_ref().and_then(|c| c.name.clone()),
+        token_claims: claims.as_ref().map(|c| json!(c).to_string()),
+        params: params.to_string(),
+    };
+    let action = action.to_string();
+    Arbiter::spawn(db.add_audit_log_entry(entry)
+                   .map_err(move |e| error!("Failed to add {} to audit log: {}", action, e)));
+}
+
+/* The api version is taken from the path. All versions share the
+ * handlers, v2 only changes the shape of some responses. */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ApiVersion {
+    V1,
+    V2,
+}
+
+pub const API_VERSIONS: &[&str] = &["v1", "v2"];
+
+impl ApiVersion {
+    pub fn from_request(req: &HttpRequest) -> ApiVersion {
+        if req.path().starts_with("/api/v2/") {
+            ApiVersion::V2
+        } else {
+            ApiVersion::V1
+        }
+    }
+
+    /* Route names have to be unique, so the v2 ones are prefixed */
+    pub fn route_name(&self, name: &str) -> String {
+        match self {
+            ApiVersion::V1 => name.to_string(),
+            ApiVersion::V2 => format!("v2_{}", name),
+        }
+    }
+}
+
+pub fn versions() -> HttpResponse {
+    HttpResponse::Ok().json(json!({
+        "versions": API_VERSIONS,
+        "latest": API_VERSIONS[API_VERSIONS.len() - 1],
+    }))
+}
+
 fn respond_with_url<T>(data: &T, req: &HttpRequest, name: &str, elements: &[String]) -> Result<HttpResponse, ApiError> where
     T: Serialize,
 {
-    match req.url_for(name, elements.clone()) {
+    let name = ApiVersion::from_request(req).route_name(name);
+    match req.url_for(&name, elements) {
         Ok(url) => Ok(HttpResponse::Ok()
                       .header(http::header::LOCATION, url.to_string())
                       .json(data)),
@@ -74,6 +144,27 @@ fn respond_with_url<T>(data: &T, req: &HttpRequest, name: &str, elements: &[Stri
     }
 }
 
+/* Shows what the calling token allows, for debugging permission errors */
+pub fn token_info(req: HttpRequest) -> HttpResponse {
+    match req.get_claims() {
+        Some(claims) => {
+            let expires_at = chrono::NaiveDateTime::from_timestamp_opt(claims.exp, 0)
+                .map(|exp| chrono::DateTime::<Utc>::from_utc(exp, Utc).to_rfc3339());
+            HttpResponse::Ok().json(json!({
+                "sub": claims.sub,
+                "name": claims.name,
+                "scope": claims.scope,
+                "prefixes": claims.prefixes,
+                "repos": claims.repos,
+                "exp": claims.exp,
+                "expires-at": expires_at,
+                "expires-in-secs": claims.exp - Utc::now().timestamp(),
+            }))
+        },
+        None => ApiError::NotEnoughPermissions("No token specified".to_string()).error_response(),
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct TokenSubsetArgs {
     sub: String,
@@ -106,6 +197,7 @@ pub fn prefix_is_subset(maybe_subset_prefix: &Option<Vec<String>>, claimed_prefi
 pub fn token_subset(
     args: Json<TokenSubsetArgs>,
     config: Data<Config>,
+    db: Data<Db>,
     req: HttpRequest
 ) -> HttpResponse {
     if let Some(claims) = req.get_claims() {
@@ -122,9 +214,13 @@ pub fn token_subset(
                     prefixes: { if let Some(ref prefixes) = args.prefixes { prefixes.clone() } else { claims.prefixes.clone() } },
                     repos: { if let Some(ref repos) = args.repos { repos.clone() } else { claims.repos.clone() } },
                     exp: new_exp,
+                    parent_sub: claims.parent_sub.clone(),
                 };
                 return match jwt::encode(&jwt::Header::default(), &new_claims, &config.secret) {
-                    Ok(token) => HttpResponse::Ok().json(TokenSubsetResponse{ token: token }),
+                    Ok(token) => {
+                        audit_log(&db, &req, "token-subset", json!(new_claims));
+                        HttpResponse::Ok().json(TokenSubsetResponse{ token })
+                    },
                     Err(e) => ApiError::InternalServerError(e.to_string()).error_response()
                 }
             }
@@ -132,6 +228,153 @@ pub fn token_subset(
     ApiError::NotEnoughPermissions("No token presented".to_string()).error_response()
 }
 
+const MAX_WORKER_TOKEN_DURATION: i64 = 24 * 60 * 60;
+
+fn default_worker_token_duration() -> i64 {
+    4 * 60 * 60
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WorkerTokenArgs {
+    #[serde(default = "default_worker_token_duration")]
+    duration: i64, // At most a day, and never past the expiry of the minting token
+}
+
+/* The worker token is named below the minting token, so its uploads
+ * count for the organizations of that (see db::acl_principal), and its
+ * usage is accounted to the subject of that */
+fn worker_token_claims(claims: Claims, build_id: i32, repo: &str, duration: i64, now: i64) -> Claims {
+    Claims {
+        sub: format!("build/{}", build_id),
+        scope: vec!["upload".to_string()],
+        name: Some(claims_publisher_name(&claims) + "/worker"),
+        prefixes: claims.prefixes.clone(),
+        repos: vec![repo.to_string()],
+        exp: i64::min(now.saturating_add(duration), claims.exp),
+        parent_sub: Some(claims.usage_subject().to_string()),
+    }
+}
+
+/* Lets a CI orchestrator hand an ephemeral build machine a token that
+ * can only upload to this one build, instead of its own credentials */
+pub fn create_worker_token(
+    args: Json<WorkerTokenArgs>,
+    params: Path<BuildPathParams>,
+    config: Data<Config>,
+    db: Data<Db>,
+    req: HttpRequest,
+) -> impl Future<Item = HttpResponse, Error = ApiError> {
+    futures::done(req.has_token_claims(&format!("build/{}", params.id), "build")
+                  .and_then(|_| req.has_token_claims(&format!("build/{}", params.id), "upload"))
+                  .and_then(|_| if args.duration <= 0 || args.duration > MAX_WORKER_TOKEN_DURATION {
+                      Err(ApiError::BadRequest(format!("Worker token duration must be between 1 and {} seconds", MAX_WORKER_TOKEN_DURATION)))
+                  } else {
+                      Ok(())
+                  }))
+        .and_then(move |_| {
+            let req2 = req.clone();
+            let build_id = params.id;
+            db
+                .lookup_build(build_id)
+                .and_then(move |build| {
+                    req2.has_token_repo(&build.repo)?;
+                    validate_accepting_uploads(&build)?;
+                    let claims = req2.get_claims()
+                        .ok_or_else(|| ApiError::NotEnoughPermissions("No token presented".to_string()))?;
+                    let new_claims = worker_token_claims(claims, build_id, &build.repo, args.duration, Utc::now().timestamp());
+                    let token = jwt::encode(&jwt::Header::default(), &new_claims, &config.secret)
+                        .map_err(|e| ApiError::InternalServerError(e.to_string()))?;
+                    audit_log(&db, &req2, "create-worker-token", json!(new_claims));
+                    Ok(HttpResponse::Ok().json(TokenSubsetResponse{ token }))
+                })
+        })
+}
+
+fn default_create_token_duration() -> i64 {
+    60 * 60 * 24 * 365
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreateTokenArgs {
+    sub: String,
+    scope: Vec<String>,
+    #[serde(default = "default_create_token_duration")]
+    duration: i64,
+    prefixes: Option<Vec<String>>,
+    repos: Option<Vec<String>>,
+    name: String,
+}
+
+pub fn create_token(
+    args: Json<CreateTokenArgs>,
+    config: Data<Config>,
+    db: Data<Db>,
+    req: HttpRequest
+) -> HttpResponse {
+    if let Err(e) = req.has_token_claims("admin", "admin") {
+        return e.error_response();
+    }
+
+    if args.duration <= 0 {
+        return ApiError::BadRequest("Token duration must be positive".to_string()).error_response();
+    }
+
+    let new_claims = Claims {
+        sub: args.sub.clone(),
+        scope: args.scope.clone(),
+        name: Some(args.name.clone()),
+        prefixes: args.prefixes.clone().unwrap_or(vec!["".to_string()]),
+        repos: args.repos.clone().unwrap_or(vec!["".to_string()]),
+        exp: Utc::now().timestamp().saturating_add(args.duration),
+        parent_sub: None,
+    };
+
+    match jwt::encode(&jwt::Header::default(), &new_claims, &config.secret) {
+        Ok(token) => {
+            audit_log(&db, &req, "create-token", json!(new_claims));
+            HttpResponse::Ok().json(TokenSubsetResponse{ token })
+        },
+        Err(e) => ApiError::InternalServerError(e.to_string()).error_response()
+    }
+}
+
+fn default_audit_log_limit() -> i64 {
+    100
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AuditLogArgs {
+    action: Option<String>,
+    organization: Option<String>,
+    #[serde(default = "default_audit_log_limit")]
+    limit: i64,
+}
+
+pub fn audit_log_entries(
+    args: web::Query<AuditLogArgs>,
+    db: Data<Db>,
+    req: HttpRequest,
+) -> impl Future<Item = HttpResponse, Error = ApiError> {
+    futures::done(req.has_token_claims("admin", "admin"))
+        .and_then(move |_| db.list_audit_log(args.action.clone(), args.organization.clone(), args.limit))
+        .and_then(|entries| Ok(HttpResponse::Ok().json(entries)))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TokenUsageArgs {
+    subject: Option<String>,
+}
+
+pub fn token_usage(
+    args: web::Query<TokenUsageArgs>,
+    db: Data<Db>,
+    req: HttpRequest,
+) -> impl Future<Item = HttpResponse, Error = ApiError> {
+    futures::done(req.has_token_claims("admin", "admin"))
+        .and_then(move |_| db.list_token_usage(args.subject.clone()))
+        .and_then(|usage| Ok(HttpResponse::Ok().json(usage)))
+}
+
 #[derive(Deserialize,Debug)]
 pub struct JobPathParams {
     id: i32,
@@ -143,6 +386,95 @@ pub struct JobArgs {
     log_offset: Option<usize>,
 }
 
+/* The job as stored, plus the results parsed according to the job kind.
+ * The raw results string is kept for older clients. */
+#[derive(Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct JobResponse {
+    #[serde(flatten)]
+    job: Job,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    typed_results: Option<TypedJobResults>,
+}
+
+/* In v2 the kind and status are names, and contents, results and
+ * progress are json rather than strings with json in them */
+#[derive(Serialize)]
+struct JobResponseV2 {
+    id: i32,
+    kind: &'static str,
+    status: &'static str,
+    contents: serde_json::Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    results: Option<TypedJobResults>,
+    log: String,
+    start_after: Option<std::time::SystemTime>,
+    repo: Option<String>,
+    started_at: Option<std::time::SystemTime>,
+    ended_at: Option<std::time::SystemTime>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    progress: Option<serde_json::Value>,
+}
+
+fn job_json(job: Job, version: ApiVersion) -> serde_json::Value {
+    let typed_results = job.typed_results();
+    match version {
+        ApiVersion::V1 => json!(JobResponse {
+            job,
+            typed_results,
+        }),
+        ApiVersion::V2 => json!(JobResponseV2 {
+            id: job.id,
+            kind: JobKind::from_db(job.kind).map(|kind| kind.name()).unwrap_or("unknown"),
+            status: JobStatus::from_db(job.status).map(|status| status.name()).unwrap_or("unknown"),
+            contents: serde_json::from_str(&job.contents).unwrap_or(serde_json::Value::Null),
+            results: typed_results,
+            log: job.log,
+            start_after: job.start_after,
+            repo: job.repo,
+            started_at: job.started_at,
+            ended_at: job.ended_at,
+            progress: job.progress.and_then(|progress| serde_json::from_str(&progress).ok()),
+        }),
+    }
+}
+
+fn job_response(job: Job, req: &HttpRequest) -> HttpResponse {
+    HttpResponse::Ok().json(job_json(job, ApiVersion::from_request(req)))
+}
+
+fn respond_with_job(job: Job, req: &HttpRequest, name: &str, elements: &[String]) -> Result<HttpResponse, ApiError> {
+    respond_with_url(&job_json(job, ApiVersion::from_request(req)), req, name, elements)
+}
+
+/* v2 adds the names of the states next to the numbers */
+#[derive(Serialize)]
+struct BuildResponseV2 {
+    #[serde(flatten)]
+    build: Build,
+    repo_state_name: &'static str,
+    published_state_name: &'static str,
+}
+
+fn build_json(build: Build, version: ApiVersion) -> serde_json::Value {
+    match version {
+        ApiVersion::V1 => json!(build),
+        ApiVersion::V2 => {
+            let repo_state_name = RepoState::from_db(build.repo_state, &build.repo_state_reason).name();
+            let published_state_name = PublishedState::from_db(build.published_state, &build.published_state_reason).name();
+            json!(BuildResponseV2 {
+                build,
+                repo_state_name,
+                published_state_name,
+            })
+        },
+    }
+}
+
+fn respond_with_build(build: Build, req: &HttpRequest, name: &str, elements: &[String]) -> Result<HttpResponse, ApiError> {
+    respond_with_url(&build_json(build, ApiVersion::from_request(req)), req, name, elements)
+}
+
 pub fn get_job(
     args: Json<JobArgs>,
     params: Path<JobPathParams>,
@@ -151,12 +483,89 @@ pub fn get_job(
 ) -> impl Future<Item = HttpResponse, Error = ApiError> {
     futures::done(req.has_token_claims("build", "jobs"))
         .and_then(move |_|  db.lookup_job(params.id, args.log_offset))
-        .and_then(|job| Ok(HttpResponse::Ok().json(job)))
+        .and_then(move |job| Ok(job_response(job, &req)))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct JobDependenciesArgs {
+    depends_on: Vec<i32>,
+}
+
+/* Make a job that hasn't started yet wait for other jobs, so clients
+ * can chain their own sequences of jobs */
+pub fn add_job_dependencies(
+    args: Json<JobDependenciesArgs>,
+    params: Path<JobPathParams>,
+    db: Data<Db>,
+    req: HttpRequest,
+) -> impl Future<Item = HttpResponse, Error = ApiError> {
+    let job_id = params.id;
+    let mut job_ids = args.depends_on.clone();
+    job_ids.push(job_id);
+    let (req2, db2, db3) = (req.clone(), db.clone(), db.clone());
+    futures::done(req.has_token_claims("build", "build"))
+        .and_then(move |_| db.lookup_job_builds(job_ids.clone()).map(|builds| (job_ids, builds)))
+        .and_then(move |(job_ids, builds)| {
+            /* The caller must own the builds of both the job and the jobs it waits for */
+            for id in job_ids {
+                let build = builds.get(&id)
+                    .ok_or_else(|| ApiError::NotEnoughPermissions(format!("Job {} is not part of a build", id)))?;
+                req2.has_token_claims(&format!("build/{}", build.id), "build")?;
+                req2.has_token_repo(&build.repo)?;
+            }
+            Ok(())
+        })
+        .and_then(move |_| {
+            let audit_params = json!({
+                "job": job_id,
+                "depends-on": args.depends_on,
+            });
+            db2.add_job_dependencies(job_id, args.depends_on.clone())
+                .and_then(move |dependencies| {
+                    audit_log(&db3, &req, "add-job-dependencies", audit_params);
+                    Ok(HttpResponse::Ok().json(dependencies))
+                })
+        })
+}
+
+pub fn get_build_jobs(
+    params: Path<BuildPathParams>,
+    db: Data<Db>,
+    req: HttpRequest,
+) -> impl Future<Item = HttpResponse, Error = ApiError> {
+    futures::done(req.has_token_claims(&format!("build/{}", params.id), "build"))
+        .and_then(move |_| db.lookup_build_job_graph(params.id))
+        .and_then(|graph| Ok(HttpResponse::Ok().json(graph)))
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct CreateBuildArgs {
-    repo: String
+    repo: String,
+    github_repository: Option<String>,
+    github_sha: Option<String>,
+}
+
+fn is_github_name(name: &str) -> bool {
+    !name.is_empty() && name != "." && name != ".."
+        && name.chars().all(|ch| ch.is_ascii_alphanumeric() || ch == '_' || ch == '.' || ch == '-')
+}
+
+/* These end up in the url that the commit statuses are posted to with
+ * the GitHub token of the server, so don't allow anything but names */
+fn validate_github_args(repository: &Option<String>, sha: &Option<String>) -> Result<(), ApiError> {
+    if let Some(repository) = repository {
+        let parts: Vec<&str> = repository.split('/').collect();
+        if parts.len() != 2 || !parts.iter().all(|part| is_github_name(part)) {
+            return Err(ApiError::BadRequest(format!("Invalid github repository {}", repository)));
+        }
+    }
+    if let Some(sha) = sha {
+        if sha.len() != 40 || !sha.chars().all(|ch| ch.is_ascii_hexdigit()) {
+            return Err(ApiError::BadRequest(format!("Invalid github sha {}", sha)));
+        }
+    }
+    Ok(())
 }
 
 pub fn create_build(
@@ -167,14 +576,21 @@ pub fn create_build(
 )  -> impl Future<Item = HttpResponse, Error = ApiError> {
     let repo1 = args.repo.clone();
     let repo2 = args.repo.clone();
-    futures::done(req.has_token_claims("build", "build"))
+    let uploader = req.get_claims().map(|claims| claims.name.unwrap_or(claims.sub));
+    futures::done(req.has_token_claims("build", "build")
+                  .and_then(|_| validate_github_args(&args.github_repository, &args.github_sha)))
         .and_then(move |_| futures::done(req.has_token_repo(&repo1))
                   .and_then(move |_| futures::done(config.get_repoconfig(&repo2).map(|rc| rc.clone())) // Ensure the repo exists
                             .and_then(move |repoconfig| {
+                                let db2 = db.clone();
                                 db
                                     .new_build (
                                         NewBuild {
                                             repo: args.repo.clone(),
+                                            uploader,
+                                            github_repository: args.github_repository.clone(),
+                                            github_sha: args.github_sha.clone(),
+                                            derived_from: None,
                                         })
                                     .and_then(move |build| {
                                         let build_repo_path = config.build_repo_base.join(build.id.to_string());
@@ -183,19 +599,112 @@ pub fn create_build(
                                         init_ostree_repo (&build_repo_path, &repoconfig.path, build.id, &repoconfig.collection_id)?;
                                         init_ostree_repo (&upload_path, &repoconfig.path, build.id, &None)?;
 
-                                        respond_with_url(&build, &req, "show_build", &[build.id.to_string()])
+                                        audit_log(&db2, &req, "create-build", json!({ "build": build.id, "repo": build.repo }));
+                                        let build_id = build.id;
+                                        respond_with_build(build, &req, "show_build", &[build_id.to_string()])
                                     })
                             })
                   ))
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeriveBuildArgs {
+    refs: Option<Vec<String>>, // Defaults to all refs of the build
+    branch: Option<String>,
+}
+
+fn derived_ref_name(ref_name: &str, branch: &Option<String>) -> String {
+    let ref_parts: Vec<&str> = ref_name.split('/').collect();
+    match branch {
+        Some(branch) if ref_parts.len() == 4 => format!("{}/{}/{}/{}", ref_parts[0], ref_parts[1], ref_parts[2], branch),
+        _ => ref_name.to_string(),
+    }
+}
+
+/* Create a new build with (some of) the refs of an existing one, for
+ * instance to retry a failed publish with another end-of-life or branch
+ * without uploading everything again */
+pub fn derive_build(
+    args: Json<DeriveBuildArgs>,
+    params: Path<BuildPathParams>,
+    db: Data<Db>,
+    config: Data<Config>,
+    req: HttpRequest,
+) -> impl Future<Item = HttpResponse, Error = ApiError> {
+    let uploader = req.get_claims().map(|claims| claims.name.unwrap_or(claims.sub));
+    futures::done(req.has_token_claims("build", "build"))
+        .and_then(move |_| {
+            let source_id = params.id;
+            let req2 = req.clone();
+            let db2 = db.clone();
+            let db3 = db.clone();
+            let config2 = config.clone();
+            db
+                .lookup_build(source_id)
+                .and_then(move |source| {
+                    req2.has_token_repo(&source.repo)?;
+                    Ok(config2.get_repoconfig(&source.repo)?.clone())
+                })
+                .and_then(move |repoconfig| {
+                    let source_repo_path = config.build_repo_base.join(source_id.to_string());
+                    db2.lookup_build_refs(source_id)
+                        .and_then(move |source_refs| {
+                            let mut new_refs = vec![];
+                            for mut source_ref in source_refs {
+                                if let Some(ref wanted) = args.refs {
+                                    if !wanted.contains(&source_ref.ref_name) {
+                                        continue;
+                                    }
+                                }
+                                /* The build repo has the commits made from the uploaded ones */
+                                source_ref.commit = ostree::parse_ref(&source_repo_path, &source_ref.ref_name)
+                                    .map_err(|e| ApiError::BadRequest(format!("Build isn't committed: {}", e)))?;
+                                let ref_name = derived_ref_name(&source_ref.ref_name, &args.branch);
+                                validate_ref(&ref_name, &req)?;
+                                new_refs.push((source_ref, ref_name));
+                            }
+                            Ok((new_refs, req))
+                        })
+                        .and_then(move |(new_refs, req)| {
+                            db.derive_build(source_id, uploader, new_refs)
+                                .and_then(move |build| {
+                                    let build_repo_path = config.build_repo_base.join(build.id.to_string());
+                                    let upload_path = build_repo_path.join("upload");
+                                    let source_repo_path = config.build_repo_base.join(source_id.to_string());
+
+                                    init_ostree_repo (&build_repo_path, &repoconfig.path, build.id, &repoconfig.collection_id)?;
+                                    init_ostree_repo (&upload_path, &source_repo_path, build.id, &None)?;
+
+                                    audit_log(&db3, &req, "derive-build", json!({ "build": build.id, "source": source_id, "repo": build.repo }));
+                                    let build_id = build.id;
+                                    respond_with_build(build, &req, "show_build", &[build_id.to_string()])
+                                })
+                        })
+                })
+        })
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BuildsArgs {
+    organization: Option<String>, // Only the builds uploaded by its members
+}
+
 pub fn builds(
+    args: web::Query<BuildsArgs>,
     db: Data<Db>,
     req: HttpRequest
 ) -> impl Future<Item = HttpResponse, Error = ApiError> {
     futures::done(req.has_token_claims("build", "build"))
-        .and_then(move |_| db.list_builds())
-        .and_then(move |builds| Ok(HttpResponse::Ok().json(builds)))
+        .and_then(move |_| db.list_builds(args.organization.clone()))
+        .and_then(move |builds| {
+            let version = ApiVersion::from_request(&req);
+            /* Only list the builds in the repos the token is for */
+            let builds: Vec<serde_json::Value> = builds.into_iter()
+                .filter(|build| req.has_token_repo(&build.repo).is_ok())
+                .map(|build| build_json(build, version))
+                .collect();
+            Ok(HttpResponse::Ok().json(builds))
+        })
 }
 
 
@@ -212,8 +721,75 @@ pub fn get_build(
     futures::done(req.has_token_claims(&format!("build/{}", params.id), "build")
                   /* We allow getting a build for uploaders too, as it is similar info, and useful */
                   .or_else(|_| req.has_token_claims(&format!("build/{}", params.id), "upload")))
-        .and_then(move |_| db.lookup_build(params.id))
-        .and_then(|build| Ok(HttpResponse::Ok().json(build)))
+        .and_then(move |_| db.lookup_build(params.id).join3(db.lookup_build_comments(params.id),
+                                                              db.lookup_build_refs(params.id)))
+        .and_then(move |(build, comments, build_refs)| {
+            req.has_token_repo(&build.repo)?;
+            let ref_sizes: HashMap<String, i64> = build_refs.into_iter()
+                .filter_map(|build_ref| build_ref.size.map(|size| (build_ref.ref_name, size)))
+                .collect();
+            let mut build_json = build_json(build, ApiVersion::from_request(&req));
+            build_json["comments"] = json!(comments);
+            build_json["ref_sizes"] = json!(ref_sizes);
+            Ok(HttpResponse::Ok().json(build_json))
+        })
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BuildCommentArgs {
+    comment: String,
+}
+
+const MAX_COMMENT_LENGTH: usize = 64 * 1024;
+
+fn validate_comment(comment: &str) -> Result<(), ApiError> {
+    if comment.trim().is_empty() {
+        Err(ApiError::BadRequest("Empty comment".to_string()))
+    } else if comment.len() > MAX_COMMENT_LENGTH {
+        Err(ApiError::BadRequest(format!("Comment longer than {} bytes", MAX_COMMENT_LENGTH)))
+    } else {
+        Ok(())
+    }
+}
+
+/* Free form notes on a build, for review workflows or to record why
+ * a build was not published */
+pub fn add_build_comment(
+    args: Json<BuildCommentArgs>,
+    params: Path<BuildPathParams>,
+    db: Data<Db>,
+    req: HttpRequest,
+) -> impl Future<Item = HttpResponse, Error = ApiError> {
+    futures::done(req.has_token_claims(&format!("build/{}", params.id), "build")
+                  .and_then(|_| validate_comment(&args.comment)))
+        .and_then(move |_| {
+            /* Tokens don't necessarily have a name, fall back to the subject */
+            let author = req.get_claims()
+                .map(|c| c.name.clone().unwrap_or(c.sub.clone()))
+                .unwrap_or("unknown".to_string());
+            let build_id = params.id;
+            let db2 = db.clone();
+            db.new_build_comment(NewBuildComment {
+                build_id,
+                author,
+                comment: args.comment.clone(),
+            })
+                .and_then(move |comment| {
+                    audit_log(&db2, &req, "add-build-comment", json!({ "build": build_id, "comment": comment.id }));
+                    Ok(HttpResponse::Ok().json(comment))
+                })
+        })
+}
+
+pub fn get_build_comments(
+    params: Path<BuildPathParams>,
+    db: Data<Db>,
+    req: HttpRequest,
+) -> impl Future<Item = HttpResponse, Error = ApiError> {
+    futures::done(req.has_token_claims(&format!("build/{}", params.id), "build")
+                  .or_else(|_| req.has_token_claims(&format!("build/{}", params.id), "upload")))
+        .and_then(move |_| db.lookup_build(params.id).join(db.lookup_build_comments(params.id)))
+        .and_then(|(_build, comments)| Ok(HttpResponse::Ok().json(comments)))
 }
 
 #[derive(Deserialize)]
@@ -232,51 +808,297 @@ pub fn get_build_ref(
         .and_then(|build_ref| Ok(HttpResponse::Ok().json(build_ref)))
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct MissingObjectsArgs {
-    wanted: Vec<String>
+/* Lets a client drop a broken ref (e.g. for one arch) and upload it
+ * again, without starting a new build */
+pub fn delete_build_ref(
+    params: Path<RefPathParams>,
+    db: Data<Db>,
+    req: HttpRequest,
+) -> impl Future<Item = HttpResponse, Error = ApiError> {
+    futures::done(req.has_token_claims(&format!("build/{}", params.id), "upload"))
+        .and_then(move |_| {
+            let build_id = params.id;
+            let ref_id = params.ref_id;
+            let req2 = req.clone();
+            let db2 = db.clone();
+            let db3 = db.clone();
+            db
+                .lookup_build(build_id)
+                .and_then(move |build| req2.has_token_repo(&build.repo))
+                .and_then(move |_ok| db2.delete_build_ref(build_id, ref_id))
+                .and_then(move |build_ref| {
+                    audit_log(&db3, &req, "delete-build-ref",
+                              json!({ "build": build_ref.build_id, "ref": build_ref.ref_name, "commit": build_ref.commit }));
+                    Ok(HttpResponse::NoContent().finish())
+                })
+        })
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct MissingObjectsResponse {
-    missing: Vec<String>
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct RefDiff {
+    #[serde(rename = "ref")] ref_name: String,
+    old_commit: Option<String>,
+    new_commit: String,
+    old_size: Option<i64>,
+    new_size: Option<i64>,
+    size_change: Option<i64>,
 }
 
-fn has_object (build_id: i32,
-               object: &str,
-               config: &Data<Config>) -> bool
-{
-    let subpath: path::PathBuf = ["objects", &object[..2], &object[2..]].iter().collect();
-    let build_path = config.build_repo_base.join(build_id.to_string()).join("upload").join(&subpath);
-    if build_path.exists() {
-        true
-    } else {
-        let parent_path = config.build_repo_base.join(build_id.to_string()).join("parent").join(&subpath);
-        parent_path.exists()
-    }
+#[derive(Debug, Serialize, Default)]
+pub struct BuildDiff {
+    new: Vec<RefDiff>,
+    updated: Vec<RefDiff>,
+    unchanged: Vec<RefDiff>,
 }
 
-pub fn missing_objects(
-    args: Json<MissingObjectsArgs>,
-    params: Path<BuildPathParams>,
-    config: Data<Config>,
-    req: HttpRequest,
-) -> HttpResponse {
-    if let Err(e) = req.has_token_claims(&format!("build/{}", params.id), "upload") {
-        return e.error_response();
-    }
-    let mut missing = vec![];
-    for object in &args.wanted {
-        if ! has_object (params.id, object, &config) {
-            missing.push(object.to_string());
+/* The size is only informative, like for build refs */
+fn diff_commit_size(repo_paths: &[path::PathBuf], commit: &String) -> Option<i64> {
+    match ostree::get_commit_size(repo_paths, commit) {
+        Ok(size) => Some(size as i64),
+        Err(e) => {
+            warn!("Can't get size of commit {}: {}", commit, e);
+            None
         }
     }
-    HttpResponse::Ok()
-        .encoding(http::header::ContentEncoding::Gzip)
-        .json(MissingObjectsResponse { missing: missing })
 }
 
-fn validate_ref (ref_name: &String, req: &HttpRequest) -> Result<(),ApiError>
+/* The committed build has new commits for all refs, so a ref counts as
+ * unchanged if the new commit has the same content as the published one */
+fn diff_build_refs(build_repo_path: &path::PathBuf,
+                   repo_path: &path::PathBuf,
+                   ref_names: Vec<String>) -> Result<BuildDiff, ApiError> {
+    let mut diff = BuildDiff::default();
+    for ref_name in ref_names {
+        let new_commit = ostree::parse_ref(build_repo_path, &ref_name)?;
+        let new = ostree::get_commit(build_repo_path, &new_commit)?;
+        let new_size = diff_commit_size(&[build_repo_path.clone(), repo_path.clone()], &new_commit);
+
+        let old_commit = match ostree::parse_ref(repo_path, &ref_name) {
+            Ok(old_commit) => Some(old_commit),
+            Err(ostree::OstreeError::NoSuchRef(_)) => None,
+            Err(e) => return Err(e.into()),
+        };
+        let old = match old_commit {
+            Some(ref old_commit) => Some(ostree::get_commit(repo_path, old_commit)?),
+            None => None,
+        };
+        let old_size = old_commit.as_ref().and_then(|old_commit| diff_commit_size(std::slice::from_ref(repo_path), old_commit));
+
+        let ref_diff = RefDiff {
+            ref_name,
+            old_commit,
+            new_commit,
+            old_size,
+            new_size,
+            size_change: match (old_size, new_size) {
+                (Some(old_size), Some(new_size)) => Some(new_size - old_size),
+                _ => None,
+            },
+        };
+        match old {
+            None => diff.new.push(ref_diff),
+            Some(ref old) if old.root_tree == new.root_tree && old.root_metadata == new.root_metadata =>
+                diff.unchanged.push(ref_diff),
+            Some(_) => diff.updated.push(ref_diff),
+        }
+    }
+    Ok(diff)
+}
+
+pub fn get_build_diff(
+    params: Path<BuildPathParams>,
+    db: Data<Db>,
+    config: Data<Config>,
+    req: HttpRequest,
+) -> impl Future<Item = HttpResponse, Error = ApiError> {
+    let build_id = params.id;
+    let db2 = db.clone();
+    futures::done(req.has_token_claims(&format!("build/{}", build_id), "build"))
+        .and_then(move |_| db.lookup_build(build_id))
+        .and_then(move |build| {
+            req.has_token_repo(&build.repo)?;
+            match RepoState::from_db(build.repo_state, &build.repo_state_reason) {
+                RepoState::Ready => Ok(build),
+                state => Err(ApiError::WrongRepoState("Build is not committed".to_string(), "ready".to_string(),
+                                                      format!("{:?}", state).to_lowercase())),
+            }
+        })
+        .and_then(move |build| {
+            db2.lookup_build_refs(build_id)
+                .and_then(move |build_refs| {
+                    let repoconfig = config.get_repoconfig(&build.repo)?;
+                    let build_repo_path = config.build_repo_base.join(build_id.to_string());
+                    let repo_path = repoconfig.get_abs_repo_path();
+                    let ref_names = build_refs.into_iter()
+                        .map(|build_ref| build_ref.ref_name)
+                        .filter(|ref_name| ref_name.starts_with("app/") || ref_name.starts_with("runtime/"))
+                        .collect::<Vec<String>>();
+                    Ok((build_repo_path, repo_path, ref_names))
+                })
+        })
+        .and_then(|(build_repo_path, repo_path, ref_names)| {
+            web::block(move || diff_build_refs(&build_repo_path, &repo_path, ref_names))
+                .map_err(ApiError::from)
+        })
+        .and_then(|diff| Ok(HttpResponse::Ok().json(diff)))
+}
+
+#[derive(Deserialize)]
+pub struct RepoPathParams {
+    repo: String,
+}
+
+#[derive(Deserialize)]
+pub struct DeltaListArgs {
+    #[serde(rename = "ref")]
+    ref_name: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct DeltaInfo {
+    from: Option<String>,
+    to: String,
+    size: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct RefDeltas {
+    #[serde(rename = "ref")] ref_name: String,
+    commit: String,
+    wanted_depth: u32,
+    deltas: Vec<DeltaInfo>,
+    missing: Vec<DeltaInfo>,
+}
+
+/* Don't follow very long histories of unpruned repos */
+const MAX_DELTA_HISTORY: usize = 100;
+
+fn delta_info(repo_path: &path::PathBuf, delta: &ostree::Delta) -> DeltaInfo {
+    DeltaInfo {
+        from: delta.from.clone(),
+        to: delta.to.clone(),
+        size: delta.size(repo_path).ok(),
+    }
+}
+
+/* Sorts the existing deltas by the ref whose history they lead to, next
+ * to the ones wanted by the delta configuration that are missing */
+fn list_ref_deltas(repoconfig: &RepoConfig, only_ref: Option<String>) -> Result<Vec<RefDeltas>, ApiError> {
+    let repo_path = repoconfig.get_abs_repo_path();
+    let existing = ostree::list_deltas(&repo_path);
+
+    let mut ref_names = ostree::list_refs(&repo_path, "");
+    ref_names.sort();
+    let mut res = vec![];
+    for ref_name in ref_names {
+        if only_ref.as_ref().is_some_and(|only_ref| only_ref != &ref_name) {
+            continue;
+        }
+        let head = match ostree::parse_ref(&repo_path, &ref_name) {
+            Ok(head) => head,
+            Err(_) => continue,
+        };
+        let mut history = vec![head.clone()];
+        while history.len() < MAX_DELTA_HISTORY {
+            match ostree::get_commit(&repo_path, history.last().unwrap()).ok().and_then(|commit| commit.parent) {
+                Some(parent) => history.push(parent),
+                None => break,
+            }
+        }
+
+        let depth = repoconfig.get_delta_depth_for_ref(&ref_name);
+        let wanted = ostree::calc_deltas_for_ref(&repo_path, &ref_name, depth);
+        res.push(RefDeltas {
+            deltas: existing.iter()
+                .filter(|delta| history.contains(&delta.to))
+                .map(|delta| delta_info(&repo_path, delta))
+                .collect(),
+            missing: wanted.iter()
+                .filter(|delta| !existing.contains(delta))
+                .map(|delta| DeltaInfo { from: delta.from.clone(), to: delta.to.clone(), size: None })
+                .collect(),
+            ref_name,
+            commit: head,
+            wanted_depth: depth,
+        });
+    }
+    if let Some(only_ref) = only_ref {
+        if res.is_empty() {
+            return Err(ApiError::BadRequest(format!("No ref {} in repo {}", only_ref, repoconfig.name)));
+        }
+    }
+    Ok(res)
+}
+
+pub fn list_deltas(
+    params: Path<RepoPathParams>,
+    args: web::Query<DeltaListArgs>,
+    config: Data<Config>,
+    req: HttpRequest,
+) -> impl Future<Item = HttpResponse, Error = ApiError> {
+    futures::done(req.has_token_claims("build", "build")
+                  .and_then(|_| req.has_token_repo(&params.repo))
+                  .and_then(|_| Ok(config.get_repoconfig(&params.repo)?.clone())))
+        .and_then(move |repoconfig| {
+            let only_ref = args.into_inner().ref_name;
+            web::block(move || list_ref_deltas(&repoconfig, only_ref))
+                .map_err(ApiError::from)
+        })
+        .and_then(|deltas| Ok(HttpResponse::Ok().json(deltas)))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MissingObjectsArgs {
+    wanted: Vec<String>
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MissingObjectsResponse {
+    missing: Vec<String>
+}
+
+fn has_object (build_id: i32,
+               subpath: &path::Path,
+               config: &Data<Config>) -> bool
+{
+    let build_path = config.build_repo_base.join(build_id.to_string()).join("upload").join(&subpath);
+    if build_path.exists() {
+        true
+    } else {
+        let parent_path = config.build_repo_base.join(build_id.to_string()).join("parent").join(&subpath);
+        parent_path.exists() ||
+            objectpool::link_from_pool(config, subpath, &config.build_repo_base.join(build_id.to_string()).join("upload"))
+    }
+}
+
+pub fn missing_objects(
+    args: Json<MissingObjectsArgs>,
+    params: Path<BuildPathParams>,
+    config: Data<Config>,
+    req: HttpRequest,
+) -> HttpResponse {
+    if let Err(e) = req.has_token_claims(&format!("build/{}", params.id), "upload") {
+        return e.error_response();
+    }
+    let mut missing = vec![];
+    for object in &args.wanted {
+        let subpath = match filename_parse_object(object) {
+            Some(subpath) => subpath,
+            None => return ApiError::BadRequest(format!("Invalid object name {}", object)).error_response(),
+        };
+        if ! has_object (params.id, &subpath, &config) {
+            missing.push(object.to_string());
+        }
+    }
+    HttpResponse::Ok()
+        .encoding(http::header::ContentEncoding::Gzip)
+        .json(MissingObjectsResponse { missing: missing })
+}
+
+fn validate_ref (ref_name: &String, req: &HttpRequest) -> Result<(),ApiError>
 {
     let ref_parts: Vec<&str> = ref_name.split('/').collect();
 
@@ -297,35 +1119,72 @@ fn validate_ref (ref_name: &String, req: &HttpRequest) -> Result<(),ApiError>
     }
 }
 
+/* Branch renames for publishing, e.g. test to stable */
+fn validate_branches (branches: &HashMap<String, String>) -> Result<(),ApiError>
+{
+    for branch in branches.keys().chain(branches.values()) {
+        if branch.is_empty() || branch.contains('/') || branch.contains(char::is_whitespace) {
+            return Err(ApiError::BadRequest(format!("Invalid branch {}", branch)))
+        }
+    }
+    Ok(())
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct CreateBuildRefArgs {
     #[serde(rename = "ref")] ref_name: String,
     commit: String,
+    #[serde(default)]
+    force: bool, // Allow refs that are end-of-life in the repo
+}
+
+/* The size is only informative, so failing to get it is not an error */
+fn commit_size(config: &Data<Config>, build_id: i32, commit: &str) -> impl Future<Item = Option<i64>, Error = ApiError> {
+    let upload_path = config.build_repo_base.join(build_id.to_string()).join("upload");
+    let repo_paths = vec![upload_path.join("parent"), upload_path];
+    let commit = commit.to_string();
+    let commit2 = commit.clone();
+    web::block(move || ostree::get_commit_size(&repo_paths, &commit))
+        .then(move |r| match r {
+            Ok(size) => Ok(Some(size as i64)),
+            Err(e) => {
+                warn!("Can't get size of commit {} in build {}: {}", commit2, build_id, e);
+                Ok(None)
+            },
+        })
 }
 
 pub fn create_build_ref (
     args: Json<CreateBuildRefArgs>,
     params: Path<BuildPathParams>,
     db: Data<Db>,
+    config: Data<Config>,
     req: HttpRequest,
 ) -> impl Future<Item = HttpResponse, Error = ApiError> {
     futures::done(req.has_token_claims(&format!("build/{}", params.id), "upload")
                   .and_then(|_| validate_ref(&args.ref_name, &req)))
         .and_then(move |_| {
             let build_id = params.id;
+            let db2 = db.clone();
             db
                 .lookup_build(params.id)
                 .and_then (move |build| futures::done(req.has_token_repo(&build.repo))
-                           .and_then (move |_ok| {
-                               db.new_build_ref (
-                                   NewBuildRef {
-                                       build_id: build_id,
-                                       ref_name: args.ref_name.clone(),
-                                       commit: args.commit.clone(),
-                                   })
+                           .and_then (move |_ok| commit_size(&config, build_id, &args.commit)
+                                      .and_then (move |size| {
+                                          db.new_build_ref (
+                                              NewBuildRef {
+                                                  build_id,
+                                                  ref_name: args.ref_name.clone(),
+                                                  commit: args.commit.clone(),
+                                                  size,
+                                              }, args.force)
+                                      }))
+                           .and_then(move |buildref| {
+                               audit_log(&db2, &req, "create-build-ref",
+                                         json!({ "build": buildref.build_id, "ref": buildref.ref_name, "commit": buildref.commit }));
+                               respond_with_url(&buildref, &req, "show_build_ref",
+                                                &[params.id.to_string(), buildref.id.to_string()])
                            })
-                           .and_then(move |buildref| respond_with_url(&buildref, &req, "show_build_ref",
-                                                                      &[params.id.to_string(), buildref.id.to_string()]))
                 )
         })
 }
@@ -356,6 +1215,7 @@ pub fn add_extra_ids (
         .and_then(move |_| {
             let req2 = req.clone();
             let build_id = params.id;
+            let db2 = db.clone();
             db
                 .lookup_build(params.id)
                 .and_then (move |build| {
@@ -363,8 +1223,11 @@ pub fn add_extra_ids (
                     req2.has_token_repo(&build.repo)
                 })
                 .and_then (move |_ok| db.add_extra_ids(build_id, args.ids.clone()))
-                .and_then(move |build| respond_with_url(&build, &req, "show_build",
-                                                        &[build_id.to_string()]))
+                .and_then(move |build| {
+                    audit_log(&db2, &req, "add-extra-ids", json!({ "build": build_id, "extra-ids": build.extra_ids }));
+                    respond_with_build(build, &req, "show_build",
+                                       &[build_id.to_string()])
+                })
         })
 }
 
@@ -394,6 +1257,65 @@ fn filename_parse_object(filename: &str) -> Option<path::PathBuf> {
     Some(path::Path::new("objects").join(&filename[..2]).join(&filename[2..]))
 }
 
+/* Metadata objects are stored as is in archive repos, so their filename
+ * is the sha256 of the uploaded data and we can verify it while saving. */
+fn filename_object_checksum(filename: &str) -> Option<String> {
+    filename_parse_object(filename)?;
+    let v: Vec<&str> = filename.split(".").collect();
+    if v[1] == "filez" {
+        return None
+    }
+    Some(v[0].to_string())
+}
+
+/* Checks that an uploaded object matches the checksum in its filename.
+ * For file objects the checksum covers the uncompressed content and the
+ * file info, so these are decompressed and checked like ostree does once
+ * saved, which can take a while. */
+enum ObjectVerifier {
+    Unchecked,
+    Metadata(String, Sha256),
+    Filez(String),
+}
+
+impl ObjectVerifier {
+    fn new(filename: &str) -> ObjectVerifier {
+        if let Some(checksum) = filename_object_checksum(filename) {
+            return ObjectVerifier::Metadata(checksum, Sha256::new());
+        }
+        match filename_parse_object(filename) {
+            Some(_) => ObjectVerifier::Filez(filename[..64].to_string()),
+            None => ObjectVerifier::Unchecked,
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        if let ObjectVerifier::Metadata(_, ref mut hasher) = *self {
+            hasher.update(data);
+        }
+    }
+
+    fn is_blocking(&self) -> bool {
+        matches!(*self, ObjectVerifier::Filez(_))
+    }
+
+    fn finish(self, saved_path: &path::Path) -> Result<(), ApiError> {
+        let (expected, checksum) = match self {
+            ObjectVerifier::Unchecked => return Ok(()),
+            ObjectVerifier::Metadata(expected, hasher) => (expected, hex::encode(hasher.finish())),
+            ObjectVerifier::Filez(expected) => {
+                let checksum = ostree::get_filez_checksum(&saved_path.to_path_buf())
+                    .map_err(|e| ApiError::BadRequest(format!("Object {}: {}", expected, e)))?;
+                (expected, checksum)
+            },
+        };
+        if checksum != expected {
+            return Err(ApiError::BadRequest(format!("Object {} has wrong checksum {}", expected, checksum)));
+        }
+        Ok(())
+    }
+}
+
 fn is_all_digits(s: &str) -> bool {
     !s.contains(|c: char| !c.is_digit(10))
 }
@@ -448,6 +1370,7 @@ fn get_upload_subpath(field: &actix_multipart::Field,
         }
     }
 
+    /* Deltas to the uploaded commits, installed in the repo when published */
     if let Some(path) = filename_parse_delta(filename) {
         return Ok(path)
     }
@@ -455,14 +1378,58 @@ fn get_upload_subpath(field: &actix_multipart::Field,
     Err(ApiError::BadRequest("Invalid upload filename".to_string()))
 }
 
+/* What is left of the upload quotas of the token. The bytes are
+ * counted as they are written, the Content-Length of an upload (if
+ * any) is only checked up front. */
+#[derive(Default)]
+struct UploadQuota {
+    left: Option<(u64, String)>, // The bytes left, and the error for going over them
+    written: AtomicU64,
+}
+
+impl UploadQuota {
+    fn check(&self, size: u64) -> Result<(), ApiError> {
+        match self.left {
+            Some((left, ref message)) if size > left => Err(ApiError::QuotaExceeded(message.clone())),
+            _ => Ok(()),
+        }
+    }
+
+    fn count(&self, bytes: u64) -> Result<(), ApiError> {
+        let written = self.written.fetch_add(bytes, Ordering::SeqCst) + bytes;
+        self.check(written)
+    }
+}
+
 struct UploadState {
     repo_path: path::PathBuf,
     only_deltas: bool,
+    quota: UploadQuota,
+    /* The objects saved so far, which stay even if the upload fails later */
+    saved_objects: AtomicI64,
+    saved_bytes: AtomicI64,
+}
+
+impl UploadState {
+    fn new(repo_path: path::PathBuf, only_deltas: bool, quota: UploadQuota) -> UploadState {
+        UploadState {
+            repo_path,
+            only_deltas,
+            quota,
+            saved_objects: AtomicI64::new(0),
+            saved_bytes: AtomicI64::new(0),
+        }
+    }
+
+    fn add_saved(&self, size: i64) {
+        self.saved_objects.fetch_add(1, Ordering::SeqCst);
+        self.saved_bytes.fetch_add(size, Ordering::SeqCst);
+    }
 }
 
 fn start_save(
     subpath: &path::PathBuf,
-    state: &Arc<UploadState>,
+    state: &UploadState,
 ) -> Result<(NamedTempFile,path::PathBuf)> {
 
     let absolute_path = state.repo_path.join(subpath);
@@ -487,6 +1454,11 @@ fn save_file(
         Err(e) => return Box::new(future::err(e)),
     };
 
+    let verifier = match field.content_disposition().and_then(|cd| cd.get_filename().map(ObjectVerifier::new)) {
+        Some(verifier) if !state.only_deltas => verifier,
+        _ => ObjectVerifier::Unchecked,
+    };
+
     let (named_file, object_file) = match start_save (&repo_subpath, state) {
         Ok((named_file, object_file)) => (named_file, object_file),
         Err(e) => return Box::new(future::err(ApiError::InternalServerError(e.to_string()))),
@@ -495,24 +1467,38 @@ fn save_file(
     // We need file in two continuations below, so put it in a Rc+RefCell
     let shared_file = Rc::new(RefCell::new(named_file));
     let shared_file2 = shared_file.clone();
+    let verifier = Rc::new(RefCell::new(verifier));
+    let verifier2 = verifier.clone();
+    let state = state.clone();
+    let state2 = state.clone();
     Box::new(
         field
-            .fold(0i64, move |acc, bytes| {
-                let rt = shared_file.borrow_mut()
-                    .write_all(bytes.as_ref())
-                    .map(|_| acc + bytes.len() as i64)
-                    .map_err(|e| {
-                        actix_multipart::MultipartError::Payload(error::PayloadError::Io(e))
-                    });
-                future::result(rt)
-            })
             .map_err(|e| {
                 ApiError::InternalServerError(e.to_string())
             })
+            .fold(0i64, move |acc, bytes| {
+                verifier.borrow_mut().update(bytes.as_ref());
+                let rt = state.quota.count(bytes.len() as u64)
+                    .and_then(|_| shared_file.borrow_mut()
+                              .write_all(bytes.as_ref())
+                              .map_err(ApiError::from))
+                    .map(|_| acc + bytes.len() as i64);
+                future::result(rt)
+            })
             .and_then (move |res| {
                 // persist consumes the named file, so we need to
                 // completely move it out of the shared Rc+RefCell
                 let named_file = Rc::try_unwrap(shared_file2).unwrap().into_inner();
+                let verifier = Rc::try_unwrap(verifier2).ok().unwrap().into_inner();
+                // Dropping the named file on errors removes it
+                if verifier.is_blocking() {
+                    future::Either::A(web::block(move || verifier.finish(named_file.path()).map(|_| (res, named_file)))
+                                      .map_err(ApiError::from))
+                } else {
+                    future::Either::B(future::result(verifier.finish(named_file.path()).map(|_| (res, named_file))))
+                }
+            })
+            .and_then (move |(res, named_file)| {
                 match named_file.persist(&object_file) {
                     Ok(persisted_file) => {
                         if let Ok(metadata) = persisted_file.metadata() {
@@ -524,6 +1510,7 @@ fn save_file(
                         } else {
                             warn!("Can't get permissions on uploaded file");
                         };
+                        state2.add_saved(res);
                         future::result(Ok(res))
                     },
                     Err(e) => future::err(ApiError::InternalServerError(e.to_string()))
@@ -532,34 +1519,277 @@ fn save_file(
     )
 }
 
+/* Refuses uploads once the subject of the token, or an organization
+ * it is a member of, has uploaded its quota. The returned quota has
+ * what is left, to count the upload against while it is written. */
+fn check_upload_quota(req: &HttpRequest, db: &Db, config: &Config) -> impl Future<Item = UploadQuota, Error = ApiError> {
+    let sub = req.get_claims().map(|claims| claims.usage_subject().to_string());
+    let quota = match (&sub, &config.upload_quota) {
+        (Some(sub), Some(upload_quota)) => upload_quota.get_quota(sub),
+        _ => None,
+    };
+    let request_size = req.headers().get(http::header::CONTENT_LENGTH)
+        .and_then(|len| len.to_str().ok())
+        .and_then(|len| len.parse::<u64>().ok())
+        .unwrap_or(0);
+    let organization_left = db.get_organization_quota_left(publisher_name(req));
+    let subject_left = match (sub, quota) {
+        (Some(sub), Some(quota)) => future::Either::B(db.get_token_usage(sub.clone())
+            .map(move |used| Some((quota.saturating_sub(used.max(0) as u64),
+                                   format!("Upload quota of {} bytes for {} exceeded ({} bytes used before this upload)", quota, sub, used))))),
+        _ => future::Either::A(future::ok(None)),
+    };
+    subject_left.join(organization_left)
+        .and_then(move |(subject_left, organization_left)| {
+            let quota = UploadQuota {
+                left: subject_left.into_iter().chain(organization_left).min_by_key(|(left, _)| *left),
+                ..Default::default()
+            };
+            quota.check(request_size)?;
+            Ok(quota)
+        })
+}
+
+fn validate_accepting_uploads(build: &Build) -> Result<(), ApiError> {
+    /* The upload repo of a deleted build goes away when it is purged */
+    if build.deleted_at.is_some() {
+        return Err(ApiError::BadRequest("Build has been deleted".to_string()))
+    }
+    /* The commit job may hardlink the uploaded objects after verifying them,
+     * so they must not change once the build is being committed */
+    match RepoState::from_db(build.repo_state, &build.repo_state_reason) {
+        RepoState::Uploading => Ok(()),
+        state => Err(ApiError::WrongRepoState("Build is not accepting uploads".to_string(), "uploading".to_string(),
+                                              format!("{:?}", state).to_lowercase())),
+    }
+}
+
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/* Limits on what an object tar may unpack to, as a small compressed
+ * tar could otherwise fill the disk */
+const MAX_TAR_OBJECT_SIZE: u64 = 4 * 1024 * 1024 * 1024;
+const MAX_TAR_TOTAL_SIZE: u64 = 64 * 1024 * 1024 * 1024;
+
+/* Unpacks a tar stream (optionally zstd compressed) of objects, with the
+ * same names as in multipart uploads, into the upload repo */
+fn unpack_object_tar(file: fs::File, state: &UploadState) -> Result<Vec<i64>, ApiError> {
+    unpack_object_tar_limited(file, state, MAX_TAR_OBJECT_SIZE, MAX_TAR_TOTAL_SIZE)
+}
+
+fn unpack_object_tar_limited(mut file: fs::File, state: &UploadState,
+                             max_object_size: u64, max_total_size: u64) -> Result<Vec<i64>, ApiError> {
+    /* The file was just spooled, so it is positioned at its end */
+    file.seek(SeekFrom::Start(0))?;
+    let mut magic = [0u8; 4];
+    let is_zstd = file.read_exact(&mut magic).is_ok() && magic == ZSTD_MAGIC;
+    file.seek(SeekFrom::Start(0))?;
+    let reader: Box<dyn Read> = if is_zstd {
+        Box::new(zstd::stream::read::Decoder::new(file)?)
+    } else {
+        Box::new(file)
+    };
+
+    let mut sizes = vec![];
+    let mut total_size = 0u64;
+    let mut archive = tar::Archive::new(reader);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if entry.header().entry_type() != tar::EntryType::Regular {
+            return Err(ApiError::BadRequest("Only regular files are allowed in object tars".to_string()));
+        }
+        let filename = entry.path()?.to_string_lossy().into_owned();
+        if filename.contains('/') {
+            return Err(ApiError::BadRequest(format!("Invalid object name {}", filename)));
+        }
+        let subpath = filename_parse_object(&filename)
+            .ok_or_else(|| ApiError::BadRequest(format!("Invalid object name {}", filename)))?;
+
+        /* Reading the entry stops at the size in its header */
+        let size = entry.header().size()?;
+        if size > max_object_size {
+            return Err(ApiError::BadRequest(format!("Object {} is too large", filename)));
+        }
+        total_size += size;
+        if total_size > max_total_size {
+            return Err(ApiError::BadRequest("Object tar is too large".to_string()));
+        }
+
+        let (mut named_file, object_file) = start_save(&subpath, state)
+            .map_err(|e| ApiError::InternalServerError(e.to_string()))?;
+
+        let mut verifier = ObjectVerifier::new(&filename);
+        let mut written = 0i64;
+        let mut buf = [0u8; 65536];
+        loop {
+            let n = entry.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            verifier.update(&buf[..n]);
+            state.quota.count(n as u64)?;
+            named_file.write_all(&buf[..n])?;
+            written += n as i64;
+        }
+        /* This runs in a blocking thread already, so file objects can be checked here too */
+        named_file.flush()?;
+        verifier.finish(named_file.path())?;
+        named_file.persist(&object_file).map_err(|e| ApiError::InternalServerError(e.to_string()))?;
+        fs::set_permissions(&object_file, fs::Permissions::from_mode(0o644))?;
+        state.add_saved(written);
+        sizes.push(written);
+    }
+    Ok(sizes)
+}
+
+/* The objects saved before an upload failed stay in the upload repo,
+ * so they are counted too */
+fn record_upload_stats(db: &Db, build_id: i32, sub: Option<String>, identity: Option<String>,
+                       state: &UploadState) -> impl Future<Item = (), Error = ApiError> {
+    db.add_upload_stats(build_id, sub, identity,
+                        state.saved_objects.load(Ordering::SeqCst),
+                        state.saved_bytes.load(Ordering::SeqCst))
+}
+
+/* The repo is that of the build, once it has been looked up */
+fn observe_upload(endpoint: &str, repo: Option<&str>, res: &Result<HttpResponse, ApiError>, start: Instant) {
+    let result = match res {
+        Ok(_) => "ok",
+        Err(_) => "error",
+    };
+    metrics::observe(metrics::UPLOAD_REQUEST_DURATION,
+                     &[("endpoint", endpoint), ("repo", repo.unwrap_or("unknown")), ("result", result)],
+                     start.elapsed());
+}
+
+pub fn upload_tar(
+    payload: web::Payload,
+    req: HttpRequest,
+    params: Path<BuildPathParams>,
+    db: Data<Db>,
+    config: Data<Config>,
+) -> impl Future<Item = HttpResponse, Error = ApiError> {
+    let start = Instant::now();
+    let upload_repo = Rc::new(RefCell::new(None));
+    let build_repo = upload_repo.clone();
+    futures::done(req.has_token_claims(&format!("build/{}", params.id), "upload")
+                  .and_then(|_| diskspace::check_free_space(&config, &config.build_repo_base)))
+        .and_then(move |_| {
+            let repo_path = config.build_repo_base.join(params.id.to_string()).join("upload");
+            let req2 = req.clone();
+            let build_id = params.id;
+            let db2 = db.clone();
+            let sub = req.get_claims().map(|claims| claims.usage_subject().to_string());
+            let identity = publisher_name(&req);
+            let quota_check = check_upload_quota(&req, &db, &config);
+            let bytes_per_second = config.upload_bytes_per_second;
+            let db3 = db.clone();
+            db
+                .lookup_build(build_id)
+                .and_then (move |build| {
+                    *build_repo.borrow_mut() = Some(build.repo.clone());
+                    req2.has_token_repo(&build.repo)?;
+                    Ok(build)
+                })
+                /* Only uploads allowed for the build keep its session alive */
+                .and_then (move |build| {
+                    db3.touch_upload_session(build_id)
+                        .and_then(move |_| validate_accepting_uploads(&build))
+                })
+                .and_then (move |_ok| quota_check)
+                .and_then (move |quota| {
+                    let uploadstate = Arc::new(UploadState::new(repo_path, false, quota));
+                    let uploadstate2 = uploadstate.clone();
+                    let uploadstate3 = uploadstate.clone();
+                    /* Spool the stream to disk first, unpacking is blocking. The
+                     * unpacked objects are what counts for the quota, but the
+                     * spooled stream can't be larger than that either. */
+                    let tmp_dir = uploadstate.repo_path.join("tmp");
+                    futures::done(fs::create_dir_all(&tmp_dir)
+                                  .and_then(|_| tempfile::tempfile_in(&tmp_dir))
+                                  .map_err(ApiError::from))
+                        .and_then(move |file| {
+                            Throttled::new(payload, bytes_per_second)
+                                .map_err(|e| ApiError::InternalServerError(e.to_string()))
+                                .fold((file, 0u64), move |(mut file, spooled), bytes| {
+                                    let spooled = spooled + bytes.len() as u64;
+                                    uploadstate2.quota.check(spooled)
+                                        .and_then(|_| file.write_all(bytes.as_ref()).map_err(ApiError::from))
+                                        .map(|_| (file, spooled))
+                                })
+                        })
+                        .and_then(move |(file, _)| {
+                            web::block(move || unpack_object_tar(file, &uploadstate3))
+                                .map_err(ApiError::from)
+                        })
+                        .then(move |res| {
+                            record_upload_stats(&db2, build_id, sub, identity, &uploadstate)
+                                .and_then(move |_| res)
+                        })
+                })
+                .map(|sizes| HttpResponse::Ok().json(sizes))
+        })
+        .then(move |res| {
+            observe_upload("upload_tar", upload_repo.borrow().as_deref(), &res, start);
+            res
+        })
+}
+
 pub fn upload(
-    multipart: Multipart,
+    payload: web::Payload,
     req: HttpRequest,
     params: Path<BuildPathParams>,
     db: Data<Db>,
     config: Data<Config>,
 ) -> impl Future<Item = HttpResponse, Error = ApiError> {
-    futures::done(req.has_token_claims(&format!("build/{}", params.id), "upload"))
+    let multipart = Multipart::new(req.headers(), Throttled::new(payload, config.upload_bytes_per_second));
+    let start = Instant::now();
+    let upload_repo = Rc::new(RefCell::new(None));
+    let build_repo = upload_repo.clone();
+    futures::done(req.has_token_claims(&format!("build/{}", params.id), "upload")
+                  .and_then(|_| diskspace::check_free_space(&config, &config.build_repo_base)))
         .and_then(move |_| {
-            let uploadstate = Arc::new(UploadState {
-                only_deltas: false,
-                repo_path: config.build_repo_base.join(params.id.to_string()).join("upload")
-            });
+            let repo_path = config.build_repo_base.join(params.id.to_string()).join("upload");
             let req2 = req.clone();
+            let build_id = params.id;
+            let db2 = db.clone();
+            let sub = req.get_claims().map(|claims| claims.usage_subject().to_string());
+            let identity = publisher_name(&req);
+            let quota_check = check_upload_quota(&req, &db, &config);
+            let db3 = db.clone();
             db
-                .lookup_build(params.id)
-                .and_then (move |build| req2.has_token_repo(&build.repo))
-                .and_then (move |_ok| {
+                .lookup_build(build_id)
+                .and_then (move |build| {
+                    *build_repo.borrow_mut() = Some(build.repo.clone());
+                    req2.has_token_repo(&build.repo)?;
+                    Ok(build)
+                })
+                /* Only uploads allowed for the build keep its session alive */
+                .and_then (move |build| {
+                    db3.touch_upload_session(build_id)
+                        .and_then(move |_| validate_accepting_uploads(&build))
+                })
+                .and_then (move |_ok| quota_check)
+                .and_then (move |quota| {
+                    let uploadstate = Arc::new(UploadState::new(repo_path, false, quota));
+                    let uploadstate2 = uploadstate.clone();
                     multipart
                         .map_err(|e| ApiError::InternalServerError(e.to_string()))
                         .map(move |field| {
-                            save_file(field, &uploadstate).into_stream()
+                            save_file(field, &uploadstate2).into_stream()
                         })
                         .flatten()
                         .collect()
-                        .map(|sizes| HttpResponse::Ok().json(sizes))
-                        .from_err()
+                        .then(move |res| {
+                            record_upload_stats(&db2, build_id, sub, identity, &uploadstate)
+                                .and_then(move |_| res)
+                        })
                 })
+                .map(|sizes| HttpResponse::Ok().json(sizes))
+        })
+        .then(move |res| {
+            observe_upload("upload", upload_repo.borrow().as_deref(), &res, start);
+            res
         })
 }
 
@@ -571,7 +1801,7 @@ pub fn get_commit_job(
 ) -> impl Future<Item = HttpResponse, Error = ApiError> {
     futures::done(req.has_token_claims(&format!("build/{}", params.id), "build"))
         .and_then(move |_|  db.lookup_commit_job(params.id, args.log_offset))
-        .and_then(|job| Ok(HttpResponse::Ok().json(job)))
+        .and_then(move |job| Ok(job_response(job, &req)))
 }
 
 #[derive(Deserialize)]
@@ -579,78 +1809,1023 @@ pub struct CommitArgs {
     endoflife: Option<String>,
     endoflife_rebase: Option<String>,
     token_type: Option<i32>,
+    metadata: Option<serde_json::Value>,
+    timestamp: Option<String>, // RFC 3339, for repos with the supplied commit-timestamp policy
+    publish_note: Option<String>, // Only used by commit_and_publish
+    publish_arches: Option<Vec<String>>, // Only used by commit_and_publish
+    #[serde(default)]
+    publish_branches: HashMap<String, String>, // Only used by commit_and_publish
 }
 
-pub fn commit(
-    args: Json<CommitArgs>,
-    params: Path<BuildPathParams>,
-    job_queue: Data<Addr<JobQueue>>,
-    db: Data<Db>,
-    req: HttpRequest,
-) -> impl Future<Item = HttpResponse, Error = ApiError> {
-    futures::done(req.has_token_claims(&format!("build/{}", params.id), "build"))
-        .and_then(move |_| {
-            let req2 = req.clone();
-            let build_id = params.id;
-            db
-                .lookup_build (build_id)
-                .and_then (move |build| req2.has_token_repo(&build.repo))
-                .and_then (move |_ok| {
-                    db.start_commit_job(build_id,
-                                        args.endoflife.clone(),
-                                        args.endoflife_rebase.clone(),
-                                        args.token_type)
-                })
-                .and_then(move |job| {
-                    job_queue.do_send(ProcessJobs(None));
-                    respond_with_url(&job, &req, "show_commit_job", &[params.id.to_string()])
-                })
-        })
+impl CommitArgs {
+    fn commit_options(&self) -> CommitOptions {
+        CommitOptions {
+            endoflife: self.endoflife.clone(),
+            endoflife_rebase: self.endoflife_rebase.clone(),
+            token_type: self.token_type,
+            timestamp: self.timestamp.clone(),
+            metadata: self.metadata.clone(),
+        }
+    }
 }
 
-pub fn get_publish_job(
-    args: Json<JobArgs>,
-    params: Path<BuildPathParams>,
-    db: Data<Db>,
-    req: HttpRequest,
-) -> impl Future<Item = HttpResponse, Error = ApiError> {
+/* Checks the end-of-life policy of the repo, if the commit marks refs
+ * end-of-life or rebases them */
+fn check_eol_policy(args: &CommitArgs, build: &Build, config: &Config, req: &HttpRequest) -> Result<(), ApiError> {
+    if args.endoflife.is_none() && args.endoflife_rebase.is_none() {
+        return Ok(());
+    }
+    let policy = match config.get_repoconfig(&build.repo)?.eol_policy {
+        Some(ref policy) => policy,
+        None => return Ok(()),
+    };
+    req.has_token_claims(&format!("build/{}", build.id), &policy.required_scope)?;
+    check_eol_reason(&args.endoflife, policy.min_reason_length)
+        .map_err(|min_length| ApiError::BadRequest(format!("An end-of-life reason of at least {} characters is required in repo {}",
+                                                           min_length, build.repo)))
+}
+
+/* Only marking refs end-of-life needs a reason, a rebase alone is
+ * described by the new ref. On failure returns the required length. */
+fn check_eol_reason(endoflife: &Option<String>, min_reason_length: usize) -> Result<(), usize> {
+    let reason = match endoflife {
+        Some(ref reason) => reason.trim(),
+        None => return Ok(()),
+    };
+    let min_length = min_reason_length.max(1);
+    if reason.chars().count() < min_length {
+        return Err(min_length);
+    }
+    Ok(())
+}
+
+fn check_commit_timestamp(args: &CommitArgs, build: &Build, config: &Config) -> Result<(), ApiError> {
+    let timestamp = match args.timestamp {
+        Some(ref timestamp) => timestamp,
+        None => return Ok(()),
+    };
+    if config.get_repoconfig(&build.repo)?.commit_timestamp != CommitTimestamp::Supplied {
+        return Err(ApiError::BadRequest(format!("Repo {} does not accept commit timestamps", build.repo)));
+    }
+    chrono::DateTime::parse_from_rfc3339(timestamp)
+        .map_err(|e| ApiError::BadRequest(format!("Invalid timestamp {}: {}", timestamp, e)))?;
+    Ok(())
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct LoadSheddingState {
+    shedding: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+    queued_jobs: i64,
+}
+
+fn get_load_shedding_state(config: &Config, db: &Db) -> impl Future<Item = Option<LoadSheddingState>, Error = ApiError> {
+    let load_shedding = match config.load_shedding {
+        Some(ref load_shedding) => load_shedding.clone(),
+        None => return future::Either::A(future::ok(None)),
+    };
+    /* The same limit as for uploads and jobs, from min-free-space-mb */
+    let low_on_space = iter::once(&config.build_repo_base)
+        .chain(config.repos.values().map(|repoconfig| &repoconfig.path)).find(|path| !diskspace::has_free_space(config, path))
+        .map(|path| format!("Low on disk space in {}", path.display()));
+    future::Either::B(db.count_queued_jobs()
+        .map(move |queued_jobs| {
+            let reason = match load_shedding.max_queued_jobs {
+                Some(max) if queued_jobs > max => Some(format!("{} jobs are queued", queued_jobs)),
+                _ => low_on_space,
+            };
+            Some(LoadSheddingState {
+                shedding: reason.is_some(),
+                reason,
+                queued_jobs,
+            })
+        }))
+}
+
+/* New commits and publishes are refused while the job queue is backed up
+ * or the disks are nearly full, rather than queueing work that would wait
+ * for hours. Uploads can go on, so the builds are ready when it clears up. */
+fn check_load_shedding(config: &Config, db: &Db) -> impl Future<Item = (), Error = ApiError> {
+    let retry_after = config.load_shedding.as_ref().map(|l| l.retry_after_secs).unwrap_or(0);
+    get_load_shedding_state(config, db)
+        .and_then(move |state| match state {
+            Some(LoadSheddingState { reason: Some(reason), .. }) =>
+                Err(ApiError::Overloaded(format!("The server is overloaded, try again later: {}", reason), retry_after)),
+            _ => Ok(()),
+        })
+}
+
+pub fn commit(
+    args: Json<CommitArgs>,
+    params: Path<BuildPathParams>,
+    job_queue: Data<Addr<JobQueue>>,
+    db: Data<Db>,
+    config: Data<Config>,
+    req: HttpRequest,
+) -> impl Future<Item = HttpResponse, Error = ApiError> {
+    let (load_config, load_db) = (config.clone(), db.clone());
+    futures::done(req.has_token_claims(&format!("build/{}", params.id), "build"))
+        .and_then(move |_| check_load_shedding(&load_config, &load_db))
+        .and_then(move |_| {
+            let req2 = req.clone();
+            let build_id = params.id;
+            let db2 = db.clone();
+            let db3 = db.clone();
+            let identity = publisher_name(&req);
+            let job_config = config.clone().into_inner();
+            let audit_params = json!({
+                "build": build_id,
+                "endoflife": args.endoflife,
+                "endoflife-rebase": args.endoflife_rebase,
+                "token-type": args.token_type,
+                "timestamp": args.timestamp,
+            });
+            db
+                .lookup_build (build_id)
+                .and_then (move |build| {
+                    req2.has_token_repo(&build.repo)?;
+                    check_eol_policy(&args, &build, &config, &req2)?;
+                    check_commit_timestamp(&args, &build, &config)?;
+                    Ok(args)
+                })
+                .and_then (move |args| db3.check_app_acl(build_id, identity).map(move |_| args))
+                .and_then (move |args| {
+                    db.start_commit_job(job_config, build_id, args.commit_options())
+                })
+                .and_then(move |job| {
+                    audit_log(&db2, &req, "commit", audit_params);
+                    job_queue.do_send(ProcessJobs(None));
+                    respond_with_job(job, &req, "show_commit_job", &[params.id.to_string()])
+                })
+        })
+}
+
+/* Both jobs are queued in one go, and the publish job only runs once
+ * the commit job has finished. If the commit fails, so does the publish. */
+pub fn commit_and_publish(
+    args: Json<CommitArgs>,
+    params: Path<BuildPathParams>,
+    job_queue: Data<Addr<JobQueue>>,
+    db: Data<Db>,
+    config: Data<Config>,
+    req: HttpRequest,
+) -> impl Future<Item = HttpResponse, Error = ApiError> {
+    let (load_config, load_db) = (config.clone(), db.clone());
+    futures::done(req.has_token_claims(&format!("build/{}", params.id), "build")
+                  .and_then(|_| req.has_token_claims(&format!("build/{}", params.id), "publish"))
+                  .and_then(|_| validate_branches(&args.publish_branches)))
+        .and_then(move |_| check_load_shedding(&load_config, &load_db))
+        .and_then(move |_| {
+            let req2 = req.clone();
+            let build_id = params.id;
+            let db2 = db.clone();
+            let db3 = db.clone();
+            let published_by = publisher_name(&req);
+            let identity = published_by.clone();
+            let job_config = config.clone().into_inner();
+            let audit_params = json!({
+                "build": build_id,
+                "endoflife": args.endoflife,
+                "endoflife-rebase": args.endoflife_rebase,
+                "token-type": args.token_type,
+                "timestamp": args.timestamp,
+                "publish-note": args.publish_note,
+                "publish-arches": args.publish_arches,
+                "publish-branches": args.publish_branches,
+            });
+            db
+                .lookup_build (build_id)
+                .and_then (move |build| {
+                    req2.has_token_repo(&build.repo)?;
+                    check_eol_policy(&args, &build, &config, &req2)?;
+                    check_commit_timestamp(&args, &build, &config)?;
+                    Ok((build, args))
+                })
+                .and_then (move |(build, args)| db3.check_app_acl(build_id, identity).map(move |_| (build, args)))
+                .and_then (move |(build, args)| {
+                    db.start_commit_and_publish_jobs(job_config,
+                                                     build_id,
+                                                     args.commit_options(),
+                                                     PublishOptions {
+                                                         repo: build.repo.clone(),
+                                                         note: args.publish_note.clone(),
+                                                         arches: args.publish_arches.clone(),
+                                                         branches: args.publish_branches.clone(),
+                                                         published_by,
+                                                     })
+                        .map(move |jobs| (build, jobs))
+                })
+                .and_then(move |(build, (commit_job, publish_job))| {
+                    audit_log(&db2, &req, "commit-and-publish", audit_params);
+                    job_queue.do_send(ProcessJobs(None));
+                    job_queue.do_send(ProcessJobs(Some(build.repo)));
+                    let version = ApiVersion::from_request(&req);
+                    respond_with_url(&json!({
+                        "commit-job": job_json(commit_job, version),
+                        "publish-job": job_json(publish_job, version),
+                    }), &req, "show_publish_job", &[params.id.to_string()])
+                })
+        })
+}
+
+pub fn get_publish_job(
+    args: Json<JobArgs>,
+    params: Path<BuildPathParams>,
+    db: Data<Db>,
+    req: HttpRequest,
+) -> impl Future<Item = HttpResponse, Error = ApiError> {
     futures::done(req.has_token_claims(&format!("build/{}", params.id), "build"))
         .and_then(move |_|  db.lookup_publish_job(params.id, args.log_offset))
-        .and_then(|job| Ok(HttpResponse::Ok().json(job)))
+        .and_then(move |job| Ok(job_response(job, &req)))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PublishArgs {
+    wait: Option<u64>, // Seconds to wait for the publish job to finish
+    note: Option<String>, // Why the build is published, e.g. a changelog
+    arches: Option<Vec<String>>, // Only publish the refs for these arches
+    #[serde(default)]
+    branches: HashMap<String, String>, // Publish refs of the key branches as the value branches
+}
+
+const JOB_WAIT_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/* The wait is capped by max-job-wait-secs, as it holds a connection open */
+fn job_wait_timeout(wait_secs: u64, max_wait_secs: u64) -> Duration {
+    Duration::from_secs(wait_secs.min(max_wait_secs))
+}
+
+/* Polls the status until the job has finished or the timeout is
+ * reached, and returns the last state of the job either way */
+fn wait_for_job(db: Data<Db>, job_id: i32, timeout: Duration) -> impl Future<Item = Job, Error = ApiError> {
+    let deadline = Instant::now() + timeout;
+    let poll_db = db.clone();
+    future::loop_fn((), move |_| {
+        let db = poll_db.clone();
+        Delay::new(Instant::now() + JOB_WAIT_POLL_INTERVAL)
+            .map_err(|e| ApiError::InternalServerError(e.to_string()))
+            .and_then(move |_| db.lookup_job_status(job_id))
+            .map(move |status| {
+                let finished = matches!(JobStatus::from_db(status), Some(JobStatus::Ended) | Some(JobStatus::Broken));
+                if finished || Instant::now() >= deadline {
+                    future::Loop::Break(())
+                } else {
+                    future::Loop::Continue(())
+                }
+            })
+    })
+        .and_then(move |_| db.lookup_job(job_id, None))
+}
+
+/* Tokens don't necessarily have a name, fall back to the subject */
+fn claims_publisher_name(claims: &Claims) -> String {
+    claims.name.clone().unwrap_or_else(|| claims.sub.clone())
+}
+
+fn publisher_name(req: &HttpRequest) -> Option<String> {
+    req.get_claims().map(|c| claims_publisher_name(&c))
+}
+
+pub fn publish(
+    args: Json<PublishArgs>,
+    params: Path<BuildPathParams>,
+    job_queue: Data<Addr<JobQueue>>,
+    db: Data<Db>,
+    config: Data<Config>,
+    req: HttpRequest,
+) -> impl Future<Item = HttpResponse, Error = ApiError> {
+    let load_db = db.clone();
+    let job_config = config.clone().into_inner();
+    let wait_timeout = args.wait.map(|wait| job_wait_timeout(wait, config.max_job_wait_secs));
+    futures::done(req.has_token_claims(&format!("build/{}", params.id), "publish")
+                  .and_then(|_| validate_branches(&args.branches)))
+        .and_then(move |_| check_load_shedding(&config, &load_db))
+        .and_then(move |_| {
+            let build_id = params.id;
+            let req2 = req.clone();
+            let db2 = db.clone();
+            let db4 = db.clone();
+            let identity = publisher_name(&req);
+
+            db
+                .lookup_build(build_id)
+                .and_then (move |build| {
+                    req2.has_token_repo(&build.repo)?;
+                    Ok(build)
+                })
+                .and_then (move |build| db4.check_app_acl(build_id, identity).map(move |_| build))
+                .and_then (move |build| {
+                    let db3 = db.clone();
+                    db.start_publish_job(job_config, build_id, PublishOptions {
+                        repo: build.repo.clone(),
+                        note: args.note.clone(),
+                        arches: args.arches.clone(),
+                        branches: args.branches.clone(),
+                        published_by: publisher_name(&req),
+                    })
+                        .and_then(move |job| {
+                            audit_log(&db2, &req, "publish", json!({ "build": build_id, "repo": build.repo, "note": args.note,
+                                                                   "arches": args.arches, "branches": args.branches }));
+                            job_queue.do_send(ProcessJobs(Some(build.repo)));
+                            match wait_timeout {
+                                Some(timeout) => future::Either::A(wait_for_job(db3, job.id, timeout)),
+                                None => future::Either::B(future::ok(job)),
+                            }
+                                .and_then(move |job| respond_with_job(job, &req, "show_publish_job", &[params.id.to_string()]))
+                        })
+                })
+        })
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OciExportArgs {
+    repo: String,
+    refs: Vec<String>,
+}
+
+pub fn oci_export(
+    args: Json<OciExportArgs>,
+    config: Data<Config>,
+    job_queue: Data<Addr<JobQueue>>,
+    db: Data<Db>,
+    req: HttpRequest,
+) -> impl Future<Item = HttpResponse, Error = ApiError> {
+    futures::done(req.has_token_claims("build", "publish")
+                  .and_then(|_| req.has_token_repo(&args.repo))
+                  .and_then(|_| config.get_repoconfig(&args.repo).map(|_| ()))
+                  .and_then(|_| {
+                      for ref_name in args.refs.iter() {
+                          if !ref_name.starts_with("app/") && !ref_name.starts_with("runtime/") {
+                              return Err(ApiError::BadRequest(format!("Can't export {} as oci image", ref_name)))
+                          }
+                          validate_ref(ref_name, &req)?;
+                      }
+                      Ok(())
+                  }))
+        .and_then(move |_| {
+            let db2 = db.clone();
+            let repo = args.repo.clone();
+            db.start_oci_export_job(args.repo.clone(), args.refs.clone())
+                .and_then(move |job| {
+                    audit_log(&db2, &req, "oci-export", json!({ "repo": repo, "refs": args.refs }));
+                    job_queue.do_send(ProcessJobs(Some(repo)));
+                    let job_id = job.id;
+                    respond_with_job(job, &req, "show_job", &[job_id.to_string()])
+                })
+        })
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PromoteArgs {
+    from_repo: String,
+    repo: String,
+    refs: Vec<String>,
+    note: Option<String>,
+}
+
+pub fn promote(
+    args: Json<PromoteArgs>,
+    config: Data<Config>,
+    job_queue: Data<Addr<JobQueue>>,
+    db: Data<Db>,
+    req: HttpRequest,
+) -> impl Future<Item = HttpResponse, Error = ApiError> {
+    futures::done(req.has_token_claims("build", "publish")
+                  .and_then(|_| req.has_token_repo(&args.from_repo))
+                  .and_then(|_| req.has_token_repo(&args.repo))
+                  .and_then(|_| config.get_repoconfig(&args.from_repo).map(|_| ()))
+                  .and_then(|_| config.get_repoconfig(&args.repo).map(|_| ()))
+                  .and_then(|_| {
+                      if args.from_repo == args.repo {
+                          return Err(ApiError::BadRequest("Can't promote refs to the same repo".to_string()))
+                      }
+                      if args.refs.is_empty() {
+                          return Err(ApiError::BadRequest("No refs to promote".to_string()))
+                      }
+                      for ref_name in args.refs.iter() {
+                          if !ref_name.starts_with("app/") && !ref_name.starts_with("runtime/") {
+                              return Err(ApiError::BadRequest(format!("Can't promote {}", ref_name)))
+                          }
+                          validate_ref(ref_name, &req)?;
+                      }
+                      Ok(())
+                  }))
+        .and_then(move |_| {
+            let db2 = db.clone();
+            let repo = args.repo.clone();
+            db.start_promote_job(args.from_repo.clone(), args.repo.clone(), args.refs.clone(), args.note.clone())
+                .and_then(move |job| {
+                    audit_log(&db2, &req, "promote", json!({ "from-repo": args.from_repo, "repo": repo, "refs": args.refs, "note": args.note }));
+                    job_queue.do_send(ProcessJobs(Some(repo)));
+                    let job_id = job.id;
+                    respond_with_job(job, &req, "show_job", &[job_id.to_string()])
+                })
+        })
+}
+
+#[derive(Deserialize)]
+pub struct RefHistoryPathParams {
+    repo: String,
+    ref_name: String,
+}
+
+fn default_ref_history_limit() -> i64 {
+    100
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RefHistoryArgs {
+    #[serde(default = "default_ref_history_limit")]
+    limit: i64,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct RefHistoryEntry {
+    commit: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    previous_commit: Option<String>,
+    published_at: chrono::NaiveDateTime,
+    build: i32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    job: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    published_by: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    uploader: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    note: Option<String>,
+}
+
+pub fn get_ref_history(
+    params: Path<RefHistoryPathParams>,
+    args: web::Query<RefHistoryArgs>,
+    db: Data<Db>,
+    req: HttpRequest,
+) -> impl Future<Item = HttpResponse, Error = ApiError> {
+    futures::done(req.has_token_claims("build", "build")
+                  .and_then(|_| req.has_token_repo(&params.repo))
+                  .and_then(|_| validate_ref(&params.ref_name, &req)))
+        .and_then(move |_| db.get_ref_history(params.repo.clone(), params.ref_name.clone(), args.limit.clamp(1, 1000)))
+        .and_then(|history| {
+            let entries: Vec<RefHistoryEntry> = history.into_iter()
+                .map(|(published, uploader)| RefHistoryEntry {
+                    commit: published.commit,
+                    previous_commit: published.previous_commit,
+                    published_at: published.published_at,
+                    build: published.build_id,
+                    job: published.job_id,
+                    published_by: published.published_by,
+                    uploader,
+                    note: published.note,
+                })
+                .collect();
+            Ok(HttpResponse::Ok().json(entries))
+        })
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RefPinArgs {
+    #[serde(rename = "ref")] ref_name: String,
+    commit: Option<String>, // Without a commit the whole history of the ref is kept
+    reason: Option<String>,
+}
+
+pub fn add_ref_pin(
+    args: Json<RefPinArgs>,
+    params: Path<RepoPathParams>,
+    config: Data<Config>,
+    db: Data<Db>,
+    req: HttpRequest,
+) -> impl Future<Item = HttpResponse, Error = ApiError> {
+    futures::done(req.has_token_claims("admin", "admin")
+                  .and_then(|_| req.has_token_repo(&params.repo))
+                  .and_then(|_| config.get_repoconfig(&params.repo).map(|_| ()))
+                  .and_then(|_| validate_ref(&args.ref_name, &req))
+                  .and_then(|_| match args.commit {
+                      Some(ref commit) if commit.len() != 64 || !commit.chars().all(|c| c.is_ascii_hexdigit()) =>
+                          Err(ApiError::BadRequest(format!("Invalid commit {}", commit))),
+                      _ => Ok(()),
+                  }))
+        .and_then(move |_| {
+            let author = req.get_claims()
+                .map(|c| c.name.clone().unwrap_or(c.sub.clone()))
+                .unwrap_or("unknown".to_string());
+            let db2 = db.clone();
+            db.new_ref_pin(NewRefPin {
+                repo: params.repo.clone(),
+                ref_name: args.ref_name.clone(),
+                commit: args.commit.clone(),
+                author,
+                reason: args.reason.clone(),
+            })
+                .and_then(move |pin| {
+                    audit_log(&db2, &req, "add-ref-pin", json!(pin));
+                    Ok(HttpResponse::Ok().json(pin))
+                })
+        })
+}
+
+pub fn list_ref_pins(
+    params: Path<RepoPathParams>,
+    db: Data<Db>,
+    req: HttpRequest,
+) -> impl Future<Item = HttpResponse, Error = ApiError> {
+    futures::done(req.has_token_claims("admin", "admin")
+                  .and_then(|_| req.has_token_repo(&params.repo)))
+        .and_then(move |_| db.list_ref_pins(params.repo.clone()))
+        .and_then(|pins| Ok(HttpResponse::Ok().json(pins)))
+}
+
+#[derive(Deserialize)]
+pub struct RefPinPathParams {
+    repo: String,
+    pin_id: i32,
+}
+
+pub fn delete_ref_pin(
+    params: Path<RefPinPathParams>,
+    db: Data<Db>,
+    req: HttpRequest,
+) -> impl Future<Item = HttpResponse, Error = ApiError> {
+    futures::done(req.has_token_claims("admin", "admin")
+                  .and_then(|_| req.has_token_repo(&params.repo)))
+        .and_then(move |_| {
+            let db2 = db.clone();
+            db.delete_ref_pin(params.repo.clone(), params.pin_id)
+                .and_then(move |pin| {
+                    audit_log(&db2, &req, "delete-ref-pin", json!(pin));
+                    Ok(HttpResponse::Ok().json(pin))
+                })
+        })
+}
+
+#[derive(Deserialize)]
+pub struct AppAclPathParams {
+    app_id: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AppAclArgs {
+    principal: String, // A token name, or group:NAME
+}
+
+pub fn add_app_acl_entry(
+    args: Json<AppAclArgs>,
+    params: Path<AppAclPathParams>,
+    db: Data<Db>,
+    req: HttpRequest,
+) -> impl Future<Item = HttpResponse, Error = ApiError> {
+    futures::done(req.has_token_claims("admin", "admin")
+                  .and_then(|_| if args.principal.is_empty() || args.principal == "group:" {
+                      Err(ApiError::BadRequest("Empty principal".to_string()))
+                  } else {
+                      Ok(())
+                  }))
+        .and_then(move |_| {
+            let db2 = db.clone();
+            db.new_app_acl_entry(NewAppAclEntry {
+                app_id: params.app_id.clone(),
+                principal: args.principal.clone(),
+                author: publisher_name(&req).unwrap_or("unknown".to_string()),
+            })
+                .and_then(move |entry| {
+                    audit_log(&db2, &req, "add-app-acl-entry", json!(entry));
+                    Ok(HttpResponse::Ok().json(entry))
+                })
+        })
+}
+
+pub fn list_app_acl(
+    params: Path<AppAclPathParams>,
+    db: Data<Db>,
+    req: HttpRequest,
+) -> impl Future<Item = HttpResponse, Error = ApiError> {
+    futures::done(req.has_token_claims("admin", "admin"))
+        .and_then(move |_| db.list_app_acl(params.app_id.clone()))
+        .and_then(|entries| Ok(HttpResponse::Ok().json(entries)))
+}
+
+#[derive(Deserialize)]
+pub struct AppAclEntryPathParams {
+    app_id: String,
+    entry_id: i32,
+}
+
+pub fn delete_app_acl_entry(
+    params: Path<AppAclEntryPathParams>,
+    db: Data<Db>,
+    req: HttpRequest,
+) -> impl Future<Item = HttpResponse, Error = ApiError> {
+    futures::done(req.has_token_claims("admin", "admin"))
+        .and_then(move |_| {
+            let db2 = db.clone();
+            db.delete_app_acl_entry(params.app_id.clone(), params.entry_id)
+                .and_then(move |entry| {
+                    audit_log(&db2, &req, "delete-app-acl-entry", json!(entry));
+                    Ok(HttpResponse::Ok().json(entry))
+                })
+        })
+}
+
+#[derive(Deserialize)]
+pub struct AclGroupPathParams {
+    group: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AclGroupMemberArgs {
+    member: String, // A token name
+}
+
+pub fn add_acl_group_member(
+    args: Json<AclGroupMemberArgs>,
+    params: Path<AclGroupPathParams>,
+    db: Data<Db>,
+    req: HttpRequest,
+) -> impl Future<Item = HttpResponse, Error = ApiError> {
+    futures::done(req.has_token_claims("admin", "admin")
+                  .and_then(|_| if args.member.is_empty() {
+                      Err(ApiError::BadRequest("Empty member".to_string()))
+                  } else {
+                      Ok(())
+                  }))
+        .and_then(move |_| {
+            let db2 = db.clone();
+            db.new_acl_group_member(NewAclGroupMember {
+                group_name: params.group.clone(),
+                member: args.member.clone(),
+                author: publisher_name(&req).unwrap_or("unknown".to_string()),
+            })
+                .and_then(move |member| {
+                    audit_log(&db2, &req, "add-acl-group-member", json!(member));
+                    Ok(HttpResponse::Ok().json(member))
+                })
+        })
+}
+
+pub fn list_acl_group(
+    params: Path<AclGroupPathParams>,
+    db: Data<Db>,
+    req: HttpRequest,
+) -> impl Future<Item = HttpResponse, Error = ApiError> {
+    futures::done(req.has_token_claims("admin", "admin"))
+        .and_then(move |_| db.list_acl_group(params.group.clone()))
+        .and_then(|members| Ok(HttpResponse::Ok().json(members)))
+}
+
+#[derive(Deserialize)]
+pub struct AclGroupMemberPathParams {
+    group: String,
+    member_id: i32,
+}
+
+pub fn delete_acl_group_member(
+    params: Path<AclGroupMemberPathParams>,
+    db: Data<Db>,
+    req: HttpRequest,
+) -> impl Future<Item = HttpResponse, Error = ApiError> {
+    futures::done(req.has_token_claims("admin", "admin"))
+        .and_then(move |_| {
+            let db2 = db.clone();
+            db.delete_acl_group_member(params.group.clone(), params.member_id)
+                .and_then(move |member| {
+                    audit_log(&db2, &req, "delete-acl-group-member", json!(member));
+                    Ok(HttpResponse::Ok().json(member))
+                })
+        })
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct OrganizationArgs {
+    name: String,
+    upload_quota: Option<i64>, // In bytes, for all members together
+}
+
+pub fn create_organization(
+    args: Json<OrganizationArgs>,
+    db: Data<Db>,
+    req: HttpRequest,
+) -> impl Future<Item = HttpResponse, Error = ApiError> {
+    futures::done(req.has_token_claims("admin", "admin")
+                  .and_then(|_| if args.name.is_empty() || args.name.contains('/') {
+                      Err(ApiError::BadRequest(format!("Invalid organization name {}", args.name)))
+                  } else {
+                      Ok(())
+                  }))
+        .and_then(move |_| {
+            let db2 = db.clone();
+            db.new_organization(NewOrganization {
+                name: args.name.clone(),
+                upload_quota: args.upload_quota,
+                author: publisher_name(&req).unwrap_or("unknown".to_string()),
+            })
+                .and_then(move |organization| {
+                    audit_log(&db2, &req, "create-organization", json!(organization));
+                    Ok(HttpResponse::Ok().json(organization))
+                })
+        })
+}
+
+pub fn list_organizations(
+    db: Data<Db>,
+    req: HttpRequest,
+) -> impl Future<Item = HttpResponse, Error = ApiError> {
+    futures::done(req.has_token_claims("admin", "admin"))
+        .and_then(move |_| db.list_organizations())
+        .and_then(|organizations| Ok(HttpResponse::Ok().json(organizations)))
+}
+
+#[derive(Deserialize)]
+pub struct OrganizationPathParams {
+    name: String,
+}
+
+pub fn get_organization(
+    params: Path<OrganizationPathParams>,
+    db: Data<Db>,
+    req: HttpRequest,
+) -> impl Future<Item = HttpResponse, Error = ApiError> {
+    futures::done(req.has_token_claims("admin", "admin"))
+        .and_then(move |_| db.lookup_organization(params.name.clone()))
+        .and_then(|(organization, members)| Ok(HttpResponse::Ok().json(json!({
+            "organization": organization,
+            "members": members,
+        }))))
+}
+
+pub fn delete_organization(
+    params: Path<OrganizationPathParams>,
+    db: Data<Db>,
+    req: HttpRequest,
+) -> impl Future<Item = HttpResponse, Error = ApiError> {
+    futures::done(req.has_token_claims("admin", "admin"))
+        .and_then(move |_| {
+            let db2 = db.clone();
+            db.delete_organization(params.name.clone())
+                .and_then(move |organization| {
+                    audit_log(&db2, &req, "delete-organization", json!(organization));
+                    Ok(HttpResponse::Ok().json(organization))
+                })
+        })
 }
 
 #[derive(Debug, Serialize, Deserialize)]
-pub struct PublishArgs {
+pub struct OrganizationMemberArgs {
+    member: String, // A token name
 }
 
-pub fn publish(
-    _args: Json<PublishArgs>,
+pub fn add_organization_member(
+    args: Json<OrganizationMemberArgs>,
+    params: Path<OrganizationPathParams>,
+    db: Data<Db>,
+    req: HttpRequest,
+) -> impl Future<Item = HttpResponse, Error = ApiError> {
+    futures::done(req.has_token_claims("admin", "admin")
+                  .and_then(|_| if args.member.is_empty() {
+                      Err(ApiError::BadRequest("Empty member".to_string()))
+                  } else {
+                      Ok(())
+                  }))
+        .and_then(move |_| {
+            let db2 = db.clone();
+            db.new_organization_member(params.name.clone(),
+                                       args.member.clone(),
+                                       publisher_name(&req).unwrap_or("unknown".to_string()))
+                .and_then(move |member| {
+                    audit_log(&db2, &req, "add-organization-member", json!({ "organization": params.name, "member": member }));
+                    Ok(HttpResponse::Ok().json(member))
+                })
+        })
+}
+
+#[derive(Deserialize)]
+pub struct OrganizationMemberPathParams {
+    name: String,
+    member_id: i32,
+}
+
+pub fn delete_organization_member(
+    params: Path<OrganizationMemberPathParams>,
+    db: Data<Db>,
+    req: HttpRequest,
+) -> impl Future<Item = HttpResponse, Error = ApiError> {
+    futures::done(req.has_token_claims("admin", "admin"))
+        .and_then(move |_| {
+            let db2 = db.clone();
+            db.delete_organization_member(params.name.clone(), params.member_id)
+                .and_then(move |member| {
+                    audit_log(&db2, &req, "delete-organization-member", json!({ "organization": params.name, "member": member }));
+                    Ok(HttpResponse::Ok().json(member))
+                })
+        })
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeprecateArgs {
+    #[serde(rename = "ref")] ref_name: String,
+    reason: String,
+    rebase: Option<String>, // The ref that replaces it
+}
+
+pub fn deprecate_ref(
+    args: Json<DeprecateArgs>,
+    params: Path<RepoPathParams>,
+    config: Data<Config>,
+    job_queue: Data<Addr<JobQueue>>,
+    db: Data<Db>,
+    req: HttpRequest,
+) -> impl Future<Item = HttpResponse, Error = ApiError> {
+    futures::done(req.has_token_claims("admin", "admin")
+                  .and_then(|_| req.has_token_repo(&params.repo))
+                  .and_then(|_| config.get_repoconfig(&params.repo).map(|_| ()))
+                  .and_then(|_| validate_ref(&args.ref_name, &req))
+                  .and_then(|_| match args.rebase {
+                      Some(ref rebase) => validate_ref(rebase, &req),
+                      None => Ok(()),
+                  })
+                  .and_then(|_| {
+                      if !args.ref_name.starts_with("app/") && !args.ref_name.starts_with("runtime/") {
+                          return Err(ApiError::BadRequest(format!("Can't mark {} end-of-life", args.ref_name)))
+                      }
+                      if args.reason.trim().is_empty() {
+                          return Err(ApiError::BadRequest("An end-of-life reason is required".to_string()))
+                      }
+                      Ok(())
+                  }))
+        .and_then(move |_| {
+            let author = req.get_claims()
+                .map(|c| c.name.clone().unwrap_or(c.sub.clone()))
+                .unwrap_or("unknown".to_string());
+            let db2 = db.clone();
+            let repo = params.repo.clone();
+            let tombstone = NewRefTombstone {
+                repo: repo.clone(),
+                ref_name: args.ref_name.clone(),
+                reason: args.reason.clone(),
+                rebase: args.rebase.clone(),
+                author,
+                job_id: None,
+            };
+            db.start_deprecate_job(tombstone.clone())
+                .and_then(move |job| {
+                    audit_log(&db2, &req, "deprecate-ref", json!(tombstone));
+                    job_queue.do_send(ProcessJobs(Some(repo)));
+                    let job_id = job.id;
+                    respond_with_job(job, &req, "show_job", &[job_id.to_string()])
+                })
+        })
+}
+
+pub fn list_ref_tombstones(
+    params: Path<RepoPathParams>,
+    db: Data<Db>,
+    req: HttpRequest,
+) -> impl Future<Item = HttpResponse, Error = ApiError> {
+    futures::done(req.has_token_claims("build", "build")
+                  .and_then(|_| req.has_token_repo(&params.repo)))
+        .and_then(move |_| db.list_ref_tombstones(params.repo.clone()))
+        .and_then(|tombstones| Ok(HttpResponse::Ok().json(tombstones)))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RollbackArgs {
+    #[serde(rename = "ref")] ref_name: String,
+    commit: Option<String>, // Defaults to the commit before the last publish
+    note: Option<String>,
+}
+
+pub fn rollback(
+    args: Json<RollbackArgs>,
+    params: Path<RepoPathParams>,
+    config: Data<Config>,
+    job_queue: Data<Addr<JobQueue>>,
+    db: Data<Db>,
+    req: HttpRequest,
+) -> impl Future<Item = HttpResponse, Error = ApiError> {
+    futures::done(req.has_token_claims("admin", "admin")
+                  .and_then(|_| req.has_token_repo(&params.repo))
+                  .and_then(|_| config.get_repoconfig(&params.repo).map(|_| ()))
+                  .and_then(|_| validate_ref(&args.ref_name, &req)))
+        .and_then(move |_| {
+            let db2 = db.clone();
+            let repo = params.repo.clone();
+            db.start_rollback_job(repo.clone(), args.ref_name.clone(), args.commit.clone(), args.note.clone())
+                .and_then(move |job| {
+                    audit_log(&db2, &req, "rollback", json!({ "repo": repo, "ref": args.ref_name, "commit": args.commit, "note": args.note }));
+                    job_queue.do_send(ProcessJobs(Some(repo)));
+                    let job_id = job.id;
+                    respond_with_job(job, &req, "show_job", &[job_id.to_string()])
+                })
+        })
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BuildBundleArgs {
+    #[serde(rename = "ref")] ref_name: String,
+}
+
+pub fn build_bundle(
+    args: Json<BuildBundleArgs>,
     params: Path<BuildPathParams>,
     job_queue: Data<Addr<JobQueue>>,
     db: Data<Db>,
     req: HttpRequest,
 ) -> impl Future<Item = HttpResponse, Error = ApiError> {
-    futures::done(req.has_token_claims(&format!("build/{}", params.id), "publish"))
+    futures::done(req.has_token_claims(&format!("build/{}", params.id), "build")
+                  .and_then(|_| validate_ref(&args.ref_name, &req)))
         .and_then(move |_| {
             let build_id = params.id;
             let req2 = req.clone();
-
+            let db2 = db.clone();
             db
                 .lookup_build(build_id)
-                .and_then (move |build| {
+                .and_then(move |build| {
                     req2.has_token_repo(&build.repo)?;
                     Ok(build)
                 })
-                .and_then (move |build| {
-                    db.start_publish_job(build_id, build.repo.clone())
-                        .and_then(move |job| {
-                            job_queue.do_send(ProcessJobs(Some(build.repo)));
-                            respond_with_url(&job, &req, "show_publish_job", &[params.id.to_string()])
-                        })
+                .and_then(move |build| db.start_bundle_job(args.ref_name.clone(), Some(build_id), build.repo))
+                .and_then(move |job| {
+                    audit_log(&db2, &req, "bundle", json!({ "build": build_id, "job": job.id }));
+                    job_queue.do_send(ProcessJobs(None));
+                    let job_id = job.id;
+                    respond_with_job(job, &req, "show_bundle", &[job_id.to_string()])
+                })
+        })
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BundleArgs {
+    repo: String,
+    #[serde(rename = "ref")] ref_name: String,
+}
+
+pub fn bundle(
+    args: Json<BundleArgs>,
+    config: Data<Config>,
+    job_queue: Data<Addr<JobQueue>>,
+    db: Data<Db>,
+    req: HttpRequest,
+) -> impl Future<Item = HttpResponse, Error = ApiError> {
+    futures::done(req.has_token_claims("build", "build")
+                  .and_then(|_| req.has_token_repo(&args.repo))
+                  .and_then(|_| config.get_repoconfig(&args.repo).map(|_| ()))
+                  .and_then(|_| validate_ref(&args.ref_name, &req)))
+        .and_then(move |_| {
+            let db2 = db.clone();
+            let repo = args.repo.clone();
+            db.start_bundle_job(args.ref_name.clone(), None, args.repo.clone())
+                .and_then(move |job| {
+                    audit_log(&db2, &req, "bundle", json!({ "repo": repo, "job": job.id }));
+                    job_queue.do_send(ProcessJobs(Some(repo)));
+                    let job_id = job.id;
+                    respond_with_job(job, &req, "show_bundle", &[job_id.to_string()])
                 })
         })
 }
 
+/* Returns the bundle once the job is done, and the job itself with
+ * 202 Accepted while it is still queued or running */
+pub fn get_bundle(
+    params: Path<JobPathParams>,
+    config: Data<Config>,
+    db: Data<Db>,
+    req: HttpRequest,
+) -> impl Future<Item = HttpResponse, Error = ApiError> {
+    db
+        .lookup_job(params.id, None)
+        .and_then(move |job| {
+            if job.kind != JobKind::Bundle.to_db() {
+                return Err(ApiError::NotFound)
+            }
+            let bundle_job = serde_json::from_str::<BundleJob>(&job.contents)
+                .map_err(|e| ApiError::InternalServerError(e.to_string()))?;
+            match bundle_job.build {
+                Some(build_id) => req.has_token_claims(&format!("build/{}", build_id), "build")?,
+                None => req.has_token_claims("build", "build")?,
+            }
+            req.has_token_repo(&bundle_job.repo)?;
+            validate_ref(&bundle_job.ref_name, &req)?;
+
+            match JobStatus::from_db(job.status) {
+                Some(JobStatus::Ended) => {
+                    let path = config.bundle_dir.join(format!("{}.flatpak", job.id));
+                    if !path.exists() {
+                        return Err(ApiError::Gone(format!("The bundle of job {} has expired", job.id)))
+                    }
+                    NamedFile::open(path)?
+                        .respond_to(&req)
+                        .map_err(|e| ApiError::InternalServerError(e.to_string()))
+                },
+                Some(JobStatus::Broken) => Err(ApiError::BadRequest(format!("Bundle job {} failed", job.id))),
+                _ => Ok(HttpResponse::Accepted().json(job)),
+            }
+        })
+}
+
 pub fn purge(
     params: Path<BuildPathParams>,
     db: Data<Db>,
@@ -663,20 +2838,96 @@ pub fn purge(
             let build_id = params.id;
             let req2 = req.clone();
             let db2 = db.clone();
+            let db3 = db.clone();
+            let config = config.into_inner();
+            let config2 = config.clone();
             db
                 .lookup_build (build_id)
                 .and_then (move |build| req2.has_token_repo(&build.repo))
-                .and_then (move |_ok| db.init_purge(build_id))
+                .and_then (move |_ok| db.init_purge(config, build_id))
                 .and_then(move |_ok| {
                     let res = fs::remove_dir_all(&build_repo_path);
-                    db2.finish_purge (build_id,
+                    db2.finish_purge (config2,
+                                     build_id,
                                      match res {
                                          Ok(()) => None,
                                          Err(e) => Some(e.to_string()),
                                      })
                 })
                 .and_then(move |build| {
-                    respond_with_url(&build, &req, "show_build", &[build_id.to_string()])
+                    audit_log(&db3, &req, "purge", json!({ "build": build_id }));
+                    respond_with_build(build, &req, "show_build", &[build_id.to_string()])
+                })
+        })
+}
+
+/* Unlike purge this keeps the build repo until delete-grace-secs has
+ * passed, so the build can still be undeleted */
+pub fn delete_build(
+    params: Path<BuildPathParams>,
+    db: Data<Db>,
+    config: Data<Config>,
+    req: HttpRequest,
+) -> impl Future<Item = HttpResponse, Error = ApiError> {
+    futures::done(req.has_token_claims(&format!("build/{}", params.id), "build"))
+        .and_then (move |_| {
+            let build_id = params.id;
+            let req2 = req.clone();
+            let db2 = db.clone();
+            let db3 = db.clone();
+            db
+                .lookup_build (build_id)
+                .and_then (move |build| req2.has_token_repo(&build.repo))
+                .and_then (move |_ok| db2.delete_build(config.into_inner(), build_id))
+                .and_then(move |build| {
+                    audit_log(&db3, &req, "delete", json!({ "build": build_id }));
+                    respond_with_build(build, &req, "show_build", &[build_id.to_string()])
+                })
+        })
+}
+
+pub fn abort_build(
+    params: Path<BuildPathParams>,
+    db: Data<Db>,
+    config: Data<Config>,
+    req: HttpRequest,
+) -> impl Future<Item = HttpResponse, Error = ApiError> {
+    futures::done(req.has_token_claims(&format!("build/{}", params.id), "build"))
+        .and_then (move |_| {
+            let build_id = params.id;
+            let req2 = req.clone();
+            let db2 = db.clone();
+            let db3 = db.clone();
+            db
+                .lookup_build (build_id)
+                .and_then (move |build| req2.has_token_repo(&build.repo))
+                .and_then (move |_ok| db2.abort_build(config.into_inner(), build_id))
+                .and_then(move |build| {
+                    audit_log(&db3, &req, "abort", json!({ "build": build_id }));
+                    respond_with_build(build, &req, "show_build", &[build_id.to_string()])
+                })
+        })
+}
+
+pub fn undelete_build(
+    params: Path<BuildPathParams>,
+    db: Data<Db>,
+    config: Data<Config>,
+    req: HttpRequest,
+) -> impl Future<Item = HttpResponse, Error = ApiError> {
+    futures::done(req.has_token_claims(&format!("build/{}", params.id), "build"))
+        .and_then (move |_| {
+            let build_id = params.id;
+            let req2 = req.clone();
+            let db2 = db.clone();
+            let db3 = db.clone();
+            db
+                .lookup_build (build_id)
+                .and_then (move |build| req2.has_token_repo(&build.repo))
+                .and_then (move |_ok| db2.undelete_build(config.into_inner(), build_id))
+                .and_then(move |build| {
+                    audit_log(&db3, &req, "undelete", json!({ "build": build_id }));
+                    respond_with_build(build, &req, "show_build", &[build_id.to_string()])
                 })
         })
 }
@@ -690,18 +2941,38 @@ struct JobStatusData {
     contents: String,
     results: String,
     log: String,
+    log_tail: String,
     finished: bool,
+    duration: String,
+    progress: String,
+}
+
+/* Lines of the log shown for each job on the build page */
+const STATUS_LOG_TAIL_LINES: usize = 20;
+/* Builds listed on the main status page */
+const STATUS_RECENT_BUILDS: i64 = 20;
+
+fn log_tail(log: &str, lines: usize) -> String {
+    let all: Vec<&str> = log.lines().collect();
+    all[all.len().saturating_sub(lines)..].join("\n")
 }
 
 fn job_status_data(job: Job) -> JobStatusData {
+    let duration = job.duration().map_or("".to_string(), |d| format!("{}s", d.as_secs()));
+    let progress = job.progress.as_ref()
+        .and_then(|p| serde_json::from_str::<serde_json::Value>(p).ok())
+        .map_or("".to_string(), |p| format!("{} {}/{}", p["stage"].as_str().unwrap_or(""), p["done"], p["total"]));
     JobStatusData {
         id: job.id,
         kind: JobKind::from_db(job.kind).map_or ("Unknown".to_string(), |k| format! ("{:?}", k)),
         status: JobStatus::from_db(job.status).map_or ("Unknown".to_string(), |s| format! ("{:?}", s)),
         contents: job.contents,
         results: job.results.unwrap_or("".to_string()),
+        log_tail: log_tail(&job.log, STATUS_LOG_TAIL_LINES),
         log: job.log,
         finished: job.status >= JobStatus::Ended as i16,
+        duration,
+        progress,
     }
 }
 
@@ -717,10 +2988,29 @@ pub fn job_status(
         })
 }
 
+struct BuildStatusSummary {
+    id: i32,
+    repo: String,
+    created: String,
+    repo_state: String,
+    published_state: String,
+}
+
+fn build_status_summary(build: &Build) -> BuildStatusSummary {
+    BuildStatusSummary {
+        id: build.id,
+        repo: build.repo.clone(),
+        created: build.created.format("%Y-%m-%d %H:%M:%S").to_string(),
+        repo_state: RepoState::from_db(build.repo_state, &build.repo_state_reason).name().to_string(),
+        published_state: PublishedState::from_db(build.published_state, &build.published_state_reason).name().to_string(),
+    }
+}
+
 #[derive(Template)]
 #[template(path = "status.html")]
 struct Status {
     jobs: Vec<JobStatusData>,
+    builds: Vec<BuildStatusSummary>,
     version: String,
 }
 
@@ -729,15 +3019,102 @@ pub fn status(
 ) -> impl Future<Item = HttpResponse, Error = ApiError> {
     db
         .list_active_jobs()
-        .and_then(move |jobs| {
+        .join(db.list_recent_builds(STATUS_RECENT_BUILDS))
+        .and_then(move |(jobs, builds)| {
             let s = Status {
                 jobs: jobs.into_iter().map(job_status_data).collect(),
+                builds: builds.iter().map(build_status_summary).collect(),
                 version: env!("CARGO_PKG_VERSION").to_string(),
             }.render().unwrap();
             Ok(HttpResponse::Ok().content_type("text/html").body(s))
         })
 }
 
+struct BuildRefStatus {
+    ref_name: String,
+    commit: String,
+}
+
+#[derive(Template)]
+#[template(path = "build.html")]
+struct BuildStatus {
+    build: BuildStatusSummary,
+    repo_state_reason: String,
+    published_state_reason: String,
+    refs: Vec<BuildRefStatus>,
+    jobs: Vec<JobStatusData>,
+    finished: bool,
+}
+
+pub fn build_status(
+    params: Path<BuildPathParams>,
+    db: Data<Db>,
+) -> impl Future<Item = HttpResponse, Error = ApiError> {
+    let build_id = params.id;
+    let db2 = db.clone();
+    db
+        .lookup_build(build_id)
+        .join(db.lookup_build_refs(build_id))
+        .join(db.lookup_build_job_graph(build_id))
+        .and_then(move |((build, refs), graph)| {
+            db2.lookup_jobs(graph.jobs.iter().map(|job| job.id).collect())
+                .map(move |jobs| (build, refs, jobs))
+        })
+        .and_then(move |(build, refs, jobs)| {
+            let jobs: Vec<JobStatusData> = jobs.into_iter().map(job_status_data).collect();
+            let s = BuildStatus {
+                build: build_status_summary(&build),
+                repo_state_reason: build.repo_state_reason.clone().unwrap_or_default(),
+                published_state_reason: build.published_state_reason.clone().unwrap_or_default(),
+                refs: refs.into_iter().map(|r| BuildRefStatus { ref_name: r.ref_name, commit: r.commit }).collect(),
+                finished: jobs.iter().all(|job| job.finished),
+                jobs,
+            }.render().unwrap();
+            Ok(HttpResponse::Ok().content_type("text/html").body(s))
+        })
+}
+
+fn health_status(ok: bool) -> &'static str {
+    if ok { "ok" } else { "error" }
+}
+
+pub fn health(
+    config: Data<Config>,
+    health: Data<Health>,
+    db: Data<Db>,
+) -> impl Future<Item = HttpResponse, Error = ApiError> {
+    let self_check = health.self_check();
+    let tools = config.tools.clone();
+    let load_shedding = get_load_shedding_state(&config, &db);
+    web::block(move || -> Result<_, ()> { Ok(health.gpg_health(&config)) })
+        .map_err(|_e| ApiError::InternalServerError("Failed to check health".to_string()))
+        .join(load_shedding)
+        .and_then(move |(gpg, load_shedding)| {
+            let self_check_ok = self_check.map(|check| check.ok);
+            let ok = gpg.ok() && self_check_ok.unwrap_or(true);
+            /* This needs no token, so the details (key ids, gpg errors) are only logged */
+            let body = json!({
+                "status": health_status(ok),
+                "version": env!("CARGO_PKG_VERSION"),
+                "tools": tools,
+                "gpg": health_status(gpg.ok()),
+                "self-check": self_check_ok.map(health_status),
+                "load-shedding": load_shedding,
+            });
+            if ok {
+                Ok(HttpResponse::Ok().json(body))
+            } else {
+                Ok(HttpResponse::ServiceUnavailable().json(body))
+            }
+        })
+}
+
+pub fn metrics() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(metrics::render())
+}
+
 #[derive(Deserialize)]
 pub struct DeltaUploadParams {
     repo: String,
@@ -750,12 +3127,12 @@ pub fn delta_upload(
     config: Data<Config>,
 ) -> impl Future<Item = HttpResponse, Error = ApiError> {
     futures::done(req.has_token_claims("delta", "generate"))
-        .and_then(move |_| futures::done(config.get_repoconfig(&params.repo).map(|rc| rc.clone())))
+        .and_then(move |_| futures::done(config.get_repoconfig(&params.repo).and_then(|rc| {
+            diskspace::check_free_space(&config, &rc.get_abs_repo_path())?;
+            Ok(rc.clone())
+        })))
         .and_then(move |repoconfig| {
-            let uploadstate = Arc::new(UploadState {
-                only_deltas: true,
-                repo_path: repoconfig.get_abs_repo_path().clone()
-            });
+            let uploadstate = Arc::new(UploadState::new(repoconfig.get_abs_repo_path(), true, UploadQuota::default()));
             multipart
                 .map_err(|e| ApiError::InternalServerError(e.to_string()))
                 .map(move |field| { save_file(field, &uploadstate).into_stream() })
@@ -779,3 +3156,313 @@ pub fn ws_delta(req: HttpRequest,
         stream
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_github_args() {
+        let sha = Some("0123456789abcdefABCDEF0123456789abcdef01".to_string());
+        assert!(validate_github_args(&None, &None).is_ok());
+        assert!(validate_github_args(&Some("flathub/org.example.App".to_string()), &sha).is_ok());
+        assert!(validate_github_args(&Some("my_org/my-repo.rs".to_string()), &None).is_ok());
+
+        for repository in &["flathub", "flathub/", "/repo", "a/b/c", "../statuses", "../..", "a/..",
+                            "a/b?x=1", "a/b#x", "a/b c", "a%2Fb/c"] {
+            assert!(validate_github_args(&Some(repository.to_string()), &sha).is_err(), "{}", repository);
+        }
+        for sha in &["abc", "0123456789abcdef0123456789abcdef0123456", "0123456789abcdef0123456789abcdef012345678",
+                     "0123456789abcdef0123456789abcdef0123456g", "../0123456789abcdef0123456789abcdef012345"] {
+            assert!(validate_github_args(&None, &Some(sha.to_string())).is_err(), "{}", sha);
+        }
+    }
+
+    #[test]
+    fn test_validate_accepting_uploads() {
+        let (uploading, _) = RepoState::Uploading.to_db();
+        let (verifying, _) = RepoState::Verifying.to_db();
+        let mut build = Build {
+            id: 1,
+            created: chrono::Utc::now().naive_utc(),
+            repo_state: uploading,
+            repo_state_reason: None,
+            published_state: 0,
+            published_state_reason: None,
+            commit_job_id: None,
+            publish_job_id: None,
+            repo: "stable".to_string(),
+            extra_ids: vec![],
+            app_id: None,
+            uploader: None,
+            metadata: json!({}),
+            github_repository: None,
+            github_sha: None,
+            deleted_at: None,
+            uploaded_objects: 0,
+            uploaded_bytes: 0,
+            derived_from: None,
+        };
+        assert!(validate_accepting_uploads(&build).is_ok());
+
+        build.deleted_at = Some(chrono::Utc::now().naive_utc());
+        assert!(validate_accepting_uploads(&build).is_err());
+
+        build.deleted_at = None;
+        build.repo_state = verifying;
+        assert!(validate_accepting_uploads(&build).is_err());
+    }
+
+    #[test]
+    fn test_worker_token_organization() {
+        let claims = Claims {
+            sub: "ci".to_string(),
+            exp: 2000,
+            scope: vec!["build".to_string(), "upload".to_string()],
+            prefixes: vec!["".to_string()],
+            repos: vec!["".to_string()],
+            name: Some("ci-example".to_string()),
+            parent_sub: None,
+        };
+        let worker = worker_token_claims(claims, 42, "stable", 3600, 1000);
+        assert_eq!(worker.sub, "build/42");
+        assert_eq!(worker.scope, vec!["upload"]);
+        assert_eq!(worker.repos, vec!["stable"]);
+        assert_eq!(worker.exp, 2000);
+        /* Quotas and usage are accounted to the minting subject */
+        assert_eq!(worker.usage_subject(), "ci");
+
+        /* Uploads with the worker token count for the organizations of the minting token */
+        let identity = claims_publisher_name(&worker);
+        assert_eq!(identity, "ci-example/worker");
+        assert_eq!(acl_principal(&identity), "ci-example");
+
+        /* Also when minted from a token that is itself derived */
+        let worker = worker_token_claims(worker, 43, "stable", 3600, 1000);
+        assert_eq!(acl_principal(&claims_publisher_name(&worker)), "ci-example");
+        assert_eq!(worker.usage_subject(), "ci");
+    }
+
+    #[test]
+    fn test_upload_quota() {
+        let unlimited = UploadQuota::default();
+        assert!(unlimited.count(u64::max_value() / 2).is_ok());
+        assert!(unlimited.count(u64::max_value() / 2).is_ok());
+
+        let quota = UploadQuota {
+            left: Some((100, "Upload quota exceeded".to_string())),
+            ..Default::default()
+        };
+        assert!(quota.check(100).is_ok());
+        assert!(quota.check(101).is_err());
+        /* Counted over all the writes of the upload, whatever the Content-Length said */
+        assert!(quota.count(60).is_ok());
+        assert!(quota.count(40).is_ok());
+        match quota.count(1) {
+            Err(ApiError::QuotaExceeded(message)) => assert_eq!(message, "Upload quota exceeded"),
+            _ => panic!("Expected the quota to be exceeded"),
+        }
+    }
+
+    #[test]
+    fn test_job_wait_timeout() {
+        assert_eq!(job_wait_timeout(60, 300), Duration::from_secs(60));
+        assert_eq!(job_wait_timeout(3600, 300), Duration::from_secs(300));
+        assert_eq!(job_wait_timeout(0, 300), Duration::from_secs(0));
+    }
+
+    #[test]
+    fn test_check_eol_reason() {
+        assert_eq!(check_eol_reason(&None, 20), Ok(()));
+        assert_eq!(check_eol_reason(&Some("Replaced by org.example.NewApp".to_string()), 20), Ok(()));
+        assert_eq!(check_eol_reason(&Some("Obsolete".to_string()), 20), Err(20));
+        assert_eq!(check_eol_reason(&Some("   Obsolete   ".to_string()), 10), Err(10));
+        assert_eq!(check_eol_reason(&Some("".to_string()), 0), Err(1));
+        assert_eq!(check_eol_reason(&Some("x".to_string()), 0), Ok(()));
+    }
+
+    /* A regular file object with uid and gid 0 and no xattrs */
+    fn filez_object(content: &[u8]) -> Vec<u8> {
+        use flate2::Compression;
+        use flate2::write::DeflateEncoder;
+
+        let mut header = vec![0u8; 24];
+        header[7] = content.len() as u8;
+        header[16..20].copy_from_slice(&0o100644u32.to_be_bytes());
+        header.extend_from_slice(&[0, 25]);
+        let mut object = vec![0, 0, 0, header.len() as u8, 0, 0, 0, 0];
+        object.extend_from_slice(&header);
+        let mut encoder = DeflateEncoder::new(object, Compression::default());
+        encoder.write_all(content).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn verify_object(filename: &str, contents: &[u8], dir: &path::Path) -> Result<(), ApiError> {
+        let path = dir.join("object");
+        fs::write(&path, contents).unwrap();
+        let mut verifier = ObjectVerifier::new(filename);
+        for chunk in contents.chunks(3) {
+            verifier.update(chunk);
+        }
+        verifier.finish(&path)
+    }
+
+    #[test]
+    fn test_object_verifier() {
+        let dir = tempfile::tempdir().unwrap();
+
+        for object_type in &["dirtree", "dirmeta", "commit"] {
+            let contents = format!("some {} data", object_type).into_bytes();
+            let checksum = hex::encode(openssl::sha::sha256(&contents));
+            let filename = format!("{}.{}", checksum, object_type);
+            assert!(!ObjectVerifier::new(&filename).is_blocking());
+            assert!(verify_object(&filename, &contents, dir.path()).is_ok(), "{}", object_type);
+
+            let mut corrupted = contents.clone();
+            corrupted[0] ^= 1;
+            assert!(verify_object(&filename, &corrupted, dir.path()).is_err(), "{}", object_type);
+            assert!(verify_object(&filename, &contents[1..], dir.path()).is_err(), "{}", object_type);
+        }
+
+        let contents = filez_object(b"hello");
+        let path = dir.path().join("valid.filez");
+        fs::write(&path, &contents).unwrap();
+        let filename = format!("{}.filez", ostree::get_filez_checksum(&path).unwrap());
+        assert!(ObjectVerifier::new(&filename).is_blocking());
+        assert!(verify_object(&filename, &contents, dir.path()).is_ok());
+
+        /* Other content with the same file info, a truncated object, and
+         * an object that doesn't decompress */
+        assert!(verify_object(&filename, &filez_object(b"hellO"), dir.path()).is_err());
+        assert!(verify_object(&filename, &contents[..20], dir.path()).is_err());
+        let mut corrupted = contents.clone();
+        let last = corrupted.len() - 1;
+        corrupted[last] ^= 0xff;
+        assert!(verify_object(&filename, &corrupted, dir.path()).is_err());
+
+        /* Deltas are verified when published */
+        let delta = "oS6QiSBxQF5nJZBVS6MJ6tCk_KN63I72Y7QipgUTh5w.superblock.delta";
+        assert!(verify_object(delta, b"anything", dir.path()).is_ok());
+    }
+
+    fn object_tar(entries: &[(&str, tar::EntryType, &[u8])]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(vec![]);
+        for &(name, entry_type, data) in entries {
+            /* Set the name directly, as the builder refuses unsafe paths */
+            let mut header = tar::Header::new_old();
+            header.as_old_mut().name[..name.len()].copy_from_slice(name.as_bytes());
+            header.set_entry_type(entry_type);
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append(&header, data).unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    fn unpack_tar(tar: &[u8], state: &UploadState, max_object_size: u64, max_total_size: u64) -> Result<Vec<i64>, ApiError> {
+        /* Left at its end, like the spooled upload */
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(tar).unwrap();
+        unpack_object_tar_limited(file, state, max_object_size, max_total_size)
+    }
+
+    #[test]
+    fn test_unpack_object_tar() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo_path = dir.path().join("upload");
+        let state = UploadState::new(repo_path.clone(), false, UploadQuota::default());
+
+        let dirmeta = b"some dirmeta data".to_vec();
+        let dirmeta_checksum = hex::encode(openssl::sha::sha256(&dirmeta));
+        let dirmeta_name = format!("{}.dirmeta", dirmeta_checksum);
+        let filez = filez_object(b"hello");
+        let filez_path = dir.path().join("valid.filez");
+        fs::write(&filez_path, &filez).unwrap();
+        let filez_checksum = ostree::get_filez_checksum(&filez_path).unwrap();
+        let filez_name = format!("{}.filez", filez_checksum);
+
+        let tar = object_tar(&[(&dirmeta_name, tar::EntryType::Regular, &dirmeta),
+                               (&filez_name, tar::EntryType::Regular, &filez)]);
+        let sizes = vec![dirmeta.len() as i64, filez.len() as i64];
+        assert_eq!(unpack_tar(&tar, &state, 1024, 1024).unwrap(), sizes);
+        let dirmeta_path = repo_path.join("objects").join(&dirmeta_checksum[..2]).join(&dirmeta_name[2..]);
+        assert_eq!(fs::read(&dirmeta_path).unwrap(), dirmeta);
+        assert!(repo_path.join("objects").join(&filez_checksum[..2]).join(&filez_name[2..]).exists());
+        assert_eq!(state.saved_objects.load(Ordering::SeqCst), 2);
+
+        let compressed = zstd::stream::encode_all(&tar[..], 0).unwrap();
+        assert_eq!(unpack_tar(&compressed, &state, 1024, 1024).unwrap(), sizes);
+
+        /* Objects with content not matching their names */
+        let other_filez = filez_object(b"hellO");
+        for &(name, data) in &[(&dirmeta_name, &b"other dirmeta data"[..]), (&filez_name, &other_filez[..])] {
+            let tar = object_tar(&[(name, tar::EntryType::Regular, data)]);
+            assert!(unpack_tar(&tar, &state, 1024, 1024).is_err(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_unpack_object_tar_rejects_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo_path = dir.path().join("upload");
+        let state = UploadState::new(repo_path.clone(), false, UploadQuota::default());
+
+        let dirmeta = b"some dirmeta data".to_vec();
+        let dirmeta_checksum = hex::encode(openssl::sha::sha256(&dirmeta));
+        let dirmeta_name = format!("{}.dirmeta", dirmeta_checksum);
+
+        /* Path traversal */
+        for name in &[format!("../{}", dirmeta_name), format!("/{}", dirmeta_name),
+                      format!("objects/{}/{}", &dirmeta_name[..2], &dirmeta_name[2..]), "../summary".to_string()] {
+            let tar = object_tar(&[(name, tar::EntryType::Regular, &dirmeta)]);
+            assert!(unpack_tar(&tar, &state, 1024, 1024).is_err(), "{}", name);
+        }
+        assert!(!dir.path().join(&dirmeta_name).exists());
+
+        /* Entries that are not objects */
+        for name in &["summary", "config", "refs", "oS6QiSBxQF5nJZBVS6MJ6tCk_KN63I72Y7QipgUTh5w.superblock.delta",
+                      &dirmeta_checksum[..], &format!("{}.dirmeta", &dirmeta_checksum[1..])] {
+            let tar = object_tar(&[(name, tar::EntryType::Regular, &dirmeta)]);
+            assert!(unpack_tar(&tar, &state, 1024, 1024).is_err(), "{}", name);
+        }
+        for &entry_type in &[tar::EntryType::Symlink, tar::EntryType::Link, tar::EntryType::Directory,
+                             tar::EntryType::Fifo] {
+            let tar = object_tar(&[(&dirmeta_name, entry_type, b"")]);
+            assert!(unpack_tar(&tar, &state, 1024, 1024).is_err(), "{:?}", entry_type);
+        }
+        assert_eq!(state.saved_objects.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn test_unpack_object_tar_limits() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = UploadState::new(dir.path().join("upload"), false, UploadQuota::default());
+
+        let objects: Vec<(String, Vec<u8>)> = (0..3).map(|i| {
+            let data = vec![i as u8; 100];
+            (format!("{}.dirtree", hex::encode(openssl::sha::sha256(&data))), data)
+        }).collect();
+        let entries: Vec<(&str, tar::EntryType, &[u8])> = objects.iter()
+            .map(|&(ref name, ref data)| (&name[..], tar::EntryType::Regular, &data[..]))
+            .collect();
+        let tar = object_tar(&entries);
+
+        assert_eq!(unpack_tar(&tar, &state, 100, 300).unwrap(), vec![100, 100, 100]);
+        /* A single object over the limit, and objects over the limit together */
+        assert!(unpack_tar(&tar, &state, 99, 300).is_err());
+        assert!(unpack_tar(&tar, &state, 100, 299).is_err());
+
+        /* Whatever the limits, the written objects count for the quota */
+        let quota = UploadQuota {
+            left: Some((250, "Upload quota exceeded".to_string())),
+            ..Default::default()
+        };
+        let state = UploadState::new(dir.path().join("upload"), false, quota);
+        match unpack_tar(&tar, &state, 100, 300) {
+            Err(ApiError::QuotaExceeded(_)) => (),
+            _ => panic!("Expected the quota to be exceeded"),
+        }
+        assert_eq!(state.saved_objects.load(Ordering::SeqCst), 2);
+    }
+}
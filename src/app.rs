@@ -3,10 +3,17 @@ use actix_web::{self, http, web, middleware, App, HttpRequest, HttpResponse, Htt
 use actix_web::error::{ErrorNotFound,ErrorBadRequest};
 use actix_web::dev::Server;
 use actix_files::NamedFile;
-use actix_web::http::header::{CACHE_CONTROL, HeaderValue};
+use actix_web::http::header::{CACHE_CONTROL, CONTENT_TYPE, HeaderValue};
 use actix_web::web::Data;
 use actix_web::Responder;
 use actix_service::{Service};
+use futures::future::{self, Either};
+use actix_http::HttpService;
+use actix_server::{self, ssl::{OpensslAcceptor, SslError}};
+use actix_service::NewService;
+use openssl::ssl::{AlpnError, SslAcceptor, SslFiletype, SslMethod, SslVerifyMode};
+use tokio_openssl::SslStream;
+use tokio_tcp::TcpStream;
 use std::path::PathBuf;
 use std::path::Path;
 use std::ffi::OsStr;
@@ -19,17 +26,26 @@ use serde;
 use serde_json;
 use serde::Deserialize;
 use base64;
+use chrono;
 use num_cpus;
+use libc;
 
 use errors::ApiError;
-use api;
+use api::{self, ApiVersion};
 use deltas::DeltaGenerator;
-use tokens::{TokenParser, ClaimsValidator};
+use tokens::{TokenParser, ClaimsValidator, PeerCertificate};
+use ratelimit::RateLimiter;
+use tokenusage;
+use cors::Cors;
 use jobs::{JobQueue};
 use logger::Logger;
 use ostree;
 use Pool;
 use db::Db;
+use health::Health;
+use tools::ToolVersions;
+use oidc;
+use summarycache::{self, SummaryCache};
 
 // Ensure we strip out .. and other risky things to avoid escaping out of the base dir
 fn canonicalize_path(path: &str) -> Result<PathBuf, actix_web::Error> {
@@ -70,6 +86,14 @@ fn from_base64<'de,D>(deserializer: D) -> Result<Vec<u8>, D::Error>
         .and_then(|string| base64::decode(&string).map_err(|err| Error::custom(err.to_string())))
 }
 
+fn from_time_of_day<'de,D>(deserializer: D) -> Result<chrono::NaiveTime, D::Error>
+    where D: serde::Deserializer<'de>
+{
+    use serde::de::Error;
+    String::deserialize(deserializer)
+        .and_then(|string| chrono::NaiveTime::parse_from_str(&string, "%H:%M").map_err(|err| Error::custom(format!("Invalid time {}: {}", string, err))))
+}
+
 fn from_opt_base64<'de,D>(deserializer: D) -> Result<Option<Vec<u8>>, D::Error>
     where D: serde::Deserializer<'de>
 {
@@ -141,6 +165,47 @@ mod tests {
         assert!(match_glob("foo*gazonk*test", "foobargazonkWOOtest"));
         assert!(!match_glob("foo*gazonk*test", "foobargazonkWOOtestXX"));
     }
+
+    #[test]
+    fn test_delta_config() {
+        let by_id = DeltaConfig { id: vec!["org.example.*".to_string()], refs: vec![], arch: vec!["x86_64".to_string()], depth: 3, ignore_ref: false };
+        assert!(by_id.matches_ref("app/org.example.App/x86_64/stable"));
+        assert!(!by_id.matches_ref("app/org.example.App/aarch64/stable"));
+        assert!(!by_id.matches_ref("app/org.other.App/x86_64/stable"));
+        assert!(!by_id.matches_ref("appstream2/x86_64"));
+
+        let by_ref = DeltaConfig { id: vec![], refs: vec!["runtime/*.Debug/*".to_string()], arch: vec![], depth: 0, ignore_ref: true };
+        assert!(by_ref.matches_ref("runtime/org.example.App.Debug/x86_64/stable"));
+        assert!(!by_ref.matches_ref("app/org.example.App/x86_64/stable"));
+        assert_eq!(by_ref.ref_patterns(), vec!["runtime/*.Debug/*".to_string()]);
+        assert_eq!(by_id.ref_patterns(), vec!["*/org.example.*/x86_64/*".to_string()]);
+    }
+
+    #[test]
+    fn test_maintenance_window() {
+        let time = |s: &str| chrono::NaiveTime::parse_from_str(s, "%H:%M").unwrap();
+
+        let night = MaintenanceWindow { start: time("02:00"), end: time("05:00") };
+        assert!(night.contains(time("02:00")));
+        assert!(night.contains(time("04:59")));
+        assert!(!night.contains(time("05:00")));
+        assert!(!night.contains(time("01:59")));
+        assert!(!night.contains(time("12:00")));
+
+        /* Windows can go past midnight */
+        let late = MaintenanceWindow { start: time("22:00"), end: time("00:30") };
+        assert!(late.contains(time("22:00")));
+        assert!(late.contains(time("23:59")));
+        assert!(late.contains(time("00:00")));
+        assert!(late.contains(time("00:29")));
+        assert!(!late.contains(time("00:30")));
+        assert!(!late.contains(time("21:59")));
+        assert!(!late.contains(time("12:00")));
+
+        /* An empty window is never open */
+        let empty = MaintenanceWindow { start: time("03:00"), end: time("03:00") };
+        assert!(!empty.contains(time("03:00")));
+    }
 }
 
 /* Claims are used in two forms, one for API calls, and one for
@@ -160,22 +225,54 @@ pub struct Claims {
     #[serde(default)]
     pub repos: Vec<String>, // list of repo names or a '' for match all
     pub name: Option<String>, // for debug/logs only
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parent_sub: Option<String>, // for tokens minted by another token, the subject of that
+}
+
+impl Claims {
+    /* Quotas, rate limits and usage tracking go by the subject of the
+     * token that minted this one, if any, so minting tokens for build
+     * workers doesn't get around them */
+    pub fn usage_subject(&self) -> &str {
+        self.parent_sub.as_ref().unwrap_or(&self.sub)
+    }
 }
 
 #[derive(Deserialize, Debug, Clone)]
 #[serde(rename_all = "kebab-case", deny_unknown_fields)]
 pub struct DeltaConfig {
+    #[serde(default)]
     pub id: Vec<String>,
+    #[serde(default, rename = "ref")]
+    pub refs: Vec<String>, // Globs on the whole ref name
     #[serde(default)]
     pub arch: Vec<String>,
+    #[serde(default)]
     pub depth: u32,
+    #[serde(default)]
+    pub ignore_ref: bool, // Never generate deltas for these, not even from flatpak
 }
 
 impl DeltaConfig {
-    pub fn matches_ref(&self, id: &str, arch: &str) -> bool {
-        self.id.iter().any(|id_glob| match_glob(id_glob, id)) &&
+    pub fn matches_ref(&self, ref_name: &str) -> bool {
+        let parts: Vec<&str> = ref_name.split("/").collect();
+        let id_matches = parts.len() == 4 &&
+            self.id.iter().any(|id_glob| match_glob(id_glob, parts[1])) &&
             (self.arch.is_empty() ||
-             self.arch.iter().any(|arch_glob| match_glob(arch_glob, arch)))
+             self.arch.iter().any(|arch_glob| match_glob(arch_glob, parts[2])));
+        id_matches || self.refs.iter().any(|ref_glob| match_glob(ref_glob, ref_name))
+    }
+
+    /* The same refs as a pattern for flatpak's --static-delta-ignore-ref */
+    pub fn ref_patterns(&self) -> Vec<String> {
+        let mut patterns = self.refs.clone();
+        let arches = if self.arch.is_empty() { vec!["*".to_string()] } else { self.arch.clone() };
+        for id in self.id.iter() {
+            for arch in arches.iter() {
+                patterns.push(format!("*/{}/{}/*", id, arch));
+            }
+        }
+        patterns
     }
 }
 
@@ -186,10 +283,100 @@ pub struct SubsetConfig {
     pub base_url: Option<String>,
 }
 
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct OciRegistryConfig {
+    pub url: String, // The skopeo destination prefix, like docker://registry.example.com/flatpak
+    pub authfile: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct ResourceLimits {
+    pub memory_max: Option<String>,
+    pub cpu_max: Option<String>,
+    pub io_max: Option<String>,
+}
+
 fn default_depth() -> u32 {
     5
 }
 
+fn default_subsummaries() -> bool {
+    true
+}
+
+fn default_prune_interval() -> u64 {
+    60 * 60 * 24
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct PruneConfig {
+    pub depth: i32, // Number of commits to keep in the history of each ref
+    #[serde(default = "default_prune_interval")]
+    pub interval_secs: u64,
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+pub fn default_gc_grace() -> u64 {
+    2 * 24 * 60 * 60
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct GcConfig {
+    /* Unreachable objects are only deleted after having been unreachable this long */
+    #[serde(default = "default_gc_grace")]
+    pub grace_secs: u64,
+    #[serde(default = "default_prune_interval")]
+    pub interval_secs: u64,
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+fn default_eol_scope() -> String {
+    "eol".to_string()
+}
+
+/* Marking refs end-of-life can't really be undone for the users that
+ * already got it, so it can require more than a normal commit */
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct EolPolicyConfig {
+    #[serde(default)]
+    pub min_reason_length: usize,
+    #[serde(default = "default_eol_scope")]
+    pub required_scope: String,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum AppstreamValidation {
+    #[default]
+    Off,
+    Warn,  // Report the problems in the commit job results
+    Error, // Also fail the commit if there are errors
+}
+
+/* The timestamp of the commits the commit job makes */
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum CommitTimestamp {
+    #[default]
+    Now,      // When the commit job runs, the same for all refs of the build
+    Preserve, // The timestamp of the uploaded commit
+    Supplied, // The timestamp in the commit request, or now if none is given
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct ScreenshotsConfig {
+    pub media_dir: PathBuf,
+    pub media_url: String, // Where media-dir is served
+}
+
 #[derive(Deserialize, Debug, Clone)]
 #[serde(rename_all = "kebab-case", deny_unknown_fields)]
 pub struct RepoConfig {
@@ -211,10 +398,29 @@ pub struct RepoConfig {
     pub runtime_repo_url: Option<String>,
     pub subsets: HashMap<String, SubsetConfig>,
     pub post_publish_script: Option<String>,
+    pub post_publish_timeout_secs: Option<u64>,
     #[serde(default)]
     pub deltas: Vec<DeltaConfig>,
     #[serde(default = "default_depth")]
     pub appstream_delta_depth: u32,
+    pub oci_registry: Option<OciRegistryConfig>,
+    pub prune: Option<PruneConfig>,
+    pub gc: Option<GcConfig>,
+    pub screenshots: Option<ScreenshotsConfig>,
+    #[serde(default)]
+    pub appstream_validation: AppstreamValidation,
+    #[serde(default)]
+    pub require_ref_bindings: bool,
+    #[serde(default = "default_subsummaries")]
+    pub subsummaries: bool,
+    pub eol_policy: Option<EolPolicyConfig>,
+    /* If set, refs for other arches are left out when publishing */
+    pub publish_arches: Option<Vec<String>>,
+    #[serde(default)]
+    pub commit_timestamp: CommitTimestamp,
+    /* Commit identical content to identical commits, and don't publish unchanged refs */
+    #[serde(default)]
+    pub reproducible_commits: bool,
 }
 
 fn default_host() -> String {
@@ -225,6 +431,280 @@ fn default_port() -> i32 {
     8080
 }
 
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct TlsConfig {
+    pub certificate: PathBuf, // PEM, with the full chain
+    pub private_key: PathBuf,
+    pub http_redirect_port: Option<i32>,
+    pub ca_file: Option<PathBuf>, // PEM, client certificates are verified against these
+}
+
+fn default_oidc_scopes() -> Vec<String> {
+    vec!["openid".to_string(), "email".to_string()]
+}
+
+fn default_session_secs() -> u64 {
+    8 * 60 * 60
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct OidcConfig {
+    pub issuer: String,
+    pub authorization_endpoint: String,
+    pub token_endpoint: String,
+    pub client_id: String,
+    pub client_secret: String,
+    #[serde(default = "default_oidc_scopes")]
+    pub scopes: Vec<String>,
+    #[serde(default)]
+    pub allowed_users: Vec<String>, // Emails, or "@example.com" for a whole domain. Empty allows anyone
+    #[serde(default = "default_session_secs")]
+    pub session_secs: u64,
+}
+
+/* Client certificates are verified by the built-in tls support (with a
+ * ca-file), or by a tls terminating proxy in front of us, which passes on
+ * the subject of the verified certificate */
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct ClientCertConfig {
+    pub subject_header: Option<String>,
+    #[serde(default)]
+    pub trusted_proxies: Vec<String>, // Addresses the header is accepted from
+    pub required_scopes: Vec<String>, // These need a certificate as well as a token
+    pub identities: HashMap<String, Vec<String>>, // Certificate subject -> allowed scopes
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct RateLimitConfig {
+    pub requests_per_second: Option<f64>, // No limit on the request rate if unset
+    pub burst: Option<u32>, // Defaults to one second worth of requests
+    pub max_concurrent_uploads: Option<u32>,
+    pub max_concurrent_uploads_per_build: Option<u32>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct UploadQuotaConfig {
+    pub default_bytes: Option<u64>, // No limit for subjects not listed if unset
+    #[serde(default)]
+    pub subjects: HashMap<String, u64>,
+}
+
+impl UploadQuotaConfig {
+    pub fn get_quota(&self, sub: &str) -> Option<u64> {
+        self.subjects.get(sub).cloned().or(self.default_bytes)
+    }
+}
+
+fn default_cors_methods() -> Vec<String> {
+    vec!["GET".to_string(), "POST".to_string()]
+}
+
+fn default_cors_headers() -> Vec<String> {
+    vec!["Authorization".to_string(), "Content-Type".to_string()]
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct CorsConfig {
+    pub allowed_origins: Vec<String>, // "*" allows any origin
+    #[serde(default = "default_cors_methods")]
+    pub allowed_methods: Vec<String>,
+    #[serde(default = "default_cors_headers")]
+    pub allowed_headers: Vec<String>,
+    pub max_age_secs: Option<u64>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct WebhookConfig {
+    pub url: String,
+    pub secret: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct SmtpConfig {
+    pub url: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub from: String,
+    pub to: Vec<String>,
+    pub only_repo: Option<String>,
+}
+
+fn default_load_shedding_retry_after_secs() -> u64 {
+    5 * 60
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct LoadSheddingConfig {
+    pub max_queued_jobs: Option<i64>,
+    #[serde(default = "default_load_shedding_retry_after_secs")]
+    pub retry_after_secs: u64,
+}
+
+fn default_failure_alert_window_secs() -> u64 {
+    60 * 60
+}
+
+fn default_failure_alert_min_failures() -> i64 {
+    3
+}
+
+fn default_failure_alert_max_failure_rate() -> f64 {
+    0.5
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct FailureAlertConfig {
+    #[serde(default = "default_failure_alert_window_secs")]
+    pub window_secs: u64,
+    #[serde(default = "default_failure_alert_min_failures")]
+    pub min_failures: i64,
+    #[serde(default = "default_failure_alert_max_failure_rate")]
+    pub max_failure_rate: f64, // Alert when more than this part of the jobs in the window failed
+    pub kinds: Option<Vec<String>>, // All kinds if unset
+}
+
+fn default_webhook_max_attempts() -> i32 {
+    5
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum RunMode {
+    #[default]
+    All,
+    Frontend,
+    Worker,
+}
+
+impl RunMode {
+    pub fn runs_jobs(&self) -> bool {
+        *self != RunMode::Frontend
+    }
+
+    pub fn serves_http(&self) -> bool {
+        *self != RunMode::Worker
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct DatabasePoolConfig {
+    pub max_size: Option<u32>,
+    pub min_idle: Option<u32>,
+    pub connection_timeout_secs: Option<u64>,
+    pub idle_timeout_secs: Option<u64>,
+    #[serde(default = "default_db_startup_retry_secs")]
+    pub startup_retry_secs: u64,
+}
+
+impl Default for DatabasePoolConfig {
+    fn default() -> DatabasePoolConfig {
+        DatabasePoolConfig {
+            max_size: None,
+            min_idle: None,
+            connection_timeout_secs: None,
+            idle_timeout_secs: None,
+            startup_retry_secs: default_db_startup_retry_secs(),
+        }
+    }
+}
+
+fn default_db_startup_retry_secs() -> u64 {
+    60
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+/* Times of day in UTC, like "02:00", the end may be past midnight */
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct MaintenanceWindow {
+    #[serde(deserialize_with = "from_time_of_day")]
+    pub start: chrono::NaiveTime,
+    #[serde(deserialize_with = "from_time_of_day")]
+    pub end: chrono::NaiveTime,
+}
+
+impl MaintenanceWindow {
+    pub fn contains(&self, time: chrono::NaiveTime) -> bool {
+        if self.start <= self.end {
+            time >= self.start && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct TracingConfig {
+    pub zipkin_url: String,
+}
+
+fn default_job_poll_interval_secs() -> u64 {
+    10
+}
+
+fn default_job_lease_secs() -> u64 {
+    60
+}
+
+fn default_max_job_wait_secs() -> u64 {
+    5 * 60
+}
+
+fn default_delete_grace_secs() -> u64 {
+    7 * 24 * 60 * 60
+}
+
+fn default_stale_tmp_secs() -> u64 {
+    24 * 60 * 60
+}
+
+fn default_bundle_expiry_secs() -> u64 {
+    24 * 60 * 60
+}
+
+fn default_startup_self_check() -> bool {
+    true
+}
+
+fn default_flatpak_path() -> PathBuf {
+    PathBuf::from("flatpak")
+}
+
+fn default_ostree_path() -> PathBuf {
+    PathBuf::from("ostree")
+}
+
+fn default_github_api_url() -> String {
+    "https://api.github.com".to_string()
+}
+
+fn default_bundle_dir() -> PathBuf {
+    PathBuf::from("bundles")
+}
+
+fn default_job_log_dir() -> PathBuf {
+    PathBuf::from("job-logs")
+}
+
 fn default_numcpu() -> u32 {
     num_cpus::get() as u32
 }
@@ -233,6 +713,8 @@ fn default_numcpu() -> u32 {
 #[serde(rename_all = "kebab-case", deny_unknown_fields)]
 pub struct Config {
     pub database_url: String,
+    #[serde(default)]
+    pub database_pool: DatabasePoolConfig,
     #[serde(default = "default_host")]
     pub host: String,
     #[serde(default = "default_port")]
@@ -246,6 +728,10 @@ pub struct Config {
     pub repo_secret: Option<Vec<u8>>,
     pub repos: HashMap<String, RepoConfig>,
     pub build_repo_base: PathBuf,
+    #[serde(default = "default_bundle_dir")]
+    pub bundle_dir: PathBuf,
+    #[serde(default = "default_job_log_dir")]
+    pub job_log_dir: PathBuf,
     pub build_gpg_key: Option<String>,
     #[serde(skip)]
     pub build_gpg_key_content: Option<String>,
@@ -253,9 +739,81 @@ pub struct Config {
     pub delay_update_secs: u64,
     #[serde(default = "default_numcpu")]
     pub local_delta_threads: u32,
+    #[serde(default = "default_numcpu")]
+    pub commit_threads: u32,
+    #[serde(default)]
+    pub link_uploaded_objects: bool,
+    pub github_token: Option<String>,
+    #[serde(default = "default_github_api_url")]
+    pub github_api_url: String,
+    #[serde(default)]
+    pub webhooks: Vec<WebhookConfig>,
+    #[serde(default = "default_webhook_max_attempts")]
+    pub webhook_max_attempts: i32,
+    pub smtp: Option<SmtpConfig>,
+    pub failure_alert: Option<FailureAlertConfig>,
+    pub slow_query_ms: Option<u64>, // Log database queries taking longer than this
+    pub sentry_dsn: Option<String>,
+    pub shutdown_deadline_secs: Option<u64>,
+    #[serde(default)]
+    pub sandbox_commands: bool,
+    pub cgroup_base: Option<PathBuf>,
+    #[serde(default)]
+    pub resource_limits: HashMap<String, ResourceLimits>,
+    pub rate_limit: Option<RateLimitConfig>,
+    pub upload_quota: Option<UploadQuotaConfig>,
+    pub upload_bytes_per_second: Option<u64>,
+    #[serde(default)]
+    pub object_pool: bool,
+    #[serde(default = "default_flatpak_path")]
+    pub flatpak_path: PathBuf,
+    #[serde(default = "default_ostree_path")]
+    pub ostree_path: PathBuf,
+    #[serde(skip)]
+    pub tools: ToolVersions,
+    #[serde(default = "default_startup_self_check")]
+    pub startup_self_check: bool,
+    pub cors: Option<CorsConfig>,
+    pub tls: Option<TlsConfig>,
+    pub client_certificates: Option<ClientCertConfig>,
+    #[serde(default)]
+    pub trusted_proxies: Vec<String>, // Addresses the Forwarded and X-Forwarded-For headers are accepted from
+    pub oidc: Option<OidcConfig>,
+    #[serde(default = "default_delete_grace_secs")]
+    pub delete_grace_secs: u64,
+    #[serde(default = "default_stale_tmp_secs")]
+    pub stale_tmp_secs: u64,
+    #[serde(default = "default_bundle_expiry_secs")]
+    pub bundle_expiry_secs: u64,
+    pub upload_session_idle_secs: Option<u64>, // Builds are never aborted for being idle if unset
+    #[serde(default)]
+    pub run_mode: RunMode,
+    #[serde(default = "default_job_poll_interval_secs")]
+    pub job_poll_interval_secs: u64,
+    #[serde(default)]
+    pub node_name: String,
+    #[serde(default = "default_job_lease_secs")]
+    pub job_lease_secs: u64,
+    #[serde(default = "default_max_job_wait_secs")]
+    pub max_job_wait_secs: u64,
+    #[serde(default)]
+    pub log_format: LogFormat,
+    pub tracing: Option<TracingConfig>,
+    #[serde(default)]
+    pub maintenance_windows: Vec<MaintenanceWindow>,
+    pub min_free_space_mb: Option<u64>,
+    pub load_shedding: Option<LoadSheddingConfig>,
 }
 
 impl RepoConfig {
+    /* Reproducible commits can't use the current time */
+    pub fn get_commit_timestamp(&self) -> CommitTimestamp {
+        match self.commit_timestamp {
+            CommitTimestamp::Now if self.reproducible_commits => CommitTimestamp::Preserve,
+            policy => policy,
+        }
+    }
+
     pub fn get_abs_repo_path(&self) -> PathBuf {
         let mut repo_path = std::env::current_dir().unwrap_or_else(|_e| PathBuf::from("/"));
 
@@ -278,24 +836,34 @@ impl RepoConfig {
         } else if ref_name.starts_with("appstream2/") {
             self.appstream_delta_depth /* This updates often, so lets have some more */
         } else if ref_name.starts_with("app/") || ref_name.starts_with("runtime/") {
-            let parts : Vec<&str> = ref_name.split("/").collect();
-            if parts.len() == 4 {
-                let id = parts[1];
-                let arch = parts[2];
-                for dc in &self.deltas {
-                    if dc.matches_ref(id, arch) {
-                        return dc.depth
-                    }
+            /* The first matching rule wins */
+            for dc in &self.deltas {
+                if dc.matches_ref(ref_name) {
+                    return if dc.ignore_ref { 0 } else { dc.depth }
                 }
-            };
+            }
             0
         } else {
             0 /* weird ref? */
         }
     }
+
+    pub fn get_static_delta_ignore_patterns(&self) -> Vec<String> {
+        self.deltas.iter()
+            .filter(|dc| dc.ignore_ref)
+            .flat_map(|dc| dc.ref_patterns())
+            .collect()
+    }
 }
 
 impl Config {
+    /* Heavy jobs can run any time if no windows are configured */
+    pub fn in_maintenance_window(&self) -> bool {
+        let now = chrono::Utc::now().time();
+        self.maintenance_windows.is_empty() ||
+            self.maintenance_windows.iter().any(|window| window.contains(now))
+    }
+
     pub fn get_repoconfig(&self, name: &str) -> Result<&RepoConfig, ApiError> {
         self.repos.get(name).ok_or_else (|| ApiError::BadRequest("No such repo".to_string()))
     }
@@ -334,6 +902,16 @@ fn load_gpg_key (maybe_gpg_homedir: &Option<String>, maybe_gpg_key: &Option<Stri
 }
 
 
+fn get_hostname() -> io::Result<String> {
+    let mut buf = [0u8; 256];
+    let res = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+    if res != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let len = buf.iter().position(|&c| c == 0).unwrap_or(buf.len());
+    Ok(String::from_utf8_lossy(&buf[..len]).into_owned())
+}
+
 pub fn load_config<P: AsRef<Path>>(path: P) -> io::Result<Config> {
     let config_contents = std::fs::read_to_string(path)?;
     let mut config_data: Config = serde_json::from_str(&config_contents).map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
@@ -348,9 +926,33 @@ pub fn load_config<P: AsRef<Path>>(path: P) -> io::Result<Config> {
         config_data.base_url = format!("http://{}:{}", config_data.host, config_data.port)
     }
 
+    /* Several processes on a host (or containers sharing a hostname) must
+     * not take each other's leases, so the default includes the pid */
+    if config_data.node_name.is_empty() {
+        config_data.node_name = format!("{}-{}", get_hostname()?, std::process::id());
+    }
+
+    config_data.tools = ToolVersions::detect(&config_data.flatpak_path, &config_data.ostree_path);
+
     Ok(config_data)
 }
 
+/* NamedFile already handles Range, ETag/If-None-Match and
+ * If-Modified-Since, but guesses the content type from the extension and
+ * sets no cache headers. Objects and deltas are content-addressed, so
+ * they can be cached forever, while the summary and refs change and must
+ * be revalidated. */
+fn build_repo_file_headers(relpath: &Path) -> (Option<&'static str>, Option<&'static str>) {
+    if relpath.starts_with("objects") || relpath.starts_with("deltas") {
+        (Some("application/octet-stream"), Some("public, max-age=31536000, immutable"))
+    } else if relpath.starts_with("refs") ||
+        relpath == Path::new("summary") || relpath == Path::new("summary.sig") || relpath == Path::new("config") {
+        (Some("application/octet-stream"), Some("no-cache"))
+    } else {
+        (None, None)
+    }
+}
+
 fn handle_build_repo(config: Data<Config>,
                      req: HttpRequest) -> Result<HttpResponse, actix_web::Error> {
     let tail = req.match_info().query("tail");
@@ -363,14 +965,23 @@ fn handle_build_repo(config: Data<Config>,
         return Err(ErrorNotFound("Ignoring directory"));
     }
 
-    NamedFile::open(path).or_else(|_e| {
+    let mut resp = NamedFile::open(path).or_else(|_e| {
         let fallback_path = Path::new(&config.build_repo_base).join(&id).join("parent").join(&relpath);
         if fallback_path.is_dir() {
             Err(ErrorNotFound("Ignoring directory"))
         } else {
             NamedFile::open(fallback_path).map_err(|e| e.into())
         }
-    })?.respond_to(&req)
+    })?.respond_to(&req)?;
+
+    let (content_type, cache_control) = build_repo_file_headers(&relpath);
+    if let Some(content_type) = content_type {
+        resp.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+    }
+    if let Some(cache_control) = cache_control {
+        resp.headers_mut().insert(CACHE_CONTROL, HeaderValue::from_static(cache_control));
+    }
+    Ok(resp)
 }
 
 fn get_commit_for_file(path: &PathBuf) -> Option<ostree::OstreeCommit> {
@@ -430,6 +1041,7 @@ fn verify_repo_token(req: &HttpRequest, commit: ostree::OstreeCommit, repoconfig
 }
 
 fn handle_repo(config: Data<Config>,
+               summary_cache: Data<SummaryCache>,
                req: HttpRequest) -> Result<HttpResponse, actix_web::Error> {
     let tail = req.match_info().query("tail");
     let tailpath = canonicalize_path(tail.trim_start_matches('/'))?;
@@ -447,6 +1059,10 @@ fn handle_repo(config: Data<Config>,
         verify_repo_token(&req, commit, repoconfig, &path)?;
     }
 
+    if summarycache::is_cached_file(relpath) {
+        return summary_cache.respond(&path, &req).map_err(|e| e.into());
+    }
+
     NamedFile::open(path).or_else(|e| {
         // Was this a delta, if so check the deltas queued for deletion
         if relpath.starts_with("deltas") {
@@ -462,57 +1078,178 @@ fn handle_repo(config: Data<Config>,
     })?.respond_to(&req)
 }
 
+/* The api routes, shared by all the api versions */
+fn configure_api(version: ApiVersion, cfg: &mut web::ServiceConfig) {
+    cfg.service(web::resource("/token")
+                 .route(web::get().to(api::token_info)))
+        .service(web::resource("/token_subset")
+                 .route(web::post().to(api::token_subset)))
+        .service(web::resource("/tokens")
+                 .route(web::post().to(api::create_token)))
+        .service(web::resource("/audit")
+                 .route(web::get().to_async(api::audit_log_entries)))
+        .service(web::resource("/token-usage")
+                 .route(web::get().to_async(api::token_usage)))
+        .service(web::resource("/job/{id}").name(&version.route_name("show_job"))
+                 .route(web::get().to_async(api::get_job)))
+        .service(web::resource("/job/{id}/dependencies")
+                 .route(web::post().to_async(api::add_job_dependencies)))
+        .service(web::resource("/build")
+                 .route(web::post().to_async(api::create_build))
+                 .route(web::get().to_async(api::builds)))
+        .service(web::resource("/build/{id}").name(&version.route_name("show_build"))
+                 .route(web::get().to_async(api::get_build)))
+        .service(web::resource("/build/{id}/comments")
+                 .route(web::post().to_async(api::add_build_comment))
+                 .route(web::get().to_async(api::get_build_comments)))
+        .service(web::resource("/build/{id}/build_ref")
+                 .route(web::post().to_async(api::create_build_ref)))
+        .service(web::resource("/build/{id}/build_ref/{ref_id}").name(&version.route_name("show_build_ref"))
+                 .route(web::get().to_async(api::get_build_ref))
+                 .route(web::delete().to_async(api::delete_build_ref)))
+        .service(web::resource("/build/{id}/missing_objects")
+                 .data(web::JsonConfig::default().limit(1024*1024*10))
+                 .route(web::get().to(api::missing_objects))
+                 .route(web::post().to(api::missing_objects)))
+        .service(web::resource("/build/{id}/add_extra_ids")
+                 .route(web::post().to_async(api::add_extra_ids)))
+        .service(web::resource("/build/{id}/upload_tar")
+                 .route(web::post().to_async(api::upload_tar)))
+        .service(web::resource("/build/{id}/upload")
+                 .route(web::post().to_async(api::upload)))
+        .service(web::resource("/build/{id}/commit").name(&version.route_name("show_commit_job"))
+                 .route(web::post().to_async(api::commit))
+                 .route(web::get().to_async(api::get_commit_job)))
+        .service(web::resource("/build/{id}/publish").name(&version.route_name("show_publish_job"))
+                 .route(web::post().to_async(api::publish))
+                 .route(web::get().to_async(api::get_publish_job)))
+        .service(web::resource("/build/{id}/commit_and_publish")
+                 .route(web::post().to_async(api::commit_and_publish)))
+        .service(web::resource("/build/{id}/bundle")
+                 .route(web::post().to_async(api::build_bundle)))
+        .service(web::resource("/build/{id}/jobs")
+                 .route(web::get().to_async(api::get_build_jobs)))
+        .service(web::resource("/build/{id}/diff")
+                 .route(web::get().to_async(api::get_build_diff)))
+        .service(web::resource("/build/{id}/purge")
+                 .route(web::post().to_async(api::purge)))
+        .service(web::resource("/build/{id}/derive")
+                 .route(web::post().to_async(api::derive_build)))
+        .service(web::resource("/build/{id}/delete")
+                 .route(web::post().to_async(api::delete_build)))
+        .service(web::resource("/build/{id}/undelete")
+                 .route(web::post().to_async(api::undelete_build)))
+        .service(web::resource("/build/{id}/worker-token")
+                 .route(web::post().to_async(api::create_worker_token)))
+        .service(web::resource("/build/{id}/abort")
+                 .route(web::post().to_async(api::abort_build)))
+        .service(web::resource("/repo/{repo}/deltas")
+                 .route(web::get().to_async(api::list_deltas)))
+        .service(web::resource("/repo/{repo}/ref/{ref_name:.+}/history")
+                 .route(web::get().to_async(api::get_ref_history)))
+        .service(web::resource("/repo/{repo}/pins")
+                 .route(web::post().to_async(api::add_ref_pin))
+                 .route(web::get().to_async(api::list_ref_pins)))
+        .service(web::resource("/repo/{repo}/pins/{pin_id}")
+                 .route(web::delete().to_async(api::delete_ref_pin)))
+        .service(web::resource("/repo/{repo}/deprecate")
+                 .route(web::post().to_async(api::deprecate_ref)))
+        .service(web::resource("/repo/{repo}/tombstones")
+                 .route(web::get().to_async(api::list_ref_tombstones)))
+        .service(web::resource("/repo/{repo}/rollback")
+                 .route(web::post().to_async(api::rollback)))
+        .service(web::resource("/acl/{app_id}")
+                 .route(web::post().to_async(api::add_app_acl_entry))
+                 .route(web::get().to_async(api::list_app_acl)))
+        .service(web::resource("/acl/{app_id}/{entry_id}")
+                 .route(web::delete().to_async(api::delete_app_acl_entry)))
+        .service(web::resource("/acl-group/{group}")
+                 .route(web::post().to_async(api::add_acl_group_member))
+                 .route(web::get().to_async(api::list_acl_group)))
+        .service(web::resource("/acl-group/{group}/{member_id}")
+                 .route(web::delete().to_async(api::delete_acl_group_member)))
+        .service(web::resource("/organizations")
+                 .route(web::post().to_async(api::create_organization))
+                 .route(web::get().to_async(api::list_organizations)))
+        .service(web::resource("/organizations/{name}")
+                 .route(web::get().to_async(api::get_organization))
+                 .route(web::delete().to_async(api::delete_organization)))
+        .service(web::resource("/organizations/{name}/members")
+                 .route(web::post().to_async(api::add_organization_member)))
+        .service(web::resource("/organizations/{name}/members/{member_id}")
+                 .route(web::delete().to_async(api::delete_organization_member)))
+        .service(web::resource("/bundle")
+                 .route(web::post().to_async(api::bundle)))
+        .service(web::resource("/bundle/{id}").name(&version.route_name("show_bundle"))
+                 .route(web::get().to_async(api::get_bundle)))
+        .service(web::resource("/oci_export")
+                 .route(web::post().to_async(api::oci_export)))
+        .service(web::resource("/promote")
+                 .route(web::post().to_async(api::promote)))
+        .service(web::resource("/delta/worker")
+                 .route(web::get().to(api::ws_delta)))
+        .service(web::resource("/delta/upload/{repo}")
+                 .route(web::post().to_async(api::delta_upload)));
+}
+
 pub fn create_app (
     pool: Pool,
     config: &Arc<Config>,
     job_queue: Addr<JobQueue>,
     delta_generator: Addr<DeltaGenerator>,
+    health: Health,
 ) -> Server {
     let c = config.clone();
     let secret = config.secret.clone();
     let repo_secret = config.repo_secret.as_ref().unwrap_or(config.secret.as_ref()).clone();
-    let http_server = HttpServer::new(move || {
+    let rate_limiter = RateLimiter::new(&config.rate_limit);
+    let token_usage = tokenusage::start_token_usage_tracker(pool.clone(), &config.trusted_proxies);
+    let https_redirect = config.tls.as_ref().map(|tls| tls.http_redirect_port.is_some()).unwrap_or(false);
+    let https_port = config.port;
+    let summary_cache = SummaryCache::new();
+    let app_factory = move || {
         App::new()
+            .wrap_fn(move |req, srv| {
+                /* With tls, only the redirect listener is not secure */
+                if https_redirect && !req.app_config().secure() {
+                    let host = req.connection_info().host().split(':').next().unwrap_or("").to_string();
+                    let path = req.uri().path_and_query().map(|p| p.as_str().to_string()).unwrap_or("/".to_string());
+                    let location = if https_port == 443 {
+                        format!("https://{}{}", host, path)
+                    } else {
+                        format!("https://{}:{}{}", host, https_port, path)
+                    };
+                    let response = HttpResponse::MovedPermanently()
+                        .header(http::header::LOCATION, location)
+                        .finish();
+                    Either::A(future::ok(req.into_response(response.into_body())))
+                } else {
+                    Either::B(srv.call(req))
+                }
+            })
             .data(job_queue.clone())
             .data(delta_generator.clone())
+            .data(health.clone())
+            .data(summary_cache.clone())
             .register_data(Data::new((*c).clone()))
             .data(Db(pool.clone()))
             .wrap(Logger::default())
             .wrap(middleware::Compress::new(http::header::ContentEncoding::Identity))
+            .service(web::resource("/api/versions")
+                     .route(web::get().to(api::versions)))
             .service(web::scope("/api/v1")
-                     .wrap(TokenParser::new(&secret))
-                     .service(web::resource("/token_subset")
-                              .route(web::post().to(api::token_subset)))
-                     .service(web::resource("/job/{id}").name("show_job")
-                              .route(web::get().to_async(api::get_job)))
-                     .service(web::resource("/build")
-                              .route(web::post().to_async(api::create_build))
-                              .route(web::get().to_async(api::builds)))
-                     .service(web::resource("/build/{id}").name("show_build")
-                              .route(web::get().to_async(api::get_build)))
-                     .service(web::resource("/build/{id}/build_ref")
-                              .route(web::post().to_async(api::create_build_ref)))
-                     .service(web::resource("/build/{id}/build_ref/{ref_id}").name("show_build_ref")
-                              .route(web::get().to_async(api::get_build_ref)))
-                     .service(web::resource("/build/{id}/missing_objects")
-                              .data(web::JsonConfig::default().limit(1024*1024*10))
-                              .route(web::get().to(api::missing_objects)))
-                     .service(web::resource("/build/{id}/add_extra_ids")
-                              .route(web::post().to_async(api::add_extra_ids)))
-                     .service(web::resource("/build/{id}/upload")
-                              .route(web::post().to_async(api::upload)))
-                     .service(web::resource("/build/{id}/commit").name("show_commit_job")
-                              .route(web::post().to_async(api::commit))
-                              .route(web::get().to_async(api::get_commit_job)))
-                     .service(web::resource("/build/{id}/publish").name("show_publish_job")
-                              .route(web::post().to_async(api::publish))
-                              .route(web::get().to_async(api::get_publish_job)))
-                     .service(web::resource("/build/{id}/purge")
-                              .route(web::post().to_async(api::purge)))
-                     .service(web::resource("/delta/worker")
-                              .route(web::get().to(api::ws_delta)))
-                     .service(web::resource("/delta/upload/{repo}")
-                              .route(web::post().to_async(api::delta_upload)))
+                     .wrap(token_usage.clone())
+                     .wrap(rate_limiter.clone()) // Runs inside the TokenParser, so it sees the claims
+                     .wrap(TokenParser::new(&secret).client_certificates(&c.client_certificates))
+                     .wrap(Cors::new(&c.cors)) // Outside the TokenParser, preflight requests have no token
+                     .configure(|cfg| configure_api(ApiVersion::V1, cfg))
+            )
+            .service(web::scope("/api/v2")
+                     .wrap(token_usage.clone())
+                     .wrap(rate_limiter.clone())
+                     .wrap(TokenParser::new(&secret).client_certificates(&c.client_certificates))
+                     .wrap(Cors::new(&c.cors))
+                     .configure(|cfg| configure_api(ApiVersion::V2, cfg))
             )
             .service(web::scope("/repo")
                      .wrap(TokenParser::optional(&repo_secret))
@@ -532,21 +1269,99 @@ pub fn create_app (
                      .route(web::head().to(handle_build_repo))
                      .to(HttpResponse::MethodNotAllowed)
             )
-            .service(web::resource("/status")
-                     .route(web::get().to_async(api::status)))
-            .service(web::resource("/status/{id}")
-                     .route(web::get().to_async(api::job_status)))
-    });
+            .service(web::scope("/status")
+                     .wrap_fn(|req, srv| {
+                         /* With oidc configured, the status pages need a login */
+                         let needs_login = match req.app_data::<Config>() {
+                             Some(config) => config.oidc.is_some() && !oidc::has_session(&req, &config),
+                             None => false,
+                         };
+                         if needs_login {
+                             let response = oidc::login_redirect(req.uri());
+                             Either::A(future::ok(req.into_response(response.into_body())))
+                         } else {
+                             Either::B(srv.call(req))
+                         }
+                     })
+                     .service(web::resource("")
+                              .route(web::get().to_async(api::status)))
+                     .service(web::resource("/build/{id}")
+                              .route(web::get().to_async(api::build_status)))
+                     .service(web::resource("/job/{id}")
+                              .route(web::get().to_async(api::job_status)))
+                     /* Old job urls */
+                     .service(web::resource("/{id}")
+                              .route(web::get().to_async(api::job_status))))
+            .service(web::resource("/login")
+                     .route(web::get().to(oidc::login)))
+            .service(web::resource("/login/callback")
+                     .route(web::get().to_async(oidc::login_callback)))
+            .service(web::resource("/health")
+                     .route(web::get().to_async(api::health)))
+            .service(web::resource("/metrics")
+                     .route(web::get().to(api::metrics)))
+    };
 
     let bind_to = format!("{}:{}", config.host, config.port);
-    let server =
-        http_server
-        .bind(&bind_to)
-        .unwrap()
-        .disable_signals()
-        .start();
-
-    info!("Started http server: {}", bind_to);
+    match config.tls {
+        None => {
+            info!("Started http server: {}", bind_to);
+            HttpServer::new(app_factory)
+                .bind(&bind_to).unwrap()
+                .disable_signals()
+                .start()
+        },
+        Some(ref tls) => {
+            let mut acceptor = SslAcceptor::mozilla_intermediate(SslMethod::tls()).unwrap();
+            acceptor.set_private_key_file(&tls.private_key, SslFiletype::PEM)
+                .expect("Failed to load tls private key");
+            acceptor.set_certificate_chain_file(&tls.certificate)
+                .expect("Failed to load tls certificate");
+            if let Some(ref ca_file) = tls.ca_file {
+                acceptor.set_ca_file(ca_file)
+                    .expect("Failed to load tls ca file");
+                /* Client certificates are optional, the scopes needing one
+                 * are checked by the TokenParser */
+                acceptor.set_verify(SslVerifyMode::PEER);
+            }
+            /* Same as HttpServer::bind_ssl */
+            acceptor.set_alpn_select_callback(|_, protos| {
+                if protos.windows(3).any(|window| window == b"\x02h2") {
+                    Ok(b"h2")
+                } else {
+                    Err(AlpnError::NOACK)
+                }
+            });
+            acceptor.set_alpn_protos(b"\x08http/1.1\x02h2").unwrap();
+            let acceptor = acceptor.build();
 
-    server
+            /* We build the server ourselves rather than with HttpServer,
+             * to get at the client certificate of the connection */
+            info!("Started https server: {}", bind_to);
+            let https_factory = app_factory.clone();
+            let server = actix_server::Server::build()
+                .bind("flat-manager-https", &bind_to, move || {
+                    OpensslAcceptor::new(acceptor.clone())
+                        .map_err(SslError::Ssl)
+                        .and_then(HttpService::build()
+                                  .on_connect(|io: &SslStream<TcpStream>| PeerCertificate::from_ssl(io.get_ref().ssl()))
+                                  .finish(https_factory())
+                                  .map_err(SslError::Service)
+                                  .map_init_err(|_| ()))
+                }).unwrap();
+            let server = match tls.http_redirect_port {
+                Some(redirect_port) => {
+                    let redirect_bind_to = format!("{}:{}", config.host, redirect_port);
+                    info!("Redirecting http from {}", redirect_bind_to);
+                    server.bind("flat-manager-http", &redirect_bind_to, move || {
+                        HttpService::build().finish(app_factory())
+                    }).unwrap()
+                },
+                None => server,
+            };
+            server
+                .disable_signals()
+                .start()
+        },
+    }
 }
@@ -199,7 +199,7 @@ fn pull_and_generate_delta_async(repo_path: &PathBuf,
     Box::new(
         // We do 5 retries, because pull is sometimes not super stable
         ostree::pull_delta_async(5, &repo_path, &url, &delta_clone)
-            .and_then(move |_| ostree::generate_delta_async(&repo_path2, &delta_clone))
+            .and_then(move |_| ostree::generate_delta_async(Path::new("flatpak"), &repo_path2, &delta_clone, None))
             .from_err()
             )
 }
@@ -0,0 +1,262 @@
+extern crate flatmanager;
+extern crate actix;
+extern crate argparse;
+extern crate dotenv;
+extern crate env_logger;
+
+use argparse::{ArgumentParser, Store, StoreOption, StoreTrue, List};
+use dotenv::dotenv;
+use std::env;
+use std::io;
+use std::path::PathBuf;
+use std::process;
+
+use flatmanager::admin::Admin;
+use flatmanager::errors::ApiError;
+
+fn main() {
+    env_logger::init();
+
+    let mut command = String::new();
+    let mut args: Vec<String> = vec![];
+
+    {
+        let mut ap = ArgumentParser::new();
+        ap.set_description("Administer flat-manager. Commands: export-metadata, fsck, gc, gentoken, import-metadata, import-repo, list-builds, prune, purge-build, regenerate-deltas, resign, reset-token-usage, retry-job, update-repo");
+        ap.refer(&mut command)
+            .required()
+            .add_argument("command", Store,
+                          "Command to run");
+        ap.refer(&mut args)
+            .add_argument("arguments", List,
+                          "Arguments for command");
+        ap.stop_on_first_argument(true);
+        ap.parse_args_or_exit();
+    }
+
+    dotenv().ok();
+
+    let config_path = PathBuf::from(env::var("REPO_CONFIG").unwrap_or ("config.json".to_string()));
+    let config = flatmanager::load_config(&config_path);
+
+    args.insert(0, format!("flat-manager-admin {}", command));
+
+    let mut sys = actix::System::new("flat-manager-admin");
+    let admin = Admin::new(&config);
+
+    let res = match command.as_ref() {
+        "gentoken" => gentoken_command(&admin, args),
+        "list-builds" => sys.block_on(admin.list_builds()),
+        "purge-build" => {
+            let build_id = parse_id_arg("Purge build", "build_id", args);
+            sys.block_on(admin.purge_build(build_id))
+        },
+        "retry-job" => {
+            let job_id = parse_id_arg("Retry broken job", "job_id", args);
+            sys.block_on(admin.retry_job(job_id))
+        },
+        "update-repo" => {
+            let mut repo = String::new();
+            {
+                let mut ap = ArgumentParser::new();
+                ap.set_description("Queue an update-repo job");
+                ap.refer(&mut repo).required()
+                    .add_argument("repo", Store, "Repo name");
+                parse_or_exit(&ap, args);
+            }
+            sys.block_on(admin.update_repo(&repo))
+        },
+        "prune" => {
+            let mut repo = String::new();
+            let mut depth: Option<i32> = None;
+            let mut dry_run = false;
+            {
+                let mut ap = ArgumentParser::new();
+                ap.set_description("Queue a prune job");
+                ap.refer(&mut repo).required()
+                    .add_argument("repo", Store, "Repo name");
+                ap.refer(&mut depth)
+                    .add_option(&["--depth"], StoreOption, "Commits to keep per ref (default: from config)");
+                ap.refer(&mut dry_run)
+                    .add_option(&["--dry-run"], StoreTrue, "Only report what would be removed");
+                parse_or_exit(&ap, args);
+            }
+            sys.block_on(admin.prune(&repo, depth, dry_run))
+        },
+        "reset-token-usage" => {
+            let mut subject = String::new();
+            {
+                let mut ap = ArgumentParser::new();
+                ap.set_description("Reset the uploaded bytes counted against the quota of a token subject");
+                ap.refer(&mut subject).required()
+                    .add_argument("subject", Store, "Token subject");
+                parse_or_exit(&ap, args);
+            }
+            sys.block_on(admin.reset_token_usage(&subject))
+        },
+        "export-metadata" => {
+            let mut path = String::new();
+            {
+                let mut ap = ArgumentParser::new();
+                ap.set_description("Write the builds, refs and jobs in the database to a json file");
+                ap.refer(&mut path).required()
+                    .add_argument("file", Store, "Dump file");
+                parse_or_exit(&ap, args);
+            }
+            sys.block_on(admin.export_metadata(&path))
+        },
+        "import-metadata" => {
+            let mut path = String::new();
+            {
+                let mut ap = ArgumentParser::new();
+                ap.set_description("Load a file written by export-metadata into an empty database");
+                ap.refer(&mut path).required()
+                    .add_argument("file", Store, "Dump file");
+                parse_or_exit(&ap, args);
+            }
+            sys.block_on(admin.import_metadata(&path))
+        },
+        "import-repo" => {
+            let mut repo = String::new();
+            {
+                let mut ap = ArgumentParser::new();
+                ap.set_description("Record the refs of a repo published without flat-manager in the database");
+                ap.refer(&mut repo).required()
+                    .add_argument("repo", Store, "Repo name");
+                parse_or_exit(&ap, args);
+            }
+            sys.block_on(admin.import_repo(&repo))
+        },
+        "gc" => {
+            let mut repo = String::new();
+            let mut dry_run = false;
+            {
+                let mut ap = ArgumentParser::new();
+                ap.set_description("Queue a gc job");
+                ap.refer(&mut repo).required()
+                    .add_argument("repo", Store, "Repo name");
+                ap.refer(&mut dry_run)
+                    .add_option(&["--dry-run"], StoreTrue, "Only report what would be deleted");
+                parse_or_exit(&ap, args);
+            }
+            sys.block_on(admin.gc(&repo, dry_run))
+        },
+        "fsck" => {
+            let mut repo = String::new();
+            {
+                let mut ap = ArgumentParser::new();
+                ap.set_description("Queue a job checking the objects of a repo");
+                ap.refer(&mut repo).required()
+                    .add_argument("repo", Store, "Repo name");
+                parse_or_exit(&ap, args);
+            }
+            sys.block_on(admin.fsck(&repo))
+        },
+        "regenerate-deltas" => {
+            let mut repo = String::new();
+            {
+                let mut ap = ArgumentParser::new();
+                ap.set_description("Queue a job generating all the deltas of a repo again");
+                ap.refer(&mut repo).required()
+                    .add_argument("repo", Store, "Repo name");
+                parse_or_exit(&ap, args);
+            }
+            sys.block_on(admin.regenerate_deltas(&repo))
+        },
+        "resign" => {
+            let mut repo = String::new();
+            let mut key = String::new();
+            let mut sign_type = "gpg".to_string();
+            let mut delete_key: Option<String> = None;
+            {
+                let mut ap = ArgumentParser::new();
+                ap.set_description("Queue a job re-signing all refs and the summary with a new key");
+                ap.refer(&mut repo).required()
+                    .add_argument("repo", Store, "Repo name");
+                ap.refer(&mut key).required()
+                    .add_argument("key", Store, "Gpg key id, or ed25519 secret keys file");
+                ap.refer(&mut sign_type)
+                    .add_option(&["--sign-type"], Store, "gpg (default) or ed25519");
+                ap.refer(&mut delete_key)
+                    .add_option(&["--delete-key"], StoreOption, "Old gpg key id to remove signatures of");
+                parse_or_exit(&ap, args);
+            }
+            sys.block_on(admin.resign(&repo, &sign_type, &key, delete_key))
+        },
+        _ => {
+            eprintln!("Unknown command {}", command);
+            process::exit(1)
+        }
+    };
+
+    if let Err(e) = res {
+        eprintln!("{}", e);
+        process::exit(1)
+    }
+}
+
+fn parse_or_exit(ap: &ArgumentParser, args: Vec<String>) {
+    if let Err(e) = ap.parse(args, &mut io::stdout(), &mut io::stderr()) {
+        process::exit(e);
+    }
+}
+
+fn parse_id_arg(description: &str, name: &str, args: Vec<String>) -> i32 {
+    let mut id: i32 = 0;
+    {
+        let mut ap = ArgumentParser::new();
+        ap.set_description(description);
+        ap.refer(&mut id).required()
+            .add_argument(name, Store, "Id");
+        parse_or_exit(&ap, args);
+    }
+    id
+}
+
+fn gentoken_command(admin: &Admin, args: Vec<String>) -> Result<(), ApiError> {
+    let mut name = "default".to_string();
+    let mut sub = "build".to_string();
+    let mut duration: i64 = 60 * 60 * 24 * 365;
+    let mut scope: Vec<String> = vec![];
+    let mut prefixes: Vec<String> = vec![];
+    let mut repos: Vec<String> = vec![];
+    {
+        let mut ap = ArgumentParser::new();
+        ap.set_description("Generate token signed with the configured secret");
+        ap.refer(&mut name)
+            .add_option(&["--name"], Store,
+                        "Name for the token");
+        ap.refer(&mut sub)
+            .add_option(&["--sub"], Store,
+                        "Subject (default: build)");
+        ap.refer(&mut scope)
+            .add_option(&["--scope"], List,
+                        "Add scope (default if none: [build, upload, publish, jobs]");
+        ap.refer(&mut prefixes)
+            .add_option(&["--prefix"], List,
+                        "Add ref prefix (default if none: ['']");
+        ap.refer(&mut repos)
+            .add_option(&["--repo"], List,
+                        "Add repo (default if none: ['']");
+        ap.refer(&mut duration)
+            .add_option(&["--duration"], Store,
+                        "Duration for key in seconds (default 1 year)");
+        parse_or_exit(&ap, args);
+    }
+
+    if scope.is_empty() {
+        scope = vec!["build".to_string(), "upload".to_string(), "publish".to_string(), "jobs".to_string()];
+    }
+
+    if prefixes.is_empty() {
+        prefixes = vec!["".to_string()];
+    }
+
+    if repos.is_empty() {
+        repos = vec!["".to_string()];
+    }
+
+    let token = admin.gentoken(&name, &sub, scope, prefixes, repos, duration)?;
+    println!("{}", token);
+    Ok(())
+}
@@ -0,0 +1,567 @@
+extern crate flatmanager;
+extern crate actix;
+extern crate actix_http;
+extern crate actix_web;
+extern crate argparse;
+extern crate awc;
+extern crate bytes;
+extern crate dotenv;
+extern crate env_logger;
+#[macro_use]
+extern crate failure;
+extern crate futures;
+extern crate futures_fs;
+#[macro_use]
+extern crate log;
+extern crate mpart_async;
+extern crate serde;
+#[macro_use]
+extern crate serde_json;
+extern crate tokio;
+
+use actix::SystemRunner;
+use actix_http::error::PayloadError;
+use actix_web::http;
+use actix_web::http::header;
+use argparse::{ArgumentParser, StoreTrue, Store, StoreOption, List};
+use awc::{Client, ClientResponse};
+use bytes::Bytes;
+use dotenv::dotenv;
+use futures::{Future, Stream};
+use futures_fs::FsPool;
+use mpart_async::MultipartRequest;
+use serde::Serialize;
+use std::collections::HashSet;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::{Duration, Instant};
+use tokio::timer::Delay;
+
+use flatmanager::ostree::{self, OstreeError};
+
+const UPLOAD_CHUNK_LIMIT: u64 = 4 * 1024 * 1024;
+const UPLOAD_BUFFER_CAPACITY_BYTES: usize = 512 * 1024;
+const MISSING_OBJECTS_CHUNK_SIZE: usize = 2000;
+const RESPONSE_LIMIT: usize = 64 * 1024 * 1024;
+// Commits and publishes can take a long time, so don't timeout unnecessary
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(90 * 60);
+
+#[derive(Fail, Debug)]
+enum ClientError {
+    #[fail(display = "Api call to {} failed with status {}, details: {}", _0, _1, _2)]
+    ApiError(String, u16, String),
+    #[fail(display = "Request to {} failed: {}", _0, _1)]
+    RequestFailed(String, String),
+    #[fail(display = "Invalid response from {}: {}", _0, _1)]
+    InvalidResponse(String, String),
+    #[fail(display = "Job {} failed", _0)]
+    JobFailed(String),
+    #[fail(display = "{}", _0)]
+    OstreeError(OstreeError),
+    #[fail(display = "{}", _0)]
+    IoError(String),
+}
+
+impl From<OstreeError> for ClientError {
+    fn from(e: OstreeError) -> Self {
+        ClientError::OstreeError(e)
+    }
+}
+
+impl From<io::Error> for ClientError {
+    fn from(e: io::Error) -> Self {
+        ClientError::IoError(e.to_string())
+    }
+}
+
+type ClientResult<T> = Result<T, ClientError>;
+
+struct ApiResponse {
+    location: Option<String>,
+    body: serde_json::Value,
+}
+
+fn handle_response<S>(url: String, mut response: ClientResponse<S>) -> impl Future<Item=ApiResponse, Error=ClientError>
+    where S: Stream<Item=Bytes, Error=PayloadError> + 'static
+{
+    let status = response.status();
+    let location = response.headers().get(header::LOCATION)
+        .and_then(|v| v.to_str().ok())
+        .map(|s| s.to_string());
+    let url2 = url.clone();
+    response.body()
+        .limit(RESPONSE_LIMIT)
+        .map_err(move |e| ClientError::RequestFailed(url2, e.to_string()))
+        .and_then(move |body| {
+            if !status.is_success() {
+                return Err(ClientError::ApiError(url, status.as_u16(), String::from_utf8_lossy(&body).to_string()));
+            }
+            let json: serde_json::Value = if body.is_empty() {
+                serde_json::Value::Null
+            } else {
+                serde_json::from_slice(&body)
+                    .map_err(|e| ClientError::InvalidResponse(url, e.to_string()))?
+            };
+            Ok(ApiResponse {
+                location,
+                body: json,
+            })
+        })
+}
+
+struct ManagerClient {
+    runner: SystemRunner,
+    client: Client,
+    fs_pool: FsPool,
+    token: String,
+}
+
+impl ManagerClient {
+    fn new(runner: SystemRunner, token: String) -> ManagerClient {
+        ManagerClient {
+            runner,
+            client: Client::build().timeout(REQUEST_TIMEOUT).finish(),
+            fs_pool: FsPool::default(),
+            token,
+        }
+    }
+
+    fn request<T: Serialize>(&mut self, method: http::Method, url: &str, body: &T) -> ClientResult<ApiResponse> {
+        let url = url.to_string();
+        let url2 = url.clone();
+        let fut = self.client.request(method, &url)
+            .header(header::AUTHORIZATION, format!("Bearer {}", self.token))
+            .send_json(body)
+            .map_err(move |e| ClientError::RequestFailed(url2, e.to_string()))
+            .and_then(move |response| handle_response(url, response));
+        self.runner.block_on(fut)
+    }
+
+    fn get<T: Serialize>(&mut self, url: &str, body: &T) -> ClientResult<ApiResponse> {
+        self.request(http::Method::GET, url, body)
+    }
+
+    fn post<T: Serialize>(&mut self, url: &str, body: &T) -> ClientResult<ApiResponse> {
+        self.request(http::Method::POST, url, body)
+    }
+
+    fn sleep(&mut self, duration: Duration) {
+        let _ = self.runner.block_on(Delay::new(Instant::now() + duration));
+    }
+
+    fn create_build(&mut self, manager_url: &str, repo: &str) -> ClientResult<String> {
+        let url = format!("{}/api/v1/build", manager_url);
+        let response = self.post(&url, &json!({ "repo": repo }))?;
+        response.location.ok_or_else(|| ClientError::InvalidResponse(url, "No location for build".to_string()))
+    }
+
+    fn missing_objects(&mut self, build_url: &str, wanted: &[String]) -> ClientResult<Vec<String>> {
+        let url = format!("{}/missing_objects", build_url);
+        let mut missing = Vec::new();
+        for chunk in wanted.chunks(MISSING_OBJECTS_CHUNK_SIZE) {
+            let response = self.get(&url, &json!({ "wanted": chunk }))?;
+            let chunk_missing: Vec<String> = serde_json::from_value(response.body["missing"].clone())
+                .map_err(|e| ClientError::InvalidResponse(url.clone(), e.to_string()))?;
+            missing.extend(chunk_missing);
+        }
+        Ok(missing)
+    }
+
+    fn upload_files(&mut self, build_url: &str, files: Vec<(String, PathBuf)>) -> ClientResult<()> {
+        if files.is_empty() {
+            return Ok(())
+        }
+
+        info!("Uploading {} files", files.len());
+        let mut mpart = MultipartRequest::default();
+        for (name, path) in files {
+            mpart.add_stream("content", &name, "application/octet-stream",
+                             self.fs_pool.read(path, futures_fs::ReadOptions::default().buffer_size(UPLOAD_BUFFER_CAPACITY_BYTES)));
+        }
+
+        let url = format!("{}/upload", build_url);
+        let url2 = url.clone();
+        let fut = self.client.post(&url)
+            .header(header::CONTENT_TYPE, format!("multipart/form-data; boundary={}", mpart.get_boundary()))
+            .header(header::AUTHORIZATION, format!("Bearer {}", self.token))
+            .send_body(actix_http::body::BodyStream::new(mpart))
+            .map_err(move |e| ClientError::RequestFailed(url2, e.to_string()))
+            .and_then(move |response| handle_response(url, response));
+        self.runner.block_on(fut).map(|_| ())
+    }
+
+    fn upload_objects(&mut self, build_url: &str, repo_path: &Path, objects: &Vec<String>) -> ClientResult<()> {
+        let mut files = Vec::new();
+        let mut total_size = 0;
+        for object in objects {
+            let path = repo_path.join("objects").join(&object[..2]).join(&object[2..]);
+            let size = fs::metadata(&path)?.len();
+            // Upload what we have so far if the new object would bring us over the limit
+            if !files.is_empty() && total_size + size > UPLOAD_CHUNK_LIMIT {
+                self.upload_files(build_url, files)?;
+                files = Vec::new();
+                total_size = 0;
+            }
+            files.push((object.to_string(), path));
+            total_size += size;
+        }
+        self.upload_files(build_url, files)
+    }
+
+    fn create_ref(&mut self, build_url: &str, ref_name: &str, commit: &str) -> ClientResult<()> {
+        println!("Creating ref {} with commit {}", ref_name, commit);
+        self.post(&format!("{}/build_ref", build_url), &json!({ "ref": ref_name, "commit": commit }))
+            .map(|_| ())
+    }
+
+    fn wait_for_job(&mut self, job_url: &str) -> ClientResult<serde_json::Value> {
+        let mut printed_len = 0;
+        let mut started = false;
+        let mut iterations_since_change = 0;
+        loop {
+            let job = self.get(job_url, &json!({ "log-offset": printed_len }))?.body;
+            let status = job["status"].as_i64().unwrap_or(0);
+            if status > 0 && !started {
+                println!("/ Job was started");
+                started = true;
+            }
+            let log = job["log"].as_str().unwrap_or("");
+            if log.is_empty() {
+                iterations_since_change += 1;
+            } else {
+                iterations_since_change = 0;
+                for line in log.lines() {
+                    println!("| {}", line);
+                }
+                printed_len += log.len();
+            }
+            if status > 1 {
+                if status == 2 {
+                    println!("\\ Job completed successfully");
+                } else {
+                    println!("\\ Job failed");
+                    return Err(ClientError::JobFailed(job_url.to_string()))
+                }
+                return Ok(job)
+            }
+            // Some polling backoff to avoid loading the server
+            let sleep_secs = match iterations_since_change {
+                0..=1 => 1,
+                2..=4 => 3,
+                5..=14 => 5,
+                15..=29 => 10,
+                _ => 60,
+            };
+            self.sleep(Duration::from_secs(sleep_secs));
+        }
+    }
+
+    fn start_job(&mut self, url: &str, args: &serde_json::Value, wait: bool) -> ClientResult<serde_json::Value> {
+        let response = self.post(url, args)?;
+        match response.location {
+            Some(ref job_url) if wait => self.wait_for_job(job_url),
+            _ => Ok(response.body),
+        }
+    }
+
+    fn commit(&mut self, build_url: &str, args: &CommitOptions, wait: bool) -> ClientResult<serde_json::Value> {
+        println!("Committing build {}", build_url);
+        let mut json = json!({
+            "endoflife": args.end_of_life,
+            "endoflife_rebase": args.end_of_life_rebase,
+        });
+        if let Some(token_type) = args.token_type {
+            json["token_type"] = json!(token_type);
+        }
+        self.start_job(&format!("{}/commit", build_url), &json, wait)
+    }
+
+    fn publish(&mut self, build_url: &str, note: &Option<String>, wait: bool) -> ClientResult<serde_json::Value> {
+        println!("Publishing build {}", build_url);
+        self.start_job(&format!("{}/publish", build_url), &json!({ "note": note }), wait)
+    }
+}
+
+fn add_dirtree_metadata(repo_path: &PathBuf, objects: &mut HashSet<String>,
+                        tree_checksum: &String, meta_checksum: &String) -> ClientResult<()> {
+    objects.insert(format!("{}.dirmeta", meta_checksum));
+    if !objects.insert(format!("{}.dirtree", tree_checksum)) {
+        return Ok(())
+    }
+    let dirtree = ostree::get_dirtree(repo_path, tree_checksum)?;
+    for dir in dirtree.dirs {
+        add_dirtree_metadata(repo_path, objects, &dir.tree_checksum, &dir.meta_checksum)?;
+    }
+    Ok(())
+}
+
+fn local_needed_metadata(repo_path: &PathBuf, commits: &Vec<String>) -> ClientResult<Vec<String>> {
+    let mut objects = HashSet::new();
+    for commit in commits {
+        objects.insert(format!("{}.commit", commit));
+        let c = ostree::get_commit(repo_path, commit)?;
+        add_dirtree_metadata(repo_path, &mut objects, &c.root_tree, &c.root_metadata)?;
+    }
+    Ok(objects.into_iter().collect())
+}
+
+fn local_needed_files(repo_path: &PathBuf, metadata_objects: &Vec<String>) -> ClientResult<Vec<String>> {
+    let mut objects = HashSet::new();
+    for object in metadata_objects {
+        if object.ends_with(".dirtree") {
+            let dirtree = ostree::get_dirtree(repo_path, &object[..object.len() - 8])?;
+            for file in dirtree.files {
+                objects.insert(format!("{}.filez", file.checksum));
+            }
+        }
+    }
+    Ok(objects.into_iter().collect())
+}
+
+struct CommitOptions {
+    end_of_life: Option<String>,
+    end_of_life_rebase: Option<String>,
+    token_type: Option<i32>,
+}
+
+fn push(client: &mut ManagerClient, build_url: &str, repo_path: &PathBuf, branches: &Vec<String>) -> ClientResult<()> {
+    let mut refs = Vec::new();
+    if branches.is_empty() {
+        for prefix in ["app", "runtime", "screenshots"].iter() {
+            for ref_name in ostree::list_refs(repo_path, prefix) {
+                let commit = ostree::parse_ref(repo_path, &ref_name)?;
+                refs.push((ref_name, commit));
+            }
+        }
+    } else {
+        for branch in branches {
+            refs.push((branch.to_string(), ostree::parse_ref(repo_path, branch)?));
+        }
+    }
+
+    println!("Uploading refs to {}: {:?}", build_url, refs.iter().map(|r| &r.0).collect::<Vec<_>>());
+
+    let commits = refs.iter().map(|r| r.1.clone()).collect();
+    let metadata_objects = local_needed_metadata(repo_path, &commits)?;
+    println!("Refs contain {} metadata objects", metadata_objects.len());
+
+    let missing_metadata_objects = client.missing_objects(build_url, &metadata_objects)?;
+    println!("Remote missing {} of those", missing_metadata_objects.len());
+
+    let file_objects = local_needed_files(repo_path, &missing_metadata_objects)?;
+    println!("Has {} file objects for those", file_objects.len());
+
+    let missing_file_objects = client.missing_objects(build_url, &file_objects)?;
+    println!("Remote missing {} of those", missing_file_objects.len());
+
+    // First upload all missing file objects, then the metadata referencing them
+    println!("Uploading file objects");
+    client.upload_objects(build_url, repo_path, &missing_file_objects)?;
+    println!("Uploading metadata objects");
+    client.upload_objects(build_url, repo_path, &missing_metadata_objects)?;
+
+    for (ref_name, commit) in refs.iter() {
+        client.create_ref(build_url, ref_name, commit)?;
+    }
+
+    Ok(())
+}
+
+fn main() {
+    let mut verbose = false;
+    let mut token: Option<String> = None;
+    let mut command = String::new();
+    let mut args: Vec<String> = vec![];
+
+    {
+        let mut ap = ArgumentParser::new();
+        ap.set_description("Client for flat-manager. Commands: create, push, commit, publish, follow-job");
+        ap.refer(&mut verbose)
+            .add_option(&["-v", "--verbose"], StoreTrue,
+                        "Be verbose");
+        ap.refer(&mut token)
+            .add_option(&["--token"], StoreOption,
+                        "Use this token (default: $REPO_TOKEN)");
+        ap.refer(&mut command)
+            .required()
+            .add_argument("command", Store,
+                          "Command to run");
+        ap.refer(&mut args)
+            .add_argument("arguments", List,
+                          "Arguments for command");
+        ap.stop_on_first_argument(true);
+        ap.parse_args_or_exit();
+    }
+
+    if verbose {
+        env::set_var("RUST_LOG", "info");
+    }
+    env_logger::init();
+
+    dotenv().ok();
+
+    let token = match token.or_else(|| env::var("REPO_TOKEN").ok()) {
+        Some(token) => token,
+        None => {
+            eprintln!("No token available, pass with --token or $REPO_TOKEN");
+            process::exit(1)
+        }
+    };
+
+    args.insert(0, format!("flat-manager-client {}", command));
+
+    let mut client = ManagerClient::new(actix::System::new("flat-manager-client"), token);
+
+    let res = match command.as_ref() {
+        "create" => create_command(&mut client, args),
+        "push" => push_command(&mut client, args),
+        "commit" => commit_command(&mut client, args),
+        "publish" => publish_command(&mut client, args),
+        "follow-job" => follow_job_command(&mut client, args),
+        _ => {
+            eprintln!("Unknown command {}", command);
+            process::exit(1)
+        }
+    };
+
+    if let Err(e) = res {
+        eprintln!("{}", e);
+        process::exit(1)
+    }
+}
+
+fn commit_options<'b>(ap: &mut ArgumentParser<'b>, options: &'b mut CommitOptions) {
+    ap.refer(&mut options.end_of_life)
+        .add_option(&["--end-of-life"], StoreOption,
+                    "Set end of life");
+    ap.refer(&mut options.end_of_life_rebase)
+        .add_option(&["--end-of-life-rebase"], StoreOption,
+                    "Set new ID which will supercede the current one");
+    ap.refer(&mut options.token_type)
+        .add_option(&["--token-type"], StoreOption,
+                    "Set token type");
+}
+
+fn create_command(client: &mut ManagerClient, args: Vec<String>) -> ClientResult<()> {
+    let mut manager_url = String::new();
+    let mut repo = String::new();
+    {
+        let mut ap = ArgumentParser::new();
+        ap.set_description("Create new build");
+        ap.refer(&mut manager_url).required()
+            .add_argument("manager_url", Store, "Remote repo manager url");
+        ap.refer(&mut repo).required()
+            .add_argument("repo", Store, "Repo name");
+        if let Err(e) = ap.parse(args, &mut io::stdout(), &mut io::stderr()) {
+            process::exit(e);
+        }
+    }
+
+    let build_url = client.create_build(manager_url.trim_end_matches('/'), &repo)?;
+    println!("{}", build_url);
+    Ok(())
+}
+
+fn push_command(client: &mut ManagerClient, args: Vec<String>) -> ClientResult<()> {
+    let mut build_url = String::new();
+    let mut repo_path = String::new();
+    let mut branches: Vec<String> = vec![];
+    let mut commit = false;
+    let mut publish = false;
+    let mut wait = false;
+    let mut note: Option<String> = None;
+    let mut options = CommitOptions { end_of_life: None, end_of_life_rebase: None, token_type: None };
+    {
+        let mut ap = ArgumentParser::new();
+        ap.set_description("Push to repo manager");
+        ap.refer(&mut build_url).required()
+            .add_argument("build_url", Store, "Remote build url");
+        ap.refer(&mut repo_path).required()
+            .add_argument("repo_path", Store, "Local repository");
+        ap.refer(&mut branches)
+            .add_argument("branches", List, "Branches to push");
+        ap.refer(&mut commit)
+            .add_option(&["--commit"], StoreTrue, "Commit build after pushing");
+        ap.refer(&mut publish)
+            .add_option(&["--publish"], StoreTrue, "Publish build after committing");
+        ap.refer(&mut wait)
+            .add_option(&["--wait"], StoreTrue, "Wait for commit/publish to finish");
+        ap.refer(&mut note)
+            .add_option(&["--note"], StoreOption, "Note on why the build is published");
+        commit_options(&mut ap, &mut options);
+        if let Err(e) = ap.parse(args, &mut io::stdout(), &mut io::stderr()) {
+            process::exit(e);
+        }
+    }
+
+    let build_url = build_url.trim_end_matches('/');
+    push(client, build_url, &PathBuf::from(repo_path), &branches)?;
+
+    if commit || publish {
+        // We always need to wait for the commit before we can publish
+        client.commit(build_url, &options, wait || publish)?;
+    }
+    if publish {
+        client.publish(build_url, &note, wait)?;
+    }
+    Ok(())
+}
+
+fn commit_command(client: &mut ManagerClient, args: Vec<String>) -> ClientResult<()> {
+    let mut build_url = String::new();
+    let mut wait = false;
+    let mut options = CommitOptions { end_of_life: None, end_of_life_rebase: None, token_type: None };
+    {
+        let mut ap = ArgumentParser::new();
+        ap.set_description("Commit build");
+        ap.refer(&mut build_url).required()
+            .add_argument("build_url", Store, "Remote build url");
+        ap.refer(&mut wait)
+            .add_option(&["--wait"], StoreTrue, "Wait for commit to finish");
+        commit_options(&mut ap, &mut options);
+        if let Err(e) = ap.parse(args, &mut io::stdout(), &mut io::stderr()) {
+            process::exit(e);
+        }
+    }
+
+    client.commit(build_url.trim_end_matches('/'), &options, wait).map(|_| ())
+}
+
+fn publish_command(client: &mut ManagerClient, args: Vec<String>) -> ClientResult<()> {
+    let mut build_url = String::new();
+    let mut wait = false;
+    let mut note: Option<String> = None;
+    {
+        let mut ap = ArgumentParser::new();
+        ap.set_description("Publish build");
+        ap.refer(&mut build_url).required()
+            .add_argument("build_url", Store, "Remote build url");
+        ap.refer(&mut wait)
+            .add_option(&["--wait"], StoreTrue, "Wait for publish to finish");
+        ap.refer(&mut note)
+            .add_option(&["--note"], StoreOption, "Note on why the build is published");
+        if let Err(e) = ap.parse(args, &mut io::stdout(), &mut io::stderr()) {
+            process::exit(e);
+        }
+    }
+
+    client.publish(build_url.trim_end_matches('/'), &note, wait).map(|_| ())
+}
+
+fn follow_job_command(client: &mut ManagerClient, args: Vec<String>) -> ClientResult<()> {
+    let mut job_url = String::new();
+    {
+        let mut ap = ArgumentParser::new();
+        ap.set_description("Follow existing job log");
+        ap.refer(&mut job_url).required()
+            .add_argument("job_url", Store, "Url of job");
+        if let Err(e) = ap.parse(args, &mut io::stdout(), &mut io::stderr()) {
+            process::exit(e);
+        }
+    }
+
+    client.wait_for_job(&job_url).map(|_| ())
+}
@@ -1,23 +1,46 @@
 extern crate flatmanager;
+extern crate argparse;
 extern crate dotenv;
-extern crate env_logger;
 
+use argparse::{ArgumentParser, StoreConst};
 use dotenv::dotenv;
 use std::env;
 use std::path::PathBuf;
+use std::sync::Arc;
+
+use flatmanager::RunMode;
 
 fn main() {
     if env::var("RUST_LOG").is_err() {
         env::set_var("RUST_LOG", "info");
     }
-    env_logger::init();
+
+    let mut run_mode = None;
+    {
+        let mut ap = ArgumentParser::new();
+        ap.set_description("Run the flat-manager server");
+        ap.refer(&mut run_mode)
+            .add_option(&["--frontend-only"], StoreConst(Some(RunMode::Frontend)),
+                        "Only serve the http api, leave running jobs to a worker")
+            .add_option(&["--worker-only"], StoreConst(Some(RunMode::Worker)),
+                        "Only run jobs, without an http server");
+        ap.parse_args_or_exit();
+    }
+
     let sys = actix::System::new("repo-manage");
 
     dotenv().ok();
 
     let config_path = PathBuf::from(env::var("REPO_CONFIG").unwrap_or ("config.json".to_string()));
 
-    let config = flatmanager::load_config(&config_path);
+    let mut config = flatmanager::load_config(&config_path);
+    if let Some(run_mode) = run_mode {
+        Arc::make_mut(&mut config).run_mode = run_mode;
+    }
+
+    flatmanager::init_logging(&config);
+
+    let _error_reporting = flatmanager::init_error_reporting(&config);
 
     let _server = flatmanager::start(&config);
 
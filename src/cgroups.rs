@@ -0,0 +1,79 @@
+use libc;
+use std::fs;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use app::Config;
+
+/**************************************************************************
+ * Resource limits for the commands run by jobs, using cgroup v2.
+ *
+ * Each kind of command listed in resource-limits ("commit", "publish",
+ * "update-repo", "delta", ...) gets a cgroup below cgroup-base with
+ * the configured limits, and commands of that kind move themselves
+ * into it before they exec. The limits thus apply to all the running
+ * commands of a kind together.
+ *
+ * cgroup-base has to be on the cgroup2 filesystem, be writable by us
+ * (for instance with Delegate=yes in the systemd unit), and must not
+ * itself contain any processes, including flat-manager.
+ ***************************************************************************/
+
+pub fn setup_cgroups(config: &Config) -> io::Result<()> {
+    let base = match config.cgroup_base {
+        Some(ref base) => base,
+        None => return Ok(()),
+    };
+
+    if config.resource_limits.is_empty() {
+        return Ok(())
+    }
+
+    fs::write(base.join("cgroup.subtree_control"), "+memory +cpu +io")?;
+
+    for (kind, limits) in config.resource_limits.iter() {
+        let dir = base.join(kind);
+        fs::create_dir_all(&dir)?;
+        if let Some(ref memory_max) = limits.memory_max {
+            fs::write(dir.join("memory.max"), memory_max)?;
+        }
+        if let Some(ref cpu_max) = limits.cpu_max {
+            fs::write(dir.join("cpu.max"), cpu_max)?;
+        }
+        if let Some(ref io_max) = limits.io_max {
+            fs::write(dir.join("io.max"), io_max)?;
+        }
+        info!("Set up cgroup {:?} for {} commands", dir, kind);
+    }
+
+    Ok(())
+}
+
+pub fn cgroup_for(config: &Config, kind: &str) -> Option<PathBuf> {
+    match config.cgroup_base {
+        Some(ref base) if config.resource_limits.contains_key(kind) => Some(base.join(kind)),
+        _ => None,
+    }
+}
+
+pub fn run_in_cgroup(cmd: &mut Command, cgroup: &Path) -> io::Result<()> {
+    let procs = fs::OpenOptions::new()
+        .write(true)
+        .open(cgroup.join("cgroup.procs"))?;
+
+    unsafe {
+        cmd
+            .pre_exec (move || {
+                // Writing 0 to cgroup.procs moves the writing process,
+                // and write() is safe to use between fork and exec
+                if libc::write(procs.as_raw_fd(), b"0".as_ptr() as *const libc::c_void, 1) < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+    }
+    Ok(())
+}
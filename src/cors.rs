@@ -0,0 +1,176 @@
+use actix_service::{Service, Transform};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::error::Error;
+use actix_web::http::header::{self, HeaderMap, HeaderValue};
+use actix_web::http::Method;
+use actix_web::HttpResponse;
+use futures::{Future, Poll};
+use futures::future::{ok, Either, FutureResult};
+use std::rc::Rc;
+
+use app::CorsConfig;
+
+/* CORS headers for the api, so browser based dashboards can use it.
+ * This has to be outside the TokenParser, as preflight requests don't
+ * have a token. Requests from origins that are not allowed are passed
+ * on without any CORS headers, and the browser will block them. */
+
+struct Inner {
+    config: Option<CorsConfig>,
+}
+
+impl Inner {
+    fn allowed_origin(&self, headers: &HeaderMap) -> Option<(&CorsConfig, HeaderValue)> {
+        let config = self.config.as_ref()?;
+        let origin = headers.get(header::ORIGIN)?;
+        let origin_str = origin.to_str().ok()?;
+        if config.allowed_origins.iter().any(|allowed| allowed == "*" || allowed == origin_str) {
+            Some((config, origin.clone()))
+        } else {
+            None
+        }
+    }
+}
+
+fn add_origin_headers(headers: &mut HeaderMap, origin: HeaderValue) {
+    headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+    headers.insert(header::VARY, HeaderValue::from_static("Origin"));
+    headers.insert(header::ACCESS_CONTROL_EXPOSE_HEADERS, HeaderValue::from_static("Location, Retry-After"));
+}
+
+fn preflight_response(config: &CorsConfig, origin: HeaderValue) -> HttpResponse {
+    let mut response = HttpResponse::Ok();
+    response
+        .header(header::ACCESS_CONTROL_ALLOW_METHODS, config.allowed_methods.join(", "))
+        .header(header::ACCESS_CONTROL_ALLOW_HEADERS, config.allowed_headers.join(", "));
+    if let Some(max_age) = config.max_age_secs {
+        response.header(header::ACCESS_CONTROL_MAX_AGE, max_age.to_string());
+    }
+    let mut response = response.finish();
+    add_origin_headers(response.headers_mut(), origin);
+    response
+}
+
+pub struct Cors(Rc<Inner>);
+
+impl Cors {
+    pub fn new(config: &Option<CorsConfig>) -> Cors {
+        Cors(Rc::new(Inner { config: config.clone() }))
+    }
+}
+
+impl<S: 'static, B> Transform<S> for Cors
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = CorsMiddleware<S>;
+    type Future = FutureResult<Self::Transform, Self::InitError>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(CorsMiddleware {
+            service,
+            inner: self.0.clone(),
+        })
+    }
+}
+
+/// Cors middleware
+pub struct CorsMiddleware<S> {
+    service: S,
+    inner: Rc<Inner>,
+}
+
+impl<S, B> Service for CorsMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Either<Box<dyn Future<Item = Self::Response, Error = Self::Error>>,
+                         FutureResult<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        self.service.poll_ready()
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let origin = match self.inner.allowed_origin(req.headers()) {
+            Some((config, origin)) => {
+                if *req.method() == Method::OPTIONS && req.headers().contains_key(header::ACCESS_CONTROL_REQUEST_METHOD) {
+                    let response = preflight_response(config, origin);
+                    return Either::B(ok(req.into_response(response.into_body())));
+                }
+                Some(origin)
+            },
+            None => None,
+        };
+
+        Either::A(Box::new(self.service.call(req)
+                           .map(move |mut resp| {
+                               if let Some(origin) = origin {
+                                   add_origin_headers(resp.headers_mut(), origin);
+                               }
+                               resp
+                           })))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json;
+
+    fn cors(config: serde_json::Value) -> Inner {
+        Inner { config: Some(serde_json::from_value(config).unwrap()) }
+    }
+
+    fn origin_headers(origin: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ORIGIN, HeaderValue::from_str(origin).unwrap());
+        headers
+    }
+
+    #[test]
+    fn test_allowed_origin() {
+        let inner = cors(json!({ "allowed-origins": ["https://dashboard.example.com"] }));
+        let (config, origin) = inner.allowed_origin(&origin_headers("https://dashboard.example.com")).unwrap();
+        assert_eq!(origin, "https://dashboard.example.com");
+        assert_eq!(config.allowed_methods, vec!["GET".to_string(), "POST".to_string()]);
+
+        /* Origins are matched exactly */
+        assert!(inner.allowed_origin(&origin_headers("http://dashboard.example.com")).is_none());
+        assert!(inner.allowed_origin(&origin_headers("https://dashboard.example.com:8443")).is_none());
+        assert!(inner.allowed_origin(&origin_headers("https://evil.example.com")).is_none());
+        assert!(inner.allowed_origin(&HeaderMap::new()).is_none());
+
+        /* The allowed origin is echoed back, not "*" */
+        let inner = cors(json!({ "allowed-origins": ["*"] }));
+        let (_config, origin) = inner.allowed_origin(&origin_headers("https://anything.example.org")).unwrap();
+        assert_eq!(origin, "https://anything.example.org");
+
+        let inner = Inner { config: None };
+        assert!(inner.allowed_origin(&origin_headers("https://dashboard.example.com")).is_none());
+    }
+
+    #[test]
+    fn test_preflight_response() {
+        let inner = cors(json!({ "allowed-origins": ["*"], "max-age-secs": 600 }));
+        let (config, origin) = inner.allowed_origin(&origin_headers("https://dashboard.example.com")).unwrap();
+        let response = preflight_response(config, origin);
+        let headers = response.headers();
+        assert_eq!(headers.get(header::ACCESS_CONTROL_ALLOW_ORIGIN).unwrap(), "https://dashboard.example.com");
+        assert_eq!(headers.get(header::ACCESS_CONTROL_ALLOW_METHODS).unwrap(), "GET, POST");
+        assert_eq!(headers.get(header::ACCESS_CONTROL_ALLOW_HEADERS).unwrap(), "Authorization, Content-Type");
+        assert_eq!(headers.get(header::ACCESS_CONTROL_MAX_AGE).unwrap(), "600");
+        assert_eq!(headers.get(header::VARY).unwrap(), "Origin");
+    }
+}
@@ -2,26 +2,77 @@ use actix::prelude::*;
 use actix_web::*;
 use diesel;
 use diesel::prelude::*;
+use diesel::pg::PgConnection;
+use diesel::sql_types::{Integer, Nullable, Text};
+use chrono;
+use serde_json;
+use std::collections::HashMap;
+use std::sync::Arc;
 
+use app::Config;
 use models::*;
 use errors::ApiError;
+use jobs;
+use metrics;
 use schema;
+use tracing;
+use webhooks;
 use Pool;
 
+#[derive(Clone)]
 pub struct Db(pub Pool);
 
+/* What a commit request asks of the commit job */
+pub struct CommitOptions {
+    pub endoflife: Option<String>,
+    pub endoflife_rebase: Option<String>,
+    pub token_type: Option<i32>,
+    pub timestamp: Option<String>,
+    pub metadata: Option<serde_json::Value>, // Merged into the build metadata
+}
+
+/* What a publish request asks of the publish job */
+pub struct PublishOptions {
+    pub repo: String,
+    pub note: Option<String>,
+    pub arches: Option<Vec<String>>,
+    pub branches: HashMap<String, String>,
+    pub published_by: Option<String>,
+}
+
+/* How many levels of dependencies are followed from a build's own jobs */
+const JOB_GRAPH_MAX_DEPTH: usize = 8;
+
+/* The tables needed to rebuild the database for the repos on disk, in
+ * an order where rows only refer to rows in the tables before them */
+const METADATA_TABLES: [&str; 13] = ["jobs", "job_dependencies", "builds", "build_refs", "build_comments", "published_refs", "ref_pins", "ref_tombstones", "app_acl", "acl_group_members", "organizations", "organization_members", "upload_sessions"];
+
+#[derive(QueryableByName)]
+struct TextRow {
+    #[sql_type = "diesel::sql_types::Text"]
+    data: String,
+}
+
+#[derive(QueryableByName)]
+struct CountRow {
+    #[sql_type = "diesel::sql_types::BigInt"]
+    count: i64,
+}
+
+/* The latest migration that was run */
+fn get_schema_version(conn: &PgConnection) -> Result<String, diesel::result::Error> {
+    Ok(diesel::sql_query("SELECT max(version) AS data FROM __diesel_schema_migrations")
+       .get_result::<TextRow>(conn)?
+       .data)
+}
+
 impl Db {
     fn run<Func, T>(self: &Self, func: Func) -> impl Future<Item = T, Error = ApiError>
         where Func: FnOnce(&r2d2::PooledConnection<diesel::r2d2::ConnectionManager<diesel::PgConnection>>) -> Result<T, ApiError>,
               Func: Send + 'static,
               T: Send + 'static,
     {
-        let p = self.0.clone();
-        web::block(move || {
-            let conn = p.get()?;
-            func(&conn)
-        })
-            .map_err(|e| ApiError::from(e))
+        self.run_named(metrics::query_name::<Func>(), func)
     }
 
     fn run_in_transaction<Func, T>(self: &Self, func: Func) -> impl Future<Item = T, Error = ApiError>
@@ -29,13 +80,44 @@ impl Db {
               Func: Send + 'static,
               T: Send + 'static,
     {
-        self.run(move |conn| {
+        self.run_named(metrics::query_name::<Func>(), move |conn| {
             conn.transaction::<T, ApiError, _>(|| func(conn))
         })
     }
 
+    /* The name is the method calling run(), for the query metrics */
+    fn run_named<Func, T>(self: &Self, name: String, func: Func) -> impl Future<Item = T, Error = ApiError>
+        where Func: FnOnce(&r2d2::PooledConnection<diesel::r2d2::ConnectionManager<diesel::PgConnection>>) -> Result<T, ApiError>,
+              Func: Send + 'static,
+              T: Send + 'static,
+    {
+        let p = self.0.clone();
+        /* Carry the trace over to the thread pool, so created jobs get it */
+        let trace = tracing::current();
+        web::block(move || {
+            let _trace = tracing::set_current(trace);
+            metrics::time_query(&name, || {
+                let conn = p.get()?;
+                func(&conn)
+            })
+        })
+            .map_err(ApiError::from)
+    }
+
     /* Jobs */
 
+    /* The jobs waiting to run, not counting the ones delayed on purpose */
+    pub fn count_queued_jobs(self: &Self) -> impl Future<Item = i64, Error = ApiError> {
+        self.run(move |conn| {
+            use schema::jobs::dsl::*;
+            Ok(jobs
+               .filter(status.eq(JobStatus::New as i16))
+               .filter(start_after.is_null().or(start_after.le(diesel::dsl::now)))
+               .count()
+               .get_result::<i64>(conn)?)
+        })
+    }
+
     pub fn lookup_job(self: &Self,
                       job_id: i32,
                       log_offset: Option<usize>) -> impl Future<Item = Job, Error = ApiError> {
@@ -48,6 +130,18 @@ impl Db {
         })
     }
 
+    /* Cheaper than lookup_job() for polling, as the log can be large */
+    pub fn lookup_job_status(self: &Self,
+                             job_id: i32) -> impl Future<Item = i16, Error = ApiError> {
+        self.run(move |conn| {
+            use schema::jobs::dsl::*;
+            Ok(jobs
+               .filter(id.eq(job_id))
+               .select(status)
+               .get_result::<i16>(conn)?)
+        })
+    }
+
     pub fn list_active_jobs(self: &Self) -> impl Future<Item = Vec<Job>, Error = ApiError> {
         self.run(move |conn| {
             use schema::jobs::dsl::*;
@@ -58,6 +152,416 @@ impl Db {
             })
     }
 
+    pub fn lookup_jobs(self: &Self,
+                       job_ids: Vec<i32>) -> impl Future<Item = Vec<Job>, Error = ApiError> {
+        self.run(move |conn| {
+            use schema::jobs::dsl::*;
+            Ok(jobs
+               .filter(id.eq_any(job_ids))
+               .order(id)
+               .get_results::<Job>(conn)?)
+        })
+    }
+
+    pub fn retry_job(self: &Self,
+                     job_id: i32) -> impl Future<Item = Job, Error = ApiError> {
+        self.run_in_transaction(move |conn| {
+            let job = schema::jobs::table
+                .filter(schema::jobs::id.eq(job_id))
+                .get_result::<Job>(conn)?;
+            if job.status != JobStatus::Broken as i16 {
+                return Err(ApiError::BadRequest("Only broken jobs can be retried".to_string()))
+            }
+
+            let aborted_builds = schema::builds::table
+                .filter(schema::builds::commit_job_id.eq(job_id).or(schema::builds::publish_job_id.eq(job_id)))
+                .filter(schema::builds::repo_state.eq(RepoState::Aborted.to_db().0))
+                .count()
+                .get_result::<i64>(conn)?;
+            if aborted_builds > 0 {
+                return Err(ApiError::BadRequest("The build of the job has been aborted".to_string()))
+            }
+
+            /* Put the build back in the state the job expects it to be in */
+            if job.kind == JobKind::Commit.to_db() {
+                let (val, reason) = RepoState::to_db(&RepoState::Verifying);
+                diesel::update(schema::builds::table)
+                    .filter(schema::builds::commit_job_id.eq(job_id))
+                    .set((schema::builds::repo_state.eq(val),
+                          schema::builds::repo_state_reason.eq(reason)))
+                    .execute(conn)?;
+            } else if job.kind == JobKind::Publish.to_db() {
+                let (val, reason) = PublishedState::to_db(&PublishedState::Publishing);
+                diesel::update(schema::builds::table)
+                    .filter(schema::builds::publish_job_id.eq(job_id))
+                    .set((schema::builds::published_state.eq(val),
+                          schema::builds::published_state_reason.eq(reason)))
+                    .execute(conn)?;
+            }
+
+            let checkpoint = job.retry_results();
+
+            Ok(diesel::update(schema::jobs::table)
+               .filter(schema::jobs::id.eq(job_id))
+               .set((schema::jobs::status.eq(JobStatus::New as i16),
+                     schema::jobs::results.eq(checkpoint),
+                     schema::jobs::started_at.eq(None::<std::time::SystemTime>),
+                     schema::jobs::ended_at.eq(None::<std::time::SystemTime>),
+                     schema::jobs::progress.eq(None::<String>),
+                     schema::jobs::log.eq(schema::jobs::log.concat("Retrying job\n"))))
+               .get_result::<Job>(conn)?)
+        })
+    }
+
+    /* The builds the jobs are the commit or publish job of, by job id */
+    pub fn lookup_job_builds(self: &Self,
+                             job_ids: Vec<i32>) -> impl Future<Item = HashMap<i32, Build>, Error = ApiError> {
+        self.run(move |conn| {
+            let mut job_builds = HashMap::new();
+            for job_id in job_ids {
+                if let Some(build) = schema::builds::table
+                    .filter(schema::builds::commit_job_id.eq(job_id)
+                            .or(schema::builds::publish_job_id.eq(job_id)))
+                    .first::<Build>(conn)
+                    .optional()? {
+                    job_builds.insert(job_id, build);
+                }
+            }
+            Ok(job_builds)
+        })
+    }
+
+    pub fn add_job_dependencies(self: &Self,
+                                job_id: i32,
+                                depends_on: Vec<i32>) -> impl Future<Item = Vec<JobDependency>, Error = ApiError> {
+        self.run_in_transaction(move |conn| {
+            /* Lock the job so it isn't started while we add to it */
+            let job = schema::jobs::table
+                .filter(schema::jobs::id.eq(job_id))
+                .for_update()
+                .get_result::<Job>(conn)?;
+            if job.status != JobStatus::New as i16 {
+                return Err(ApiError::BadRequest("Dependencies can only be added to jobs that have not started".to_string()))
+            }
+
+            for dependency in depends_on.iter() {
+                schema::jobs::table
+                    .filter(schema::jobs::id.eq(dependency))
+                    .select(schema::jobs::id)
+                    .get_result::<i32>(conn)
+                    .map_err(|_e| ApiError::BadRequest(format!("No such job {}", dependency)))?;
+            }
+
+            /* Walk everything the new dependencies (transitively) depend on, if
+             * that reaches the job itself we would create a cycle. */
+            let mut to_visit = depends_on.clone();
+            let mut visited = std::collections::HashSet::new();
+            while let Some(current) = to_visit.pop() {
+                if current == job_id {
+                    return Err(ApiError::BadRequest("Dependency would create a cycle".to_string()))
+                }
+                if visited.insert(current) {
+                    to_visit.extend(schema::job_dependencies::table
+                                    .filter(schema::job_dependencies::job_id.eq(current))
+                                    .select(schema::job_dependencies::depends_on)
+                                    .get_results::<i32>(conn)?);
+                }
+            }
+
+            for dependency in depends_on.iter() {
+                diesel::insert_into(schema::job_dependencies::table)
+                    .values(JobDependency {
+                        job_id,
+                        depends_on: *dependency,
+                    })
+                    .on_conflict_do_nothing()
+                    .execute(conn)?;
+            }
+
+            Ok(schema::job_dependencies::table
+               .filter(schema::job_dependencies::job_id.eq(job_id))
+               .get_results::<JobDependency>(conn)?)
+        })
+    }
+
+    pub fn lookup_build_job_graph(self: &Self,
+                                  build_id: i32) -> impl Future<Item = JobGraph, Error = ApiError> {
+        self.run(move |conn| {
+            let build = schema::builds::table
+                .filter(schema::builds::id.eq(build_id))
+                .get_result::<Build>(conn)?;
+
+            /* Start from the jobs the build refers to and follow what they
+             * depend on. Jobs depending on them directly (like the update-repo
+             * job queued by the publish) are included, but not followed, as
+             * those are shared with other builds. */
+            let own_job_ids: Vec<i32> = build.commit_job_id.iter().chain(build.publish_job_id.iter()).cloned().collect();
+            let mut job_ids: std::collections::HashSet<i32> = own_job_ids.iter().cloned().collect();
+            let mut dependencies = schema::job_dependencies::table
+                .filter(schema::job_dependencies::depends_on.eq_any(&own_job_ids))
+                .get_results::<JobDependency>(conn)?;
+            let mut to_visit = own_job_ids;
+            let mut depth = 0;
+            while !to_visit.is_empty() && depth < JOB_GRAPH_MAX_DEPTH {
+                let edges = schema::job_dependencies::table
+                    .filter(schema::job_dependencies::job_id.eq_any(&to_visit))
+                    .get_results::<JobDependency>(conn)?;
+                to_visit = vec![];
+                for edge in edges {
+                    if job_ids.insert(edge.depends_on) {
+                        to_visit.push(edge.depends_on);
+                    }
+                    if !dependencies.contains(&edge) {
+                        dependencies.push(edge);
+                    }
+                }
+                depth += 1;
+            }
+            for edge in dependencies.iter() {
+                job_ids.insert(edge.job_id);
+            }
+
+            let jobs = schema::jobs::table
+                .filter(schema::jobs::id.eq_any(job_ids.into_iter().collect::<Vec<i32>>()))
+                .order(schema::jobs::id)
+                .get_results::<Job>(conn)?
+                .into_iter()
+                .map(|job| JobGraphNode {
+                    id: job.id,
+                    kind: JobKind::from_db(job.kind).map(|kind| kind.name()).unwrap_or("unknown"),
+                    status: job.status,
+                })
+                .collect();
+
+            Ok(JobGraph {
+                jobs,
+                dependencies,
+            })
+        })
+    }
+
+    pub fn queue_update_repo(self: &Self,
+                             repo: String) -> impl Future<Item = Job, Error = ApiError> {
+        self.run(move |conn| {
+            let (_is_new, job) = jobs::queue_update_job(0, conn, &repo, None)?;
+            Ok(job)
+        })
+    }
+
+    pub fn queue_prune(self: &Self,
+                       repo: String,
+                       depth: i32,
+                       dry_run: bool) -> impl Future<Item = Job, Error = ApiError> {
+        self.run(move |conn| {
+            Ok(jobs::queue_prune_job(conn, &repo, depth, dry_run, false, 0)?)
+        })
+    }
+
+    pub fn queue_gc(self: &Self,
+                    repo: String,
+                    dry_run: bool) -> impl Future<Item = Job, Error = ApiError> {
+        self.run(move |conn| {
+            Ok(jobs::queue_gc_job(conn, &repo, dry_run, false, 0)?)
+        })
+    }
+
+    pub fn queue_fsck(self: &Self,
+                      repo: String) -> impl Future<Item = Job, Error = ApiError> {
+        self.run(move |conn| {
+            Ok(jobs::queue_fsck_job(conn, &repo)?)
+        })
+    }
+
+    pub fn queue_regenerate_deltas(self: &Self,
+                                   repo: String) -> impl Future<Item = Job, Error = ApiError> {
+        self.run(move |conn| {
+            Ok(jobs::queue_regenerate_deltas_job(conn, &repo)?)
+        })
+    }
+
+    pub fn queue_resign(self: &Self,
+                        repo: String,
+                        sign_type: SignType,
+                        key: String,
+                        delete_key: Option<String>) -> impl Future<Item = Job, Error = ApiError> {
+        self.run(move |conn| {
+            Ok(jobs::queue_resign_job(conn, &repo, sign_type, &key, delete_key)?)
+        })
+    }
+
+    pub fn start_oci_export_job(self: &Self,
+                                repo: String,
+                                refs: Vec<String>) -> impl Future<Item = Job, Error = ApiError> {
+        self.run(move |conn| {
+            Ok(diesel::insert_into(schema::jobs::table)
+               .values(NewJob {
+                   kind: JobKind::OciExport.to_db(),
+                   contents: json!(OciExportJob {
+                       repo: repo.clone(),
+                       refs,
+                   }).to_string(),
+                   start_after: None,
+                   trace_parent: tracing::current_traceparent(),
+                   repo: Some(repo),
+               })
+               .get_result::<Job>(conn)?)
+        })
+    }
+
+    pub fn start_promote_job(self: &Self,
+                             from_repo: String,
+                             repo: String,
+                             refs: Vec<String>,
+                             note: Option<String>) -> impl Future<Item = Job, Error = ApiError> {
+        self.run(move |conn| {
+            Ok(diesel::insert_into(schema::jobs::table)
+               .values(NewJob {
+                   kind: JobKind::Promote.to_db(),
+                   contents: json!(PromoteJob {
+                       from_repo,
+                       repo: repo.clone(),
+                       refs,
+                       note,
+                   }).to_string(),
+                   start_after: None,
+                   trace_parent: tracing::current_traceparent(),
+                   repo: Some(repo),
+               })
+               .get_result::<Job>(conn)?)
+        })
+    }
+
+    /* Without a commit, the ref goes back to the commit before the last publish */
+    pub fn start_rollback_job(self: &Self,
+                              repo: String,
+                              ref_name: String,
+                              commit: Option<String>,
+                              note: Option<String>) -> impl Future<Item = Job, Error = ApiError> {
+        self.run_in_transaction(move |conn| {
+            use schema::published_refs::dsl as p;
+            let history = p::published_refs
+                .filter(p::repo.eq(&repo))
+                .filter(p::ref_name.eq(&ref_name))
+                .order(p::id.desc())
+                .get_results::<PublishedRef>(conn)?;
+            let latest = history.first()
+                .ok_or_else(|| ApiError::BadRequest(format!("Ref {} has never been published in {}", ref_name, repo)))?;
+            let commit = match commit {
+                Some(commit) => commit,
+                None => latest.previous_commit.clone()
+                    .ok_or_else(|| ApiError::BadRequest(format!("Ref {} has no earlier commit", ref_name)))?,
+            };
+            if commit == latest.commit {
+                return Err(ApiError::BadRequest(format!("Commit {} is the last published commit of {}", commit, ref_name)));
+            }
+            /* Only commits that were published, not anything in the repo */
+            let build_id = history.iter()
+                .find(|published| published.commit == commit)
+                .map(|published| published.build_id)
+                .unwrap_or(latest.build_id);
+            if !history.iter().any(|published| published.commit == commit || published.previous_commit.as_ref() == Some(&commit)) {
+                return Err(ApiError::BadRequest(format!("Commit {} was never published as {}", commit, ref_name)));
+            }
+            Ok(diesel::insert_into(schema::jobs::table)
+               .values(NewJob {
+                   kind: JobKind::Rollback.to_db(),
+                   contents: json!(RollbackJob {
+                       repo: repo.clone(),
+                       ref_name,
+                       commit,
+                       build_id,
+                       note,
+                   }).to_string(),
+                   start_after: None,
+                   trace_parent: tracing::current_traceparent(),
+                   repo: Some(repo),
+               })
+               .get_result::<Job>(conn)?)
+        })
+    }
+
+    /* The tombstone is recorded by the job, once the end-of-life commit is written */
+    pub fn start_deprecate_job(self: &Self,
+                               a_tombstone: NewRefTombstone) -> impl Future<Item = Job, Error = ApiError> {
+        self.run_in_transaction(move |conn| {
+            let published = schema::published_refs::table
+                .filter(schema::published_refs::repo.eq(&a_tombstone.repo))
+                .filter(schema::published_refs::ref_name.eq(&a_tombstone.ref_name))
+                .count()
+                .get_result::<i64>(conn)?;
+            if published == 0 {
+                return Err(ApiError::BadRequest(format!("Ref {} has never been published in {}", a_tombstone.ref_name, a_tombstone.repo)));
+            }
+            let existing = schema::ref_tombstones::table
+                .filter(schema::ref_tombstones::repo.eq(&a_tombstone.repo))
+                .filter(schema::ref_tombstones::ref_name.eq(&a_tombstone.ref_name))
+                .count()
+                .get_result::<i64>(conn)?;
+            if existing > 0 {
+                return Err(ApiError::BadRequest(format!("Ref {} is already end-of-life", a_tombstone.ref_name)));
+            }
+            let pending = schema::jobs::table
+                .filter(schema::jobs::kind.eq(JobKind::Deprecate.to_db()))
+                .filter(schema::jobs::status.le(JobStatus::Started as i16))
+                .filter(schema::jobs::repo.eq(&a_tombstone.repo))
+                .select(schema::jobs::contents)
+                .get_results::<String>(conn)?
+                .iter()
+                .filter_map(|contents| serde_json::from_str::<DeprecateJob>(contents).ok())
+                .any(|job| job.ref_name == a_tombstone.ref_name);
+            if pending {
+                return Err(ApiError::BadRequest(format!("Ref {} is already being marked end-of-life", a_tombstone.ref_name)));
+            }
+            Ok(diesel::insert_into(schema::jobs::table)
+                .values(NewJob {
+                    kind: JobKind::Deprecate.to_db(),
+                    contents: json!(DeprecateJob {
+                        repo: a_tombstone.repo.clone(),
+                        ref_name: a_tombstone.ref_name.clone(),
+                        reason: a_tombstone.reason.clone(),
+                        rebase: a_tombstone.rebase.clone(),
+                        author: a_tombstone.author.clone(),
+                    }).to_string(),
+                    start_after: None,
+                    trace_parent: tracing::current_traceparent(),
+                    repo: Some(a_tombstone.repo.clone()),
+                })
+                .get_result::<Job>(conn)?)
+        })
+    }
+
+    pub fn list_ref_tombstones(self: &Self, the_repo: String) -> impl Future<Item = Vec<RefTombstone>, Error = ApiError> {
+        self.run(move |conn| {
+            use schema::ref_tombstones::dsl::*;
+            Ok(ref_tombstones
+               .filter(repo.eq(the_repo))
+               .order(id)
+               .get_results::<RefTombstone>(conn)?)
+        })
+    }
+
+    pub fn start_bundle_job(self: &Self,
+                            ref_name: String,
+                            build_id: Option<i32>,
+                            repo: String) -> impl Future<Item = Job, Error = ApiError> {
+        self.run(move |conn| {
+            Ok(diesel::insert_into(schema::jobs::table)
+               .values(NewJob {
+                   kind: JobKind::Bundle.to_db(),
+                   contents: json!(BundleJob {
+                       ref_name,
+                       build: build_id,
+                       repo: repo.clone(),
+                   }).to_string(),
+                   start_after: None,
+                   trace_parent: tracing::current_traceparent(),
+                   /* Bundles of the published repo are serialized with the updates of it */
+                   repo: if build_id.is_some() { None } else { Some(repo) },
+               })
+               .get_result::<Job>(conn)?)
+        })
+    }
+
     pub fn lookup_commit_job(self: &Self,
                              build_id: i32,
                              log_offset: Option<usize>) -> impl Future<Item = Job, Error = ApiError> {
@@ -91,104 +595,67 @@ impl Db {
     }
 
     pub fn start_commit_job(self: &Self,
+                            config: Arc<Config>,
                             build_id: i32,
-                            endoflife: Option<String>,
-                            endoflife_rebase: Option<String>,
-                            token_type: Option<i32>) -> impl Future<Item = Job, Error = ApiError> {
-        self.run_in_transaction(move |conn| {
-            let current_build = schema::builds::table
-                .filter(schema::builds::id.eq(build_id))
-                .get_result::<Build>(conn)?;
-            let current_repo_state = RepoState::from_db(current_build.repo_state, &current_build.repo_state_reason);
-            match current_repo_state {
-                RepoState::Uploading => (),
-                RepoState::Verifying => return Err(ApiError::WrongRepoState(format!("Build is currently being commited"), "uploading".to_string(), "verifying".to_string())),
-                RepoState::Ready => return Err(ApiError::WrongRepoState(format!("Build is already commited"), "uploading".to_string(), "ready".to_string())),
-                RepoState::Failed(s) => return Err(ApiError::WrongRepoState(format!("Commit already failed: {}", s), "uploading".to_string(), "failed".to_string())),
-                RepoState::Purging |
-                RepoState::Purged => return Err(ApiError::WrongRepoState("Build has been purged".to_string(), "uploading".to_string(), "purged".to_string())),
-            }
-            let (val, reason) = RepoState::to_db(&RepoState::Verifying);
-            let job =
-                diesel::insert_into(schema::jobs::table)
-                .values(NewJob {
-                    kind: JobKind::Commit.to_db(),
-                    start_after: None,
-                    repo: None,
-                    contents: json!(CommitJob {
-                        build: build_id,
-                        endoflife: endoflife,
-                        endoflife_rebase: endoflife_rebase,
-                        token_type: token_type,
-                    }).to_string(),
-                })
-                .get_result::<Job>(conn)?;
-            diesel::update(schema::builds::table)
-                .filter(schema::builds::id.eq(build_id))
-                .set((schema::builds::commit_job_id.eq(job.id),
-                      schema::builds::repo_state.eq(val),
-                      schema::builds::repo_state_reason.eq(reason)))
-                .get_result::<Build>(conn)?;
-            Ok(job)
-        })
+                            options: CommitOptions) -> impl Future<Item = Job, Error = ApiError> {
+        self.run_in_transaction(move |conn| queue_commit_job(conn, &config, build_id, options))
     }
 
     pub fn start_publish_job(self: &Self,
+                             config: Arc<Config>,
                              build_id: i32,
-                             repo: String) -> impl Future<Item = Job, Error = ApiError> {
-        self.run_in_transaction(move |conn| {
-            let current_build = schema::builds::table
-                .filter(schema::builds::id.eq(build_id))
-                .get_result::<Build>(conn)?;
-            let current_published_state = PublishedState::from_db(current_build.published_state, &current_build.published_state_reason);
-
-            match current_published_state {
-                PublishedState::Unpublished => (),
-                PublishedState::Publishing => return Err(ApiError::WrongPublishedState("Build is currently being published".to_string(), "unpublished".to_string(), "publishing".to_string())),
-                PublishedState::Published => return Err(ApiError::WrongPublishedState("Build has already been published".to_string(), "unpublished".to_string(), "published".to_string())),
-                PublishedState::Failed(s) => return Err(ApiError::WrongPublishedState(format!("Previous publish failed: {}", s), "unpublished".to_string(), "failed".to_string())),
-            }
+                             options: PublishOptions) -> impl Future<Item = Job, Error = ApiError> {
+        self.run_in_transaction(move |conn| queue_publish_job(conn, &config, build_id, None, options))
+    }
 
-            let current_repo_state = RepoState::from_db(current_build.repo_state, &current_build.repo_state_reason);
-            match current_repo_state {
-                RepoState::Uploading => return Err(ApiError::WrongRepoState("Build is not commited".to_string(), "ready".to_string(), "uploading".to_string())),
-                RepoState::Verifying => return Err(ApiError::WrongRepoState("Build is not commited".to_string(), "ready".to_string(), "verifying".to_string())),
-                RepoState::Ready => (),
-                RepoState::Failed(s) => return Err(ApiError::WrongRepoState(format!("Build failed: {}", s), "ready".to_string(), "failed".to_string())),
-                RepoState::Purging |
-                RepoState::Purged => return Err(ApiError::WrongRepoState("Build has been purged".to_string(), "ready".to_string(), "purged".to_string())),
-            }
-
-            let (val, reason) = PublishedState::to_db(&PublishedState::Publishing);
-            let job =
-                diesel::insert_into(schema::jobs::table)
-                .values(NewJob {
-                    kind: JobKind::Publish.to_db(),
-                    start_after: None,
-                    repo: Some(repo),
-                    contents: json!(PublishJob {
-                        build: build_id,
-                    }).to_string(),
-                })
-                .get_result::<Job>(conn)?;
-            diesel::update(schema::builds::table)
-                .filter(schema::builds::id.eq(build_id))
-                .set((schema::builds::publish_job_id.eq(job.id),
-                      schema::builds::published_state.eq(val),
-                      schema::builds::published_state_reason.eq(reason)))
-                .get_result::<Build>(conn)?;
-            Ok(job)
+    /* Queues both jobs at once, with the publish job waiting for the commit job */
+    pub fn start_commit_and_publish_jobs(self: &Self,
+                                         config: Arc<Config>,
+                                         build_id: i32,
+                                         commit_options: CommitOptions,
+                                         publish_options: PublishOptions) -> impl Future<Item = (Job, Job), Error = ApiError> {
+        self.run_in_transaction(move |conn| {
+            let commit_job = queue_commit_job(conn, &config, build_id, commit_options)?;
+            let publish_job = queue_publish_job(conn, &config, build_id, Some(commit_job.id), publish_options)?;
+            Ok((commit_job, publish_job))
         })
     }
 
     /* Builds */
 
     pub fn new_build(self: &Self, a_build: NewBuild) -> impl Future<Item = Build, Error = ApiError> {
-        self.run(move |conn| {
-            use schema::builds::dsl::*;
-            Ok(diesel::insert_into(builds)
-               .values(&a_build)
-               .get_result::<Build>(conn)?)
+        self.run_in_transaction(move |conn| {
+            let new_build = diesel::insert_into(schema::builds::table)
+                .values(&a_build)
+                .get_result::<Build>(conn)?;
+            diesel::insert_into(schema::upload_sessions::table)
+                .values(schema::upload_sessions::build_id.eq(new_build.id))
+                .execute(conn)?;
+            Ok(new_build)
+        })
+    }
+
+    /* Called for each upload, refusing it once the session has expired */
+    pub fn touch_upload_session(self: &Self,
+                                the_build_id: i32) -> impl Future<Item = (), Error = ApiError> {
+        self.run_in_transaction(move |conn| {
+            use schema::upload_sessions::dsl::*;
+            let session = upload_sessions
+                .filter(build_id.eq(the_build_id))
+                .for_update()
+                .get_result::<UploadSession>(conn)
+                .optional()?;
+            if let Some(UploadSession { expired_at: Some(expired), .. }) = session {
+                return Err(ApiError::WrongRepoState(format!("The upload session of the build expired at {}", expired),
+                                                    "uploading".to_string(), "aborted".to_string()));
+            }
+            diesel::insert_into(upload_sessions)
+                .values(build_id.eq(the_build_id))
+                .on_conflict(build_id)
+                .do_update()
+                .set(last_activity_at.eq(diesel::dsl::now))
+                .execute(conn)?;
+            Ok(())
         })
     }
 
@@ -202,12 +669,30 @@ impl Db {
         })
     }
 
-    pub fn list_builds(self: &Self) -> impl Future<Item = Vec<Build>, Error = ApiError> {
+    pub fn list_builds(self: &Self,
+                       organization: Option<String>) -> impl Future<Item = Vec<Build>, Error = ApiError> {
         self.run(move |conn| {
             use schema::builds::dsl::*;
             let (val, _) = RepoState::Purged.to_db();
+            let mut query = builds
+                .filter(repo_state.ne(val))
+                .filter(deleted_at.is_null())
+                .into_boxed();
+            if let Some(organization) = organization {
+                query = query.filter(split_part(uploader, "/", 1).eq_any(organization_member_names(conn, &organization)?));
+            }
+            Ok(query.get_results::<Build>(conn)?)
+        })
+    }
+
+    pub fn list_recent_builds(self: &Self,
+                              limit: i64) -> impl Future<Item = Vec<Build>, Error = ApiError> {
+        self.run(move |conn| {
+            use schema::builds::dsl::*;
             Ok(builds
-               .filter(repo_state.ne(val))
+               .filter(deleted_at.is_null())
+               .order(id.desc())
+               .limit(limit)
                .get_results::<Build>(conn)?)
         })
     }
@@ -234,65 +719,437 @@ impl Db {
     }
 
     pub fn init_purge(self: &Self,
+                      config: Arc<Config>,
                       build_id: i32) -> impl Future<Item = (), Error = ApiError> {
+        self.run_in_transaction(move |conn| init_purge_build(conn, &config, build_id))
+    }
+
+    pub fn finish_purge(self: &Self,
+                        config: Arc<Config>,
+                        build_id: i32,
+                        error: Option<String>,) -> impl Future<Item = Build, Error = ApiError> {
+        self.run_in_transaction(move |conn| finish_purge_build(conn, &config, build_id, error))
+    }
+
+    /* A derived build starts out with refs of the source build, and its
+     * upload repo has the source build repo as parent, so the objects
+     * don't have to be uploaded again */
+    pub fn derive_build(self: &Self,
+                        source_id: i32,
+                        uploader: Option<String>,
+                        new_refs: Vec<(BuildRef, String)>) -> impl Future<Item = Build, Error = ApiError> {
+        self.run_in_transaction(move |conn| {
+            let source = schema::builds::table
+                .filter(schema::builds::id.eq(source_id))
+                .for_update()
+                .get_result::<Build>(conn)?;
+            if source.deleted_at.is_some() {
+                return Err(ApiError::BadRequest("Build has been deleted".to_string()))
+            }
+            /* The upload repo is removed on commit, so only the committed
+             * build repo can be used as parent */
+            let source_repo_state = RepoState::from_db(source.repo_state, &source.repo_state_reason);
+            if !source_repo_state.same_state_as(&RepoState::Ready) {
+                return Err(ApiError::WrongRepoState(format!("Build is in state {}, not ready", source_repo_state.name()),
+                                                    "ready".to_string(),
+                                                    source_repo_state.name().to_string()))
+            }
+            if new_refs.is_empty() {
+                return Err(ApiError::BadRequest("No refs to derive".to_string()))
+            }
+
+            let new_build = diesel::insert_into(schema::builds::table)
+                .values(NewBuild {
+                    repo: source.repo.clone(),
+                    uploader,
+                    github_repository: source.github_repository.clone(),
+                    github_sha: source.github_sha.clone(),
+                    derived_from: Some(source_id),
+                })
+                .get_result::<Build>(conn)?;
+
+            let mut app_id = None;
+            for (source_ref, ref_name) in new_refs {
+                let ref_parts: Vec<&str> = ref_name.split('/').collect();
+                if app_id.is_none() && ref_parts.len() == 4 && ref_parts[0] == "app" {
+                    app_id = Some(ref_parts[1].to_string());
+                }
+                diesel::insert_into(schema::build_refs::table)
+                    .values(NewBuildRef {
+                        build_id: new_build.id,
+                        ref_name: ref_name.clone(),
+                        commit: source_ref.commit,
+                        size: source_ref.size,
+                    })
+                    .execute(conn)?;
+            }
+
+            Ok(diesel::update(schema::builds::table)
+               .filter(schema::builds::id.eq(new_build.id))
+               .set(schema::builds::app_id.eq(app_id))
+               .get_result::<Build>(conn)?)
+        })
+    }
+
+    /* Deleted builds are hidden, and purged after the grace period unless undeleted */
+    pub fn delete_build(self: &Self,
+                        config: Arc<Config>,
+                        build_id: i32) -> impl Future<Item = Build, Error = ApiError> {
         self.run_in_transaction(move |conn| {
             use schema::builds::dsl::*;
             let current_build = builds
                 .filter(id.eq(build_id))
+                .for_update()
                 .get_result::<Build>(conn)?;
-            let current_repo_state = RepoState::from_db(current_build.repo_state, &current_build.repo_state_reason);
-            let current_published_state = PublishedState::from_db(current_build.published_state, &current_build.published_state_reason);
-            if current_repo_state.same_state_as(&RepoState::Verifying) ||
-                current_repo_state.same_state_as(&RepoState::Purging) ||
-                current_published_state.same_state_as(&PublishedState::Publishing) {
-                    /* Only allow pruning when we're not working on the build repo */
-                    return Err(ApiError::BadRequest("Can't prune build while in use".to_string()))
-                };
-            let (val, reason) = RepoState::to_db(&RepoState::Purging);
-            diesel::update(builds)
+            if current_build.deleted_at.is_some() {
+                return Err(ApiError::BadRequest("Build is already deleted".to_string()))
+            }
+            let new_build = diesel::update(builds)
                 .filter(id.eq(build_id))
-                .set((repo_state.eq(val),
-                      repo_state_reason.eq(reason)))
-                .execute(conn)?;
-            Ok(())
+                .set(deleted_at.eq(diesel::dsl::now))
+                .get_result::<Build>(conn)?;
+            webhooks::queue_build_deleted(conn, &config, &new_build)?;
+            Ok(new_build)
         })
     }
 
-    pub fn finish_purge(self: &Self,
-                        build_id: i32,
-                        error: Option<String>,) -> impl Future<Item = Build, Error = ApiError> {
+    /* Stops a build that is not committed yet: its queued jobs are
+     * cancelled, and the build is deleted so that it gets purged */
+    pub fn abort_build(self: &Self,
+                       config: Arc<Config>,
+                       build_id: i32) -> impl Future<Item = Build, Error = ApiError> {
+        self.run_in_transaction(move |conn| {
+            do_abort_build(conn, &config, build_id)
+        })
+    }
+
+    pub fn undelete_build(self: &Self,
+                          config: Arc<Config>,
+                          build_id: i32) -> impl Future<Item = Build, Error = ApiError> {
         self.run_in_transaction(move |conn| {
             use schema::builds::dsl::*;
             let current_build = builds
                 .filter(id.eq(build_id))
+                .for_update()
                 .get_result::<Build>(conn)?;
+            if current_build.deleted_at.is_none() {
+                return Err(ApiError::BadRequest("Build is not deleted".to_string()))
+            }
             let current_repo_state = RepoState::from_db(current_build.repo_state, &current_build.repo_state_reason);
-            if !current_repo_state.same_state_as(&RepoState::Purging) {
-                return Err(ApiError::BadRequest("Unexpected repo state, was not purging".to_string()))
-            };
-            let new_state = match error {
-                None => RepoState::Purged,
-                Some(err_string) => RepoState::Failed(format!("Failed to Purge build: {}", err_string)),
-            };
-            let (val, reason) = RepoState::to_db(&new_state);
-            let new_build =
-                diesel::update(builds)
+            if current_repo_state.same_state_as(&RepoState::Purging) ||
+                current_repo_state.same_state_as(&RepoState::Purged) {
+                    return Err(ApiError::WrongRepoState("Build has already been purged".to_string(), "uploading".to_string(), "purged".to_string()))
+                }
+            if current_repo_state.same_state_as(&RepoState::Aborted) {
+                return Err(ApiError::WrongRepoState("Build has been aborted".to_string(), "uploading".to_string(), "aborted".to_string()))
+            }
+            let new_build = diesel::update(builds)
                 .filter(id.eq(build_id))
-                .set((repo_state.eq(val),
-                      repo_state_reason.eq(reason)))
+                .set(deleted_at.eq(None::<chrono::NaiveDateTime>))
                 .get_result::<Build>(conn)?;
+            webhooks::queue_build_deleted(conn, &config, &new_build)?;
             Ok(new_build)
         })
     }
 
-    /* Build refs */
+    pub fn add_upload_stats(self: &Self,
+                            build_id: i32,
+                            subject: Option<String>,
+                            identity: Option<String>,
+                            objects: i64,
+                            bytes: i64) -> impl Future<Item = (), Error = ApiError> {
+        self.run_in_transaction(move |conn| {
+            {
+                use schema::builds::dsl::*;
+                diesel::update(builds)
+                    .filter(id.eq(build_id))
+                    .set((uploaded_objects.eq(uploaded_objects + objects),
+                          uploaded_bytes.eq(uploaded_bytes + bytes)))
+                    .execute(conn)?;
+            }
+            /* Organizations are accounted under org:NAME, next to the token subjects */
+            let mut subjects: Vec<String> = subject.into_iter().collect();
+            if let Some(identity) = identity {
+                subjects.extend(organizations_of(conn, &identity)?.into_iter().map(|org| format!("org:{}", org.name)));
+            }
+            for subject in subjects {
+                use schema::token_usage;
+                diesel::insert_into(token_usage::table)
+                    .values((token_usage::subject.eq(subject), token_usage::uploaded_bytes.eq(bytes)))
+                    .on_conflict(token_usage::subject)
+                    .do_update()
+                    .set((token_usage::uploaded_bytes.eq(token_usage::uploaded_bytes + bytes),
+                          token_usage::updated_at.eq(diesel::dsl::now)))
+                    .execute(conn)?;
+            }
+            Ok(())
+        })
+    }
 
-    pub fn new_build_ref(self: &Self, a_build_ref: NewBuildRef) -> impl Future<Item = BuildRef, Error = ApiError> {
+    /* Total bytes uploaded with tokens for this subject */
+    pub fn get_token_usage(self: &Self,
+                           subject: String) -> impl Future<Item = i64, Error = ApiError> {
         self.run(move |conn| {
-            use self::schema::build_refs::dsl::*;
-            Ok(diesel::insert_into(build_refs)
-               .values(&a_build_ref)
-               .get_result::<BuildRef>(conn)?)
+            use schema::token_usage;
+            Ok(token_usage::table
+               .filter(token_usage::subject.eq(subject))
+               .select(token_usage::uploaded_bytes)
+               .first::<i64>(conn)
+               .optional()?
+               .unwrap_or(0))
+        })
+    }
+
+    /* The least any organization of the identity has left of its quota,
+     * and the error for going over that */
+    pub fn get_organization_quota_left(self: &Self,
+                                       identity: Option<String>) -> impl Future<Item = Option<(u64, String)>, Error = ApiError> {
+        self.run(move |conn| {
+            let identity = match identity {
+                Some(identity) => identity,
+                None => return Ok(None),
+            };
+            let mut lefts = Vec::new();
+            for org in organizations_of(conn, &identity)? {
+                let quota = match org.upload_quota {
+                    Some(quota) => quota,
+                    None => continue,
+                };
+                use schema::token_usage;
+                let used = token_usage::table
+                    .filter(token_usage::subject.eq(format!("org:{}", org.name)))
+                    .select(token_usage::uploaded_bytes)
+                    .first::<i64>(conn)
+                    .optional()?
+                    .unwrap_or(0);
+                let left = (quota.max(0) as u64).saturating_sub(used.max(0) as u64);
+                lefts.push((left, format!("Upload quota of {} bytes for organization {} exceeded ({} bytes used before this upload)",
+                                          quota, org.name, used)));
+            }
+            Ok(lefts.into_iter().min_by_key(|(left, _)| *left))
+        })
+    }
+
+    pub fn list_token_usage(self: &Self,
+                            the_subject: Option<String>) -> impl Future<Item = Vec<TokenUsage>, Error = ApiError> {
+        self.run(move |conn| {
+            use schema::token_usage::dsl::*;
+            let mut query = token_usage.into_boxed();
+            if let Some(the_subject) = the_subject {
+                query = query.filter(subject.eq(the_subject));
+            }
+            Ok(query
+               .order(subject)
+               .get_results::<TokenUsage>(conn)?)
+        })
+    }
+
+    pub fn reset_token_usage(self: &Self,
+                             subject: String) -> impl Future<Item = (), Error = ApiError> {
+        self.run(move |conn| {
+            use schema::token_usage;
+            /* The request counts are kept, only the quota starts over */
+            diesel::update(token_usage::table.filter(token_usage::subject.eq(subject)))
+                .set((token_usage::uploaded_bytes.eq(0),
+                      token_usage::updated_at.eq(diesel::dsl::now)))
+                .execute(conn)?;
+            Ok(())
+        })
+    }
+
+    /* Metadata dumps */
+
+    /* All of METADATA_TABLES as json, read in one snapshot */
+    pub fn export_metadata(self: &Self) -> impl Future<Item = serde_json::Value, Error = ApiError> {
+        self.run(move |conn| {
+            conn.build_transaction()
+                .repeatable_read()
+                .read_only()
+                .run(|| {
+                    let mut tables = serde_json::Map::new();
+                    for table in METADATA_TABLES.iter() {
+                        let rows = diesel::sql_query(format!("SELECT coalesce(json_agg(t), '[]'::json)::text AS data FROM {} t", table))
+                            .get_result::<TextRow>(conn)?;
+                        tables.insert(table.to_string(), serde_json::from_str(&rows.data)
+                                      .map_err(|e| diesel::result::Error::DeserializationError(Box::new(e)))?);
+                    }
+                    Ok::<_, diesel::result::Error>(json!({
+                        "schema-version": get_schema_version(conn)?,
+                        "tables": tables,
+                    }))
+                })
+                .map_err(ApiError::from)
+        })
+    }
+
+    /* Loads a dump from export_metadata() into an empty database with the
+     * same schema version, returning the number of rows of each table */
+    pub fn import_metadata(self: &Self,
+                           dump: serde_json::Value) -> impl Future<Item = Vec<(String, usize)>, Error = ApiError> {
+        self.run_in_transaction(move |conn| {
+            let schema_version = get_schema_version(conn)?;
+            if dump["schema-version"].as_str() != Some(schema_version.as_str()) {
+                return Err(ApiError::BadRequest(format!("The dump is for schema version {}, but the database has {}",
+                                                        dump["schema-version"], schema_version)));
+            }
+            let mut counts = vec![];
+            for table in METADATA_TABLES.iter() {
+                let rows = dump["tables"][table].as_array()
+                    .ok_or_else(|| ApiError::BadRequest(format!("No {} in the dump", table)))?;
+                let existing = diesel::sql_query(format!("SELECT count(*) AS count FROM {}", table))
+                    .get_result::<CountRow>(conn)?;
+                if existing.count > 0 {
+                    return Err(ApiError::BadRequest(format!("Table {} is not empty, only importing into a new database is supported", table)));
+                }
+                diesel::sql_query(format!("INSERT INTO {0} SELECT * FROM json_populate_recordset(NULL::{0}, $1::json)", table))
+                    .bind::<diesel::sql_types::Text, _>(serde_json::Value::Array(rows.clone()).to_string())
+                    .execute(conn)?;
+                counts.push((table.to_string(), rows.len()));
+            }
+            /* New rows must not reuse the imported ids */
+            for table in METADATA_TABLES.iter().filter(|table| **table != "job_dependencies") {
+                diesel::sql_query(format!("SELECT setval('{0}_id_seq', coalesce((SELECT max(id) FROM {0}), 0) + 1, false)", table))
+                    .execute(conn)?;
+            }
+            Ok(counts)
+        })
+    }
+
+    /* Records the refs of a repo that was published without flat-manager
+     * as one published build, so later publishes and prunes know about them */
+    pub fn adopt_repo(self: &Self,
+                      repo: String,
+                      refs: Vec<(String, String)>) -> impl Future<Item = Build, Error = ApiError> {
+        self.run_in_transaction(move |conn| {
+            let n_published = schema::published_refs::table
+                .filter(schema::published_refs::repo.eq(&repo))
+                .count()
+                .get_result::<i64>(conn)?;
+            if n_published > 0 {
+                return Err(ApiError::BadRequest(format!("Repo {} already has published builds", repo)));
+            }
+            if refs.is_empty() {
+                return Err(ApiError::BadRequest(format!("Repo {} has no app or runtime refs", repo)));
+            }
+
+            let build = diesel::insert_into(schema::builds::table)
+                .values(NewBuild {
+                    repo: repo.clone(),
+                    uploader: Some("import".to_string()),
+                    github_repository: None,
+                    github_sha: None,
+                    derived_from: None,
+                })
+                .get_result::<Build>(conn)?;
+            /* There is no build repo, the objects only exist in the main repo */
+            let (repo_state, repo_state_reason) = RepoState::Purged.to_db();
+            let (published_state, published_state_reason) = PublishedState::Published.to_db();
+            let build = diesel::update(schema::builds::table)
+                .filter(schema::builds::id.eq(build.id))
+                .set((schema::builds::repo_state.eq(repo_state),
+                      schema::builds::repo_state_reason.eq(repo_state_reason),
+                      schema::builds::published_state.eq(published_state),
+                      schema::builds::published_state_reason.eq(published_state_reason)))
+                .get_result::<Build>(conn)?;
+
+            for (ref_name, commit) in refs {
+                diesel::insert_into(schema::build_refs::table)
+                    .values(NewBuildRef {
+                        build_id: build.id,
+                        ref_name: ref_name.clone(),
+                        commit: commit.clone(),
+                        size: None,
+                    })
+                    .execute(conn)?;
+                diesel::insert_into(schema::published_refs::table)
+                    .values(NewPublishedRef {
+                        build_id: build.id,
+                        ref_name,
+                        commit,
+                        job_id: None,
+                        repo: repo.clone(),
+                        previous_commit: None,
+                        note: Some("Imported from the existing repo".to_string()),
+                        published_by: None,
+                    })
+                    .execute(conn)?;
+            }
+            Ok(build)
+        })
+    }
+
+    /* Build refs */
+
+    /* Refs that were marked end-of-life in the repo of the build need force */
+    pub fn new_build_ref(self: &Self, a_build_ref: NewBuildRef, force: bool) -> impl Future<Item = BuildRef, Error = ApiError> {
+        self.run_in_transaction(move |conn| {
+            if !force {
+                let build_repo = schema::builds::table
+                    .filter(schema::builds::id.eq(a_build_ref.build_id))
+                    .select(schema::builds::repo)
+                    .get_result::<String>(conn)?;
+                let tombstone = schema::ref_tombstones::table
+                    .filter(schema::ref_tombstones::repo.eq(&build_repo))
+                    .filter(schema::ref_tombstones::ref_name.eq(&a_build_ref.ref_name))
+                    .get_result::<RefTombstone>(conn)
+                    .optional()?;
+                if let Some(tombstone) = tombstone {
+                    return Err(ApiError::BadRequest(format!("Ref {} is end-of-life in {}: {}", tombstone.ref_name, build_repo, tombstone.reason)));
+                }
+            }
+            let new_build_ref = diesel::insert_into(schema::build_refs::table)
+                .values(&a_build_ref)
+                .get_result::<BuildRef>(conn)?;
+
+            /* The first uploaded app ref decides the primary app id of the build */
+            let ref_parts: Vec<&str> = new_build_ref.ref_name.split('/').collect();
+            if ref_parts.len() == 4 && ref_parts[0] == "app" {
+                diesel::update(schema::builds::table)
+                    .filter(schema::builds::id.eq(new_build_ref.build_id))
+                    .filter(schema::builds::app_id.is_null())
+                    .set(schema::builds::app_id.eq(ref_parts[1]))
+                    .execute(conn)?;
+            }
+            Ok(new_build_ref)
+        })
+    }
+
+    /* Only possible while uploading, the commit job uses the refs that are left */
+    pub fn delete_build_ref(self: &Self,
+                            the_build_id: i32,
+                            ref_id: i32) -> impl Future<Item = BuildRef, Error = ApiError> {
+        self.run_in_transaction(move |conn| {
+            let current_build = schema::builds::table
+                .filter(schema::builds::id.eq(the_build_id))
+                .for_update()
+                .get_result::<Build>(conn)?;
+            let current_repo_state = RepoState::from_db(current_build.repo_state, &current_build.repo_state_reason);
+            if !current_repo_state.same_state_as(&RepoState::Uploading) {
+                return Err(ApiError::WrongRepoState(format!("Build is in {} state", current_repo_state.name()),
+                                                    "uploading".to_string(),
+                                                    current_repo_state.name().to_string()))
+            }
+
+            let deleted_ref = diesel::delete(schema::build_refs::table)
+                .filter(schema::build_refs::build_id.eq(the_build_id))
+                .filter(schema::build_refs::id.eq(ref_id))
+                .get_result::<BuildRef>(conn)?;
+
+            /* The primary app id may have come from the deleted ref */
+            let remaining_refs = schema::build_refs::table
+                .filter(schema::build_refs::build_id.eq(the_build_id))
+                .order(schema::build_refs::id)
+                .get_results::<BuildRef>(conn)?;
+            let new_app_id = remaining_refs.iter()
+                .map(|build_ref| build_ref.ref_name.split('/').collect::<Vec<&str>>())
+                .find(|parts| parts.len() == 4 && parts[0] == "app")
+                .map(|parts| parts[1].to_string());
+            diesel::update(schema::builds::table)
+                .filter(schema::builds::id.eq(the_build_id))
+                .set(schema::builds::app_id.eq(new_app_id))
+                .execute(conn)?;
+
+            Ok(deleted_ref)
         })
     }
 
@@ -308,7 +1165,6 @@ impl Db {
         })
     }
 
-    #[allow(dead_code)]
     pub fn lookup_build_refs(self: &Self,
                              the_build_id: i32) -> impl Future<Item = Vec<BuildRef>, Error = ApiError> {
         self.run(move |conn| {
@@ -318,4 +1174,617 @@ impl Db {
                .get_results::<BuildRef>(conn)?)
         })
     }
+
+    /* Build comments */
+
+    pub fn new_build_comment(self: &Self, a_comment: NewBuildComment) -> impl Future<Item = BuildComment, Error = ApiError> {
+        self.run(move |conn| {
+            /* Fails with NotFound rather than a foreign key error for missing builds */
+            schema::builds::table
+                .filter(schema::builds::id.eq(a_comment.build_id))
+                .select(schema::builds::id)
+                .get_result::<i32>(conn)?;
+            Ok(diesel::insert_into(schema::build_comments::table)
+               .values(&a_comment)
+               .get_result::<BuildComment>(conn)?)
+        })
+    }
+
+    pub fn lookup_build_comments(self: &Self,
+                                 the_build_id: i32) -> impl Future<Item = Vec<BuildComment>, Error = ApiError> {
+        self.run(move |conn| {
+            use schema::build_comments::dsl::*;
+            Ok(build_comments
+               .filter(build_id.eq(the_build_id))
+               .order(id)
+               .get_results::<BuildComment>(conn)?)
+        })
+    }
+
+    /* Newest first, with the uploader of the build */
+    pub fn get_ref_history(self: &Self,
+                           the_repo: String,
+                           the_ref_name: String,
+                           limit: i64) -> impl Future<Item = Vec<(PublishedRef, Option<String>)>, Error = ApiError> {
+        self.run(move |conn| {
+            use schema::published_refs::dsl::*;
+            Ok(published_refs
+               .inner_join(schema::builds::table)
+               .filter(repo.eq(the_repo))
+               .filter(ref_name.eq(the_ref_name))
+               .order(id.desc())
+               .limit(limit)
+               .select((schema::published_refs::all_columns, schema::builds::uploader))
+               .get_results::<(PublishedRef, Option<String>)>(conn)?)
+        })
+    }
+
+    /* Ref pins */
+
+    pub fn new_ref_pin(self: &Self, a_pin: NewRefPin) -> impl Future<Item = RefPin, Error = ApiError> {
+        self.run(move |conn| {
+            Ok(diesel::insert_into(schema::ref_pins::table)
+               .values(&a_pin)
+               .get_result::<RefPin>(conn)?)
+        })
+    }
+
+    pub fn list_ref_pins(self: &Self, the_repo: String) -> impl Future<Item = Vec<RefPin>, Error = ApiError> {
+        self.run(move |conn| {
+            use schema::ref_pins::dsl::*;
+            Ok(ref_pins
+               .filter(repo.eq(the_repo))
+               .order(id)
+               .get_results::<RefPin>(conn)?)
+        })
+    }
+
+    pub fn delete_ref_pin(self: &Self, the_repo: String, pin_id: i32) -> impl Future<Item = RefPin, Error = ApiError> {
+        self.run(move |conn| {
+            use schema::ref_pins::dsl::*;
+            Ok(diesel::delete(ref_pins.filter(repo.eq(the_repo)).filter(id.eq(pin_id)))
+               .get_result::<RefPin>(conn)?)
+        })
+    }
+
+    /* App ACLs */
+
+    pub fn new_app_acl_entry(self: &Self, an_entry: NewAppAclEntry) -> impl Future<Item = AppAclEntry, Error = ApiError> {
+        self.run(move |conn| {
+            Ok(diesel::insert_into(schema::app_acl::table)
+               .values(&an_entry)
+               .get_result::<AppAclEntry>(conn)?)
+        })
+    }
+
+    pub fn list_app_acl(self: &Self, the_app_id: String) -> impl Future<Item = Vec<AppAclEntry>, Error = ApiError> {
+        self.run(move |conn| {
+            use schema::app_acl::dsl::*;
+            Ok(app_acl
+               .filter(app_id.eq(the_app_id))
+               .order(id)
+               .get_results::<AppAclEntry>(conn)?)
+        })
+    }
+
+    pub fn delete_app_acl_entry(self: &Self, the_app_id: String, entry_id: i32) -> impl Future<Item = AppAclEntry, Error = ApiError> {
+        self.run(move |conn| {
+            use schema::app_acl::dsl::*;
+            Ok(diesel::delete(app_acl.filter(app_id.eq(the_app_id)).filter(id.eq(entry_id)))
+               .get_result::<AppAclEntry>(conn)?)
+        })
+    }
+
+    pub fn new_acl_group_member(self: &Self, a_member: NewAclGroupMember) -> impl Future<Item = AclGroupMember, Error = ApiError> {
+        self.run(move |conn| {
+            Ok(diesel::insert_into(schema::acl_group_members::table)
+               .values(&a_member)
+               .get_result::<AclGroupMember>(conn)?)
+        })
+    }
+
+    pub fn list_acl_group(self: &Self, the_group: String) -> impl Future<Item = Vec<AclGroupMember>, Error = ApiError> {
+        self.run(move |conn| {
+            use schema::acl_group_members::dsl::*;
+            Ok(acl_group_members
+               .filter(group_name.eq(the_group))
+               .order(id)
+               .get_results::<AclGroupMember>(conn)?)
+        })
+    }
+
+    pub fn delete_acl_group_member(self: &Self, the_group: String, member_id: i32) -> impl Future<Item = AclGroupMember, Error = ApiError> {
+        self.run(move |conn| {
+            use schema::acl_group_members::dsl::*;
+            Ok(diesel::delete(acl_group_members.filter(group_name.eq(the_group)).filter(id.eq(member_id)))
+               .get_result::<AclGroupMember>(conn)?)
+        })
+    }
+
+    /* An entry for an app id also covers the ids below it, so that
+     * e.g. org.foo.App.Locale can't be published around the ACL of
+     * org.foo.App. Apps without any entries are open to every token
+     * that can access the build. */
+    pub fn check_app_acl(self: &Self,
+                         the_build_id: i32,
+                         identity: Option<String>) -> impl Future<Item = (), Error = ApiError> {
+        self.run(move |conn| {
+            let ref_names = schema::build_refs::table
+                .filter(schema::build_refs::build_id.eq(the_build_id))
+                .select(schema::build_refs::ref_name)
+                .get_results::<String>(conn)?;
+
+            let mut principals = Vec::new();
+            if let Some(ref identity) = identity {
+                let identity = acl_principal(identity);
+                principals.push(identity.to_string());
+                let groups = schema::acl_group_members::table
+                    .filter(schema::acl_group_members::member.eq(identity))
+                    .select(schema::acl_group_members::group_name)
+                    .get_results::<String>(conn)?;
+                principals.extend(groups.into_iter().map(|g| format!("group:{}", g)));
+                principals.extend(organizations_of(conn, identity)?.into_iter().map(|org| format!("org:{}", org.name)));
+            }
+
+            for ref_name in ref_names {
+                let parts: Vec<&str> = ref_name.split('/').collect();
+                if parts.len() != 4 || (parts[0] != "app" && parts[0] != "runtime") {
+                    continue;
+                }
+                let id_parts: Vec<&str> = parts[1].split('.').collect();
+                let candidates: Vec<String> = (1..=id_parts.len()).map(|n| id_parts[..n].join(".")).collect();
+                let allowed = schema::app_acl::table
+                    .filter(schema::app_acl::app_id.eq_any(&candidates))
+                    .select(schema::app_acl::principal)
+                    .get_results::<String>(conn)?;
+                if !allowed.is_empty() && !allowed.iter().any(|p| principals.contains(p)) {
+                    return Err(ApiError::NotEnoughPermissions(format!("Not allowed to publish {}", parts[1])));
+                }
+            }
+            Ok(())
+        })
+    }
+
+    /* Organizations */
+
+    pub fn new_organization(self: &Self, an_organization: NewOrganization) -> impl Future<Item = Organization, Error = ApiError> {
+        self.run(move |conn| {
+            Ok(diesel::insert_into(schema::organizations::table)
+               .values(&an_organization)
+               .get_result::<Organization>(conn)?)
+        })
+    }
+
+    pub fn list_organizations(self: &Self) -> impl Future<Item = Vec<Organization>, Error = ApiError> {
+        self.run(move |conn| {
+            use schema::organizations::dsl::*;
+            Ok(organizations
+               .order(name)
+               .get_results::<Organization>(conn)?)
+        })
+    }
+
+    pub fn lookup_organization(self: &Self,
+                               the_name: String) -> impl Future<Item = (Organization, Vec<OrganizationMember>), Error = ApiError> {
+        self.run(move |conn| {
+            let organization = schema::organizations::table
+                .filter(schema::organizations::name.eq(the_name))
+                .get_result::<Organization>(conn)?;
+            let members = OrganizationMember::belonging_to(&organization)
+                .order(schema::organization_members::id)
+                .get_results::<OrganizationMember>(conn)?;
+            Ok((organization, members))
+        })
+    }
+
+    /* The members go with it, the usage of the organization is kept */
+    pub fn delete_organization(self: &Self, the_name: String) -> impl Future<Item = Organization, Error = ApiError> {
+        self.run(move |conn| {
+            use schema::organizations::dsl::*;
+            Ok(diesel::delete(organizations.filter(name.eq(the_name)))
+               .get_result::<Organization>(conn)?)
+        })
+    }
+
+    pub fn new_organization_member(self: &Self,
+                                   the_name: String,
+                                   the_member: String,
+                                   the_author: String) -> impl Future<Item = OrganizationMember, Error = ApiError> {
+        self.run(move |conn| {
+            let organization = schema::organizations::table
+                .filter(schema::organizations::name.eq(the_name))
+                .get_result::<Organization>(conn)?;
+            Ok(diesel::insert_into(schema::organization_members::table)
+               .values(NewOrganizationMember {
+                   organization_id: organization.id,
+                   member: the_member,
+                   author: the_author,
+               })
+               .get_result::<OrganizationMember>(conn)?)
+        })
+    }
+
+    pub fn delete_organization_member(self: &Self,
+                                      the_name: String,
+                                      member_id: i32) -> impl Future<Item = OrganizationMember, Error = ApiError> {
+        self.run(move |conn| {
+            let organization = schema::organizations::table
+                .filter(schema::organizations::name.eq(the_name))
+                .get_result::<Organization>(conn)?;
+            Ok(diesel::delete(OrganizationMember::belonging_to(&organization)
+                              .filter(schema::organization_members::id.eq(member_id)))
+               .get_result::<OrganizationMember>(conn)?)
+        })
+    }
+
+    /* Audit log */
+
+    pub fn add_audit_log_entry(self: &Self, entry: NewAuditLogEntry) -> impl Future<Item = (), Error = ApiError> {
+        self.run(move |conn| {
+            use schema::audit_log::dsl::*;
+            diesel::insert_into(audit_log)
+                .values(&entry)
+                .execute(conn)?;
+            Ok(())
+        })
+    }
+
+    pub fn list_audit_log(self: &Self,
+                          for_action: Option<String>,
+                          for_organization: Option<String>,
+                          max_entries: i64) -> impl Future<Item = Vec<AuditLogEntry>, Error = ApiError> {
+        self.run(move |conn| {
+            use schema::audit_log::dsl::*;
+            let mut query = audit_log.into_boxed();
+            if let Some(the_action) = for_action {
+                query = query.filter(action.eq(the_action));
+            }
+            if let Some(the_organization) = for_organization {
+                /* Entries are attributed the same way as uploads, by token name or else subject */
+                let members = organization_member_names(conn, &the_organization)?;
+                query = query.filter(split_part(token_name, "/", 1).eq_any(members.clone())
+                                     .or(token_name.is_null().and(split_part(token_sub, "/", 1).eq_any(members))));
+            }
+            Ok(query
+               .order(id.desc())
+               .limit(max_entries)
+               .get_results::<AuditLogEntry>(conn)?)
+        })
+    }
+}
+
+/* The token names in an organization, for filtering by it */
+fn organization_member_names(conn: &PgConnection,
+                             organization: &str) -> Result<Vec<String>, ApiError> {
+    let organization = schema::organizations::table
+        .filter(schema::organizations::name.eq(organization))
+        .get_result::<Organization>(conn)?;
+    Ok(OrganizationMember::belonging_to(&organization)
+       .select(schema::organization_members::member)
+       .get_results::<String>(conn)?)
+}
+
+/* Tokens derived from another token, like the "$name/worker" tokens
+ * for uploading, are named below it, and have its ACL entries and
+ * organizations. This is split_part(identity, '/', 1) in queries. */
+pub fn acl_principal(identity: &str) -> &str {
+    identity.split('/').next().unwrap_or(identity)
+}
+
+sql_function!(fn split_part(string: Nullable<Text>, delimiter: Text, field: Integer) -> Nullable<Text>);
+
+fn organizations_of(conn: &PgConnection,
+                    identity: &str) -> Result<Vec<Organization>, ApiError> {
+    Ok(schema::organizations::table
+       .inner_join(schema::organization_members::table)
+       .filter(schema::organization_members::member.eq(acl_principal(identity)))
+       .select(schema::organizations::all_columns)
+       .order(schema::organizations::name)
+       .get_results::<Organization>(conn)?)
+}
+
+/* These are also used by the BuildPurger, so they take a connection */
+
+/* Returns None if there was an upload since the session was found idle */
+pub fn expire_upload_session(conn: &PgConnection,
+                             config: &Config,
+                             the_build_id: i32,
+                             idle_before: chrono::NaiveDateTime) -> Result<Option<Build>, ApiError> {
+    use schema::upload_sessions::dsl::*;
+    /* Locking the session waits for a concurrent touch_upload_session */
+    let session = upload_sessions
+        .filter(build_id.eq(the_build_id))
+        .for_update()
+        .get_result::<UploadSession>(conn)?;
+    if session.expired_at.is_some() || session.last_activity_at >= idle_before {
+        return Ok(None);
+    }
+    let build = do_abort_build(conn, config, the_build_id)?;
+    diesel::update(upload_sessions)
+        .filter(build_id.eq(the_build_id))
+        .set(expired_at.eq(diesel::dsl::now))
+        .execute(conn)?;
+    Ok(Some(build))
+}
+
+pub fn do_abort_build(conn: &PgConnection,
+                      config: &Config,
+                      build_id: i32) -> Result<Build, ApiError> {
+    let current_build = schema::builds::table
+        .filter(schema::builds::id.eq(build_id))
+        .for_update()
+        .get_result::<Build>(conn)?;
+    let current_repo_state = RepoState::from_db(current_build.repo_state, &current_build.repo_state_reason);
+    let build_job_ids: Vec<i32> = current_build.commit_job_id.iter().chain(current_build.publish_job_id.iter()).cloned().collect();
+    /* Locking the jobs keeps the job executor from starting them while we check */
+    let build_jobs = schema::jobs::table
+        .filter(schema::jobs::id.eq_any(build_job_ids.clone()))
+        .for_update()
+        .get_results::<Job>(conn)?;
+    match current_repo_state {
+        RepoState::Uploading => (),
+        /* The commit can only be stopped before it starts */
+        RepoState::Verifying => {
+            if build_jobs.iter().any(|job| job.status != JobStatus::New as i16) {
+                return Err(ApiError::WrongRepoState("Build is currently being commited".to_string(), "uploading".to_string(), "verifying".to_string()))
+            }
+        },
+        state => return Err(ApiError::WrongRepoState(format!("Can't abort build in {} state", state.name()), "uploading".to_string(), state.name().to_string())),
+    }
+
+    let cancelled = diesel::update(schema::jobs::table)
+        .filter(schema::jobs::id.eq_any(build_job_ids))
+        .filter(schema::jobs::status.eq(JobStatus::New as i16))
+        .set((schema::jobs::status.eq(JobStatus::Broken as i16),
+              schema::jobs::results.eq(json!(JobResults::new(FailedJobResult { error_message: "Build aborted".to_string(), committed_refs: HashMap::new() })).to_string())))
+        .get_results::<Job>(conn)?;
+    for job in cancelled.iter() {
+        info!("Cancelled job {} of aborted build {}", job.id, build_id);
+    }
+
+    let (val, reason) = RepoState::to_db(&RepoState::Aborted);
+    let mut new_published_state = PublishedState::from_db(current_build.published_state, &current_build.published_state_reason);
+    if new_published_state.same_state_as(&PublishedState::Publishing) {
+        new_published_state = PublishedState::Failed("Build aborted".to_string());
+    }
+    let (published_val, published_reason) = new_published_state.to_db();
+    let new_build = diesel::update(schema::builds::table)
+        .filter(schema::builds::id.eq(build_id))
+        .set((schema::builds::repo_state.eq(val),
+              schema::builds::repo_state_reason.eq(reason),
+              schema::builds::published_state.eq(published_val),
+              schema::builds::published_state_reason.eq(published_reason),
+              schema::builds::deleted_at.eq(current_build.deleted_at.unwrap_or(chrono::Utc::now().naive_utc()))))
+        .get_result::<Build>(conn)?;
+    webhooks::queue_build_repo_state(conn, config, &new_build)?;
+    if new_build.published_state != current_build.published_state {
+        webhooks::queue_build_published_state(conn, config, &new_build, &None)?;
+    }
+    if current_build.deleted_at.is_none() {
+        webhooks::queue_build_deleted(conn, config, &new_build)?;
+    }
+    Ok(new_build)
+}
+
+
+pub fn init_purge_build(conn: &PgConnection,
+                        config: &Config,
+                        build_id: i32) -> Result<(), ApiError> {
+    use schema::builds::dsl::*;
+    let current_build = builds
+        .filter(id.eq(build_id))
+        .get_result::<Build>(conn)?;
+    let current_repo_state = RepoState::from_db(current_build.repo_state, &current_build.repo_state_reason);
+    let current_published_state = PublishedState::from_db(current_build.published_state, &current_build.published_state_reason);
+    if current_repo_state.same_state_as(&RepoState::Verifying) ||
+        current_repo_state.same_state_as(&RepoState::Purging) ||
+        current_published_state.same_state_as(&PublishedState::Publishing) {
+            /* Only allow pruning when we're not working on the build repo */
+            return Err(ApiError::BadRequest("Can't prune build while in use".to_string()))
+        };
+    /* Uncommitted derived builds still need the objects of this build */
+    let (uploading, _) = RepoState::Uploading.to_db();
+    let (verifying, _) = RepoState::Verifying.to_db();
+    let uncommitted_derived = builds
+        .filter(derived_from.eq(build_id))
+        .filter(repo_state.eq_any(vec![uploading, verifying]))
+        .count()
+        .get_result::<i64>(conn)?;
+    if uncommitted_derived > 0 {
+        return Err(ApiError::BadRequest("Can't prune build while builds derived from it are not committed".to_string()))
+    }
+    let (val, reason) = RepoState::to_db(&RepoState::Purging);
+    let new_build = diesel::update(builds)
+        .filter(id.eq(build_id))
+        .set((repo_state.eq(val),
+              repo_state_reason.eq(reason)))
+        .get_result::<Build>(conn)?;
+    webhooks::queue_build_repo_state(conn, config, &new_build)?;
+    Ok(())
+}
+
+pub fn finish_purge_build(conn: &PgConnection,
+                          config: &Config,
+                          build_id: i32,
+                          error: Option<String>) -> Result<Build, ApiError> {
+    use schema::builds::dsl::*;
+    let current_build = builds
+        .filter(id.eq(build_id))
+        .get_result::<Build>(conn)?;
+    let current_repo_state = RepoState::from_db(current_build.repo_state, &current_build.repo_state_reason);
+    if !current_repo_state.same_state_as(&RepoState::Purging) {
+        return Err(ApiError::BadRequest("Unexpected repo state, was not purging".to_string()))
+    };
+    let new_state = match error {
+        None => RepoState::Purged,
+        Some(err_string) => RepoState::Failed(format!("Failed to Purge build: {}", err_string)),
+    };
+    let (val, reason) = RepoState::to_db(&new_state);
+    let new_build =
+        diesel::update(builds)
+        .filter(id.eq(build_id))
+        .set((repo_state.eq(val),
+              repo_state_reason.eq(reason)))
+        .get_result::<Build>(conn)?;
+    webhooks::queue_build_repo_state(conn, config, &new_build)?;
+    Ok(new_build)
+}
+
+fn queue_commit_job(conn: &PgConnection,
+                    config: &Config,
+                    build_id: i32,
+                    options: CommitOptions) -> Result<Job, ApiError> {
+    let current_build = schema::builds::table
+        .filter(schema::builds::id.eq(build_id))
+        /* Keeps the build from being deleted while we queue the commit */
+        .for_update()
+        .get_result::<Build>(conn)?;
+    if current_build.deleted_at.is_some() {
+        return Err(ApiError::BadRequest("Build has been deleted".to_string()))
+    }
+    let current_repo_state = RepoState::from_db(current_build.repo_state, &current_build.repo_state_reason);
+    match current_repo_state {
+        RepoState::Uploading => (),
+        RepoState::Verifying => return Err(ApiError::WrongRepoState("Build is currently being commited".to_string(), "uploading".to_string(), "verifying".to_string())),
+        RepoState::Ready => return Err(ApiError::WrongRepoState("Build is already commited".to_string(), "uploading".to_string(), "ready".to_string())),
+        RepoState::Failed(s) => return Err(ApiError::WrongRepoState(format!("Commit already failed: {}", s), "uploading".to_string(), "failed".to_string())),
+        RepoState::Purging |
+        RepoState::Purged => return Err(ApiError::WrongRepoState("Build has been purged".to_string(), "uploading".to_string(), "purged".to_string())),
+        RepoState::Aborted => return Err(ApiError::WrongRepoState("Build has been aborted".to_string(), "uploading".to_string(), "aborted".to_string())),
+    }
+    let mut new_metadata = current_build.metadata.clone();
+    if let Some(serde_json::Value::Object(extra)) = options.metadata {
+        if let serde_json::Value::Object(ref mut current) = new_metadata {
+            current.extend(extra);
+        }
+    }
+    let (val, reason) = RepoState::to_db(&RepoState::Verifying);
+    let job =
+        diesel::insert_into(schema::jobs::table)
+        .values(NewJob {
+            kind: JobKind::Commit.to_db(),
+            start_after: None,
+            trace_parent: tracing::current_traceparent(),
+            repo: None,
+            contents: json!(CommitJob {
+                build: build_id,
+                endoflife: options.endoflife,
+                endoflife_rebase: options.endoflife_rebase,
+                token_type: options.token_type,
+                timestamp: options.timestamp,
+            }).to_string(),
+        })
+        .get_result::<Job>(conn)?;
+    let new_build = diesel::update(schema::builds::table)
+        .filter(schema::builds::id.eq(build_id))
+        .set((schema::builds::commit_job_id.eq(job.id),
+              schema::builds::repo_state.eq(val),
+              schema::builds::repo_state_reason.eq(reason),
+              schema::builds::metadata.eq(new_metadata)))
+        .get_result::<Build>(conn)?;
+    webhooks::queue_build_repo_state(conn, config, &new_build)?;
+    Ok(job)
+}
+
+/* With a commit job, the publish job is queued while the build is still
+ * being committed, and it waits for the commit job */
+fn queue_publish_job(conn: &PgConnection,
+                     config: &Config,
+                     build_id: i32,
+                     commit_job_id: Option<i32>,
+                     options: PublishOptions) -> Result<Job, ApiError> {
+    let PublishOptions { repo, note, arches, branches, published_by } = options;
+    let current_build = schema::builds::table
+        .filter(schema::builds::id.eq(build_id))
+        .get_result::<Build>(conn)?;
+    if current_build.deleted_at.is_some() {
+        return Err(ApiError::BadRequest("Build has been deleted".to_string()))
+    }
+    let current_published_state = PublishedState::from_db(current_build.published_state, &current_build.published_state_reason);
+
+    match current_published_state {
+        PublishedState::Unpublished => (),
+        PublishedState::Publishing => return Err(ApiError::WrongPublishedState("Build is currently being published".to_string(), "unpublished".to_string(), "publishing".to_string())),
+        PublishedState::Published => return Err(ApiError::WrongPublishedState("Build has already been published".to_string(), "unpublished".to_string(), "published".to_string())),
+        PublishedState::Failed(s) => return Err(ApiError::WrongPublishedState(format!("Previous publish failed: {}", s), "unpublished".to_string(), "failed".to_string())),
+    }
+
+    let current_repo_state = RepoState::from_db(current_build.repo_state, &current_build.repo_state_reason);
+    match current_repo_state {
+        RepoState::Uploading => return Err(ApiError::WrongRepoState("Build is not commited".to_string(), "ready".to_string(), "uploading".to_string())),
+        RepoState::Verifying if commit_job_id.is_some() => (),
+        RepoState::Verifying => return Err(ApiError::WrongRepoState("Build is not commited".to_string(), "ready".to_string(), "verifying".to_string())),
+        RepoState::Ready => (),
+        RepoState::Failed(s) => return Err(ApiError::WrongRepoState(format!("Build failed: {}", s), "ready".to_string(), "failed".to_string())),
+        RepoState::Purging |
+        RepoState::Purged => return Err(ApiError::WrongRepoState("Build has been purged".to_string(), "ready".to_string(), "purged".to_string())),
+        RepoState::Aborted => return Err(ApiError::WrongRepoState("Build has been aborted".to_string(), "ready".to_string(), "aborted".to_string())),
+    }
+
+    /* The build refs were checked against the tombstones when created,
+     * but not the refs that they are published as */
+    if !branches.is_empty() {
+        let ref_names = schema::build_refs::table
+            .filter(schema::build_refs::build_id.eq(build_id))
+            .select(schema::build_refs::ref_name)
+            .get_results::<String>(conn)?;
+        let renamed: Vec<String> = ref_names.iter()
+            .map(|ref_name| jobs::published_ref_name(ref_name, &branches))
+            .filter(|dst_ref| !ref_names.contains(dst_ref))
+            .collect();
+        let tombstone = schema::ref_tombstones::table
+            .filter(schema::ref_tombstones::repo.eq(&repo))
+            .filter(schema::ref_tombstones::ref_name.eq_any(&renamed))
+            .first::<RefTombstone>(conn)
+            .optional()?;
+        if let Some(tombstone) = tombstone {
+            return Err(ApiError::BadRequest(format!("Ref {} is end-of-life in {}: {}", tombstone.ref_name, repo, tombstone.reason)));
+        }
+    }
+
+    let (val, reason) = PublishedState::to_db(&PublishedState::Publishing);
+    let job =
+        diesel::insert_into(schema::jobs::table)
+        .values(NewJob {
+            kind: JobKind::Publish.to_db(),
+            start_after: None,
+            trace_parent: tracing::current_traceparent(),
+            repo: Some(repo),
+            contents: json!(PublishJob {
+                build: build_id,
+                note: note.clone(),
+                arches,
+                branches,
+                published_by,
+            }).to_string(),
+        })
+        .get_result::<Job>(conn)?;
+    if let Some(commit_job_id) = commit_job_id {
+        diesel::insert_into(schema::job_dependencies::table)
+            .values(JobDependency {
+                job_id: job.id,
+                depends_on: commit_job_id,
+            })
+            .execute(conn)?;
+    }
+    let new_build = diesel::update(schema::builds::table)
+        .filter(schema::builds::id.eq(build_id))
+        .set((schema::builds::publish_job_id.eq(job.id),
+              schema::builds::published_state.eq(val),
+              schema::builds::published_state_reason.eq(reason)))
+        .get_result::<Build>(conn)?;
+    webhooks::queue_build_published_state(conn, config, &new_build, &note)?;
+    Ok(job)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_acl_principal() {
+        assert_eq!(acl_principal("ci-example"), "ci-example");
+        assert_eq!(acl_principal("ci-example/worker"), "ci-example");
+        assert_eq!(acl_principal("ci-example/worker/worker"), "ci-example");
+    }
 }
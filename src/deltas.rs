@@ -19,6 +19,7 @@ use rand::prelude::IteratorRandom;
 use std::sync::mpsc;
 
 use delayed::DelayedResult;
+use cgroups;
 
 const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
 const CLIENT_TIMEOUT: Duration = Duration::from_secs(60);
@@ -311,7 +312,7 @@ impl Handler<DeltaRequest> for LocalWorker {
         let delta = msg.delta;
 
         Box::new(
-            ostree::generate_delta_async(&repo_path, &delta)
+            ostree::generate_delta_async(&self.config.flatpak_path, &repo_path, &delta, cgroups::cgroup_for(&self.config, "delta"))
                 .from_err()
                 .into_actor(self))
     }
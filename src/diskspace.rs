@@ -0,0 +1,48 @@
+use libc;
+use std::ffi::CString;
+use std::io;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+use app::Config;
+use errors::ApiError;
+
+/* Running out of disk space in the middle of writing objects or a commit
+ * can leave a repo broken, so with min-free-space-mb configured we refuse
+ * uploads, and hold back commit and publish jobs, while the filesystem
+ * they write to has less free space than that. */
+
+pub fn free_bytes(path: &Path) -> io::Result<u64> {
+    let c_path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    unsafe {
+        let mut stat: libc::statvfs = mem::zeroed();
+        if libc::statvfs(c_path.as_ptr(), &mut stat) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+    }
+}
+
+pub fn has_free_space(config: &Config, path: &Path) -> bool {
+    match config.min_free_space_mb {
+        None => true,
+        Some(min_mb) => match free_bytes(path) {
+            Ok(free) => free >= min_mb * 1024 * 1024,
+            Err(e) => {
+                /* Don't block everything if we can't tell */
+                warn!("Can't get free space of {:?}: {}", path, e);
+                true
+            },
+        },
+    }
+}
+
+pub fn check_free_space(config: &Config, path: &Path) -> Result<(), ApiError> {
+    if has_free_space(config, path) {
+        Ok(())
+    } else {
+        Err(ApiError::InsufficientStorage("Not enough free disk space on the server, try again later".to_string()))
+    }
+}
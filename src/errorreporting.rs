@@ -0,0 +1,27 @@
+use sentry;
+
+use app::Config;
+
+/* Optional reporting of errors to Sentry. If no sentry-dsn is configured
+ * the sentry client is never initialized and report_error() is a no-op. */
+
+pub fn init(config: &Config) -> Option<sentry::internals::ClientInitGuard> {
+    config.sentry_dsn.as_ref().map(|dsn| {
+        let guard = sentry::init((dsn.as_str(), sentry::ClientOptions {
+            release: Some(env!("CARGO_PKG_VERSION").into()),
+            ..Default::default()
+        }));
+        sentry::integrations::panic::register_panic_handler();
+        guard
+    })
+}
+
+pub fn report_error(message: &str, tags: &[(&str, String)]) {
+    sentry::with_scope(|scope| {
+        for (key, value) in tags {
+            scope.set_tag(key, value);
+        }
+    }, || {
+        sentry::capture_message(message, sentry::Level::Error);
+    });
+}
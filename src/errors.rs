@@ -3,8 +3,10 @@ use actix_web::{error::ResponseError, HttpResponse};
 use diesel::result::{Error as DieselError};
 use std::io;
 use actix_web::http::StatusCode;
+use actix_web::http::header::RETRY_AFTER;
 use ostree::OstreeError;
 use actix_web::error::BlockingError;
+use errorreporting;
 
 #[derive(Fail, Debug, Clone)]
 pub enum DeltaGenerationError {
@@ -136,6 +138,21 @@ pub enum ApiError {
 
     #[fail(display = "NotEnoughPermissions")]
     NotEnoughPermissions(String),
+
+    #[fail(display = "TooManyRequests: {}", _0)]
+    TooManyRequests(String, u64), // The message and seconds to wait before retrying
+
+    #[fail(display = "InsufficientStorage: {}", _0)]
+    InsufficientStorage(String),
+
+    #[fail(display = "QuotaExceeded: {}", _0)]
+    QuotaExceeded(String),
+
+    #[fail(display = "Overloaded: {}", _0)]
+    Overloaded(String, u64), // The message and seconds to wait before retrying
+
+    #[fail(display = "Gone: {}", _0)]
+    Gone(String),
 }
 
 impl From<DieselError> for ApiError {
@@ -211,6 +228,33 @@ impl ApiError {
                 "error-type": "token-insufficient",
                 "message": format!("Not enough permissions: {}", message),
             }),
+            ApiError::TooManyRequests(ref message, retry_after) => json!({
+                "status": 429,
+                "error-type": "too-many-requests",
+                "message": message,
+                "retry-after": retry_after,
+            }),
+            ApiError::InsufficientStorage(ref message) => json!({
+                "status": 507,
+                "error-type": "insufficient-storage",
+                "message": message,
+            }),
+            ApiError::QuotaExceeded(ref message) => json!({
+                "status": 403,
+                "error-type": "quota-exceeded",
+                "message": message,
+            }),
+            ApiError::Overloaded(ref message, retry_after) => json!({
+                "status": 503,
+                "error-type": "overloaded",
+                "message": message,
+                "retry-after": retry_after,
+            }),
+            ApiError::Gone(ref message) => json!({
+                "status": 410,
+                "error-type": "gone",
+                "message": message,
+            }),
         }
     }
 
@@ -223,6 +267,11 @@ impl ApiError {
             ApiError::WrongPublishedState(_,_,_) => StatusCode::BAD_REQUEST,
             ApiError::InvalidToken(_) => StatusCode::UNAUTHORIZED,
             ApiError::NotEnoughPermissions(ref _message) => StatusCode::FORBIDDEN,
+            ApiError::TooManyRequests(_, _) => StatusCode::TOO_MANY_REQUESTS,
+            ApiError::InsufficientStorage(_) => StatusCode::INSUFFICIENT_STORAGE,
+            ApiError::QuotaExceeded(_) => StatusCode::FORBIDDEN,
+            ApiError::Overloaded(_, _) => StatusCode::SERVICE_UNAVAILABLE,
+            ApiError::Gone(_) => StatusCode::GONE,
         }
     }
 }
@@ -231,10 +280,18 @@ impl ResponseError for ApiError {
     fn error_response(&self) -> HttpResponse {
         if let ApiError::InternalServerError(internal_message) = self {
             error!("Responding with internal error: {}", internal_message);
+            errorreporting::report_error(&format!("Responding with internal error: {}", internal_message), &[]);
         }
         if let ApiError::NotEnoughPermissions(internal_message) = self {
             error!("Responding with NotEnoughPermissions error: {}", internal_message);
         }
-        HttpResponse::build(self.status_code()).json(self.to_json())
+        let mut response = HttpResponse::build(self.status_code());
+        match self {
+            ApiError::TooManyRequests(_, retry_after) | ApiError::Overloaded(_, retry_after) => {
+                response.header(RETRY_AFTER, retry_after.to_string());
+            },
+            _ => (),
+        }
+        response.json(self.to_json())
     }
 }
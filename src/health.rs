@@ -0,0 +1,288 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tempfile;
+
+use app::Config;
+use jobs;
+use ostree;
+
+/* A broken signing key (expired, missing from the homedir, needing a
+ * passphrase) otherwise only shows up as a failed commit or publish job.
+ * So we do a real test signature with each configured key, at startup
+ * and again from the health endpoint every now and then. */
+
+const RECHECK_INTERVAL: Duration = Duration::from_secs(5 * 60);
+const GPG_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Debug)]
+pub struct KeyCheck {
+    pub key: String,
+    pub ok: bool,
+    pub error: Option<String>,
+}
+
+#[derive(Clone, Debug)]
+pub struct GpgHealth {
+    pub build_key: Option<KeyCheck>,
+    pub repos: BTreeMap<String, KeyCheck>,
+}
+
+impl GpgHealth {
+    pub fn ok(&self) -> bool {
+        self.build_key.iter().chain(self.repos.values()).all(|check| check.ok)
+    }
+}
+
+fn test_signature(config: &Config, key: &str) -> Result<(), String> {
+    /* The file to sign is passed by name, as the command may be sandboxed */
+    let dir = tempfile::Builder::new()
+        .prefix("gpg-check-")
+        .tempdir_in(&config.build_repo_base)
+        .map_err(|e| format!("Failed to create the test file: {}", e))?;
+    let data_path = dir.path().join("data");
+    fs::write(&data_path, "flat-manager signing key check\n")
+        .map_err(|e| format!("Failed to create the test file: {}", e))?;
+
+    let mut cmd = jobs::new_command(config, "gpg2", &[], &[dir.path()]);
+    if let Some(ref gpg_homedir) = config.gpg_homedir {
+        cmd.arg(format!("--homedir={}", gpg_homedir));
+    }
+    cmd
+        .arg("--batch")
+        .arg("--pinentry-mode=error")
+        .arg(format!("--local-user={}", key))
+        .arg("--output=-")
+        .arg("--detach-sign")
+        .arg(&data_path);
+
+    let output = jobs::command_output(cmd, Some(GPG_TIMEOUT)).map_err(|e| format!("Failed to run gpg2: {}", e))?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(format!("Test signature failed: {}", String::from_utf8_lossy(&output.stderr).trim()))
+    }
+}
+
+fn check_key(config: &Config, key: &str) -> KeyCheck {
+    let res = test_signature(config, key);
+    KeyCheck {
+        key: key.to_string(),
+        ok: res.is_ok(),
+        error: res.err(),
+    }
+}
+
+pub fn check_gpg_keys(config: &Config) -> GpgHealth {
+    let build_key = config.build_gpg_key.as_ref().map(|key| check_key(config, key));
+    let mut repos = BTreeMap::new();
+    for (name, repoconfig) in config.repos.iter() {
+        if let Some(ref key) = repoconfig.gpg_key {
+            repos.insert(name.clone(), check_key(config, key));
+        }
+    }
+    GpgHealth {
+        build_key,
+        repos,
+    }
+}
+
+pub fn log_gpg_health(health: &GpgHealth) {
+    if let Some(ref check) = health.build_key {
+        if let Some(ref error) = check.error {
+            error!("Build gpg key {} is not usable: {}", check.key, error);
+        }
+    }
+    for (name, check) in health.repos.iter() {
+        if let Some(ref error) = check.error {
+            error!("Gpg key {} for repo {} is not usable: {}", check.key, name, error);
+        }
+    }
+}
+
+/* A problem with the tools or the keys otherwise only shows up when the
+ * first build is committed or published. So at startup we also go
+ * through the whole pipeline once in a scratch repo: commit some files
+ * signed with all the configured keys, update the repo (which signs the
+ * summary) and check the commit signatures with gpg. */
+
+const SELF_CHECK_REF: &str = "app/org.flatpak.FlatManagerSelfCheck/x86_64/master";
+const SELF_CHECK_STEP_TIMEOUT: Duration = Duration::from_secs(120);
+
+#[derive(Clone, Debug)]
+pub struct SelfCheck {
+    pub ok: bool,
+}
+
+/* The errors, with the stderr of the failed command, only go to the log */
+fn run_step(step: &str, cmd: Command, timeout: Duration) -> Result<(), String> {
+    let description = format!("{:?}", cmd);
+    let output = jobs::command_output(cmd, Some(timeout))
+        .map_err(|e| format!("{}: Failed to run {}: {}", step, description, e))?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(format!("{}: {}", step, String::from_utf8_lossy(&output.stderr).trim()))
+    }
+}
+
+fn add_gpg_args(cmd: &mut Command, config: &Config, keys: &[String]) {
+    if let Some(ref gpg_homedir) = config.gpg_homedir {
+        cmd.arg(format!("--gpg-homedir={}", gpg_homedir));
+    }
+    for key in keys {
+        cmd.arg(format!("--gpg-sign={}", key));
+    }
+}
+
+fn verify_signature(config: &Config, commit_path: &Path, signature: &[u8], dir: &Path) -> Result<(), String> {
+    let signature_path = dir.join("signature");
+    fs::write(&signature_path, signature).map_err(|e| format!("Verify: {}", e))?;
+    let mut cmd = jobs::new_command(config, "gpg2", &[], &[dir, commit_path]);
+    if let Some(ref gpg_homedir) = config.gpg_homedir {
+        cmd.arg(format!("--homedir={}", gpg_homedir));
+    }
+    cmd
+        .arg("--batch")
+        .arg("--verify")
+        .arg(&signature_path)
+        .arg(commit_path);
+    run_step("Verify", cmd, GPG_TIMEOUT)
+}
+
+fn run_self_check_steps(config: &Config) -> Result<(), String> {
+    let dir = tempfile::Builder::new()
+        .prefix("self-check-")
+        .tempdir_in(&config.build_repo_base)
+        .map_err(|e| format!("Setup: {}", e))?;
+    let repo_path = dir.path().join("repo");
+    let files_path = dir.path().join("files");
+    fs::create_dir_all(files_path.join("files"))
+        .and_then(|_| fs::write(files_path.join("files/self-check"), "flat-manager self-check\n"))
+        .and_then(|_| fs::write(files_path.join("metadata"), "[Application]\nname=org.flatpak.FlatManagerSelfCheck\n"))
+        .map_err(|e| format!("Setup: {}", e))?;
+
+    let mut keys: Vec<String> = config.build_gpg_key.iter()
+        .chain(config.repos.values().filter_map(|repoconfig| repoconfig.gpg_key.as_ref()))
+        .cloned()
+        .collect();
+    keys.sort();
+    keys.dedup();
+
+    fs::create_dir(&repo_path).map_err(|e| format!("Setup: {}", e))?;
+    let mut cmd = jobs::new_command(config, "ostree", &[&repo_path], &[]);
+    cmd
+        .arg(format!("--repo={}", repo_path.display()))
+        .arg("init")
+        .arg("--mode=archive-z2");
+    run_step("Init repo", cmd, SELF_CHECK_STEP_TIMEOUT)?;
+
+    let mut cmd = jobs::new_command(config, "ostree", &[&repo_path], &[&files_path]);
+    cmd
+        .arg(format!("--repo={}", repo_path.display()))
+        .arg("commit")
+        .arg(format!("--branch={}", SELF_CHECK_REF))
+        .arg("--subject=flat-manager self-check");
+    add_gpg_args(&mut cmd, config, &keys);
+    cmd.arg(&files_path);
+    run_step("Commit", cmd, SELF_CHECK_STEP_TIMEOUT)?;
+
+    let mut cmd = jobs::new_command(config, "flatpak", &[&repo_path], &[]);
+    cmd.arg("build-update-repo");
+    add_gpg_args(&mut cmd, config, &keys);
+    cmd.arg(&repo_path);
+    run_step("Update repo", cmd, SELF_CHECK_STEP_TIMEOUT)?;
+
+    let commit = ostree::parse_ref(&repo_path, SELF_CHECK_REF).map_err(|e| format!("Verify: {}", e))?;
+    let signatures = ostree::get_commit_gpg_signatures(&repo_path, &commit).map_err(|e| format!("Verify: {}", e))?;
+    if signatures.len() != keys.len() {
+        return Err(format!("Verify: Expected {} signatures on the commit, found {}", keys.len(), signatures.len()));
+    }
+    let commit_path = ostree::get_object_path(&repo_path, &commit, "commit").map_err(|e| format!("Verify: {}", e))?;
+    for signature in signatures {
+        verify_signature(config, &commit_path, &signature, dir.path())?;
+    }
+    if !keys.is_empty() && !repo_path.join("summary.sig").exists() {
+        return Err("Verify: The summary was not signed".to_string());
+    }
+    Ok(())
+}
+
+pub fn run_self_check(config: &Config) -> SelfCheck {
+    let res = run_self_check_steps(config);
+    match res {
+        Ok(()) => info!("Startup self-check passed"),
+        Err(ref error) => error!("Startup self-check failed: {}", error),
+    }
+    SelfCheck {
+        ok: res.is_ok(),
+    }
+}
+
+struct State {
+    checked: Instant,
+    gpg: GpgHealth,
+    self_check: Option<SelfCheck>,
+}
+
+/* This is shared between all the http workers, so clone it into each */
+#[derive(Clone)]
+pub struct Health(Arc<Mutex<State>>);
+
+impl Health {
+    pub fn new(gpg: GpgHealth, self_check: Option<SelfCheck>) -> Health {
+        Health(Arc::new(Mutex::new(State {
+            checked: Instant::now(),
+            gpg,
+            self_check,
+        })))
+    }
+
+    /* This only runs once, at startup */
+    pub fn self_check(&self) -> Option<SelfCheck> {
+        self.0.lock().unwrap().self_check.clone()
+    }
+
+    /* Returns the last result, unless it is too old. This blocks while
+     * rechecking, so call it from a web::block. The lock is not held
+     * while gpg runs, the other requests get the last result meanwhile. */
+    pub fn gpg_health(&self, config: &Config) -> GpgHealth {
+        {
+            let mut state = self.0.lock().unwrap();
+            if state.checked.elapsed() <= RECHECK_INTERVAL {
+                return state.gpg.clone();
+            }
+            state.checked = Instant::now();
+        }
+
+        let gpg = check_gpg_keys(config);
+        log_gpg_health(&gpg);
+        self.0.lock().unwrap().gpg = gpg.clone();
+        gpg
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_run_step() {
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg("echo committed");
+        assert_eq!(run_step("Commit", cmd, SELF_CHECK_STEP_TIMEOUT), Ok(()));
+
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg("echo ignored; echo 'Signing failed' >&2; exit 1");
+        assert_eq!(run_step("Commit", cmd, SELF_CHECK_STEP_TIMEOUT), Err("Commit: Signing failed".to_string()));
+
+        let mut cmd = Command::new("sleep");
+        cmd.arg("10");
+        let err = run_step("Update repo", cmd, Duration::from_millis(100)).unwrap_err();
+        assert!(err.starts_with("Update repo: Failed to run"), "{}", err);
+        assert!(err.contains("Timed out"), "{}", err);
+    }
+}
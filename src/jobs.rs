@@ -1,37 +1,67 @@
 use actix::prelude::*;
 use actix::{Actor, SyncContext};
+use actix_web::http::header;
+use actix_web::web;
+use awc;
 use diesel::pg::PgConnection;
 use diesel::prelude::*;
 use diesel::result::{Error as DieselError};
 use diesel::result::DatabaseErrorKind::SerializationFailure;
+use diesel::sql_types::{BigInt, Bool};
 use diesel;
+use chrono;
+use chrono::Datelike;
 use filetime;
 use serde_json;
 use std::cell::RefCell;
 use std::str;
 use std::ffi::OsString;
 use std::fs::{self, File};
-use std::io::Write;
-use std::process::{Command, Stdio};
-use std::sync::{Arc};
-use std::path::PathBuf;
+use std::io::{self, BufReader, Read, Seek, Write};
+use std::process::{Command, Output, Stdio};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::env;
+use std::path::{Path, PathBuf};
+use std::thread;
 use std::time;
 use std::os::unix::process::CommandExt;
 use libc;
-use std::collections::{HashMap,HashSet};
+use std::collections::{HashMap,HashSet,VecDeque};
 use std::iter::FromIterator;
 use walkdir::WalkDir;
+use flate2::Compression;
+use flate2::write::GzEncoder;
 use std::sync::mpsc;
+use futures::{future, stream, Future, Stream};
+use tokio;
+use tokio::runtime::current_thread;
+use tokio::timer::Timeout;
+use tokio_process::CommandExt as TokioCommandExt;
+use tempfile;
+use openssl::sha::sha256;
 
 use ostree;
-use app::{RepoConfig, Config};
+use app::{RepoConfig, Config, CommitTimestamp, default_gc_grace, SmtpConfig, OciRegistryConfig, ScreenshotsConfig, AppstreamValidation};
 use Pool;
 use errors::{JobError, JobResult};
-use models::{NewJob, Job, JobDependency, JobKind, CommitJob, PublishJob, UpdateRepoJob, JobStatus, job_dependencies_with_status, RepoState, PublishedState };
+use models::{NewJob, Job, JobDependency, JobKind, CommitJob, PublishJob, UpdateRepoJob, OciExportJob, BundleJob, PruneJob, GcJob, FsckJob, ResignJob, SignType, PromoteJob, RollbackJob, DeprecateJob, JobStatus, job_dependencies_with_status, RepoState, PublishedState, NewPublishedRef, NewRefTombstone };
+use models::{JobResults, AppstreamValidationResult, CommitJobResult, PublishJobResult, UpdateRepoJobResult, OciExportJobResult, BundleJobResult, PruneJobResult, GcJobResult, FsckJobResult, ResignJobResult, PromoteJobResult, RollbackJobResult, DeprecateJobResult, FailedJobResult, CommitCheckpoint};
 use deltas::{DeltaGenerator, DeltaRequest, DeltaRequestSync};
+use metadata;
+use screenshots;
 use models;
+use diskspace;
+use objectpool;
 use schema::*;
 use schema;
+use webhooks;
+use tracing::{self, TraceContext};
+use mail;
+use errorreporting;
+use logging;
+use cgroups;
+use metrics;
 
 /**************************************************************************
  * Job handling - theory of operations.
@@ -133,6 +163,203 @@ Url={}
     (filename, contents)
 }
 
+/* Creates a command for running program. With sandbox-commands enabled
+ * it is run in a bubblewrap sandbox without network access (unless the
+ * program needs it), as the tools work on repository data uploaded by
+ * the builders. The sandbox only has the system directories, the gpg
+ * homedir and the given paths. */
+fn new_sandboxed_command(config: &Config, program: &Path, writable_paths: &[&Path], readonly_paths: &[&Path], network: bool) -> Command {
+    if !config.sandbox_commands {
+        return Command::new(program);
+    }
+
+    let cwd = env::current_dir().unwrap_or_else(|_e| PathBuf::from("/"));
+    let mut cmd = Command::new("bwrap");
+    cmd
+        .arg("--unshare-all");
+    if network {
+        cmd.arg("--share-net");
+    }
+    cmd
+        .arg("--die-with-parent")
+        .args(["--ro-bind", "/usr", "/usr"])
+        .args(["--ro-bind", "/etc", "/etc"])
+        .args(["--ro-bind-try", "/lib", "/lib"])
+        .args(["--ro-bind-try", "/lib64", "/lib64"])
+        .args(["--ro-bind-try", "/bin", "/bin"])
+        .args(["--ro-bind-try", "/sbin", "/sbin"])
+        .args(["--proc", "/proc"])
+        .args(["--dev", "/dev"])
+        .args(["--tmpfs", "/tmp"]);
+
+    /* Configured absolute paths of the tools may be outside /usr */
+    if program.is_absolute() {
+        cmd.arg("--ro-bind").arg(program).arg(program);
+    }
+
+    /* The paths are often relative to the working directory, so we keep that */
+    for path in readonly_paths {
+        let path = cwd.join(path);
+        cmd.arg("--ro-bind").arg(&path).arg(&path);
+    }
+    /* bwrap fails if a bind source is missing, while the commands often
+     * create the directories they write to (e.g. ostree init), so the
+     * writable ones are created beforehand */
+    for path in writable_paths {
+        let path = cwd.join(path);
+        if let Err(e) = fs::create_dir_all(&path) {
+            warn!("Failed to create {:?} for the sandbox: {}", path, e);
+        }
+        cmd.arg("--bind").arg(&path).arg(&path);
+    }
+    if let Some(ref gpg_homedir) = config.gpg_homedir {
+        let path = cwd.join(gpg_homedir);
+        if let Err(e) = fs::create_dir_all(&path) {
+            warn!("Failed to create {:?} for the sandbox: {}", path, e);
+        }
+        cmd.arg("--bind").arg(&path).arg(&path);
+    } else if let Some(home) = env::var_os("HOME") {
+        let path = PathBuf::from(home).join(".gnupg");
+        cmd.arg("--bind-try").arg(&path).arg(&path);
+    }
+
+    cmd
+        .arg("--chdir").arg(&cwd)
+        .arg(program);
+    cmd
+}
+
+/**************************************************************************
+ * The jobs run all their commands (flatpak, ostree, ...) through the
+ * CommandBackend of their JobExecutor, so that these can be run some
+ * other way, like through the libflatpak bindings, in containers, or
+ * by a test double recording them, without changing the job handlers.
+ * The job handlers use the new_command(), command_future() and
+ * command_output() helpers, which use the backend of the executor that
+ * runs on the current thread.
+ ***************************************************************************/
+pub trait CommandBackend: Send + Sync {
+    /* Creates a command for running program, which may write to writable_paths and read readonly_paths */
+    fn new_command(&self, config: &Config, program: &str, writable_paths: &[&Path], readonly_paths: &[&Path]) -> Command;
+
+    /* Like new_command, for the programs that need network access */
+    fn new_network_command(&self, config: &Config, program: &str, writable_paths: &[&Path], readonly_paths: &[&Path]) -> Command;
+
+    /* Runs cmd, appending its output to the job log, failing if it fails or takes longer than timeout.
+     * Resolves to the last lines of its stdout. */
+    fn command_future<'a>(&self,
+                          cmd: Command,
+                          job_id: i32,
+                          conn: &'a PgConnection,
+                          timeout: Option<time::Duration>) -> Box<dyn Future<Item = Vec<String>, Error = JobError> + 'a>;
+
+    /* Runs cmd and returns its output, for the commands whose output is parsed,
+     * failing with TimedOut if it takes longer than timeout */
+    fn output(&self, cmd: Command, timeout: Option<time::Duration>) -> io::Result<Output>;
+}
+
+/* Runs the commands as subprocesses, sandboxed if configured */
+pub struct SubprocessBackend;
+
+/* Commands can continue the trace of the job too, via the environment */
+fn new_subprocess_command(config: &Config, program: &str, writable_paths: &[&Path], readonly_paths: &[&Path], network: bool) -> Command {
+    let program = match program {
+        "flatpak" => config.flatpak_path.as_path(),
+        "ostree" => config.ostree_path.as_path(),
+        _ => Path::new(program),
+    };
+    let mut cmd = new_sandboxed_command(config, program, writable_paths, readonly_paths, network);
+    if let Some(traceparent) = tracing::current_traceparent() {
+        cmd.env("TRACEPARENT", traceparent);
+    }
+    cmd
+}
+
+impl CommandBackend for SubprocessBackend {
+    fn new_command(&self, config: &Config, program: &str, writable_paths: &[&Path], readonly_paths: &[&Path]) -> Command {
+        new_subprocess_command(config, program, writable_paths, readonly_paths, false)
+    }
+
+    fn new_network_command(&self, config: &Config, program: &str, writable_paths: &[&Path], readonly_paths: &[&Path]) -> Command {
+        new_subprocess_command(config, program, writable_paths, readonly_paths, true)
+    }
+
+    fn command_future<'a>(&self,
+                          cmd: Command,
+                          job_id: i32,
+                          conn: &'a PgConnection,
+                          timeout: Option<time::Duration>) -> Box<dyn Future<Item = Vec<String>, Error = JobError> + 'a> {
+        subprocess_future(cmd, job_id, conn, timeout)
+    }
+
+    fn output(&self, cmd: Command, timeout: Option<time::Duration>) -> io::Result<Output> {
+        subprocess_output(cmd, timeout)
+    }
+}
+
+fn subprocess_output(mut cmd: Command, timeout: Option<time::Duration>) -> io::Result<Output> {
+    let timeout = match timeout {
+        Some(timeout) => timeout,
+        None => return cmd.output(),
+    };
+
+    /* The output goes to files rather than pipes, so the child never blocks
+     * on a full pipe while we wait for it */
+    let mut stdout = tempfile::tempfile()?;
+    let mut stderr = tempfile::tempfile()?;
+    let mut child = cmd
+        .stdin(Stdio::null())
+        .stdout(stdout.try_clone()?)
+        .stderr(stderr.try_clone()?)
+        .spawn()?;
+
+    let deadline = time::Instant::now() + timeout;
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if time::Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(io::Error::new(io::ErrorKind::TimedOut,
+                                      format!("Timed out after {} seconds", timeout.as_secs())));
+        }
+        thread::sleep(time::Duration::from_millis(50));
+    };
+
+    let mut output = Output { status, stdout: vec![], stderr: vec![] };
+    stdout.seek(io::SeekFrom::Start(0))?;
+    stdout.read_to_end(&mut output.stdout)?;
+    stderr.seek(io::SeekFrom::Start(0))?;
+    stderr.read_to_end(&mut output.stderr)?;
+    Ok(output)
+}
+
+fn command_backend() -> Arc<dyn CommandBackend> {
+    COMMAND_BACKEND.with(|backend| backend.borrow().clone())
+        .unwrap_or_else(|| Arc::new(SubprocessBackend))
+}
+
+pub fn new_command(config: &Config, program: &str, writable_paths: &[&Path], readonly_paths: &[&Path]) -> Command {
+    command_backend().new_command(config, program, writable_paths, readonly_paths)
+}
+
+pub fn new_network_command(config: &Config, program: &str, writable_paths: &[&Path], readonly_paths: &[&Path]) -> Command {
+    command_backend().new_network_command(config, program, writable_paths, readonly_paths)
+}
+
+fn command_future<'a>(cmd: Command,
+                      job_id: i32,
+                      conn: &'a PgConnection,
+                      timeout: Option<time::Duration>) -> Box<dyn Future<Item = Vec<String>, Error = JobError> + 'a>
+{
+    command_backend().command_future(cmd, job_id, conn, timeout)
+}
+
+pub fn command_output(cmd: Command, timeout: Option<time::Duration>) -> io::Result<Output> {
+    command_backend().output(cmd, timeout)
+}
+
 fn add_gpg_args(cmd: &mut Command, maybe_gpg_key: &Option<String>, maybe_gpg_homedir: &Option<String>) {
     if let Some(gpg_homedir) = maybe_gpg_homedir {
         cmd
@@ -145,10 +372,10 @@ fn add_gpg_args(cmd: &mut Command, maybe_gpg_key: &Option<String>, maybe_gpg_hom
     };
 }
 
-fn queue_update_job (delay_secs: u64,
-                     conn: &PgConnection,
-                     repo: &str,
-                     starting_job_id: Option<i32>) -> Result<(bool,Job), DieselError>
+pub fn queue_update_job (delay_secs: u64,
+                         conn: &PgConnection,
+                         repo: &str,
+                         starting_job_id: Option<i32>) -> Result<(bool,Job), DieselError>
 {
     /* We wrap everything in a serializable transaction, because if something else
      * starts the job while we're adding dependencies to it the dependencies will be
@@ -200,6 +427,7 @@ fn queue_update_job (delay_secs: u64,
                             kind: JobKind::UpdateRepo.to_db(),
                             repo: Some(repo.to_string()),
                             start_after: Some(time::SystemTime::now() + time::Duration::new(delay_secs, 0)),
+                            trace_parent: tracing::current_traceparent(),
                             contents: json!(UpdateRepoJob {
                                 repo: repo.to_string()
                             }).to_string(),
@@ -243,6 +471,11 @@ pub struct JobExecutor {
     pub config: Arc<Config>,
     pub delta_generator: Addr<DeltaGenerator>,
     pub pool: Pool,
+    pub running_commands: RunningCommands,
+    pub commands: Arc<dyn CommandBackend>,
+    /* For round-robin between builds, see pick_next_job() */
+    pub picked_count: u64,
+    pub last_picked: HashMap<String, u64>,
 }
 
 impl Actor for JobExecutor {
@@ -250,10 +483,10 @@ impl Actor for JobExecutor {
 }
 
 fn job_log(job_id: i32, conn: &PgConnection, output: &str) {
-    if let Err(e) = diesel::update(jobs::table)
+    if let Err(e) = metrics::time_query("job_log", || diesel::update(jobs::table)
         .filter(jobs::id.eq(job_id))
         .set((jobs::log.eq(jobs::log.concat(&output)),))
-        .execute(conn) {
+        .execute(conn)) {
             error!("Error appending to job {} log: {}", job_id, e.to_string());
         }
 }
@@ -268,41 +501,495 @@ fn job_log_and_error(job_id: i32, conn: &PgConnection, output: &str) {
     job_log(job_id, conn, &format!("{}\n", output));
 }
 
-fn do_command(mut cmd: Command) -> JobResult<()>
+/**************************************************************************
+ * The jobs that use a repo (publish, update-repo, prune, resign, oci-export
+ * and bundle) take a lock on it, so that they never run build-commit-from/
+ * build-update-repo on the same repo at the same time, or read it while it
+ * is pruned, even when they run on different nodes. Similarly the jobs that
+ * use a build repo (commit, publish and bundle) take a lock on the build.
+ * This is a postgres session level advisory lock on the executor connection,
+ * so it also goes away if the node dies. They are reentrant, so a job can
+ * take the lock again while holding it. Jobs that take both take the repo
+ * lock first.
+ ***************************************************************************/
+
+sql_function!(fn pg_try_advisory_lock(key: BigInt) -> Bool);
+sql_function!(fn pg_advisory_unlock(key: BigInt) -> Bool);
+
+/* Not a rust Hasher, as all nodes must agree on the key */
+fn lock_key(name: &str) -> i64 {
+    let hash = sha256(format!("flat-manager-{}", name).as_bytes());
+    let mut key = 0i64;
+    for b in hash.iter().take(8) {
+        key = (key << 8) | *b as i64;
+    }
+    key
+}
+
+struct RepoLock<'a> {
+    conn: &'a PgConnection,
+    description: String,
+    key: i64,
+}
+
+impl<'a> Drop for RepoLock<'a> {
+    fn drop(&mut self) {
+        if let Err(e) = diesel::select(pg_advisory_unlock(self.key)).get_result::<bool>(self.conn) {
+            error!("Failed to unlock {}: {}", self.description, e);
+        }
+    }
+}
+
+fn take_lock<'a>(job_id: i32, conn: &'a PgConnection, key: i64, description: String) -> JobResult<RepoLock<'a>> {
+    if !diesel::select(pg_try_advisory_lock(key)).get_result::<bool>(conn)? {
+        job_log_and_info(job_id, conn, &format!("Waiting for another job to finish with {}", description));
+        diesel::sql_query("SELECT pg_advisory_lock($1)")
+            .bind::<BigInt, _>(key)
+            .execute(conn)?;
+    }
+    Ok(RepoLock {
+        conn,
+        description,
+        key,
+    })
+}
+
+fn lock_repo<'a>(job_id: i32, conn: &'a PgConnection, repo: &str) -> JobResult<RepoLock<'a>> {
+    take_lock(job_id, conn, lock_key(&format!("repo:{}", repo)), format!("repo {}", repo))
+}
+
+/* Jobs using several repos lock them in sorted order, so that two of
+ * them can't end up waiting for each other */
+fn repo_lock_order<'a>(repos: &[&'a str]) -> Vec<&'a str> {
+    let mut sorted = repos.to_vec();
+    sorted.sort();
+    sorted.dedup();
+    sorted
+}
+
+fn lock_repos<'a>(job_id: i32, conn: &'a PgConnection, repos: &[&str]) -> JobResult<Vec<RepoLock<'a>>> {
+    repo_lock_order(repos).into_iter().map(|repo| lock_repo(job_id, conn, repo)).collect()
+}
+
+fn lock_build<'a>(job_id: i32, conn: &'a PgConnection, build_id: i32) -> JobResult<RepoLock<'a>> {
+    take_lock(job_id, conn, lock_key(&format!("build:{}", build_id)), format!("build {}", build_id))
+}
+
+/* At most this many lines of output per command go into the job log in
+ * the database, the rest is written to a per-job log file in job-log-dir */
+const MAX_LOGGED_LINES: usize = 1000;
+/* The number of lines of stderr included in the error for failed commands,
+ * and of stdout returned to the job handlers that parse it */
+const ERROR_TAIL_LINES: usize = 50;
+/* The timeout for reporting a commit status to GitHub */
+const GITHUB_STATUS_TIMEOUT: time::Duration = time::Duration::from_secs(30);
+
+thread_local! {
+    /* Each job executor thread drives the commands it runs on its own
+     * single-threaded runtime, created on first use. */
+    static COMMAND_RUNTIME: RefCell<Option<current_thread::Runtime>> = const { RefCell::new(None) };
+    static COMMAND_LOG_DIR: RefCell<Option<PathBuf>> = const { RefCell::new(None) };
+    static RUNNING_COMMANDS: RefCell<Option<RunningCommands>> = const { RefCell::new(None) };
+    static COMMAND_CGROUP: RefCell<Option<PathBuf>> = const { RefCell::new(None) };
+    static COMMAND_BACKEND: RefCell<Option<Arc<dyn CommandBackend>>> = RefCell::new(None);
+}
+
+/* How long to wait after SIGTERM before using SIGKILL on shutdown */
+const SHUTDOWN_KILL_DELAY: time::Duration = time::Duration::from_secs(10);
+
+/* The process groups of the commands run by the job executors, with
+ * the job they run for. This is shared with the job queue, so that on
+ * shutdown it can terminate the commands that are still running after
+ * the shutdown deadline, and remembers which jobs it interrupted. */
+#[derive(Clone, Default)]
+pub struct RunningCommands {
+    pids: Arc<Mutex<HashMap<u32, i32>>>,
+    interrupted_jobs: Arc<Mutex<HashSet<i32>>>,
+    shutting_down: Arc<AtomicBool>,
+}
+
+impl RunningCommands {
+    fn register(&self, pid: u32, job_id: i32) -> RegisteredCommand {
+        self.pids.lock().unwrap().insert(pid, job_id);
+        RegisteredCommand {
+            running: self.clone(),
+            pid,
+        }
+    }
+
+    fn start_shutdown(&self) {
+        self.shutting_down.store(true, Ordering::SeqCst);
+    }
+
+    fn is_shutting_down(&self) -> bool {
+        self.shutting_down.load(Ordering::SeqCst)
+    }
+
+    fn signal_all(&self, signal: libc::c_int) {
+        let mut interrupted_jobs = self.interrupted_jobs.lock().unwrap();
+        for (pid, job_id) in self.pids.lock().unwrap().iter() {
+            /* The commands are started with setsid, so signal the whole process group */
+            unsafe {
+                libc::kill(-(*pid as libc::pid_t), signal);
+            }
+            interrupted_jobs.insert(*job_id);
+        }
+    }
+
+    /* Whether a command of the job was killed by the shutdown */
+    fn take_interrupted(&self, job_id: i32) -> bool {
+        self.interrupted_jobs.lock().unwrap().remove(&job_id)
+    }
+}
+
+/* Unregisters the command when dropped */
+struct RegisteredCommand {
+    running: RunningCommands,
+    pid: u32,
+}
+
+impl Drop for RegisteredCommand {
+    fn drop(&mut self) {
+        self.running.pids.lock().unwrap().remove(&self.pid);
+    }
+}
+
+fn run_on_command_runtime<F>(f: F) -> JobResult<F::Item>
+    where F: Future<Error = JobError>
 {
-    let output =
-        unsafe {
-            cmd
-                .stdin(Stdio::null())
-                .stdout(Stdio::piped())
-                .stderr(Stdio::piped())
-                .pre_exec (|| {
-                    // Setsid in the child to avoid SIGINT on server killing
-                    // child and breaking the graceful shutdown
-                    libc::setsid();
-                    Ok(())
-                })
-                .output()
-                .map_err(|e| JobError::new(&format!("Failed to run {:?}: {}", &cmd, e)))?
-        };
+    COMMAND_RUNTIME.with(|runtime| {
+        let mut runtime = runtime.borrow_mut();
+        if runtime.is_none() {
+            *runtime = Some(current_thread::Runtime::new()?);
+        }
+        runtime.as_mut().unwrap().block_on(f)
+    })
+}
+
+fn job_log_file(job_id: i32) -> Option<PathBuf> {
+    COMMAND_LOG_DIR.with(|dir| dir.borrow().as_ref().map(|dir| dir.join(format!("{}.log", job_id))))
+}
+
+/* The command output is appended to the job log in batches rather than
+ * with one update per line, at the latest when LOG_BATCH_LINES lines
+ * or LOG_BATCH_INTERVAL have accumulated, and when the command ends. */
+const LOG_BATCH_LINES: usize = 100;
+const LOG_BATCH_INTERVAL: time::Duration = time::Duration::from_secs(1);
 
-    if !output.status.success() {
-        return Err(JobError::new(&format!("Command {:?} exited unsuccesfully: {}", &cmd, String::from_utf8_lossy(&output.stderr))))
+struct LogBatch {
+    pending: String,
+    lines: usize,
+    started: time::Instant,
+}
+
+impl LogBatch {
+    fn new() -> Self {
+        LogBatch {
+            pending: String::new(),
+            lines: 0,
+            started: time::Instant::now(),
+        }
+    }
+
+    /* Adds a line, returning the batch if it is due to be written */
+    fn push(&mut self, line: &str, now: time::Instant) -> Option<String> {
+        if self.lines == 0 {
+            self.started = now;
+        }
+        self.pending.push_str(line);
+        self.pending.push('\n');
+        self.lines += 1;
+        if self.lines >= LOG_BATCH_LINES || now.duration_since(self.started) >= LOG_BATCH_INTERVAL {
+            self.take()
+        } else {
+            None
+        }
+    }
+
+    fn take(&mut self) -> Option<String> {
+        if self.lines == 0 {
+            return None;
+        }
+        self.lines = 0;
+        Some(std::mem::take(&mut self.pending))
+    }
+}
+
+struct CommandOutput<'a> {
+    job_id: i32,
+    conn: &'a PgConnection,
+    log_batch: LogBatch,
+    logged_lines: usize,
+    spill_file: Option<File>,
+    stdout_tail: VecDeque<String>,
+    stderr_tail: VecDeque<String>,
+}
+
+impl<'a> CommandOutput<'a> {
+    fn new(job_id: i32, conn: &'a PgConnection) -> Self {
+        CommandOutput {
+            job_id,
+            conn,
+            log_batch: LogBatch::new(),
+            logged_lines: 0,
+            spill_file: None,
+            stdout_tail: VecDeque::new(),
+            stderr_tail: VecDeque::new(),
+        }
+    }
+
+    fn flush_log(&mut self) {
+        if let Some(batch) = self.log_batch.take() {
+            job_log(self.job_id, self.conn, &batch);
+        }
+    }
+
+    fn add_line(&mut self, is_stderr: bool, line: String) -> io::Result<()> {
+        /* Enable with RUST_LOG=command-output=debug to get this in the server log too */
+        debug!(target: "command-output", "{}", line);
+
+        if self.logged_lines < MAX_LOGGED_LINES {
+            if let Some(batch) = self.log_batch.push(&line, time::Instant::now()) {
+                job_log(self.job_id, self.conn, &batch);
+            }
+            self.logged_lines += 1;
+        } else {
+            if self.spill_file.is_none() {
+                let path = job_log_file(self.job_id)
+                    .ok_or_else(|| io::Error::other("No job log directory"))?;
+                if let Some(parent) = path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                self.flush_log();
+                job_log(self.job_id, self.conn, &format!("Output truncated, the rest is in {}\n", path.display()));
+                self.spill_file = Some(fs::OpenOptions::new().create(true).append(true).open(path)?);
+            }
+            if let Some(ref mut file) = self.spill_file {
+                writeln!(file, "{}", line)?;
+            }
+        }
+
+        let tail = if is_stderr { &mut self.stderr_tail } else { &mut self.stdout_tail };
+        if tail.len() == ERROR_TAIL_LINES {
+            tail.pop_front();
+        }
+        tail.push_back(line);
+        Ok(())
+    }
+}
+
+/* Whatever way the command ends, including timeouts, the rest of its
+ * output goes into the log */
+impl<'a> Drop for CommandOutput<'a> {
+    fn drop(&mut self) {
+        self.flush_log();
+    }
+}
+
+/* Runs cmd, appending its output to the job log in batches as it
+ * arrives. If the timeout expires the future fails, and as the child
+ * is killed when dropped, so does the command. */
+fn subprocess_future<'a>(mut cmd: Command,
+                      job_id: i32,
+                      conn: &'a PgConnection,
+                      timeout: Option<time::Duration>) -> Box<dyn Future<Item = Vec<String>, Error = JobError> + 'a>
+{
+    unsafe {
+        cmd
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .pre_exec (|| {
+                // Setsid in the child to avoid SIGINT on server killing
+                // child and breaking the graceful shutdown
+                libc::setsid();
+                Ok(())
+            });
+    }
+
+    if let Some(cgroup) = COMMAND_CGROUP.with(|cgroup| cgroup.borrow().clone()) {
+        if let Err(e) = cgroups::run_in_cgroup(&mut cmd, &cgroup) {
+            return Box::new(future::err(JobError::new(&format!("Failed to use cgroup {:?}: {}", cgroup, e))));
+        }
+    }
+
+    let description = format!("{:?}", cmd);
+    let mut child = match cmd.spawn_async() {
+        Ok(child) => child,
+        Err(e) => return Box::new(future::err(JobError::new(&format!("Failed to run {}: {}", description, e)))),
+    };
+    let registered = RUNNING_COMMANDS.with(|running| running.borrow().as_ref().map(|running| running.register(child.id(), job_id)));
+    let stdout = tokio::io::lines(BufReader::new(child.stdout().take().unwrap()));
+    let stderr = tokio::io::lines(BufReader::new(child.stderr().take().unwrap()));
+
+    let output = stdout.map(|line| (false, line))
+        .select(stderr.map(|line| (true, line)))
+        .fold(CommandOutput::new(job_id, conn), move |mut output, (is_stderr, line)| {
+            output.add_line(is_stderr, line)?;
+            Ok::<_, io::Error>(output)
+        });
+
+    let description2 = description.clone();
+    let finished = output
+        .join(child)
+        .map_err(move |e| JobError::new(&format!("Failed to run {}: {}", description2, e)))
+        .and_then(move |(mut output, status)| {
+            drop(registered);
+            if !status.success() {
+                let errors: Vec<String> = output.stderr_tail.drain(..).collect();
+                return Err(JobError::new(&format!("Command {} exited unsuccesfully: {}", description, errors.join("\n"))))
+            }
+            Ok(output.stdout_tail.drain(..).collect())
+        });
+
+    match timeout {
+        None => Box::new(finished),
+        Some(timeout) => Box::new(
+            Timeout::new(finished, timeout)
+                .map_err(move |e| e.into_inner().unwrap_or_else(|| JobError::new(&format!("Command timed out after {} seconds", timeout.as_secs()))))),
+    }
+}
+
+fn run_command(cmd: Command, job_id: i32, conn: &PgConnection, timeout: Option<time::Duration>) -> JobResult<Vec<String>>
+{
+    run_on_command_runtime(command_future(cmd, job_id, conn, timeout))
+}
+
+/* The progress is stored as json like {"stage": "refs", "done": 1, "total": 4} */
+fn job_progress(job_id: i32, conn: &PgConnection, stage: &str, done: usize, total: usize) {
+    if let Err(e) = metrics::time_query("job_progress", || diesel::update(jobs::table)
+        .filter(jobs::id.eq(job_id))
+        .set(jobs::progress.eq(json!({ "stage": stage, "done": done, "total": total }).to_string()))
+        .execute(conn)) {
+            error!("Error updating job {} progress: {}", job_id, e);
+        }
+}
+
+/* A commit job stores the refs it has committed into the build repo in
+ * its results as a CommitCheckpoint while it runs. This is kept in the
+ * results of the job if it fails, and by retry_job() */
+fn load_commit_checkpoint(job_id: i32, conn: &PgConnection) -> HashMap<String, String> {
+    let results = metrics::time_query("load_commit_checkpoint", || jobs::table
+        .filter(jobs::id.eq(job_id))
+        .select(jobs::results)
+        .get_result::<Option<String>>(conn));
+    match results {
+        Ok(Some(results)) => CommitCheckpoint::from_results(&results)
+            .map(|checkpoint| checkpoint.committed_refs)
+            .unwrap_or_default(),
+        Ok(None) => HashMap::new(),
+        Err(e) => {
+            error!("Error loading job {} checkpoint: {}", job_id, e);
+            HashMap::new()
+        },
+    }
+}
+
+fn save_commit_checkpoint(job_id: i32, conn: &PgConnection, committed_refs: &HashMap<String, String>) {
+    let checkpoint = CommitCheckpoint { committed_refs: committed_refs.clone() };
+    if let Err(e) = metrics::time_query("save_commit_checkpoint", || diesel::update(jobs::table)
+        .filter(jobs::id.eq(job_id))
+        .set(jobs::results.eq(checkpoint.to_results()))
+        .execute(conn)) {
+            error!("Error updating job {} checkpoint: {}", job_id, e);
+        }
+}
+
+/* The checkpointed refs that are still in the build repo as they were
+ * committed, given the current commits of the refs there */
+fn resumable_refs<F>(committed_refs: HashMap<String, String>,
+                     build_refs: &[models::BuildRef],
+                     current_commit: F) -> HashMap<String, String>
+    where F: Fn(&str) -> Option<String> {
+    committed_refs.into_iter()
+        .filter(|(ref_name, commit)| {
+            build_refs.iter().any(|build_ref| &build_ref.ref_name == ref_name) &&
+                current_commit(ref_name).as_ref() == Some(commit)
+        })
+        .collect()
+}
+
+fn do_command(cmd: Command, job_id: i32, conn: &PgConnection) -> JobResult<()>
+{
+    run_command(cmd, job_id, conn, None).map(|_| ())
+}
+
+fn post_github_status(url: &str, token: &str, body: &serde_json::Value) -> JobResult<()> {
+    let url = url.to_string();
+    let authorization = format!("token {}", token);
+    let body = body.clone();
+    /* The client spawns its connection pool on the current runtime, so it is created inside it */
+    run_on_command_runtime(future::lazy(move || {
+        awc::Client::new()
+            .post(url)
+            .header(header::AUTHORIZATION, authorization)
+            .header(header::USER_AGENT, "flat-manager")
+            .timeout(GITHUB_STATUS_TIMEOUT)
+            .send_json(&body)
+            .then(|r| match r {
+                Ok(ref response) if response.status().is_success() => Ok(()),
+                Ok(response) => Err(JobError::new(&format!("Unexpected status {}", response.status()))),
+                Err(e) => Err(JobError::new(&e.to_string())),
+            })
+    }))
+}
+
+/* Reports the state of a job to the GitHub Statuses API for builds that
+ * were created with a github repository and sha. This is best effort, a
+ * failure to report is logged but doesn't affect the job. The request is
+ * driven on the executor thread's command runtime, like the commands. */
+fn report_github_status(job_id: i32,
+                        build: &models::Build,
+                        config: &Config,
+                        context: &str,
+                        state: &str,
+                        description: &str,
+                        conn: &PgConnection) {
+    let (repository, sha, token) = match (&build.github_repository, &build.github_sha, &config.github_token) {
+        (Some(repository), Some(sha), Some(token)) => (repository, sha, token),
+        _ => return,
+    };
+
+    let body = json!({
+        "state": state,
+        "target_url": format!("{}/status/build/{}", config.base_url, build.id),
+        "description": description,
+        "context": context,
+    });
+
+    let url = format!("{}/repos/{}/statuses/{}", config.github_api_url, repository, sha);
+    if let Err(e) = post_github_status(&url, token, &body) {
+        job_log_and_error(job_id, conn, &format!("Failed to report {} status to GitHub: {}", state, e));
     }
-    Ok(())
 }
 
 fn new_job_instance(executor: &JobExecutor, job: Job) -> Box<dyn JobInstance> {
     match JobKind::from_db(job.kind) {
         Some(JobKind::Commit) => CommitJobInstance::new(job),
         Some(JobKind::Publish) => PublishJobInstance::new(job),
-        Some(JobKind::UpdateRepo) => UpdateRepoJobInstance::new(job, executor.delta_generator.clone()),
+        Some(JobKind::UpdateRepo) => UpdateRepoJobInstance::new(job, executor.delta_generator.clone(), false),
+        Some(JobKind::RegenerateDeltas) => UpdateRepoJobInstance::new(job, executor.delta_generator.clone(), true),
+        Some(JobKind::OciExport) => OciExportJobInstance::new(job),
+        Some(JobKind::Bundle) => BundleJobInstance::new(job),
+        Some(JobKind::Prune) => PruneJobInstance::new(job),
+        Some(JobKind::Resign) => ResignJobInstance::new(job),
+        Some(JobKind::Gc) => GcJobInstance::new(job),
+        Some(JobKind::Promote) => PromoteJobInstance::new(job),
+        Some(JobKind::Rollback) => RollbackJobInstance::new(job),
+        Some(JobKind::Deprecate) => DeprecateJobInstance::new(job),
+        Some(JobKind::Fsck) => FsckJobInstance::new(job),
         _ => InvalidJobInstance::new(job, JobError::new("Unknown job type")),
     }
 }
 
 pub trait JobInstance {
     fn get_job_id (&self) -> i32;
+    fn get_kind (&self) -> Option<JobKind> {
+        None
+    }
+    fn get_build_id (&self) -> Option<i32> {
+        None
+    }
     fn order (&self) -> i32 {
         0
     }
@@ -335,6 +1022,110 @@ impl JobInstance for InvalidJobInstance {
 }
 
 
+const APPSTREAM_VALIDATE_TIMEOUT: time::Duration = time::Duration::from_secs(60);
+
+/* appstreamcli lists the problems like "E: org.example.App:12: tag-missing" */
+fn parse_appstream_validation(output: &Output) -> AppstreamValidationResult {
+    let mut result = AppstreamValidationResult::default();
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        let line = line.trim();
+        if let Some(error) = line.strip_prefix("E:") {
+            result.errors.push(error.trim().to_string());
+        } else if let Some(warning) = line.strip_prefix("W:") {
+            result.warnings.push(warning.trim().to_string());
+        }
+    }
+    if !output.status.success() && result.errors.is_empty() && result.warnings.is_empty() {
+        result.errors.push(format!("appstreamcli validate failed: {}", String::from_utf8_lossy(&output.stderr).trim()));
+    }
+    result
+}
+
+/* Runs appstreamcli validate on the metainfo (or older appdata) file of an app */
+fn validate_appstream(job_id: i32,
+                      ref_name: &str,
+                      commit: &str,
+                      build_repo_path: &PathBuf,
+                      config: &Config,
+                      repoconfig: &RepoConfig,
+                      conn: &PgConnection) -> JobResult<AppstreamValidationResult> {
+    let app_id = ref_name.split('/').nth(1).unwrap_or("");
+    let candidates = [format!("/files/share/metainfo/{}.metainfo.xml", app_id),
+                      format!("/files/share/metainfo/{}.appdata.xml", app_id),
+                      format!("/files/share/appdata/{}.appdata.xml", app_id)];
+
+    /* The build repo has the repo as parent */
+    let repo_paths = [build_repo_path.clone(), repoconfig.get_abs_repo_path()];
+    let contents = candidates.iter()
+        .filter_map(|candidate| ostree::read_commit_file(&repo_paths, commit, candidate).ok())
+        .next();
+    let contents = match contents {
+        Some(contents) => contents,
+        None => {
+            let mut result = AppstreamValidationResult::default();
+            result.errors.push(format!("No metainfo file found for {}", app_id));
+            return Ok(result)
+        },
+    };
+
+    let dir = tempfile::Builder::new()
+        .prefix("appstream-")
+        .tempdir_in(build_repo_path)?;
+    let path = dir.path().join(format!("{}.metainfo.xml", app_id));
+    fs::write(&path, &contents)?;
+
+    job_log_and_info(job_id, conn, &format!("Validating appstream data of {}", ref_name));
+    let mut cmd = new_command(config, "appstreamcli", &[], &[dir.path()]);
+    cmd
+        .arg("validate")
+        .arg("--no-net")
+        .arg("--no-color")
+        .arg(&path);
+    let output = command_output(cmd, Some(APPSTREAM_VALIDATE_TIMEOUT))
+        .map_err(|e| JobError::new(&format!("Failed to run appstreamcli: {}", e)))?;
+
+    let result = parse_appstream_validation(&output);
+    for line in result.errors.iter().map(|e| format!("  error: {}", e))
+        .chain(result.warnings.iter().map(|w| format!("  warning: {}", w))) {
+        job_log(job_id, conn, &format!("{}\n", line));
+    }
+    Ok(result)
+}
+
+/* With "error" validation, apps with appstream errors can't be committed or published */
+fn check_appstream_validation(ref_name: &str, result: &AppstreamValidationResult, repoconfig: &RepoConfig) -> JobResult<()> {
+    if repoconfig.appstream_validation == AppstreamValidation::Error && !result.errors.is_empty() {
+        return Err(JobError::new(&format!("Invalid appstream data for {}: {}",
+                                          ref_name, result.errors.join("; "))));
+    }
+    Ok(())
+}
+
+/* Whether a build commit has the same files as the published commit of the ref */
+fn same_content_as_published(repo_path: &PathBuf, build_repo_path: &PathBuf, ref_name: &str, commit: &String) -> JobResult<bool> {
+    let published = match ostree::parse_ref(repo_path, ref_name) {
+        Ok(published) => ostree::get_commit(repo_path, &published)?,
+        Err(_) => return Ok(false),
+    };
+    let built = ostree::get_commit(build_repo_path, commit)?;
+    Ok(built.root_tree == published.root_tree && built.root_metadata == published.root_metadata)
+}
+
+/* The --timestamp for build-commit-from to keep the timestamp of an
+ * uploaded commit. That is whatever the uploader put in the commit, so
+ * it may be out of range. */
+fn preserved_commit_timestamp(timestamp: u64) -> Option<String> {
+    if timestamp > i64::MAX as u64 {
+        return None;
+    }
+    chrono::NaiveDateTime::from_timestamp_opt(timestamp as i64, 0)
+        .filter(|datetime| datetime.year() <= 9999)
+        .map(|datetime| datetime.format("%Y-%m-%dT%H:%M:%SZ").to_string())
+}
+
+/* In the build repo, laid out like a repo with only deltas */
+const UPLOADED_DELTAS_DIR: &str = "uploaded-deltas";
+
 #[derive(Debug)]
 struct CommitJobInstance {
     pub job_id: i32,
@@ -342,6 +1133,7 @@ struct CommitJobInstance {
     pub endoflife: Option<String>,
     pub endoflife_rebase: Option<String>,
     pub token_type: Option<i32>,
+    pub timestamp: Option<String>,
 }
 
 impl CommitJobInstance {
@@ -353,24 +1145,146 @@ impl CommitJobInstance {
                 endoflife: commit_job.endoflife,
                 endoflife_rebase: commit_job.endoflife_rebase,
                 token_type: commit_job.token_type,
+                timestamp: commit_job.timestamp,
             })
         } else {
             InvalidJobInstance::new(job, JobError::new("Can't parse commit job"))
         }
     }
 
+    /* Runs the build-commit-from commands, given as (ref name, description,
+     * command), at most max_parallel at a time, all driven from this thread.
+     * Each committed ref is added to the checkpoint of the job when done. */
+    fn run_commit_commands (&self,
+                            cmds: Vec<(String, String, Command)>,
+                            build_repo_path: &PathBuf,
+                            committed_refs: HashMap<String, String>,
+                            max_parallel: u32,
+                            conn: &PgConnection) -> JobResult<()> {
+        let job_id = self.job_id;
+        let total = cmds.len();
+        let build_repo_path = build_repo_path.clone();
+        job_progress(job_id, conn, "refs", 0, total);
+        let commits = stream::iter_ok::<_, JobError>(cmds)
+            .map(move |(ref_name, description, cmd)| {
+                job_log_and_info(job_id, conn, &format!("Committing ref {}", description));
+                command_future(cmd, job_id, conn, None)
+                    .then(move |res| Ok::<_, JobError>((ref_name, description, res)))
+            })
+            .buffer_unordered(std::cmp::max(max_parallel, 1) as usize)
+            .fold((None, 0, committed_refs), move |(first_error, done, mut committed_refs), (ref_name, description, res)| {
+                job_progress(job_id, conn, "refs", done + 1, total);
+                match res {
+                    Ok(_) => {
+                        job_log_and_info(job_id, conn, &format!("Committed ref {}", description));
+                        match ostree::parse_ref(&build_repo_path, &ref_name) {
+                            Ok(commit) => {
+                                committed_refs.insert(ref_name, commit);
+                                save_commit_checkpoint(job_id, conn, &committed_refs);
+                            },
+                            Err(e) => error!("Can't checkpoint ref {} of job {}: {}", ref_name, job_id, e),
+                        }
+                        Ok::<_, JobError>((first_error, done + 1, committed_refs))
+                    },
+                    Err(e) => {
+                        job_log_and_error(job_id, conn, &format!("Failed to commit ref {}: {}", description, e));
+                        Ok((first_error.or(Some(e)), done + 1, committed_refs))
+                    },
+                }
+            });
+
+        match run_on_command_runtime(commits)?.0 {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    fn validate_ref_metadata(&self,
+                             ref_name: &str,
+                             commit: &String,
+                             build_repo_path: &PathBuf,
+                             build_refs: &[models::BuildRef],
+                             repoconfig: &RepoConfig,
+                             conn: &PgConnection) -> JobResult<()> {
+        if !ref_name.starts_with("app/") && !ref_name.starts_with("runtime/") {
+            return Ok(())
+        }
+        let ostree_commit = ostree::get_commit(build_repo_path, commit)?;
+        let ref_metadata = metadata::validate_metadata(ref_name, &ostree_commit)
+            .map_err(|e| JobError::new(&format!("Invalid metadata: {}", e)))?;
+
+        if let Some(runtime) = ref_metadata.runtime {
+            let in_build = build_refs.iter().any(|build_ref| build_ref.ref_name == runtime);
+            if !in_build && ostree::parse_ref(&repoconfig.get_abs_repo_path(), &runtime).is_err() {
+                /* With runtime-repo-url the runtime comes from another repo, which we can't check */
+                if repoconfig.runtime_repo_url.is_none() {
+                    return Err(JobError::new(&format!("Invalid metadata: {} uses {}, which is not in repo {}",
+                                                      ref_name, runtime, repoconfig.name)));
+                }
+                job_log_and_info(self.job_id, conn,
+                                 &format!("Runtime {} of {} is not in repo {}, assuming it is in {}",
+                                          runtime, ref_name, repoconfig.name, repoconfig.runtime_repo_url.as_ref().unwrap()));
+            }
+        }
+
+        if !ref_metadata.permissions.is_empty() {
+            job_log_and_info(self.job_id, conn,
+                             &format!("Permissions of {}: {}", ref_name, ref_metadata.permissions.join(" ")));
+        }
+        Ok(())
+    }
+
+    /* Deltas can be uploaded with the build, to the uploaded commits. These
+     * are checked and kept until the build is published, the upload repo
+     * itself is removed after the commit */
+    fn keep_uploaded_deltas (&self,
+                             upload_path: &PathBuf,
+                             build_repo_path: &Path,
+                             build_refs: &[models::BuildRef],
+                             conn: &PgConnection) -> JobResult<()> {
+        let uploaded_deltas_path = build_repo_path.join(UPLOADED_DELTAS_DIR);
+        for delta in ostree::list_deltas(upload_path) {
+            if !build_refs.iter().any(|build_ref| build_ref.commit == delta.to) {
+                return Err(JobError::new(&format!("Uploaded delta {} is not to a commit of the build", delta.to_string())));
+            }
+            ostree::verify_delta(upload_path, &delta)
+                .map_err(|e| JobError::new(&format!("Invalid uploaded delta {}: {}", delta.to_string(), e)))?;
+
+            let dst_path = delta.delta_path(&uploaded_deltas_path)?;
+            if dst_path.exists() {
+                fs::remove_dir_all(&dst_path)?;
+            }
+            if let Some(parent) = dst_path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::rename(delta.delta_path(upload_path)?, &dst_path)?;
+            job_log_and_info(self.job_id, conn, &format!("Keeping uploaded delta {}", delta.to_string()));
+        }
+        Ok(())
+    }
+
     fn do_commit_build_refs (&self,
                              build_refs: &Vec<models::BuildRef>,
+                             derived_from: Option<i32>,
                              config: &Config,
                              repoconfig: &RepoConfig,
                              conn: &PgConnection)  -> JobResult<serde_json::Value> {
+        let _span = tracing::start_span("commit-build-refs");
         let build_repo_path = config.build_repo_base.join(self.build_id.to_string());
         let upload_path = build_repo_path.join("upload");
 
+        /* The upload repo of a derived build has the source build repo as parent */
+        let source_repo_path = derived_from.map(|source_id| config.build_repo_base.join(source_id.to_string()));
+        let mut readonly_paths = vec![repoconfig.path.as_path()];
+        if let Some(ref source_repo_path) = source_repo_path {
+            readonly_paths.push(source_repo_path.as_path());
+        }
+
         let mut src_repo_arg = OsString::from("--src-repo=");
         src_repo_arg.push(&upload_path);
 
         let mut commits = HashMap::new();
+        let mut appstream = HashMap::new();
 
         let endoflife_rebase_arg = if let Some(endoflife_rebase) = &self.endoflife_rebase {
             if let Some(app_ref) = build_refs.iter().filter(|app_ref| app_ref.ref_name.starts_with("app/")).nth(0) {
@@ -382,20 +1296,85 @@ impl CommitJobInstance {
             None
         };
 
+        /* Refuse uploaded commits that were made for another ref or repo */
+        let mut upload_timestamps = HashMap::new();
         for build_ref in build_refs.iter() {
-            let mut src_ref_arg = String::from("--src-ref=");
-            src_ref_arg.push_str(&build_ref.commit);
+            let upload_commit = ostree::get_commit(&upload_path, &build_ref.commit)
+                .or_else(|e| match source_repo_path {
+                    Some(ref source_repo_path) => ostree::get_commit(source_repo_path, &build_ref.commit),
+                    None => Err(e),
+                })?;
+            ostree::check_ref_bindings(&upload_commit, &build_ref.ref_name, &repoconfig.collection_id,
+                                       repoconfig.require_ref_bindings, derived_from.is_some())
+                .map_err(|e| JobError::new(&format!("Invalid uploaded commit {}: {}", build_ref.commit, e)))?;
+            if repoconfig.get_commit_timestamp() == CommitTimestamp::Preserve {
+                let timestamp = preserved_commit_timestamp(upload_commit.timestamp)
+                    .ok_or_else(|| JobError::new(&format!("Invalid uploaded commit {}: timestamp {} is out of range",
+                                                          build_ref.commit, upload_commit.timestamp)))?;
+                upload_timestamps.insert(build_ref.commit.clone(), timestamp);
+            }
+        }
 
-            let mut cmd = Command::new("flatpak");
-            cmd
-                .arg("build-commit-from")
-                .arg("--timestamp=NOW")     // All builds have the same timestamp, not when the individual builds finished
-                .arg("--no-update-summary") // We update it once at the end
-                .arg("--untrusted")         // Verify that the uploaded objects are correct
-                .arg("--force")             // Always generate a new commit even if nothing changed
-                .arg("--disable-fsync");    // There is a sync in flatpak build-update-repo, so avoid it here
+        /* All builds have the same timestamp by default, not when the individual builds finished */
+        let supplied_timestamp = match self.timestamp {
+            Some(ref timestamp) if repoconfig.get_commit_timestamp() == CommitTimestamp::Supplied => {
+                let parsed = chrono::DateTime::parse_from_rfc3339(timestamp)
+                    .map_err(|e| JobError::new(&format!("Invalid timestamp {}: {}", timestamp, e)))?;
+                Some(parsed.with_timezone(&chrono::Utc).format("%Y-%m-%dT%H:%M:%SZ").to_string())
+            },
+            _ => None,
+        };
 
-            add_gpg_args(&mut cmd, &config.build_gpg_key, &config.gpg_homedir);
+        /* If we verify the uploaded commits up front, build-commit-from can import their
+         * objects in trusted mode, which hardlinks (or reflinks) them instead of copying */
+        if config.link_uploaded_objects {
+            let mut upload_paths = vec![upload_path.clone()];
+            upload_paths.extend(source_repo_path.clone());
+            let parent_paths = [repoconfig.get_abs_repo_path()];
+            for build_ref in build_refs.iter() {
+                let n_objects = ostree::verify_commit_objects(&upload_paths, &parent_paths, &build_ref.commit)
+                    .map_err(|e| JobError::new(&format!("Invalid uploaded commit {}: {}", build_ref.commit, e)))?;
+                job_log_and_info(self.job_id, conn, &format!("Verified {} uploaded objects of {}", n_objects, build_ref.ref_name));
+            }
+        }
+
+        /* A retried job doesn't redo the refs that are still as it committed them */
+        let committed_refs = resumable_refs(load_commit_checkpoint(self.job_id, conn), build_refs,
+                                            |ref_name| ostree::parse_ref(&build_repo_path, ref_name).ok());
+
+        let mut commit_cmds = Vec::new();
+        for build_ref in build_refs.iter() {
+            if committed_refs.contains_key(&build_ref.ref_name) {
+                job_log_and_info(self.job_id, conn, &format!("Ref {} was already committed, skipping", build_ref.ref_name));
+                continue;
+            }
+
+            let mut src_ref_arg = String::from("--src-ref=");
+            src_ref_arg.push_str(&build_ref.commit);
+
+            let timestamp = match repoconfig.get_commit_timestamp() {
+                CommitTimestamp::Preserve => upload_timestamps[&build_ref.commit].clone(),
+                _ => supplied_timestamp.clone().unwrap_or("NOW".to_string()),
+            };
+
+            let mut cmd = new_command(config, "flatpak", &[build_repo_path.as_path()], &readonly_paths);
+            cmd
+                .arg("build-commit-from")
+                .arg(format!("--timestamp={}", timestamp))
+                .arg("--no-update-summary") // We update it once at the end
+                .arg("--disable-fsync");    // There is a sync in flatpak build-update-repo, so avoid it here
+
+            if !repoconfig.reproducible_commits {
+                cmd
+                    .arg("--force");        // Always generate a new commit even if nothing changed
+            }
+
+            if !config.link_uploaded_objects {
+                cmd
+                    .arg("--untrusted");    // Verify that the uploaded objects are correct
+            }
+
+            add_gpg_args(&mut cmd, &config.build_gpg_key, &config.gpg_homedir);
 
             if let Some(endoflife) = &self.endoflife {
                 cmd
@@ -418,10 +1397,24 @@ impl CommitJobInstance {
                 .arg(&build_repo_path)
                 .arg(&build_ref.ref_name);
 
-            job_log_and_info(self.job_id, conn, &format!("Committing ref {} ({})", build_ref.ref_name, build_ref.commit));
-            do_command(cmd)?;
+            commit_cmds.push((build_ref.ref_name.to_string(), format!("{} ({})", build_ref.ref_name, build_ref.commit), cmd));
+        }
+
+        self.run_commit_commands(commit_cmds, &build_repo_path, committed_refs, config.commit_threads, conn)?;
 
+        let mut unchanged_refs = vec![];
+        for build_ref in build_refs.iter() {
             let commit = ostree::parse_ref(&build_repo_path, &build_ref.ref_name)?;
+            self.validate_ref_metadata(&build_ref.ref_name, &commit, &build_repo_path, build_refs, repoconfig, conn)?;
+            if repoconfig.appstream_validation != AppstreamValidation::Off && build_ref.ref_name.starts_with("app/") {
+                let result = validate_appstream(self.job_id, &build_ref.ref_name, &commit, &build_repo_path, config, repoconfig, conn)?;
+                check_appstream_validation(&build_ref.ref_name, &result, repoconfig)?;
+                appstream.insert(build_ref.ref_name.to_string(), result);
+            }
+            if repoconfig.reproducible_commits && self.endoflife.is_none() && same_content_as_published(&repoconfig.get_abs_repo_path(), &build_repo_path, &build_ref.ref_name, &commit)? {
+                job_log_and_info(self.job_id, conn, &format!("{} is unchanged from the published commit", build_ref.ref_name));
+                unchanged_refs.push(build_ref.ref_name.to_string());
+            }
             commits.insert(build_ref.ref_name.to_string(), commit);
 
             let unwanted_exts = [".Debug", ".Locale", ".Sources", ".Docs"];
@@ -434,8 +1427,9 @@ impl CommitJobInstance {
             }
         }
 
+        self.keep_uploaded_deltas(&upload_path, &build_repo_path, build_refs, conn)?;
 
-        let mut cmd = Command::new("flatpak");
+        let mut cmd = new_command(config, "flatpak", &[build_repo_path.as_path()], &[repoconfig.path.as_path()]);
         cmd
             .arg("build-update-repo")
             .arg(&build_repo_path);
@@ -443,12 +1437,13 @@ impl CommitJobInstance {
         add_gpg_args(&mut cmd, &config.build_gpg_key, &config.gpg_homedir);
 
         job_log_and_info(self.job_id, conn, "running build-update-repo");
-        do_command(cmd)?;
+        do_command(cmd, self.job_id, conn)?;
 
         job_log_and_info(self.job_id, conn, "Removing upload directory");
         fs::remove_dir_all(&upload_path)?;
 
-        Ok(json!({ "refs": commits}))
+        unchanged_refs.sort();
+        Ok(json!(JobResults::new(CommitJobResult { refs: commits, appstream, unchanged_refs })))
     }
 }
 
@@ -457,11 +1452,20 @@ impl JobInstance for CommitJobInstance {
         self.job_id
     }
 
+    fn get_kind (&self) -> Option<JobKind> {
+        Some(JobKind::Commit)
+    }
+
+    fn get_build_id (&self) -> Option<i32> {
+        Some(self.build_id)
+    }
+
     fn handle_job (&mut self, executor: &JobExecutor, conn: &PgConnection) -> JobResult<serde_json::Value> {
         info!("#{}: Handling Job Commit: build: {}, end-of-life: {}, eol-rebase: {}, token-type: {:?}",
               &self.job_id, &self.build_id, self.endoflife.as_ref().unwrap_or(&"".to_string()), self.endoflife_rebase.as_ref().unwrap_or(&"".to_string()), self.token_type);
 
         let config = &executor.config;
+        let _lock = lock_build(self.job_id, conn, self.build_id)?;
 
         // Get build details
         let build_data = builds::table
@@ -483,9 +1487,22 @@ impl JobInstance for CommitJobInstance {
             return Err(JobError::new("No refs in build"));
         }
 
+        report_github_status(self.job_id, &build_data, config, "flat-manager/commit", "pending", "Committing build", conn);
+
         // Do the actual work
 
-        let res = self.do_commit_build_refs(&build_refs, config, repoconfig, conn);
+        let res = self.do_commit_build_refs(&build_refs, build_data.derived_from, config, repoconfig, conn);
+
+        match &res {
+            Ok(_) => report_github_status(self.job_id, &build_data, config, "flat-manager/commit", "success", "Build committed", conn),
+            Err(_) => report_github_status(self.job_id, &build_data, config, "flat-manager/commit", "failure", "Build commit failed", conn),
+        };
+
+        if res.is_ok() {
+            /* The committed objects are verified now, so other builds can reuse them */
+            let ref_names: Vec<String> = build_refs.iter().map(|build_ref| build_ref.ref_name.clone()).collect();
+            objectpool::add_build_objects(config, self.build_id, &repoconfig.get_abs_repo_path(), &ref_names);
+        }
 
         // Update the build repo state in db
 
@@ -504,11 +1521,13 @@ impl JobInstance for CommitJobInstance {
                 return Err(DieselError::RollbackTransaction)
             };
             let (val, reason) = RepoState::to_db(&new_repo_state);
-            diesel::update(builds::table)
+            let new_build = diesel::update(builds::table)
                 .filter(builds::id.eq(self.build_id))
                 .set((builds::repo_state.eq(val),
                       builds::repo_state_reason.eq(reason)))
-                .get_result::<models::Build>(conn)
+                .get_result::<models::Build>(conn)?;
+            webhooks::queue_build_repo_state(conn, config, &new_build)?;
+            Ok(new_build)
         })?;
 
         res
@@ -520,6 +1539,54 @@ impl JobInstance for CommitJobInstance {
 struct PublishJobInstance {
     pub job_id: i32,
     pub build_id: i32,
+    pub note: Option<String>,
+    pub arches: Option<Vec<String>>,
+    pub branches: HashMap<String, String>,
+    pub published_by: Option<String>,
+}
+
+/* A build that has been imported into the repo, but whose repo update
+ * is still to be queued */
+#[derive(Debug)]
+struct ImportedBuild {
+    repo: String,
+    refs: HashMap<String, String>,
+    filtered_refs: Vec<String>,
+    unchanged_refs: Vec<String>,
+}
+
+/* The results of a publish job, once the repo update has been queued
+ * for the whole batch */
+fn publish_result(imported: JobResult<ImportedBuild>, update_job: &JobResult<i32>) -> JobResult<PublishJobResult> {
+    let imported = imported?;
+    let update_repo_job = update_job.clone()?;
+    Ok(PublishJobResult {
+        refs: imported.refs,
+        update_repo_job,
+        filtered_refs: imported.filtered_refs,
+        unchanged_refs: imported.unchanged_refs,
+    })
+}
+
+/* The ref a build ref is published as, with the branch renamed if requested */
+pub fn published_ref_name(ref_name: &str, branches: &HashMap<String, String>) -> String {
+    let parts: Vec<&str> = ref_name.split('/').collect();
+    if parts.len() == 4 && (parts[0] == "app" || parts[0] == "runtime") {
+        if let Some(branch) = branches.get(parts[3]) {
+            return format!("{}/{}/{}/{}", parts[0], parts[1], parts[2], branch);
+        }
+    }
+    ref_name.to_string()
+}
+
+/* The arch of app, runtime and screenshot refs, other refs have none */
+fn ref_arch(ref_name: &str) -> Option<&str> {
+    let parts: Vec<&str> = ref_name.split('/').collect();
+    match parts[0] {
+        "app" | "runtime" if parts.len() == 4 => Some(parts[2]),
+        "screenshots" if parts.len() == 2 => Some(parts[1]),
+        _ => None,
+    }
 }
 
 impl PublishJobInstance {
@@ -528,90 +1595,71 @@ impl PublishJobInstance {
             Box::new(PublishJobInstance {
                 job_id: job.id,
                 build_id: publish_job.build,
+                note: publish_job.note,
+                arches: publish_job.arches,
+                branches: publish_job.branches,
+                published_by: publish_job.published_by,
             })
         } else {
             InvalidJobInstance::new(job, JobError::new("Can't parse publish job"))
         }
     }
 
-    fn do_publish (&self,
-                   build: &models::Build,
-                   build_refs: &Vec<models::BuildRef>,
-                   config: &Config,
-                   repoconfig: &RepoConfig,
-                   conn: &PgConnection)  -> JobResult<serde_json::Value> {
-        let build_repo_path = config.build_repo_base.join(self.build_id.to_string());
+    fn dst_ref_name(&self, ref_name: &str) -> String {
+        published_ref_name(ref_name, &self.branches)
+    }
 
-        let mut src_repo_arg = OsString::from("--src-repo=");
-        src_repo_arg.push(&build_repo_path);
+    fn import_build (&self, executor: &JobExecutor, conn: &PgConnection) -> JobResult<ImportedBuild> {
+        info!("#{}: Handling Job Publish: build: {}",
+              &self.job_id, &self.build_id);
 
-        // Import commit and modify refs
+        let config = &executor.config;
 
-        let mut cmd = Command::new("flatpak");
-        cmd
-            .arg("build-commit-from")
-            .arg("--force")             // Always generate a new commit even if nothing changed
-            .arg("--no-update-summary"); // We update it separately
+        // Get build details
+        let build_data = builds::table
+            .filter(builds::id.eq(self.build_id))
+            .get_result::<models::Build>(conn).map_err(|_e| JobError::new("Can't load build"))?;
 
-        add_gpg_args(&mut cmd, &repoconfig.gpg_key, &config.gpg_homedir);
+        // Get repo config
+        let repoconfig = config.get_repoconfig(&build_data.repo).map_err(|_e| JobError::new(&format!("Can't find repo {}", &build_data.repo)))?;
 
-        if let Some(collection_id) = &repoconfig.collection_id {
-            for ref extra_id in build.extra_ids.iter() {
-                cmd.arg(format!("--extra-collection-id={}.{}", collection_id, extra_id));
-            }
+        // Get the uploaded refs from db
+        let build_refs = build_refs::table
+        .filter(build_refs::build_id.eq(self.build_id))
+            .get_results::<models::BuildRef>(conn).map_err(|_e| JobError::new("Can't load build refs"))?;
+        if build_refs.is_empty() {
+            return Err(JobError::new("No refs in build"));
         }
 
-        cmd
-            .arg(&src_repo_arg)
-            .arg(&repoconfig.path);
-
-        job_log_and_info(self.job_id, conn,
-                         &format!("Importing build to repo {}", repoconfig.name));
-        do_command(cmd)?;
-
-        let appstream_dir = repoconfig.path.join("appstream");
-        fs::create_dir_all(&appstream_dir)?;
-
-        let screenshots_dir = repoconfig.path.join("screenshots");
-        fs::create_dir_all(&screenshots_dir)?;
-
-        let mut commits = HashMap::new();
-        for build_ref in build_refs.iter() {
-            if build_ref.ref_name.starts_with("app/") || build_ref.ref_name.starts_with("runtime/") {
-                let commit = ostree::parse_ref(&repoconfig.path, &build_ref.ref_name)?;
-                commits.insert(build_ref.ref_name.to_string(), commit);
-            }
-
-            if build_ref.ref_name.starts_with("app/") {
-                let (filename, contents) = generate_flatpakref(&build_ref.ref_name, None, config, repoconfig);
-                let path = appstream_dir.join(&filename);
-                job_log_and_info (self.job_id, conn, &format!("generating {}", &filename));
-                let old_contents = fs::read_to_string(&path).unwrap_or_default();
-                if contents != old_contents {
-                    File::create(&path)?.write_all(contents.as_bytes())?;
+        let (build_refs, filtered_refs): (Vec<models::BuildRef>, Vec<models::BuildRef>) =
+            build_refs.into_iter().partition(|build_ref| {
+                match ref_arch(&build_ref.ref_name) {
+                    Some(arch) => {
+                        repoconfig.publish_arches.as_ref().map(|arches| arches.iter().any(|a| a == arch)).unwrap_or(true) &&
+                            self.arches.as_ref().map(|arches| arches.iter().any(|a| a == arch)).unwrap_or(true)
+                    },
+                    None => true,
                 }
-            }
+            });
+        for build_ref in filtered_refs.iter() {
+            job_log_and_info(self.job_id, conn,
+                             &format!("Not publishing {}, its arch is filtered out", build_ref.ref_name));
         }
+        let filtered_refs: Vec<String> = filtered_refs.into_iter().map(|build_ref| build_ref.ref_name).collect();
 
-        for build_ref in build_refs.iter() {
-            if build_ref.ref_name.starts_with("screenshots/") {
-                job_log_and_info (self.job_id, conn, &format!("extracting {}", build_ref.ref_name));
-                let mut cmd = Command::new("ostree");
-                cmd
-                    .arg(&format!("--repo={}", &build_repo_path.to_str().unwrap()))
-                    .arg("checkout")
-                    .arg("--user-mode")
-                    .arg("--bareuseronly-dirs")
-                    .arg("--union")
-                    .arg(&build_ref.ref_name)
-                    .arg(&screenshots_dir);
-                do_command(cmd)?;
-            }
+        report_github_status(self.job_id, &build_data, config, "flat-manager/publish", "pending", "Publishing build", conn);
+
+        // Do the actual work, if the build got committed (it may have been queued with the commit)
+        match RepoState::from_db(build_data.repo_state, &build_data.repo_state_reason) {
+            RepoState::Ready => self.do_import(&build_data, &build_refs, filtered_refs, config, repoconfig, conn),
+            state => Err(JobError::new(&format!("Build is not committed ({})", state.name()))),
         }
+    }
 
-        /* Create update repo job */
-        let delay = config.delay_update_secs;
-        let (is_new, update_job) = queue_update_job (delay, conn, &repoconfig.name, Some(self.job_id))?;
+    /* Queues the update of the repo for all the builds imported by the batch */
+    fn queue_repo_update (&self, executor: &JobExecutor, conn: &PgConnection, repo: &str) -> JobResult<i32> {
+        let delay = executor.config.delay_update_secs;
+        let (is_new, update_job) = queue_update_job (delay, conn, repo, Some(self.job_id))?;
         if is_new {
             job_log_and_info(self.job_id, conn,
                                     &format!("Queued repository update job {}{}",
@@ -623,51 +1671,26 @@ impl PublishJobInstance {
             job_log_and_info(self.job_id, conn,
                              &format!("Piggy-backed on existing update job {}", update_job.id));
         }
-
-        Ok(json!({
-            "refs": commits,
-            "update-repo-job": update_job.id,
-        }))
-    }
-}
-
-impl JobInstance for PublishJobInstance {
-    fn get_job_id (&self) -> i32 {
-        self.job_id
-    }
-
-    fn order (&self) -> i32 {
-        1 /* Delay publish after commits (and other normal ops). because the
-            commits may generate more publishes. */
+        Ok(update_job.id)
     }
 
-    fn handle_job (&mut self, executor: &JobExecutor, conn: &PgConnection) -> JobResult<serde_json::Value> {
-        info!("#{}: Handling Job Publish: build: {}",
-              &self.job_id, &self.build_id);
-
+    fn finish_publish (&self,
+                       executor: &JobExecutor,
+                       conn: &PgConnection,
+                       imported: JobResult<ImportedBuild>,
+                       update_job: &JobResult<i32>) -> JobResult<serde_json::Value> {
         let config = &executor.config;
+        let res = publish_result(imported, update_job);
 
-        // Get build details
         let build_data = builds::table
             .filter(builds::id.eq(self.build_id))
             .get_result::<models::Build>(conn)
-            .or_else(|_e| Err(JobError::new("Can't load build")))?;
-
-        // Get repo config
-        let repoconfig = config.get_repoconfig(&build_data.repo)
-            .or_else(|_e| Err(JobError::new(&format!("Can't find repo {}", &build_data.repo))))?;
-
-        // Get the uploaded refs from db
-        let build_refs = build_refs::table
-        .filter(build_refs::build_id.eq(self.build_id))
-            .get_results::<models::BuildRef>(conn)
-            .or_else(|_e| Err(JobError::new("Can't load build refs")))?;
-        if build_refs.len() == 0 {
-            return Err(JobError::new("No refs in build"));
-        }
+            .map_err(|_e| JobError::new("Can't load build"))?;
 
-        // Do the actual work
-        let res = self.do_publish(&build_data, &build_refs, config, repoconfig, conn);
+        match &res {
+            Ok(_) => report_github_status(self.job_id, &build_data, config, "flat-manager/publish", "success", "Build published", conn),
+            Err(_) => report_github_status(self.job_id, &build_data, config, "flat-manager/publish", "failure", "Build publish failed", conn),
+        };
 
         // Update the publish repo state in db
 
@@ -687,112 +1710,479 @@ impl JobInstance for PublishJobInstance {
                 return Err(DieselError::RollbackTransaction)
             };
             let (val, reason) = PublishedState::to_db(&new_published_state);
-            diesel::update(builds::table)
+            let new_build = diesel::update(builds::table)
                 .filter(builds::id.eq(self.build_id))
                 .set((builds::published_state.eq(val),
                       builds::published_state_reason.eq(reason)))
-                .get_result::<models::Build>(conn)
+                .get_result::<models::Build>(conn)?;
+            webhooks::queue_build_published_state(conn, config, &new_build, &self.note)?;
+            Ok(new_build)
         })?;
 
-        res
+        res.map(|result| json!(JobResults::new(result)))
     }
-}
 
-#[derive(Debug)]
-struct UpdateRepoJobInstance {
-    pub delta_generator: Addr<DeltaGenerator>,
-    pub job_id: i32,
-    pub repo: String,
-}
+    /* The deltas uploaded with the build are to the uploaded commits, the
+     * published commits have the same content so they apply to those too.
+     * The refs are already published, so failures here are only logged,
+     * the update-repo job generates the deltas that are missing. */
+    fn install_uploaded_deltas (&self,
+                                build_refs: &[models::BuildRef],
+                                commits: &HashMap<String, String>,
+                                build_repo_path: &Path,
+                                repoconfig: &RepoConfig,
+                                conn: &PgConnection) {
+        let uploaded_deltas_path = build_repo_path.join(UPLOADED_DELTAS_DIR);
+        let uploaded_deltas = ostree::list_deltas(&uploaded_deltas_path);
 
-impl UpdateRepoJobInstance {
-    fn new(job: Job, delta_generator: Addr<DeltaGenerator>) -> Box<dyn JobInstance> {
-        if let Ok(update_repo_job) = serde_json::from_str::<UpdateRepoJob>(&job.contents) {
-            Box::new(UpdateRepoJobInstance {
-                delta_generator: delta_generator,
-                job_id: job.id,
-                repo: update_repo_job.repo,
-            })
-        } else {
-            InvalidJobInstance::new(job, JobError::new("Can't parse publish job"))
+        for build_ref in build_refs.iter() {
+            let commit = match commits.get(&self.dst_ref_name(&build_ref.ref_name)) {
+                Some(commit) => commit,
+                None => continue,
+            };
+            let parent = ostree::get_commit(&repoconfig.path, commit).ok().and_then(|commit| commit.parent);
+            for delta in uploaded_deltas.iter().filter(|delta| delta.to == build_ref.commit) {
+                /* The update-repo job removes deltas from other commits than the previous one */
+                if delta.from.is_some() && delta.from != parent {
+                    job_log_and_info(self.job_id, conn,
+                                     &format!("Not installing uploaded delta {}, it is not from the previous commit of {}",
+                                              delta.to_string(), build_ref.ref_name));
+                    continue;
+                }
+                match ostree::rebase_delta(&uploaded_deltas_path, delta, &repoconfig.path, commit) {
+                    Ok(installed) => job_log_and_info(self.job_id, conn,
+                                                      &format!("Installed uploaded delta {} as {}", delta.to_string(), installed.to_string())),
+                    Err(e) => job_log_and_error(self.job_id, conn,
+                                                &format!("Failed to install uploaded delta {}: {}", delta.to_string(), e)),
+                }
+            }
         }
     }
 
-    fn calculate_deltas(&self, repoconfig: &RepoConfig) -> (HashSet<ostree::Delta>, HashSet<ostree::Delta>) {
-        let repo_path = repoconfig.get_abs_repo_path();
+    fn do_import (&self,
+                  build: &models::Build,
+                  build_refs: &[models::BuildRef],
+                  filtered_refs: Vec<String>,
+                  config: &Config,
+                  repoconfig: &RepoConfig,
+                  conn: &PgConnection)  -> JobResult<ImportedBuild> {
+        let _span = tracing::start_span("publish-build-refs");
+
+        if !build_refs.iter().any(|build_ref| build_ref.ref_name.starts_with("app/") || build_ref.ref_name.starts_with("runtime/")) {
+            return Err(JobError::new("All refs of the build are for arches that are not published"));
+        }
 
-        let mut wanted_deltas = HashSet::new();
-        let refs = ostree::list_refs (&repo_path, "");
+        /* The build may have been committed while the repo only warned
+         * about appstream errors */
+        let build_repo_path = config.build_repo_base.join(self.build_id.to_string());
+        if repoconfig.appstream_validation == AppstreamValidation::Error {
+            for build_ref in build_refs.iter().filter(|build_ref| build_ref.ref_name.starts_with("app/")) {
+                let commit = ostree::parse_ref(&build_repo_path, &build_ref.ref_name)?;
+                let result = validate_appstream(self.job_id, &build_ref.ref_name, &commit, &build_repo_path, config, repoconfig, conn)?;
+                check_appstream_validation(&build_ref.ref_name, &result, repoconfig)?;
+            }
+        }
 
-        for ref_name in refs {
-            let depth = repoconfig.get_delta_depth_for_ref(&ref_name);
+        let _lock = lock_repo(self.job_id, conn, &repoconfig.name)?;
+        let _build_lock = lock_build(self.job_id, conn, self.build_id)?;
 
-            if depth > 0 {
-                let ref_deltas = ostree::calc_deltas_for_ref(&repo_path, &ref_name, depth);
-                for ref_delta in ref_deltas {
-                    wanted_deltas.insert(ref_delta);
+        let mut src_repo_arg = OsString::from("--src-repo=");
+        src_repo_arg.push(&build_repo_path);
+
+        // Make sure we never move a ref back to an older build, which could happen
+        // if a newer build got published before this job ran
+
+        let mut previous_commits = HashMap::new();
+        for build_ref in build_refs.iter() {
+            if build_ref.ref_name.starts_with("app/") || build_ref.ref_name.starts_with("runtime/") {
+                let dst_ref = self.dst_ref_name(&build_ref.ref_name);
+                let superseded_by = published_refs::table
+                    .filter(published_refs::repo.eq(&repoconfig.name))
+                    .filter(published_refs::ref_name.eq(&dst_ref))
+                    .filter(published_refs::build_id.gt(self.build_id))
+                    .select(published_refs::build_id)
+                    .first::<i32>(conn)
+                    .optional()?;
+                if let Some(newer_build_id) = superseded_by {
+                    return Err(JobError::new(&format!("Ref {} was already published by newer build {}",
+                                                      dst_ref, newer_build_id)));
                 }
+                previous_commits.insert(dst_ref.clone(),
+                                        ostree::parse_ref(&repoconfig.path, &dst_ref).ok());
             }
         }
-        let old_deltas = HashSet::from_iter(ostree::list_deltas (&repo_path).iter().cloned());
 
-        let missing_deltas = wanted_deltas.difference(&old_deltas).cloned().collect();
-        let unwanted_deltas = old_deltas.difference(&wanted_deltas).cloned().collect();
+        // Import commit and modify refs
 
-        (missing_deltas, unwanted_deltas)
-    }
+        let new_commit_from_cmd = || {
+            let mut cmd = new_command(config, "flatpak", &[repoconfig.path.as_path()], &[build_repo_path.as_path()]);
+            cmd
+                .arg("build-commit-from")
+                .arg("--no-update-summary"); // We update it separately
 
-    fn generate_deltas(&self,
-                       deltas: &HashSet<ostree::Delta>,
-                       repoconfig: &RepoConfig,
-                       conn: &PgConnection) -> JobResult<()> {
-        job_log_and_info(self.job_id, conn, "Generating deltas");
+            /* Without it, refs with unchanged content keep their commit */
+            if !repoconfig.reproducible_commits {
+                cmd.arg("--force");         // Always generate a new commit even if nothing changed
+            }
 
-        let (tx, rx) = mpsc::channel();
+            add_gpg_args(&mut cmd, &repoconfig.gpg_key, &config.gpg_homedir);
 
-        /* We can't use a regular .send() here, as that requres a current task which is
-         * not available in a sync actor like this. Instead we use the non-blocking
-         * do_send and implement returns using a mpsc::channel.
-        */
+            if let Some(collection_id) = &repoconfig.collection_id {
+                for ref extra_id in build.extra_ids.iter() {
+                    cmd.arg(format!("--extra-collection-id={}.{}", collection_id, extra_id));
+                }
+            }
 
-        for delta in deltas.iter() {
-            self.delta_generator.do_send(DeltaRequestSync {
-                delta_request: DeltaRequest {
-                    repo: repoconfig.name.clone(),
-                    delta: delta.clone(),
-                },
-                tx: tx.clone(),
-            })
-        }
+            cmd.arg(&src_repo_arg);
+            cmd
+        };
 
-        for (delta, result) in rx.iter().take(deltas.len()) {
-            let message = match result {
-                Ok(()) => format!(" {}", delta.to_string()),
-                Err(e) => format!(" failed to generate {}: {}", delta.to_string(), e),
-            };
-            job_log_and_info(self.job_id, conn, &message);
-        }
+        let (renamed_refs, same_refs): (Vec<&models::BuildRef>, Vec<&models::BuildRef>) =
+            build_refs.iter().partition(|build_ref| self.dst_ref_name(&build_ref.ref_name) != build_ref.ref_name);
 
-        job_log_and_info(self.job_id, conn, "All deltas generated");
+        job_log_and_info(self.job_id, conn,
+                         &format!("Importing build to repo {}", repoconfig.name));
+        if filtered_refs.is_empty() && renamed_refs.is_empty() {
+            /* Without refs all refs of the build repo are imported */
+            let mut cmd = new_commit_from_cmd();
+            cmd.arg(&repoconfig.path);
+            do_command(cmd, self.job_id, conn)?;
+        } else if !same_refs.is_empty() {
+            let mut cmd = new_commit_from_cmd();
+            cmd.arg(&repoconfig.path);
+            for build_ref in same_refs.iter() {
+                cmd.arg(&build_ref.ref_name);
+            }
+            do_command(cmd, self.job_id, conn)?;
+        }
 
-        Ok(())
-    }
+        /* A different destination ref takes one import per ref */
+        for build_ref in renamed_refs.iter() {
+            let dst_ref = self.dst_ref_name(&build_ref.ref_name);
+            job_log_and_info(self.job_id, conn,
+                             &format!("Publishing {} as {}", build_ref.ref_name, dst_ref));
+            let mut cmd = new_commit_from_cmd();
+            cmd
+                .arg(format!("--src-ref={}", build_ref.ref_name))
+                .arg(&repoconfig.path)
+                .arg(&dst_ref);
+            do_command(cmd, self.job_id, conn)?;
+        }
 
-    fn retire_deltas(&self,
-                     deltas: &HashSet<ostree::Delta>,
-                     repoconfig: &RepoConfig,
-                     conn: &PgConnection) -> JobResult<()> {
-        job_log_and_info(self.job_id, conn, "Cleaning out old deltas");
-        let repo_path = repoconfig.get_abs_repo_path();
-        let deltas_dir = repo_path.join("deltas");
-        let tmp_deltas_dir = repo_path.join("tmp/deltas");
-        fs::create_dir_all(&tmp_deltas_dir)?;
+        let appstream_dir = repoconfig.path.join("appstream");
+        fs::create_dir_all(&appstream_dir)?;
 
-        let now = time::SystemTime::now();
-        let now_filetime = filetime::FileTime::from_system_time(now);
+        let screenshots_dir = repoconfig.path.join("screenshots");
+        fs::create_dir_all(&screenshots_dir)?;
 
-        /* Instead of directly removing the deltas we move them to a different
-         * directory which is *also* used as a source for the delta dir when accessed
+        let mut commits = HashMap::new();
+        let mut unchanged_refs = vec![];
+        for build_ref in build_refs.iter() {
+            let dst_ref = self.dst_ref_name(&build_ref.ref_name);
+            if dst_ref.starts_with("app/") || dst_ref.starts_with("runtime/") {
+                let commit = ostree::parse_ref(&repoconfig.path, &dst_ref)?;
+                /* With reproducible commits, a ref with the same content keeps its commit */
+                if previous_commits.get(&dst_ref) == Some(&Some(commit.clone())) {
+                    job_log_and_info(self.job_id, conn, &format!("{} is unchanged, not publishing it", dst_ref));
+                    unchanged_refs.push(dst_ref);
+                    continue;
+                }
+                diesel::insert_into(published_refs::table)
+                    .values(NewPublishedRef {
+                        build_id: self.build_id,
+                        ref_name: dst_ref.clone(),
+                        commit: commit.clone(),
+                        job_id: Some(self.job_id),
+                        repo: repoconfig.name.clone(),
+                        previous_commit: previous_commits.remove(&dst_ref).unwrap_or(None),
+                        note: self.note.clone(),
+                        published_by: self.published_by.clone(),
+                    })
+                    .execute(conn)?;
+                /* A forced publish brings an end-of-life ref back */
+                diesel::delete(ref_tombstones::table
+                               .filter(ref_tombstones::repo.eq(&repoconfig.name))
+                               .filter(ref_tombstones::ref_name.eq(&dst_ref)))
+                    .execute(conn)?;
+                commits.insert(dst_ref.clone(), commit);
+            }
+
+            if dst_ref.starts_with("app/") {
+                let (filename, contents) = generate_flatpakref(&dst_ref, None, config, repoconfig);
+                let path = appstream_dir.join(&filename);
+                job_log_and_info (self.job_id, conn, &format!("generating {}", &filename));
+                let old_contents = fs::read_to_string(&path).unwrap_or_default();
+                if contents != old_contents {
+                    File::create(&path)?.write_all(contents.as_bytes())?;
+                }
+            }
+        }
+
+        for build_ref in build_refs.iter() {
+            if build_ref.ref_name.starts_with("screenshots/") {
+                job_log_and_info (self.job_id, conn, &format!("extracting {}", build_ref.ref_name));
+                let mut cmd = new_command(config, "ostree", &[screenshots_dir.as_path()], &[build_repo_path.as_path(), repoconfig.path.as_path()]);
+                cmd
+                    .arg(&format!("--repo={}", &build_repo_path.to_str().unwrap()))
+                    .arg("checkout")
+                    .arg("--user-mode")
+                    .arg("--bareuseronly-dirs")
+                    .arg("--union")
+                    .arg(&build_ref.ref_name)
+                    .arg(&screenshots_dir);
+                do_command(cmd, self.job_id, conn)?;
+            }
+        }
+
+        self.install_uploaded_deltas(build_refs, &commits, &build_repo_path, repoconfig, conn);
+
+        unchanged_refs.sort();
+        Ok(ImportedBuild {
+            repo: repoconfig.name.clone(),
+            refs: commits,
+            filtered_refs,
+            unchanged_refs,
+        })
+    }
+}
+
+const MAX_PUBLISH_BATCH: i64 = 20;
+
+/* Marks the publish jobs for the executor's repo that are ready to run
+ * as started, so the running publish job can handle them as well */
+fn claim_queued_publish_jobs(executor: &JobExecutor, conn: &PgConnection) -> Result<Vec<PublishJobInstance>, DieselError> {
+    use diesel::dsl::exists;
+    use diesel::dsl::not;
+    use diesel::dsl::now;
+
+    let repo = match executor.repo {
+        Some(ref repo) => repo.clone(),
+        None => return Ok(vec![]),
+    };
+
+    conn
+        .transaction(|| {
+            let ready_jobs = jobs::table
+                .order(jobs::id)
+                .filter(jobs::kind.eq(JobKind::Publish.to_db()))
+                .filter(jobs::repo.eq(&repo))
+                .filter(jobs::status.eq(JobStatus::New as i16))
+                .filter(jobs::start_after.is_null().or(jobs::start_after.lt(now)))
+                .filter(not(exists(
+                    job_dependencies_with_status::table.filter(
+                        job_dependencies_with_status::job_id.eq(jobs::id)
+                            .and(job_dependencies_with_status::dependant_status.le(JobStatus::Started as i16))
+                    ))))
+                .limit(MAX_PUBLISH_BATCH)
+                .for_update()
+                .skip_locked()
+                .get_results::<models::Job>(conn)?;
+
+            let mut claimed = vec![];
+            for job in ready_jobs {
+                let publish_job = match serde_json::from_str::<PublishJob>(&job.contents) {
+                    Ok(publish_job) => publish_job,
+                    Err(_) => continue, /* Let the executor fail it as usual */
+                };
+                start_job_lease(&executor.config, conn, job.id)?;
+                claimed.push(PublishJobInstance {
+                    job_id: job.id,
+                    build_id: publish_job.build,
+                    note: publish_job.note,
+                    arches: publish_job.arches,
+                    branches: publish_job.branches,
+                    published_by: publish_job.published_by,
+                });
+            }
+            Ok(claimed)
+        })
+}
+
+impl JobInstance for PublishJobInstance {
+    fn get_job_id (&self) -> i32 {
+        self.job_id
+    }
+
+    fn get_kind (&self) -> Option<JobKind> {
+        Some(JobKind::Publish)
+    }
+
+    fn get_build_id (&self) -> Option<i32> {
+        Some(self.build_id)
+    }
+
+    fn order (&self) -> i32 {
+        1 /* Delay publish after commits (and other normal ops). because the
+            commits may generate more publishes. */
+    }
+
+    fn handle_job (&mut self, executor: &JobExecutor, conn: &PgConnection) -> JobResult<serde_json::Value> {
+        let imported = self.import_build(executor, conn);
+
+        /* Import the other builds queued for this repo in the same go, so
+         * they all end up in the same repo update */
+        let mut claimed_imports = vec![];
+        match metrics::time_query("claim_queued_publish_jobs", || claim_queued_publish_jobs(executor, conn)) {
+            Ok(claimed) => {
+                for instance in claimed {
+                    if executor.running_commands.is_shutting_down() {
+                        requeue_interrupted_job(instance.job_id, conn, &JobError::new("Shutting down"));
+                        continue;
+                    }
+                    job_log_and_info(self.job_id, conn,
+                                     &format!("Also publishing build {} (job {})", instance.build_id, instance.job_id));
+                    job_log_and_info(instance.job_id, conn,
+                                     &format!("Published together with job {}", self.job_id));
+                    let span = start_job_span(conn, instance.job_id, JobKind::Publish);
+                    let claimed_imported = {
+                        let _context = logging::job_context(instance.job_id, Some(instance.build_id));
+                        instance.import_build(executor, conn)
+                    };
+                    claimed_imports.push((instance, claimed_imported, span));
+                }
+            },
+            Err(e) => error!("#{}: Failed to claim queued publish jobs: {}", self.job_id, e),
+        }
+
+        /* Then update the repo once for all the imported builds */
+        let imported_repo = imported.as_ref().ok()
+            .or_else(|| claimed_imports.iter().filter_map(|(_, claimed_imported, _)| claimed_imported.as_ref().ok()).next())
+            .map(|imported| imported.repo.clone());
+        let update_job = match imported_repo {
+            Some(repo) => self.queue_repo_update(executor, conn, &repo),
+            None => Err(JobError::new("No build was imported")),
+        };
+
+        for (instance, claimed_imported, _span) in claimed_imports {
+            let _context = logging::job_context(instance.job_id, Some(instance.build_id));
+            if let (Ok(_), Ok(update_job_id)) = (&claimed_imported, &update_job) {
+                job_log_and_info(instance.job_id, conn,
+                                 &format!("Repository update job {} was queued by job {}", update_job_id, self.job_id));
+            }
+            let claimed_res = instance.finish_publish(executor, conn, claimed_imported, &update_job);
+            finish_job(executor, conn, instance.job_id, Some(instance.build_id), claimed_res);
+        }
+
+        self.finish_publish(executor, conn, imported, &update_job)
+    }
+}
+
+#[derive(Debug)]
+struct UpdateRepoJobInstance {
+    pub delta_generator: Addr<DeltaGenerator>,
+    pub job_id: i32,
+    pub repo: String,
+    pub regenerate_deltas: bool, // Generate all the wanted deltas again, not only the missing ones
+}
+
+/* appstream2/x86_64 -> appstream/x86_64 */
+fn legacy_appstream_ref(appstream2_ref: &str) -> String {
+    format!("appstream/{}", appstream2_ref.trim_start_matches("appstream2/"))
+}
+
+impl UpdateRepoJobInstance {
+    fn new(job: Job, delta_generator: Addr<DeltaGenerator>, regenerate_deltas: bool) -> Box<dyn JobInstance> {
+        if let Ok(update_repo_job) = serde_json::from_str::<UpdateRepoJob>(&job.contents) {
+            Box::new(UpdateRepoJobInstance {
+                delta_generator: delta_generator,
+                job_id: job.id,
+                repo: update_repo_job.repo,
+                regenerate_deltas,
+            })
+        } else {
+            InvalidJobInstance::new(job, JobError::new("Can't parse publish job"))
+        }
+    }
+
+    fn calculate_deltas(&self, repoconfig: &RepoConfig) -> (HashSet<ostree::Delta>, HashSet<ostree::Delta>) {
+        let repo_path = repoconfig.get_abs_repo_path();
+
+        let mut wanted_deltas = HashSet::new();
+        let refs = ostree::list_refs (&repo_path, "");
+
+        for ref_name in refs {
+            let depth = repoconfig.get_delta_depth_for_ref(&ref_name);
+
+            if depth > 0 {
+                let ref_deltas = ostree::calc_deltas_for_ref(&repo_path, &ref_name, depth);
+                for ref_delta in ref_deltas {
+                    wanted_deltas.insert(ref_delta);
+                }
+            }
+        }
+        let old_deltas = HashSet::from_iter(ostree::list_deltas (&repo_path).iter().cloned());
+
+        /* When regenerating, the wanted deltas are generated over the
+         * existing ones, so these stay available until they are replaced */
+        let missing_deltas = if self.regenerate_deltas {
+            wanted_deltas.clone()
+        } else {
+            wanted_deltas.difference(&old_deltas).cloned().collect()
+        };
+        let unwanted_deltas = old_deltas.difference(&wanted_deltas).cloned().collect();
+
+        (missing_deltas, unwanted_deltas)
+    }
+
+    fn generate_deltas(&self,
+                       deltas: &HashSet<ostree::Delta>,
+                       repoconfig: &RepoConfig,
+                       conn: &PgConnection) -> JobResult<usize> {
+        let mut span = tracing::start_span("generate-deltas");
+        span.tag("deltas", &deltas.len().to_string());
+        job_log_and_info(self.job_id, conn, "Generating deltas");
+
+        let (tx, rx) = mpsc::channel();
+
+        /* We can't use a regular .send() here, as that requres a current task which is
+         * not available in a sync actor like this. Instead we use the non-blocking
+         * do_send and implement returns using a mpsc::channel.
+        */
+
+        for delta in deltas.iter() {
+            self.delta_generator.do_send(DeltaRequestSync {
+                delta_request: DeltaRequest {
+                    repo: repoconfig.name.clone(),
+                    delta: delta.clone(),
+                },
+                tx: tx.clone(),
+            })
+        }
+
+        let mut failed = 0;
+        job_progress(self.job_id, conn, "deltas", 0, deltas.len());
+        for (done, (delta, result)) in rx.iter().take(deltas.len()).enumerate() {
+            let message = match result {
+                Ok(()) => format!(" {}", delta.to_string()),
+                Err(e) => {
+                    failed += 1;
+                    format!(" failed to generate {}: {}", delta.to_string(), e)
+                },
+            };
+            job_log_and_info(self.job_id, conn, &message);
+            job_progress(self.job_id, conn, "deltas", done + 1, deltas.len());
+        }
+
+        job_log_and_info(self.job_id, conn, "All deltas generated");
+
+        Ok(failed)
+    }
+
+    fn retire_deltas(&self,
+                     deltas: &HashSet<ostree::Delta>,
+                     repoconfig: &RepoConfig,
+                     conn: &PgConnection) -> JobResult<()> {
+        job_log_and_info(self.job_id, conn, "Cleaning out old deltas");
+        let repo_path = repoconfig.get_abs_repo_path();
+        let deltas_dir = repo_path.join("deltas");
+        let tmp_deltas_dir = repo_path.join("tmp/deltas");
+        fs::create_dir_all(&tmp_deltas_dir)?;
+
+        let now = time::SystemTime::now();
+        let now_filetime = filetime::FileTime::from_system_time(now);
+
+        /* Instead of directly removing the deltas we move them to a different
+         * directory which is *also* used as a source for the delta dir when accessed
          * via http. This way we avoid them disappearing while possibly in use, yet
          * ensure they are not picked up for the new summary file */
 
@@ -847,10 +2237,11 @@ impl UpdateRepoJobInstance {
                          config: &Config,
                          repoconfig: &RepoConfig,
                          conn: &PgConnection) -> JobResult<()> {
+        let _span = tracing::start_span("update-appstream");
         job_log_and_info(self.job_id, conn, "Regenerating appstream branches");
         let repo_path = repoconfig.get_abs_repo_path();
 
-        let mut cmd = Command::new("flatpak");
+        let mut cmd = new_command(config, "flatpak", &[repo_path.as_path()], &[]);
         cmd
             .arg("build-update-repo")
             .arg("--no-update-summary");
@@ -858,7 +2249,144 @@ impl UpdateRepoJobInstance {
         cmd
             .arg(&repo_path);
 
-        do_command(cmd)?;
+        do_command(cmd, self.job_id, conn)?;
+        Ok(())
+    }
+
+    /* Rewrites the screenshot urls in the appstream2 branches (which
+     * build-update-repo just regenerated) to our mirror */
+    fn checkout_appstream (&self,
+                           config: &Config,
+                           repo_path: &Path,
+                           appstream_ref: &str,
+                           conn: &PgConnection) -> JobResult<(tempfile::TempDir, PathBuf)> {
+        let checkout_dir = tempfile::Builder::new()
+            .prefix("appstream-")
+            .tempdir_in(repo_path.join("tmp"))?;
+        let tree_path = checkout_dir.path().join("tree");
+
+        let mut cmd = new_command(config, "ostree", &[checkout_dir.path()], &[repo_path]);
+        cmd
+            .arg(format!("--repo={}", repo_path.display()))
+            .arg("checkout")
+            .arg("--user-mode")
+            .arg(appstream_ref)
+            .arg(&tree_path);
+        do_command(cmd, self.job_id, conn)?;
+        Ok((checkout_dir, tree_path))
+    }
+
+    /* The screenshot urls in the appstream data that aren't mirrored yet */
+    fn list_screenshot_urls (&self,
+                             config: &Config,
+                             repoconfig: &RepoConfig,
+                             screenshots_config: &ScreenshotsConfig,
+                             conn: &PgConnection) -> JobResult<Vec<String>> {
+        let repo_path = repoconfig.get_abs_repo_path();
+        let media_url = screenshots_config.media_url.trim_end_matches('/').to_string();
+
+        let mut urls = vec![];
+        for appstream_ref in ostree::list_refs(&repo_path, "appstream2") {
+            let (_checkout_dir, tree_path) = self.checkout_appstream(config, &repo_path, &appstream_ref, conn)?;
+            let xml = fs::read_to_string(tree_path.join("appstream.xml"))?;
+            for url in screenshots::list_image_urls(&xml) {
+                if url.starts_with(&media_url) || !(url.starts_with("http://") || url.starts_with("https://")) {
+                    continue;
+                }
+                if screenshots::mirrored_url(screenshots_config, &url).is_none() && !urls.contains(&url) {
+                    urls.push(url);
+                }
+            }
+        }
+        Ok(urls)
+    }
+
+    /* This runs without the repo lock, as downloads can be slow */
+    fn download_screenshots (&self,
+                             config: &Config,
+                             screenshots_config: &ScreenshotsConfig,
+                             urls: &[String],
+                             conn: &PgConnection) {
+        let _span = tracing::start_span("download-screenshots");
+        let new_curl = || new_network_command(config, "curl", &[screenshots_config.media_dir.as_path()], &[]);
+        let mut failed = 0;
+        for url in urls {
+            if let Err(e) = screenshots::download_screenshot(screenshots_config, &new_curl, url) {
+                job_log_and_info(self.job_id, conn, &format!("Failed to mirror screenshot {}: {}", url, e));
+                failed += 1;
+            }
+        }
+        job_log_and_info(self.job_id, conn,
+                         &format!("Downloaded {} screenshots ({} failed)", urls.len() - failed, failed));
+    }
+
+    /* Commits the tree of a checkout_appstream() checkout */
+    fn commit_appstream (&self,
+                         config: &Config,
+                         repoconfig: &RepoConfig,
+                         checkout_dir: &Path,
+                         branch: &str,
+                         conn: &PgConnection) -> JobResult<()> {
+        let repo_path = repoconfig.get_abs_repo_path();
+        let tree_path = checkout_dir.join("tree");
+        let mut cmd = new_command(config, "ostree", &[repo_path.as_path()], &[checkout_dir]);
+        cmd
+            .arg(format!("--repo={}", repo_path.display()))
+            .arg("commit")
+            .arg(format!("--branch={}", branch))
+            .arg("--subject=Mirror screenshots")
+            .arg("--owner-uid=0")
+            .arg("--owner-gid=0")
+            .arg("--no-xattrs");
+        add_gpg_args(&mut cmd, &repoconfig.gpg_key, &config.gpg_homedir);
+        cmd
+            .arg(&tree_path);
+        do_command(cmd, self.job_id, conn)
+    }
+
+    /* Points the appstream data to the downloaded screenshots. The old
+     * appstream branch of an arch has the same tree as the appstream2 one,
+     * except for having the xml gzipped, so both are committed from the
+     * checkout of appstream2. */
+    fn mirror_screenshots (&self,
+                           config: &Config,
+                           repoconfig: &RepoConfig,
+                           screenshots_config: &ScreenshotsConfig,
+                           conn: &PgConnection) -> JobResult<()> {
+        let _span = tracing::start_span("mirror-screenshots");
+        let repo_path = repoconfig.get_abs_repo_path();
+        let media_url = screenshots_config.media_url.trim_end_matches('/').to_string();
+        let legacy_refs = ostree::list_refs(&repo_path, "appstream");
+
+        for appstream_ref in ostree::list_refs(&repo_path, "appstream2") {
+            let (checkout_dir, tree_path) = self.checkout_appstream(config, &repo_path, &appstream_ref, conn)?;
+
+            let xml_path = tree_path.join("appstream.xml");
+            let xml = fs::read_to_string(&xml_path)?;
+            let (new_xml, n_mirrored) = screenshots::rewrite_image_urls(&xml, |url| {
+                if url.starts_with(&media_url) {
+                    return None
+                }
+                screenshots::mirrored_url(screenshots_config, url)
+            });
+            if n_mirrored == 0 {
+                continue;
+            }
+
+            job_log_and_info(self.job_id, conn,
+                             &format!("Mirrored {} screenshots in {}", n_mirrored, appstream_ref));
+            fs::write(&xml_path, &new_xml)?;
+            self.commit_appstream(config, repoconfig, checkout_dir.path(), &appstream_ref, conn)?;
+
+            let legacy_ref = legacy_appstream_ref(&appstream_ref);
+            if legacy_refs.contains(&legacy_ref) {
+                fs::remove_file(&xml_path)?;
+                let mut encoder = GzEncoder::new(File::create(tree_path.join("appstream.xml.gz"))?, Compression::default());
+                encoder.write_all(new_xml.as_bytes())?;
+                encoder.finish()?;
+                self.commit_appstream(config, repoconfig, checkout_dir.path(), &legacy_ref, conn)?;
+            }
+        }
         Ok(())
     }
 
@@ -866,37 +2394,83 @@ impl UpdateRepoJobInstance {
                        config: &Config,
                        repoconfig: &RepoConfig,
                        conn: &PgConnection) -> JobResult<()> {
+        let _span = tracing::start_span("update-summary");
         job_log_and_info(self.job_id, conn, "Updating summary");
         let repo_path = repoconfig.get_abs_repo_path();
 
-        let mut cmd = Command::new("flatpak");
+        let mut cmd = new_command(config, "flatpak", &[repo_path.as_path()], &[]);
         cmd
             .arg("build-update-repo")
             .arg("--no-update-appstream");
+        if !repoconfig.subsummaries {
+            /* Older versions don't write a summary index anyway */
+            if config.tools.build_update_repo_supports("--no-summary-index") {
+                cmd.arg("--no-summary-index");
+            }
+        }
+        let ignore_patterns = repoconfig.get_static_delta_ignore_patterns();
+        if !ignore_patterns.is_empty() && !config.tools.build_update_repo_supports("--static-delta-ignore-ref") {
+            job_log_and_info(self.job_id, conn,
+                             &format!("flatpak {} doesn't support --static-delta-ignore-ref, generating deltas for all refs",
+                                      config.tools.flatpak.as_deref().unwrap_or("(unknown version)")));
+        } else {
+            for pattern in ignore_patterns {
+                cmd.arg(format!("--static-delta-ignore-ref={}", pattern));
+            }
+        }
         add_gpg_args(&mut cmd, &repoconfig.gpg_key, &config.gpg_homedir);
         cmd
             .arg(&repo_path);
 
-        do_command(cmd)?;
+        do_command(cmd, self.job_id, conn)?;
         Ok(())
     }
 
+    /* flatpak writes a summary.idx with a subsummary per arch next to
+     * the summary, so clients only download the refs for their arch */
+    fn check_subsummaries (&self,
+                           repoconfig: &RepoConfig,
+                           conn: &PgConnection) -> JobResult<Vec<String>> {
+        if !repoconfig.subsummaries {
+            return Ok(vec![]);
+        }
+
+        match ostree::list_subsummaries(&repoconfig.get_abs_repo_path()) {
+            Ok(subsummaries) => {
+                let mut names: Vec<String> = subsummaries.keys().cloned().collect();
+                names.sort();
+                job_log_and_info(self.job_id, conn, &format!("Subsummaries: {}", names.join(", ")));
+                Ok(names)
+            },
+            Err(ostree::OstreeError::NoSuchObject(_)) => {
+                job_log_and_error(self.job_id, conn, "No summary.idx generated, subsummaries need flatpak 1.9.1 or later");
+                Ok(vec![])
+            },
+            Err(e) => Err(e.into()),
+        }
+    }
+
     fn run_post_publish (&self,
+                         config: &Config,
                          repoconfig: &RepoConfig,
                          conn: &PgConnection) -> JobResult<()> {
         if let Some(post_publish_script) = &repoconfig.post_publish_script {
             let repo_path = repoconfig.get_abs_repo_path();
-            let mut cmd = Command::new(post_publish_script);
+            /* The script typically syncs the repo somewhere, so it gets the network */
+            let mut cmd = new_network_command(config, post_publish_script, &[repo_path.as_path()],
+                                              &[Path::new(post_publish_script)]);
             cmd
                 .arg(&repoconfig.name)
                 .arg(&repo_path);
             job_log_and_info(self.job_id, conn, "Running post-publish script");
-            do_command(cmd)?;
+            run_command(cmd, self.job_id, conn,
+                        repoconfig.post_publish_timeout_secs.map(time::Duration::from_secs))?;
         };
         Ok(())
     }
 
     fn extract_appstream (&self,
+                          config: &Config,
                           repoconfig: &RepoConfig,
                           conn: &PgConnection) -> JobResult<()> {
         job_log_and_info(self.job_id, conn, "Extracting appstream branches");
@@ -905,7 +2479,7 @@ impl UpdateRepoJobInstance {
         let appstream_refs = ostree::list_refs (&repoconfig.path, "appstream");
         for appstream_ref in appstream_refs {
             let arch = appstream_ref.split("/").nth(1).unwrap();
-            let mut cmd = Command::new("ostree");
+            let mut cmd = new_command(config, "ostree", &[repo_path.as_path()], &[]);
             cmd
                 .arg(&format!("--repo={}", &repoconfig.path.to_str().unwrap()))
                 .arg("checkout")
@@ -914,44 +2488,1307 @@ impl UpdateRepoJobInstance {
                 .arg("--bareuseronly-dirs")
                 .arg(&appstream_ref)
                 .arg(appstream_dir.join(arch));
-            do_command(cmd)?;
+            do_command(cmd, self.job_id, conn)?;
         };
         Ok(())
     }
 }
 
-
-impl JobInstance for UpdateRepoJobInstance {
+
+impl JobInstance for UpdateRepoJobInstance {
+    fn get_job_id (&self) -> i32 {
+        self.job_id
+    }
+
+    fn get_kind (&self) -> Option<JobKind> {
+        if self.regenerate_deltas {
+            Some(JobKind::RegenerateDeltas)
+        } else {
+            Some(JobKind::UpdateRepo)
+        }
+    }
+
+    fn order (&self) -> i32 {
+        if self.regenerate_deltas {
+            4 /* Like prune */
+        } else {
+            2 /* Delay updates after publish so they can be chunked. */
+        }
+    }
+
+    fn handle_job (&mut self, executor: &JobExecutor, conn: &PgConnection) -> JobResult<serde_json::Value> {
+        info!("#{}: Handling Job UpdateRepo: repo: {}",
+              &self.job_id, &self.repo);
+
+        // Get repo config
+        let config = &executor.config;
+        let repoconfig = config.get_repoconfig(&self.repo)
+            .or_else(|_e| Err(JobError::new(&format!("Can't find repo {}", &self.repo))))?;
+        let mut lock = lock_repo(self.job_id, conn, &self.repo)?;
+
+        self.update_appstream(config, repoconfig, conn)?;
+        if let Some(ref screenshots_config) = repoconfig.screenshots {
+            let urls = self.list_screenshot_urls(config, repoconfig, screenshots_config, conn)?;
+            if !urls.is_empty() {
+                drop(lock);
+                self.download_screenshots(config, screenshots_config, &urls, conn);
+                lock = lock_repo(self.job_id, conn, &self.repo)?;
+            }
+            self.mirror_screenshots(config, repoconfig, screenshots_config, conn)?;
+        }
+        let _lock = lock;
+
+        let (missing_deltas, unwanted_deltas) = self.calculate_deltas(repoconfig);
+        let deltas_failed = self.generate_deltas(&missing_deltas, repoconfig, conn)?;
+        self.retire_deltas(&unwanted_deltas, repoconfig, conn)?;
+
+        self.update_summary(config, repoconfig, conn)?;
+        let subsummaries = self.check_subsummaries(repoconfig, conn)?;
+
+        self.run_post_publish(config, repoconfig, conn)?;
+
+        self.extract_appstream(config, repoconfig, conn)?;
+
+        Ok(json!(JobResults::new(UpdateRepoJobResult {
+            deltas_generated: missing_deltas.len() - deltas_failed,
+            deltas_failed,
+            deltas_retired: unwanted_deltas.len(),
+            subsummaries,
+        })))
+    }
+}
+
+#[derive(Debug)]
+struct OciExportJobInstance {
+    pub job_id: i32,
+    pub repo: String,
+    pub refs: Vec<String>,
+}
+
+impl OciExportJobInstance {
+    fn new(job: Job) -> Box<dyn JobInstance> {
+        if let Ok(oci_export_job) = serde_json::from_str::<OciExportJob>(&job.contents) {
+            Box::new(OciExportJobInstance {
+                job_id: job.id,
+                repo: oci_export_job.repo,
+                refs: oci_export_job.refs,
+            })
+        } else {
+            InvalidJobInstance::new(job, JobError::new("Can't parse oci export job"))
+        }
+    }
+
+    fn export_ref(&self, ref_name: &str, repo_path: &PathBuf, config: &Config, registry: &OciRegistryConfig, conn: &PgConnection) -> JobResult<String> {
+        let parts: Vec<&str> = ref_name.split('/').collect();
+        if parts.len() != 4 || (parts[0] != "app" && parts[0] != "runtime") {
+            return Err(JobError::new(&format!("Invalid ref {}", ref_name)));
+        }
+        ostree::parse_ref(repo_path, ref_name)
+            .map_err(|_e| JobError::new(&format!("Ref {} is not published", ref_name)))?;
+
+        let oci_dir = tempfile::Builder::new()
+            .prefix("oci-export-")
+            .tempdir_in(repo_path.join("tmp"))?;
+        let image_path = oci_dir.path().join("image");
+
+        job_log_and_info(self.job_id, conn, &format!("Building oci image for {}", ref_name));
+        let mut cmd = new_command(config, "flatpak", &[oci_dir.path()], &[repo_path.as_path()]);
+        cmd
+            .arg("build-bundle")
+            .arg("--oci")
+            .arg(format!("--arch={}", parts[2]));
+        if parts[0] == "runtime" {
+            cmd.arg("--runtime");
+        }
+        cmd
+            .arg(repo_path)
+            .arg(&image_path)
+            .arg(parts[1])
+            .arg(parts[3]);
+        do_command(cmd, self.job_id, conn)?;
+
+        /* Image names in registries have to be lowercase */
+        let destination = format!("{}/{}:{}-{}", registry.url.trim_end_matches('/'),
+                                  parts[1].to_lowercase(), parts[3], parts[2]);
+        job_log_and_info(self.job_id, conn, &format!("Pushing {} to {}", ref_name, destination));
+        let mut readonly_paths = vec![oci_dir.path()];
+        if let Some(ref authfile) = registry.authfile {
+            readonly_paths.push(Path::new(authfile));
+        }
+        let mut cmd = new_network_command(config, "skopeo", &[], &readonly_paths);
+        cmd.arg("copy");
+        if let Some(ref authfile) = registry.authfile {
+            cmd.arg("--authfile").arg(authfile);
+        }
+        cmd
+            .arg(format!("oci:{}", image_path.display()))
+            .arg(&destination);
+        do_command(cmd, self.job_id, conn)?;
+
+        Ok(destination)
+    }
+}
+
+impl JobInstance for OciExportJobInstance {
+    fn get_job_id (&self) -> i32 {
+        self.job_id
+    }
+
+    fn get_kind (&self) -> Option<JobKind> {
+        Some(JobKind::OciExport)
+    }
+
+    fn order (&self) -> i32 {
+        3 /* Export after the repo has been updated */
+    }
+
+    fn handle_job (&mut self, executor: &JobExecutor, conn: &PgConnection) -> JobResult<serde_json::Value> {
+        info!("#{}: Handling Job OciExport: repo: {}, refs: {:?}",
+              &self.job_id, &self.repo, &self.refs);
+
+        let repoconfig = executor.config.get_repoconfig(&self.repo).map_err(|_e| JobError::new(&format!("Can't find repo {}", &self.repo)))?;
+        let registry = repoconfig.oci_registry.as_ref()
+            .ok_or_else(|| JobError::new(&format!("No oci registry configured for repo {}", &self.repo)))?;
+        let repo_path = repoconfig.get_abs_repo_path();
+        let _lock = lock_repo(self.job_id, conn, &self.repo)?;
+
+        let mut images = HashMap::<String, String>::new();
+        for ref_name in self.refs.iter() {
+            let destination = self.export_ref(ref_name, &repo_path, &executor.config, registry, conn)?;
+            images.insert(ref_name.clone(), destination);
+        }
+
+        Ok(json!(JobResults::new(OciExportJobResult { images })))
+    }
+}
+
+#[derive(Debug)]
+struct BundleJobInstance {
+    pub job_id: i32,
+    pub ref_name: String,
+    pub build_id: Option<i32>,
+    pub repo: String,
+}
+
+impl BundleJobInstance {
+    fn new(job: Job) -> Box<dyn JobInstance> {
+        if let Ok(bundle_job) = serde_json::from_str::<BundleJob>(&job.contents) {
+            Box::new(BundleJobInstance {
+                job_id: job.id,
+                ref_name: bundle_job.ref_name,
+                build_id: bundle_job.build,
+                repo: bundle_job.repo,
+            })
+        } else {
+            InvalidJobInstance::new(job, JobError::new("Can't parse bundle job"))
+        }
+    }
+}
+
+impl JobInstance for BundleJobInstance {
+    fn get_job_id (&self) -> i32 {
+        self.job_id
+    }
+
+    fn get_kind (&self) -> Option<JobKind> {
+        Some(JobKind::Bundle)
+    }
+
+    fn get_build_id (&self) -> Option<i32> {
+        self.build_id
+    }
+
+    fn handle_job (&mut self, executor: &JobExecutor, conn: &PgConnection) -> JobResult<serde_json::Value> {
+        info!("#{}: Handling Job Bundle: ref: {}, build: {:?}, repo: {}",
+              &self.job_id, &self.ref_name, &self.build_id, &self.repo);
+
+        let config = &executor.config;
+        let repoconfig = config.get_repoconfig(&self.repo).map_err(|_e| JobError::new(&format!("Can't find repo {}", &self.repo)))?;
+
+        let (repo_path, repo_url) = match self.build_id {
+            Some(build_id) => (config.build_repo_base.join(build_id.to_string()),
+                               format!("{}/build-repo/{}", config.base_url, build_id)),
+            None => (repoconfig.get_abs_repo_path(),
+                     repoconfig.get_base_url(config)),
+        };
+
+        let _lock = match self.build_id {
+            Some(build_id) => lock_build(self.job_id, conn, build_id)?,
+            None => lock_repo(self.job_id, conn, &self.repo)?,
+        };
+
+        let parts: Vec<&str> = self.ref_name.split('/').collect();
+        if parts.len() != 4 || (parts[0] != "app" && parts[0] != "runtime") {
+            return Err(JobError::new(&format!("Invalid ref {}", self.ref_name)));
+        }
+        ostree::parse_ref(&repo_path, &self.ref_name)
+            .map_err(|_e| JobError::new(&format!("No ref {} in repo", self.ref_name)))?;
+
+        fs::create_dir_all(&config.bundle_dir)?;
+        let bundle_path = config.bundle_dir.join(format!("{}.flatpak", self.job_id));
+        let tmp_path = config.bundle_dir.join(format!("{}.flatpak.tmp", self.job_id));
+
+        job_log_and_info(self.job_id, conn, &format!("Creating bundle for {}", self.ref_name));
+        let mut cmd = new_command(config, "flatpak", &[config.bundle_dir.as_path()], &[repo_path.as_path(), repoconfig.path.as_path()]);
+        cmd
+            .arg("build-bundle")
+            .arg(format!("--arch={}", parts[2]))
+            .arg(format!("--repo-url={}", repo_url));
+        if parts[0] == "runtime" {
+            cmd.arg("--runtime");
+        } else if let Some(ref runtime_repo_url) = repoconfig.runtime_repo_url {
+            cmd.arg(format!("--runtime-repo={}", runtime_repo_url));
+        }
+        cmd
+            .arg(&repo_path)
+            .arg(&tmp_path)
+            .arg(parts[1])
+            .arg(parts[3]);
+
+        if let Err(e) = do_command(cmd, self.job_id, conn) {
+            let _ = fs::remove_file(&tmp_path);
+            return Err(e);
+        }
+        fs::rename(&tmp_path, &bundle_path)?;
+
+        Ok(json!(JobResults::new(BundleJobResult { bundle: format!("{}.flatpak", self.job_id) })))
+    }
+}
+
+pub fn queue_prune_job(conn: &PgConnection,
+                       repo: &str,
+                       depth: i32,
+                       dry_run: bool,
+                       scheduled: bool,
+                       delay_secs: u64) -> Result<Job, DieselError> {
+    diesel::insert_into(schema::jobs::table)
+        .values(NewJob {
+            kind: JobKind::Prune.to_db(),
+            repo: Some(repo.to_string()),
+            start_after: Some(time::SystemTime::now() + time::Duration::from_secs(delay_secs)),
+            trace_parent: tracing::current_traceparent(),
+            contents: json!(PruneJob {
+                repo: repo.to_string(),
+                depth,
+                dry_run,
+                scheduled,
+            }).to_string(),
+        })
+        .get_result::<Job>(conn)
+}
+
+/* Makes sure there is a queued scheduled prune job for the repo, if it
+ * has pruning configured */
+fn schedule_prune_job(conn: &PgConnection, repoconfig: &RepoConfig) -> Result<(), DieselError> {
+    let prune = match repoconfig.prune {
+        Some(ref prune) => prune,
+        None => return Ok(()),
+    };
+
+    let queued = jobs::table
+        .filter(jobs::kind.eq(JobKind::Prune.to_db()))
+        .filter(jobs::status.eq(JobStatus::New as i16))
+        .filter(jobs::repo.eq(&repoconfig.name))
+        .get_results::<Job>(conn)?
+        .into_iter()
+        .any(|job| serde_json::from_str::<PruneJob>(&job.contents).map(|data| data.scheduled).unwrap_or(false));
+    if !queued {
+        queue_prune_job(conn, &repoconfig.name, prune.depth, prune.dry_run, true, prune.interval_secs)?;
+    }
+    Ok(())
+}
+
+#[derive(Debug)]
+struct PruneJobInstance {
+    pub job_id: i32,
+    pub repo: String,
+    pub depth: i32,
+    pub dry_run: bool,
+    pub scheduled: bool,
+}
+
+impl PruneJobInstance {
+    fn new(job: Job) -> Box<dyn JobInstance> {
+        if let Ok(prune_job) = serde_json::from_str::<PruneJob>(&job.contents) {
+            Box::new(PruneJobInstance {
+                job_id: job.id,
+                repo: prune_job.repo,
+                depth: prune_job.depth,
+                dry_run: prune_job.dry_run,
+                scheduled: prune_job.scheduled,
+            })
+        } else {
+            InvalidJobInstance::new(job, JobError::new("Can't parse prune job"))
+        }
+    }
+
+    /* ostree prune --no-prune only reports what would be removed, the
+     * last line is like "Would delete: 12 objects, freeing 3.4 MB" */
+    fn check_prune(&self, config: &Config, repo_path: &Path, pinned_refs: &[String], conn: &PgConnection) -> JobResult<Option<String>> {
+        let mut cmd = new_command(config, "ostree", &[repo_path], &[]);
+        cmd
+            .arg(format!("--repo={}", repo_path.display()))
+            .arg("prune")
+            .arg("--refs-only")
+            .arg("--no-prune")
+            .arg(format!("--depth={}", self.depth));
+        for ref_name in pinned_refs {
+            cmd.arg(format!("--retain-branch-depth={}=-1", ref_name));
+        }
+        let stdout_tail = run_command(cmd, self.job_id, conn, None)?;
+        Ok(stdout_tail.last().cloned())
+    }
+
+    fn prune(&self, config: &Config, repoconfig: &RepoConfig, repo_path: &PathBuf, pinned_refs: &[String], conn: &PgConnection) -> JobResult<()> {
+        if pinned_refs.is_empty() {
+            let mut cmd = new_command(config, "flatpak", &[repo_path.as_path()], &[]);
+            cmd
+                .arg("build-update-repo")
+                .arg("--no-update-appstream")
+                .arg("--prune")
+                .arg(format!("--prune-depth={}", self.depth));
+            add_gpg_args(&mut cmd, &repoconfig.gpg_key, &config.gpg_homedir);
+            cmd
+                .arg(repo_path);
+            return do_command(cmd, self.job_id, conn);
+        }
+
+        /* flatpak can't keep the history of some refs, so prune with
+         * ostree and only update the summary with flatpak */
+        let mut cmd = new_command(config, "ostree", &[repo_path.as_path()], &[]);
+        cmd
+            .arg(format!("--repo={}", repo_path.display()))
+            .arg("prune")
+            .arg("--refs-only")
+            .arg(format!("--depth={}", self.depth));
+        for ref_name in pinned_refs {
+            cmd.arg(format!("--retain-branch-depth={}=-1", ref_name));
+        }
+        do_command(cmd, self.job_id, conn)?;
+
+        let mut cmd = new_command(config, "flatpak", &[repo_path.as_path()], &[]);
+        cmd
+            .arg("build-update-repo")
+            .arg("--no-update-appstream");
+        add_gpg_args(&mut cmd, &repoconfig.gpg_key, &config.gpg_homedir);
+        cmd
+            .arg(repo_path);
+        do_command(cmd, self.job_id, conn)
+    }
+}
+
+/* The pin refs are refs of this (unconfigured) remote, so they are not
+ * in the summary, nor in anything else that goes by the refs under
+ * refs/heads, like the deltas */
+const PIN_REMOTE: &str = "flat-manager-pins";
+
+/* Pinned commits are kept alive by a ref each, which pruning respects
+ * like any other ref. Returns the refs that are pinned as a whole,
+ * which pruning has to keep the history of. */
+fn sync_pin_refs(job_id: i32, repo: &str, repo_path: &PathBuf, conn: &PgConnection) -> JobResult<Vec<String>> {
+    let pins = ref_pins::table
+        .filter(ref_pins::repo.eq(repo))
+        .get_results::<models::RefPin>(conn)?;
+
+    let wanted: HashMap<String, &str> = pins.iter()
+        .filter_map(|pin| pin.commit.as_ref().map(|commit| (pin.id.to_string(), commit.as_str())))
+        .collect();
+    let existing = ostree::list_remote_refs(repo_path, PIN_REMOTE);
+
+    for ref_name in existing.keys().filter(|ref_name| !wanted.contains_key(*ref_name)) {
+        ostree::set_remote_ref(repo_path, PIN_REMOTE, ref_name, None)?;
+    }
+    for (ref_name, commit) in wanted.iter() {
+        if existing.get(ref_name).map(|c| c.as_str()) == Some(*commit) {
+            continue;
+        }
+        if ostree::get_commit(repo_path, &commit.to_string()).is_err() {
+            job_log_and_info(job_id, conn, &format!("Pinned commit {} is not in the repo", commit));
+            continue;
+        }
+        ostree::set_remote_ref(repo_path, PIN_REMOTE, ref_name, Some(commit))?;
+    }
+
+    let mut pinned_refs: Vec<String> = pins.into_iter()
+        .filter(|pin| pin.commit.is_none())
+        .map(|pin| pin.ref_name)
+        .collect();
+    pinned_refs.sort();
+    pinned_refs.dedup();
+    Ok(pinned_refs)
+}
+
+impl JobInstance for PruneJobInstance {
+    fn get_job_id (&self) -> i32 {
+        self.job_id
+    }
+
+    fn get_kind (&self) -> Option<JobKind> {
+        Some(JobKind::Prune)
+    }
+
+    fn order (&self) -> i32 {
+        4 /* Prune when nothing else is pending for the repo */
+    }
+
+    fn handle_job (&mut self, executor: &JobExecutor, conn: &PgConnection) -> JobResult<serde_json::Value> {
+        info!("#{}: Handling Job Prune: repo: {}, depth: {}, dry-run: {}",
+              &self.job_id, &self.repo, self.depth, self.dry_run);
+
+        let config = &executor.config;
+        let repoconfig = config.get_repoconfig(&self.repo).map_err(|_e| JobError::new(&format!("Can't find repo {}", &self.repo)))?;
+        let repo_path = repoconfig.get_abs_repo_path();
+
+        /* Queue the next run first, so the schedule continues even if this one fails */
+        if self.scheduled {
+            schedule_prune_job(conn, repoconfig)?;
+        }
+
+        let _lock = lock_repo(self.job_id, conn, &self.repo)?;
+
+        let pinned_refs = sync_pin_refs(self.job_id, &self.repo, &repo_path, conn)?;
+        if !pinned_refs.is_empty() {
+            job_log_and_info(self.job_id, conn, &format!("Keeping the history of pinned refs {}", pinned_refs.join(", ")));
+        }
+
+        let summary = if self.dry_run {
+            job_log_and_info(self.job_id, conn, &format!("Checking what pruning to depth {} would remove", self.depth));
+            self.check_prune(config, &repo_path, &pinned_refs, conn)?
+        } else {
+            job_log_and_info(self.job_id, conn, &format!("Pruning to depth {}", self.depth));
+            self.prune(config, repoconfig, &repo_path, &pinned_refs, conn)?;
+            None
+        };
+
+        Ok(json!(JobResults::new(PruneJobResult {
+            dry_run: self.dry_run,
+            depth: self.depth,
+            summary,
+        })))
+    }
+}
+
+pub fn queue_gc_job(conn: &PgConnection,
+                    repo: &str,
+                    dry_run: bool,
+                    scheduled: bool,
+                    delay_secs: u64) -> Result<Job, DieselError> {
+    diesel::insert_into(schema::jobs::table)
+        .values(NewJob {
+            kind: JobKind::Gc.to_db(),
+            repo: Some(repo.to_string()),
+            start_after: Some(time::SystemTime::now() + time::Duration::from_secs(delay_secs)),
+            trace_parent: tracing::current_traceparent(),
+            contents: json!(GcJob {
+                repo: repo.to_string(),
+                dry_run,
+                scheduled,
+            }).to_string(),
+        })
+        .get_result::<Job>(conn)
+}
+
+fn schedule_gc_job(conn: &PgConnection, repoconfig: &RepoConfig) -> Result<(), DieselError> {
+    let gc = match repoconfig.gc {
+        Some(ref gc) => gc,
+        None => return Ok(()),
+    };
+
+    let queued = jobs::table
+        .filter(jobs::kind.eq(JobKind::Gc.to_db()))
+        .filter(jobs::status.eq(JobStatus::New as i16))
+        .filter(jobs::repo.eq(&repoconfig.name))
+        .get_results::<Job>(conn)?
+        .into_iter()
+        .any(|job| serde_json::from_str::<GcJob>(&job.contents).map(|data| data.scheduled).unwrap_or(false));
+    if !queued {
+        queue_gc_job(conn, &repoconfig.name, gc.dry_run, true, gc.interval_secs)?;
+    }
+    Ok(())
+}
+
+/* When each currently unreachable object was first seen unreachable,
+ * kept between gc runs. It is a dot file, so it isn't served. */
+const GC_STATE_FILE: &str = ".flat-manager-gc-state.json";
+
+/* Deletes the objects that no ref leads to. An object only goes once it
+ * has been unreachable for the grace period, as clients may still be
+ * pulling a commit that a ref pointed to until recently. Objects of
+ * builds that are not committed yet are kept too, as uploads skip the
+ * objects that are already in the repo. */
+#[derive(Debug)]
+struct GcJobInstance {
+    pub job_id: i32,
+    pub repo: String,
+    pub dry_run: bool,
+    pub scheduled: bool,
+}
+
+impl GcJobInstance {
+    fn new(job: Job) -> Box<dyn JobInstance> {
+        if let Ok(gc_job) = serde_json::from_str::<GcJob>(&job.contents) {
+            Box::new(GcJobInstance {
+                job_id: job.id,
+                repo: gc_job.repo,
+                dry_run: gc_job.dry_run,
+                scheduled: gc_job.scheduled,
+            })
+        } else {
+            InvalidJobInstance::new(job, JobError::new("Can't parse gc job"))
+        }
+    }
+
+    fn find_reachable(&self, config: &Config, repo_path: &PathBuf, conn: &PgConnection) -> JobResult<HashSet<String>> {
+        let mut reachable = HashSet::new();
+        for ref_name in ostree::list_refs(repo_path, "") {
+            let commit = ostree::parse_ref(repo_path, &ref_name)?;
+            ostree::add_reachable_objects(std::slice::from_ref(repo_path), &commit, true, &mut reachable)
+                .map_err(|e| JobError::new(&format!("Can't walk ref {}: {}", ref_name, e)))?;
+        }
+        for (ref_name, commit) in ostree::list_remote_refs(repo_path, PIN_REMOTE) {
+            ostree::add_reachable_objects(std::slice::from_ref(repo_path), &commit, true, &mut reachable)
+                .map_err(|e| JobError::new(&format!("Can't walk the commit of pin {}: {}", ref_name, e)))?;
+        }
+
+        let (uploading, _) = RepoState::Uploading.to_db();
+        let (verifying, _) = RepoState::Verifying.to_db();
+        let uncommitted = builds::table
+            .filter(builds::repo.eq(&self.repo))
+            .filter(builds::repo_state.eq_any(vec![uploading, verifying]))
+            .select(builds::id)
+            .get_results::<i32>(conn)?;
+        for build_id in uncommitted {
+            let build_repo_path = config.build_repo_base.join(build_id.to_string());
+            let repo_paths = [build_repo_path.join("upload"), build_repo_path, repo_path.clone()];
+            let build_refs = build_refs::table
+                .filter(build_refs::build_id.eq(build_id))
+                .get_results::<models::BuildRef>(conn)?;
+            for build_ref in build_refs {
+                /* Uploads may be incomplete, keep what we can find */
+                if let Err(e) = ostree::add_reachable_objects(&repo_paths, &build_ref.commit, false, &mut reachable) {
+                    info!("#{}: Not all objects of {} in build {} found: {}", self.job_id, build_ref.ref_name, build_id, e);
+                }
+            }
+        }
+
+        /* Committed builds that are not published yet have the repo as parent too */
+        let (ready, _) = RepoState::Ready.to_db();
+        let (published, _) = PublishedState::Published.to_db();
+        let unpublished = builds::table
+            .filter(builds::repo.eq(&self.repo))
+            .filter(builds::repo_state.eq(ready))
+            .filter(builds::published_state.ne(published))
+            .select(builds::id)
+            .get_results::<i32>(conn)?;
+        for build_id in unpublished {
+            let build_repo_path = config.build_repo_base.join(build_id.to_string());
+            let repo_paths = [build_repo_path.clone(), repo_path.clone()];
+            for ref_name in ostree::list_refs(&build_repo_path, "") {
+                let commit = ostree::parse_ref(&build_repo_path, &ref_name)?;
+                ostree::add_reachable_objects(&repo_paths, &commit, false, &mut reachable)
+                    .map_err(|e| JobError::new(&format!("Can't walk ref {} of build {}: {}", ref_name, build_id, e)))?;
+            }
+        }
+        Ok(reachable)
+    }
+}
+
+impl JobInstance for GcJobInstance {
+    fn get_job_id (&self) -> i32 {
+        self.job_id
+    }
+
+    fn get_kind (&self) -> Option<JobKind> {
+        Some(JobKind::Gc)
+    }
+
+    fn order (&self) -> i32 {
+        4 /* Like prune */
+    }
+
+    fn handle_job (&mut self, executor: &JobExecutor, conn: &PgConnection) -> JobResult<serde_json::Value> {
+        info!("#{}: Handling Job Gc: repo: {}, dry-run: {}",
+              &self.job_id, &self.repo, self.dry_run);
+
+        let config = &executor.config;
+        let repoconfig = config.get_repoconfig(&self.repo).map_err(|_e| JobError::new(&format!("Can't find repo {}", &self.repo)))?;
+        let repo_path = repoconfig.get_abs_repo_path();
+        let grace_secs = repoconfig.gc.as_ref().map(|gc| gc.grace_secs).unwrap_or_else(default_gc_grace);
+
+        if self.scheduled {
+            schedule_gc_job(conn, repoconfig)?;
+        }
+
+        let _lock = lock_repo(self.job_id, conn, &self.repo)?;
+
+        sync_pin_refs(self.job_id, &self.repo, &repo_path, conn)?;
+
+        job_log_and_info(self.job_id, conn, "Finding reachable objects");
+        let reachable = self.find_reachable(config, &repo_path, conn)?;
+
+        let state_path = repo_path.join(GC_STATE_FILE);
+        let first_seen: HashMap<String, u64> = fs::read_to_string(&state_path).ok()
+            .and_then(|contents| serde_json::from_str(&contents).ok())
+            .unwrap_or_default();
+        let now = time::SystemTime::now().duration_since(time::UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+
+        let mut new_first_seen = HashMap::new();
+        let (mut unreachable_objects, mut unreachable_bytes) = (0, 0);
+        let (mut deleted_objects, mut reclaimed_bytes) = (0, 0);
+        for (object, path) in ostree::list_objects(&repo_path) {
+            if reachable.contains(&object) {
+                continue;
+            }
+            let size = fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+            unreachable_objects += 1;
+            unreachable_bytes += size;
+
+            let seen = *first_seen.get(&object).unwrap_or(&now);
+            if self.dry_run || now.saturating_sub(seen) < grace_secs {
+                new_first_seen.insert(object, seen);
+                continue;
+            }
+            match fs::remove_file(&path) {
+                Ok(()) => {
+                    deleted_objects += 1;
+                    reclaimed_bytes += size;
+                },
+                Err(e) => {
+                    job_log_and_error(self.job_id, conn, &format!("Failed to delete {}: {}", object, e));
+                    new_first_seen.insert(object, seen);
+                },
+            }
+        }
+
+        if !self.dry_run {
+            fs::write(&state_path, json!(new_first_seen).to_string())?;
+        }
+
+        job_log_and_info(self.job_id, conn,
+                         &format!("{} unreachable objects ({} bytes), deleted {} ({} bytes)",
+                                  unreachable_objects, unreachable_bytes, deleted_objects, reclaimed_bytes));
+
+        Ok(json!(JobResults::new(GcJobResult {
+            dry_run: self.dry_run,
+            grace_secs,
+            unreachable_objects,
+            unreachable_bytes,
+            deleted_objects,
+            reclaimed_bytes,
+        })))
+    }
+}
+
+pub fn queue_fsck_job(conn: &PgConnection, repo: &str) -> Result<Job, DieselError> {
+    diesel::insert_into(schema::jobs::table)
+        .values(NewJob {
+            kind: JobKind::Fsck.to_db(),
+            repo: Some(repo.to_string()),
+            start_after: None,
+            trace_parent: tracing::current_traceparent(),
+            contents: json!(FsckJob {
+                repo: repo.to_string(),
+            }).to_string(),
+        })
+        .get_result::<Job>(conn)
+}
+
+/* Checks that all the objects in the repo are intact */
+#[derive(Debug)]
+struct FsckJobInstance {
+    pub job_id: i32,
+    pub repo: String,
+}
+
+impl FsckJobInstance {
+    fn new(job: Job) -> Box<dyn JobInstance> {
+        if let Ok(fsck_job) = serde_json::from_str::<FsckJob>(&job.contents) {
+            Box::new(FsckJobInstance {
+                job_id: job.id,
+                repo: fsck_job.repo,
+            })
+        } else {
+            InvalidJobInstance::new(job, JobError::new("Can't parse fsck job"))
+        }
+    }
+}
+
+impl JobInstance for FsckJobInstance {
+    fn get_job_id (&self) -> i32 {
+        self.job_id
+    }
+
+    fn get_kind (&self) -> Option<JobKind> {
+        Some(JobKind::Fsck)
+    }
+
+    fn order (&self) -> i32 {
+        4 /* Like prune */
+    }
+
+    fn handle_job (&mut self, executor: &JobExecutor, conn: &PgConnection) -> JobResult<serde_json::Value> {
+        info!("#{}: Handling Job Fsck: repo: {}",
+              &self.job_id, &self.repo);
+
+        let config = &executor.config;
+        let repoconfig = config.get_repoconfig(&self.repo).map_err(|_e| JobError::new(&format!("Can't find repo {}", &self.repo)))?;
+        let repo_path = repoconfig.get_abs_repo_path();
+        let _lock = lock_repo(self.job_id, conn, &self.repo)?;
+
+        job_log_and_info(self.job_id, conn, "Checking the repo objects");
+        let mut cmd = new_command(config, "ostree", &[repo_path.as_path()], &[]);
+        cmd
+            .arg(format!("--repo={}", repo_path.display()))
+            .arg("fsck");
+        let stdout_tail = run_command(cmd, self.job_id, conn, None)?;
+
+        Ok(json!(JobResults::new(FsckJobResult {
+            summary: stdout_tail.last().cloned(),
+        })))
+    }
+}
+
+pub fn queue_regenerate_deltas_job(conn: &PgConnection, repo: &str) -> Result<Job, DieselError> {
+    diesel::insert_into(schema::jobs::table)
+        .values(NewJob {
+            kind: JobKind::RegenerateDeltas.to_db(),
+            repo: Some(repo.to_string()),
+            start_after: None,
+            trace_parent: tracing::current_traceparent(),
+            contents: json!(UpdateRepoJob {
+                repo: repo.to_string(),
+            }).to_string(),
+        })
+        .get_result::<Job>(conn)
+}
+
+pub fn queue_resign_job(conn: &PgConnection,
+                        repo: &str,
+                        sign_type: SignType,
+                        key: &str,
+                        delete_key: Option<String>) -> Result<Job, DieselError> {
+    diesel::insert_into(schema::jobs::table)
+        .values(NewJob {
+            kind: JobKind::Resign.to_db(),
+            repo: Some(repo.to_string()),
+            start_after: None,
+            trace_parent: tracing::current_traceparent(),
+            contents: json!(ResignJob {
+                repo: repo.to_string(),
+                sign_type,
+                key: key.to_string(),
+                delete_key,
+            }).to_string(),
+        })
+        .get_result::<Job>(conn)
+}
+
+/* Signs the current commit of every ref and the summary with a new key,
+ * for key rotation. The repo config has to be switched to the new key
+ * as well, or the next repo update signs the summary with the old one. */
+#[derive(Debug)]
+struct ResignJobInstance {
+    pub job_id: i32,
+    pub repo: String,
+    pub sign_type: SignType,
+    pub key: String,
+    pub delete_key: Option<String>,
+}
+
+impl ResignJobInstance {
+    fn new(job: Job) -> Box<dyn JobInstance> {
+        if let Ok(resign_job) = serde_json::from_str::<ResignJob>(&job.contents) {
+            Box::new(ResignJobInstance {
+                job_id: job.id,
+                repo: resign_job.repo,
+                sign_type: resign_job.sign_type,
+                key: resign_job.key,
+                delete_key: resign_job.delete_key,
+            })
+        } else {
+            InvalidJobInstance::new(job, JobError::new("Can't parse resign job"))
+        }
+    }
+
+    fn sign_commit(&self, config: &Config, repo_path: &Path, commit: &str, conn: &PgConnection) -> JobResult<()> {
+        let mut cmd = new_command(config, "ostree", &[repo_path], &[]);
+        cmd
+            .arg(format!("--repo={}", repo_path.display()));
+        if self.sign_type == SignType::Gpg {
+            cmd.arg("gpg-sign");
+            if let Some(ref gpg_homedir) = config.gpg_homedir {
+                cmd.arg(format!("--gpg-homedir={}", gpg_homedir));
+            }
+            cmd
+                .arg(commit)
+                .arg(&self.key);
+        } else {
+            cmd
+                .arg("sign")
+                .arg(format!("--sign-type={}", self.sign_type.name()))
+                .arg(format!("--keys-file={}", self.key))
+                .arg(commit);
+        }
+        match do_command(cmd, self.job_id, conn) {
+            /* When a failed job is retried, the commits signed the first time are skipped */
+            Err(ref e) if self.sign_type == SignType::Gpg && is_already_signed(e) => {
+                job_log_and_info(self.job_id, conn, &format!("Commit {} is already signed with {}", commit, self.key));
+            },
+            res => res?,
+        }
+
+        if let Some(ref delete_key) = self.delete_key {
+            let mut cmd = new_command(config, "ostree", &[repo_path], &[]);
+            cmd
+                .arg(format!("--repo={}", repo_path.display()))
+                .arg("gpg-sign")
+                .arg("--delete");
+            if let Some(ref gpg_homedir) = config.gpg_homedir {
+                cmd.arg(format!("--gpg-homedir={}", gpg_homedir));
+            }
+            cmd
+                .arg(commit)
+                .arg(delete_key);
+            do_command(cmd, self.job_id, conn)?;
+        }
+        Ok(())
+    }
+
+    fn sign_summary(&self, config: &Config, repo_path: &PathBuf, conn: &PgConnection) -> JobResult<()> {
+        if self.sign_type == SignType::Gpg {
+            /* This regenerates the summary (and subsummaries) signed with the new key */
+            let mut cmd = new_command(config, "flatpak", &[repo_path.as_path()], &[]);
+            cmd
+                .arg("build-update-repo")
+                .arg("--no-update-appstream");
+            add_gpg_args(&mut cmd, &Some(self.key.clone()), &config.gpg_homedir);
+            cmd
+                .arg(repo_path);
+            do_command(cmd, self.job_id, conn)
+        } else {
+            /* Without --update this only signs the existing summary */
+            let mut cmd = new_command(config, "ostree", &[repo_path.as_path()], &[]);
+            cmd
+                .arg(format!("--repo={}", repo_path.display()))
+                .arg("summary")
+                .arg(format!("--sign-type={}", self.sign_type.name()))
+                .arg(format!("--keys-file={}", self.key));
+            do_command(cmd, self.job_id, conn)
+        }
+    }
+}
+
+impl JobInstance for ResignJobInstance {
+    fn get_job_id (&self) -> i32 {
+        self.job_id
+    }
+
+    fn get_kind (&self) -> Option<JobKind> {
+        Some(JobKind::Resign)
+    }
+
+    fn order (&self) -> i32 {
+        4 /* Like pruning, this can wait for everything else */
+    }
+
+    fn handle_job (&mut self, executor: &JobExecutor, conn: &PgConnection) -> JobResult<serde_json::Value> {
+        info!("#{}: Handling Job Resign: repo: {}, sign-type: {}",
+              &self.job_id, &self.repo, self.sign_type.name());
+
+        let config = &executor.config;
+        let repoconfig = config.get_repoconfig(&self.repo).map_err(|_e| JobError::new(&format!("Can't find repo {}", &self.repo)))?;
+        let repo_path = repoconfig.get_abs_repo_path();
+        let _lock = lock_repo(self.job_id, conn, &self.repo)?;
+
+        if self.sign_type == SignType::Gpg && repoconfig.gpg_key.as_ref() != Some(&self.key) {
+            job_log_and_info(self.job_id, conn,
+                             &format!("Warning: repo {} is not configured with gpg key {}, the next repo update will sign the summary with the configured key", &self.repo, &self.key));
+        }
+
+        /* Refs can share commits (e.g. on branch renames), sign them once */
+        let mut commits: Vec<(String, Vec<String>)> = vec![];
+        for ref_name in ostree::list_refs(&repo_path, "") {
+            let commit = ostree::parse_ref(&repo_path, &ref_name)?;
+            match commits.iter_mut().find(|(c, _)| *c == commit) {
+                Some((_, ref_names)) => ref_names.push(ref_name),
+                None => commits.push((commit, vec![ref_name])),
+            }
+        }
+
+        /* A failed commit doesn't stop the others from being signed, and
+         * the summary is signed either way. The job then fails with the
+         * refs left unsigned, and can be retried. */
+        let total = commits.len();
+        let mut signed_refs = HashMap::new();
+        let mut failed_refs = vec![];
+        job_log_and_info(self.job_id, conn, &format!("Signing {} commits with {} key", total, self.sign_type.name()));
+        job_progress(self.job_id, conn, "commits", 0, total);
+        for (done, (commit, ref_names)) in commits.into_iter().enumerate() {
+            match self.sign_commit(config, &repo_path, &commit, conn) {
+                Ok(()) => {
+                    for ref_name in ref_names {
+                        signed_refs.insert(ref_name, commit.clone());
+                    }
+                },
+                Err(e) => {
+                    for ref_name in ref_names {
+                        job_log_and_error(self.job_id, conn, &format!("Failed to sign {} (commit {}): {}", ref_name, commit, e));
+                        failed_refs.push(ref_name);
+                    }
+                },
+            }
+            job_progress(self.job_id, conn, "commits", done + 1, total);
+        }
+
+        job_log_and_info(self.job_id, conn, "Signing summary");
+        self.sign_summary(config, &repo_path, conn)?;
+
+        if !failed_refs.is_empty() {
+            failed_refs.sort();
+            return Err(JobError::new(&format!("Failed to sign {} of {} refs: {}",
+                                              failed_refs.len(), failed_refs.len() + signed_refs.len(), failed_refs.join(", "))));
+        }
+
+        Ok(json!(JobResults::new(ResignJobResult {
+            sign_type: self.sign_type,
+            refs: signed_refs,
+        })))
+    }
+}
+
+/* ostree gpg-sign refuses to add a second signature with the same key */
+fn is_already_signed(e: &JobError) -> bool {
+    e.to_string().contains("is already signed with GPG key")
+}
+
+/* Copies refs as they are published in one managed repo to another, e.g.
+ * from beta to stable, signed with the key of the destination repo. The
+ * source repo is locked too, so its objects aren't pruned while they are
+ * copied, but it is not changed, so only the destination needs an update. */
+#[derive(Debug)]
+struct PromoteJobInstance {
+    pub job_id: i32,
+    pub from_repo: String,
+    pub repo: String,
+    pub refs: Vec<String>,
+    pub note: Option<String>,
+}
+
+impl PromoteJobInstance {
+    fn new(job: Job) -> Box<dyn JobInstance> {
+        if let Ok(promote_job) = serde_json::from_str::<PromoteJob>(&job.contents) {
+            Box::new(PromoteJobInstance {
+                job_id: job.id,
+                from_repo: promote_job.from_repo,
+                repo: promote_job.repo,
+                refs: promote_job.refs,
+                note: promote_job.note,
+            })
+        } else {
+            InvalidJobInstance::new(job, JobError::new("Can't parse promote job"))
+        }
+    }
+}
+
+impl JobInstance for PromoteJobInstance {
+    fn get_job_id (&self) -> i32 {
+        self.job_id
+    }
+
+    fn get_kind (&self) -> Option<JobKind> {
+        Some(JobKind::Promote)
+    }
+
+    fn order (&self) -> i32 {
+        1 /* Like publish */
+    }
+
+    fn handle_job (&mut self, executor: &JobExecutor, conn: &PgConnection) -> JobResult<serde_json::Value> {
+        info!("#{}: Handling Job Promote: from: {}, to: {}, refs: {:?}",
+              &self.job_id, &self.from_repo, &self.repo, &self.refs);
+
+        let config = &executor.config;
+        let from_repoconfig = config.get_repoconfig(&self.from_repo).map_err(|_e| JobError::new(&format!("Can't find repo {}", &self.from_repo)))?;
+        let repoconfig = config.get_repoconfig(&self.repo).map_err(|_e| JobError::new(&format!("Can't find repo {}", &self.repo)))?;
+        let from_repo_path = from_repoconfig.get_abs_repo_path();
+        let repo_path = repoconfig.get_abs_repo_path();
+
+        let _locks = lock_repos(self.job_id, conn, &[&self.from_repo, &self.repo])?;
+
+        /* The build each ref was last published by in the source repo, to
+         * record the promoted refs as published by the same build */
+        let mut previous_commits = HashMap::new();
+        let mut source_builds = HashMap::new();
+        for ref_name in self.refs.iter() {
+            let commit = ostree::parse_ref(&from_repo_path, ref_name)?;
+            let build_id = published_refs::table
+                .filter(published_refs::repo.eq(&self.from_repo))
+                .filter(published_refs::ref_name.eq(ref_name))
+                .filter(published_refs::commit.eq(&commit))
+                .order(published_refs::id.desc())
+                .select(published_refs::build_id)
+                .first::<i32>(conn)
+                .optional()?;
+            source_builds.insert(ref_name.clone(), build_id);
+            previous_commits.insert(ref_name.clone(), ostree::parse_ref(&repo_path, ref_name).ok());
+        }
+
+        let mut src_repo_arg = OsString::from("--src-repo=");
+        src_repo_arg.push(&from_repo_path);
+
+        let mut cmd = new_command(config, "flatpak", &[repo_path.as_path()], &[from_repo_path.as_path()]);
+        cmd
+            .arg("build-commit-from")
+            .arg("--no-update-summary"); // We update it separately
+        add_gpg_args(&mut cmd, &repoconfig.gpg_key, &config.gpg_homedir);
+        cmd
+            .arg(&src_repo_arg)
+            .arg(&repo_path)
+            .args(&self.refs);
+
+        job_log_and_info(self.job_id, conn,
+                         &format!("Promoting {} refs from repo {} to {}", self.refs.len(), self.from_repo, self.repo));
+        do_command(cmd, self.job_id, conn)?;
+
+        let mut commits = HashMap::new();
+        for ref_name in self.refs.iter() {
+            let commit = ostree::parse_ref(&repo_path, ref_name)?;
+            match source_builds.remove(ref_name).unwrap_or(None) {
+                Some(build_id) => {
+                    diesel::insert_into(published_refs::table)
+                        .values(NewPublishedRef {
+                            build_id,
+                            ref_name: ref_name.clone(),
+                            commit: commit.clone(),
+                            job_id: Some(self.job_id),
+                            repo: self.repo.clone(),
+                            previous_commit: previous_commits.remove(ref_name).unwrap_or(None),
+                            note: self.note.clone(),
+                            published_by: None,
+                        })
+                        .execute(conn)?;
+                },
+                None => job_log_and_info(self.job_id, conn,
+                                         &format!("Ref {} was not published by a build in {}, not recording it", ref_name, self.from_repo)),
+            }
+            commits.insert(ref_name.clone(), commit);
+        }
+
+        let delay = config.delay_update_secs;
+        let (_is_new, update_job) = queue_update_job(delay, conn, &self.repo, Some(self.job_id))?;
+        job_log_and_info(self.job_id, conn, &format!("Queued repository update job {}", update_job.id));
+
+        Ok(json!(JobResults::new(PromoteJobResult {
+            from_repo: self.from_repo.clone(),
+            refs: commits,
+            update_repo_job: update_job.id,
+        })))
+    }
+}
+
+/* Moves a published ref back to an earlier commit, for when a broken
+ * release got out */
+#[derive(Debug)]
+struct RollbackJobInstance {
+    pub job_id: i32,
+    pub repo: String,
+    pub ref_name: String,
+    pub commit: String,
+    pub build_id: i32,
+    pub note: Option<String>,
+}
+
+impl RollbackJobInstance {
+    fn new(job: Job) -> Box<dyn JobInstance> {
+        if let Ok(rollback_job) = serde_json::from_str::<RollbackJob>(&job.contents) {
+            Box::new(RollbackJobInstance {
+                job_id: job.id,
+                repo: rollback_job.repo,
+                ref_name: rollback_job.ref_name,
+                commit: rollback_job.commit,
+                build_id: rollback_job.build_id,
+                note: rollback_job.note,
+            })
+        } else {
+            InvalidJobInstance::new(job, JobError::new("Can't parse rollback job"))
+        }
+    }
+}
+
+impl JobInstance for RollbackJobInstance {
+    fn get_job_id (&self) -> i32 {
+        self.job_id
+    }
+
+    fn get_kind (&self) -> Option<JobKind> {
+        Some(JobKind::Rollback)
+    }
+
+    fn order (&self) -> i32 {
+        0 /* Urgent, this undoes a broken publish */
+    }
+
+    fn handle_job (&mut self, executor: &JobExecutor, conn: &PgConnection) -> JobResult<serde_json::Value> {
+        info!("#{}: Handling Job Rollback: repo: {}, ref: {}, commit: {}",
+              &self.job_id, &self.repo, &self.ref_name, &self.commit);
+
+        let config = &executor.config;
+        let repoconfig = config.get_repoconfig(&self.repo).map_err(|_e| JobError::new(&format!("Can't find repo {}", &self.repo)))?;
+        let repo_path = repoconfig.get_abs_repo_path();
+        let _lock = lock_repo(self.job_id, conn, &self.repo)?;
+
+        /* It may have been pruned since */
+        ostree::get_commit(&repo_path, &self.commit)
+            .map_err(|e| JobError::new(&format!("Can't roll back to {}: {}", self.commit, e)))?;
+        let previous_commit = ostree::parse_ref(&repo_path, &self.ref_name).ok();
+
+        let mut cmd = new_command(config, "ostree", &[repo_path.as_path()], &[]);
+        cmd
+            .arg(format!("--repo={}", repo_path.display()))
+            .arg("reset")
+            .arg(&self.ref_name)
+            .arg(&self.commit);
+        job_log_and_info(self.job_id, conn,
+                         &format!("Resetting {} to {}", self.ref_name, self.commit));
+        do_command(cmd, self.job_id, conn)?;
+
+        diesel::insert_into(published_refs::table)
+            .values(NewPublishedRef {
+                build_id: self.build_id,
+                ref_name: self.ref_name.clone(),
+                commit: self.commit.clone(),
+                job_id: Some(self.job_id),
+                repo: self.repo.clone(),
+                previous_commit: previous_commit.clone(),
+                note: self.note.clone(),
+                published_by: None,
+            })
+            .execute(conn)?;
+
+        let (_is_new, update_job) = queue_update_job(0, conn, &self.repo, Some(self.job_id))?;
+        job_log_and_info(self.job_id, conn, &format!("Queued repository update job {}", update_job.id));
+
+        Ok(json!(JobResults::new(RollbackJobResult {
+            ref_name: self.ref_name.clone(),
+            commit: self.commit.clone(),
+            previous_commit,
+            update_repo_job: update_job.id,
+        })))
+    }
+}
+
+/* Publishes a new commit of a ref marking it end-of-life, with the same
+ * content, so clients tell their users and possibly rebase to another ref */
+#[derive(Debug)]
+struct DeprecateJobInstance {
+    pub job_id: i32,
+    pub repo: String,
+    pub ref_name: String,
+    pub reason: String,
+    pub rebase: Option<String>,
+    pub author: String,
+}
+
+impl DeprecateJobInstance {
+    fn new(job: Job) -> Box<dyn JobInstance> {
+        if let Ok(deprecate_job) = serde_json::from_str::<DeprecateJob>(&job.contents) {
+            Box::new(DeprecateJobInstance {
+                job_id: job.id,
+                repo: deprecate_job.repo,
+                ref_name: deprecate_job.ref_name,
+                reason: deprecate_job.reason,
+                rebase: deprecate_job.rebase,
+                author: deprecate_job.author,
+            })
+        } else {
+            InvalidJobInstance::new(job, JobError::new("Can't parse deprecate job"))
+        }
+    }
+}
+
+impl JobInstance for DeprecateJobInstance {
     fn get_job_id (&self) -> i32 {
         self.job_id
     }
 
+    fn get_kind (&self) -> Option<JobKind> {
+        Some(JobKind::Deprecate)
+    }
+
     fn order (&self) -> i32 {
-        2 /* Delay updates after publish so they can be chunked. */
+        1 /* Like publish */
     }
 
     fn handle_job (&mut self, executor: &JobExecutor, conn: &PgConnection) -> JobResult<serde_json::Value> {
-        info!("#{}: Handling Job UpdateRepo: repo: {}",
-              &self.job_id, &self.repo);
+        info!("#{}: Handling Job Deprecate: repo: {}, ref: {}",
+              &self.job_id, &self.repo, &self.ref_name);
 
-        // Get repo config
         let config = &executor.config;
-        let repoconfig = config.get_repoconfig(&self.repo)
-            .or_else(|_e| Err(JobError::new(&format!("Can't find repo {}", &self.repo))))?;
-
-        self.update_appstream(config, repoconfig, conn)?;
-
-        let (missing_deltas, unwanted_deltas) = self.calculate_deltas(repoconfig);
-        self.generate_deltas(&missing_deltas, repoconfig, conn)?;
-        self.retire_deltas(&unwanted_deltas, repoconfig, conn)?;
-
-        self.update_summary(config, repoconfig, conn)?;
-
-        self.run_post_publish(repoconfig, conn)?;
+        let repoconfig = config.get_repoconfig(&self.repo).map_err(|_e| JobError::new(&format!("Can't find repo {}", &self.repo)))?;
+        let repo_path = repoconfig.get_abs_repo_path();
+        let _lock = lock_repo(self.job_id, conn, &self.repo)?;
+
+        let previous_commit = ostree::parse_ref(&repo_path, &self.ref_name)?;
+        let build_id = published_refs::table
+            .filter(published_refs::repo.eq(&self.repo))
+            .filter(published_refs::ref_name.eq(&self.ref_name))
+            .order(published_refs::id.desc())
+            .select(published_refs::build_id)
+            .first::<i32>(conn)?;
+
+        /* Without --src-repo the ref is committed from the repo itself */
+        let mut cmd = new_command(config, "flatpak", &[repo_path.as_path()], &[]);
+        cmd
+            .arg("build-commit-from")
+            .arg("--force")
+            .arg("--no-update-summary")
+            .arg(format!("--end-of-life={}", self.reason));
+        if let Some(ref rebase) = self.rebase {
+            cmd.arg(format!("--end-of-life-rebase={}", rebase));
+        }
+        add_gpg_args(&mut cmd, &repoconfig.gpg_key, &config.gpg_homedir);
+        cmd
+            .arg(&repo_path)
+            .arg(&self.ref_name);
+        job_log_and_info(self.job_id, conn, &format!("Marking {} end-of-life", self.ref_name));
+        do_command(cmd, self.job_id, conn)?;
+
+        /* Only now that the ref is end-of-life, new builds of it are refused */
+        let commit = ostree::parse_ref(&repo_path, &self.ref_name)?;
+        conn.transaction::<_, DieselError, _>(|| {
+            diesel::insert_into(published_refs::table)
+                .values(NewPublishedRef {
+                    build_id,
+                    ref_name: self.ref_name.clone(),
+                    commit: commit.clone(),
+                    job_id: Some(self.job_id),
+                    repo: self.repo.clone(),
+                    previous_commit: Some(previous_commit),
+                    note: Some(format!("End-of-life: {}", self.reason)),
+                    published_by: None,
+                })
+                .execute(conn)?;
+            diesel::insert_into(ref_tombstones::table)
+                .values(NewRefTombstone {
+                    repo: self.repo.clone(),
+                    ref_name: self.ref_name.clone(),
+                    reason: self.reason.clone(),
+                    rebase: self.rebase.clone(),
+                    author: self.author.clone(),
+                    job_id: Some(self.job_id),
+                })
+                .execute(conn)?;
+            Ok(())
+        })?;
 
-        self.extract_appstream(repoconfig, conn)?;
+        let (_is_new, update_job) = queue_update_job(config.delay_update_secs, conn, &self.repo, Some(self.job_id))?;
+        job_log_and_info(self.job_id, conn, &format!("Queued repository update job {}", update_job.id));
 
-        Ok(json!({ }))
+        Ok(json!(JobResults::new(DeprecateJobResult {
+            ref_name: self.ref_name.clone(),
+            commit,
+            update_repo_job: update_job.id,
+        })))
     }
 }
 
@@ -960,15 +3797,12 @@ fn pick_next_job (executor: &mut JobExecutor, conn: &PgConnection) -> Result<Box
     use diesel::dsl::not;
     use diesel::dsl::now;
 
-    /* Find next job (if any) and mark it started */
+    /* Find next job (if any) and mark it started. Other nodes may be
+     * looking at the same time, so skip jobs they have locked */
 
     let for_repo = executor.repo.clone();
-    let transaction_result =
-        conn
-        .build_transaction()
-        .serializable()
-        .deferrable()
-        .run(|| {
+    conn
+        .transaction(|| {
             let ready_job_filter = jobs::status.eq(JobStatus::New as i16)
                 .and(jobs::start_after.is_null().or(jobs::start_after.lt(now)))
                 .and(
@@ -984,6 +3818,8 @@ fn pick_next_job (executor: &mut JobExecutor, conn: &PgConnection) -> Result<Box
                     jobs::table
                         .order(jobs::id)
                         .filter(ready_job_filter.and(jobs::repo.is_null()))
+                        .for_update()
+                        .skip_locked()
                         .get_results::<models::Job>(conn)?
                         .into_iter()
                         .map(|job| new_job_instance(executor, job))
@@ -993,6 +3829,8 @@ fn pick_next_job (executor: &mut JobExecutor, conn: &PgConnection) -> Result<Box
                     jobs::table
                         .order(jobs::id)
                         .filter(ready_job_filter.and(jobs::repo.eq(repo)))
+                        .for_update()
+                        .skip_locked()
                         .get_results::<models::Job>(conn)?
                         .into_iter()
                         .map(|job| new_job_instance(executor, job))
@@ -1000,56 +3838,349 @@ fn pick_next_job (executor: &mut JobExecutor, conn: &PgConnection) -> Result<Box
                 },
             };
 
-            /* Sort by prio */
-            new_instances.sort_by(|a, b| a.order().cmp(&b.order()));
+            /* Outside of the maintenance windows the heavy jobs stay queued */
+            if !executor.config.in_maintenance_window() {
+                new_instances.retain(|instance| !instance.get_kind().map(|kind| kind.is_heavy()).unwrap_or(false));
+            }
+
+            /* Commits write to the build repos and publishes to the repo of
+             * this executor, so they wait while that is low on disk space */
+            let write_path = match executor.repo {
+                None => Some(executor.config.build_repo_base.clone()),
+                Some(ref repo) => executor.config.get_repoconfig(repo).ok().map(|repoconfig| repoconfig.get_abs_repo_path()),
+            };
+            if let Some(write_path) = write_path {
+                if !diskspace::has_free_space(&executor.config, &write_path) {
+                    let n_queued = new_instances.len();
+                    new_instances.retain(|instance| !matches!(instance.get_kind(), Some(JobKind::Commit) | Some(JobKind::Publish)));
+                    if new_instances.len() != n_queued {
+                        warn!("Not enough free space in {:?}, deferring {} jobs", write_path, n_queued - new_instances.len());
+                    }
+                }
+            }
+
+            sort_round_robin(&mut new_instances, &mut executor.last_picked);
 
             /* Handle the first, if any */
             for new_instance in new_instances {
-                diesel::update(jobs::table)
-                    .filter(jobs::id.eq(new_instance.get_job_id()))
-                    .set((jobs::status.eq(JobStatus::Started as i16),))
-                    .execute(conn)?;
+                start_job_lease(&executor.config, conn, new_instance.get_job_id())?;
+                executor.picked_count += 1;
+                executor.last_picked.insert(fairness_group(new_instance.as_ref()), executor.picked_count);
                 return Ok(new_instance)
             }
 
             Err(diesel::NotFound)
-        });
+        })
+}
 
-    /* Retry on serialization failure */
-    match transaction_result {
-        Err(DieselError::DatabaseError(SerializationFailure, _)) => pick_next_job (executor, conn),
-        _ => transaction_result
+/* Sort by prio, and then round-robin between the builds (and the kinds
+ * of jobs not for a build), so that one build queueing a lot of jobs
+ * doesn't starve the others. Within a build the oldest job goes first.
+ * last_picked has when each group last had a job picked. */
+fn sort_round_robin(instances: &mut Vec<Box<dyn JobInstance>>, last_picked: &mut HashMap<String, u64>) {
+    instances.sort_by_key(|instance| {
+        let group_last_picked = last_picked.get(&fairness_group(instance.as_ref())).cloned().unwrap_or(0);
+        (instance.order(), group_last_picked, instance.get_job_id())
+    });
+
+    /* Forget about the groups that have nothing queued */
+    let queued_groups: HashSet<String> = instances.iter().map(|instance| fairness_group(instance.as_ref())).collect();
+    last_picked.retain(|group, _| queued_groups.contains(group));
+}
+
+fn fairness_group(instance: &dyn JobInstance) -> String {
+    match (instance.get_build_id(), instance.get_kind()) {
+        (Some(build_id), _) => format!("build/{}", build_id),
+        (None, Some(kind)) => kind.name().to_string(),
+        (None, None) => "invalid".to_string(),
+    }
+}
+
+/* Marks the job as started by this node. The lease is renewed by the
+ * LeaseKeeper while the job runs, if it runs out the node is assumed
+ * dead and the job is broken by another node. */
+fn start_job_lease(config: &Config, conn: &PgConnection, job_id: i32) -> Result<(), DieselError> {
+    use diesel::dsl::{now, IntervalDsl};
+
+    diesel::update(jobs::table)
+        .filter(jobs::id.eq(job_id))
+        .set((jobs::status.eq(JobStatus::Started as i16),
+              jobs::started_at.eq(now),
+              jobs::lease_owner.eq(&config.node_name),
+              jobs::lease_expires_at.eq((now + (config.job_lease_secs as i64).seconds()).nullable())))
+        .execute(conn)?;
+    Ok(())
+}
+
+
+const FAILURE_MAIL_LOG_LINES: usize = 50;
+
+fn queue_job_failure_mail(smtp: &SmtpConfig, config: &Config, conn: &PgConnection, job_id: i32) -> JobResult<()> {
+    let job = jobs::table
+        .filter(jobs::id.eq(job_id))
+        .get_result::<Job>(conn)?;
+
+    /* We only mail about commits and publishes, update-repo failures are retried by the next publish */
+    let kind = JobKind::from_db(job.kind);
+    let build_id = match kind {
+        Some(JobKind::Commit) => serde_json::from_str::<CommitJob>(&job.contents).map(|commit_job| commit_job.build),
+        Some(JobKind::Publish) => serde_json::from_str::<PublishJob>(&job.contents).map(|publish_job| publish_job.build),
+        _ => return Ok(()),
+    }.map_err(|e| JobError::new(&format!("Can't parse job: {}", e)))?;
+
+    let build = builds::table
+        .filter(builds::id.eq(build_id))
+        .get_result::<models::Build>(conn)?;
+
+    if let Some(ref only_repo) = smtp.only_repo {
+        if &build.repo != only_repo {
+            return Ok(())
+        }
+    }
+
+    let log_lines: Vec<&str> = job.log.lines().collect();
+    let log_tail = log_lines[log_lines.len().saturating_sub(FAILURE_MAIL_LOG_LINES)..].join("\n");
+
+    let subject = format!("{:?} job {} for build {} in repo {} failed", kind.unwrap(), job.id, build.id, build.repo);
+    let body = format!("Job status: {}/status/job/{}\n\nLast lines of the job log:\n\n{}\n",
+                       config.base_url, job.id, log_tail);
+    mail::queue_mail(conn, &subject, &body)?;
+    Ok(())
+}
+
+/* The alerts are deduplicated per window-secs period, even with several
+ * job executors, by the unique index on the kind and start of the period */
+fn failure_alert_window_start(now: time::SystemTime, window_secs: u64) -> time::SystemTime {
+    let secs = now.duration_since(time::UNIX_EPOCH).unwrap_or_default().as_secs();
+    time::UNIX_EPOCH + time::Duration::from_secs(secs - secs % window_secs.max(1))
+}
+
+fn check_job_failure_rate(config: &Config, conn: &PgConnection, job: &Job) -> JobResult<()> {
+    let alert_config = match config.failure_alert {
+        Some(ref alert_config) => alert_config,
+        None => return Ok(()),
+    };
+    let kind = match JobKind::from_db(job.kind) {
+        Some(kind) => kind,
+        None => return Ok(()),
+    };
+    if let Some(ref kinds) = alert_config.kinds {
+        if !kinds.iter().any(|k| k == kind.name()) {
+            return Ok(())
+        }
+    }
+
+    let since = time::SystemTime::now() - time::Duration::from_secs(alert_config.window_secs);
+    let statuses = jobs::table
+        .filter(jobs::kind.eq(job.kind))
+        .filter(jobs::ended_at.gt(since))
+        .select(jobs::status)
+        .get_results::<i16>(conn)?;
+    let failed = statuses.iter().filter(|status| **status == JobStatus::Broken as i16).count() as i64;
+    let total = statuses.iter().filter(|status| **status == JobStatus::Broken as i16 || **status == JobStatus::Ended as i16).count() as i64;
+    if failed < alert_config.min_failures || failed as f64 <= alert_config.max_failure_rate * total as f64 {
+        return Ok(())
+    }
+
+    let window_start = failure_alert_window_start(time::SystemTime::now(), alert_config.window_secs);
+    let inserted = diesel::insert_into(job_failure_alerts::table)
+        .values((job_failure_alerts::kind.eq(job.kind),
+                 job_failure_alerts::failed.eq(failed as i32),
+                 job_failure_alerts::total.eq(total as i32),
+                 job_failure_alerts::job_id.eq(job.id),
+                 job_failure_alerts::window_start.eq(window_start)))
+        .on_conflict_do_nothing()
+        .execute(conn)?;
+    if inserted == 0 {
+        return Ok(()) /* Already alerted in this window */
+    }
+
+    let message = format!("{} of the last {} {} jobs failed in the last {} minutes",
+                          failed, total, kind.name(), alert_config.window_secs / 60);
+    warn!("{}", message);
+    webhooks::queue_event(conn, config, "job-failure-rate", json!({
+        "kind": kind.name(),
+        "failed": failed,
+        "total": total,
+        "window-secs": alert_config.window_secs,
+        "job": job.id,
+    }))?;
+    if config.smtp.is_some() {
+        let body = format!("{}.\n\nLast failure: {}/status/job/{}\n", message, config.base_url, job.id);
+        mail::queue_mail(conn, &format!("Many {} jobs are failing", kind.name()), &body)?;
+    }
+    Ok(())
+}
+
+/* Only queues the mail, it is sent by the MailSender, so that a slow
+ * smtp server doesn't hold up the job executor */
+fn notify_job_failure(executor: &JobExecutor, conn: &PgConnection, job_id: i32) {
+    if let Some(ref smtp) = executor.config.smtp {
+        if let Err(e) = queue_job_failure_mail(smtp, &executor.config, conn, job_id) {
+            error!("#{}: Failed to queue failure mail: {}", job_id, e);
+        }
+    }
+}
+
+/* A job that failed because its command was killed by the shutdown is
+ * put back in the queue for the next run. The
+ * commit and publish jobs have already marked their build as failed by
+ * then, so that is undone too, for the restarted job to find it in the
+ * state it expects. */
+fn requeue_interrupted_job(job_id: i32, conn: &PgConnection, error: &JobError) {
+    job_log_and_info(job_id, conn, &format!("Job interrupted by shutdown, it will be restarted: {}", error));
+    let res = conn.transaction::<_, DieselError, _>(|| {
+        let (failed, _) = RepoState::Failed("".to_string()).to_db();
+        let (verifying, _) = RepoState::Verifying.to_db();
+        diesel::update(builds::table)
+            .filter(builds::commit_job_id.eq(job_id).and(builds::repo_state.eq(failed)))
+            .set((builds::repo_state.eq(verifying),
+                  builds::repo_state_reason.eq(None::<String>)))
+            .execute(conn)?;
+        let (failed_publish, _) = PublishedState::Failed("".to_string()).to_db();
+        let (publishing, _) = PublishedState::Publishing.to_db();
+        diesel::update(builds::table)
+            .filter(builds::publish_job_id.eq(job_id).and(builds::published_state.eq(failed_publish)))
+            .set((builds::published_state.eq(publishing),
+                  builds::published_state_reason.eq(None::<String>)))
+            .execute(conn)?;
+        diesel::update(jobs::table)
+            .filter(jobs::id.eq(job_id))
+            .set((jobs::status.eq(JobStatus::New as i16),
+                  jobs::started_at.eq(None::<time::SystemTime>),
+                  jobs::ended_at.eq(None::<time::SystemTime>),
+                  jobs::progress.eq(None::<String>),
+                  jobs::lease_owner.eq(None::<String>),
+                  jobs::lease_expires_at.eq(None::<time::SystemTime>)))
+            .execute(conn)?;
+        Ok(())
+    });
+    if let Err(e) = res {
+        error!("Error requeueing job {}: {}", job_id, e);
+    }
+}
+
+/* Jobs on build repos, like commits, aren't queued for a repo, but are
+ * labeled with the repo of their build */
+fn job_metrics_repo(job: &Job, build_id: Option<i32>, conn: &PgConnection) -> String {
+    if let Some(ref repo) = job.repo {
+        return repo.clone();
+    }
+    build_id
+        .and_then(|build_id| metrics::time_query("job_metrics_repo", || builds::table
+            .filter(builds::id.eq(build_id))
+            .select(builds::repo)
+            .get_result::<String>(conn))
+            .ok())
+        .unwrap_or_else(|| "builds".to_string())
+}
+
+/* Store the outcome of a job that was started by pick_next_job(), or
+ * claimed by another job */
+fn finish_job(executor: &JobExecutor, conn: &PgConnection, job_id: i32, build_id: Option<i32>, res: JobResult<serde_json::Value>) {
+    let (new_status, mut new_results) =
+        match res {
+            Ok(json) =>  {
+                info!("#{}: Job succeeded", job_id);
+                (JobStatus::Ended, json)
+            },
+            Err(ref e) if executor.running_commands.take_interrupted(job_id) => {
+                requeue_interrupted_job(job_id, conn, e);
+                return;
+            },
+            Err(e) => {
+                job_log_and_error(job_id, conn,
+                                  &format!("Job failed: {}", e));
+                let mut tags = vec![("job", job_id.to_string()),
+                                    ("repo", executor.repo.clone().unwrap_or("builds".to_string()))];
+                if let Some(build_id) = build_id {
+                    tags.push(("build", build_id.to_string()));
+                }
+                errorreporting::report_error(&format!("Job failed: {}", e), &tags);
+                let committed_refs = load_commit_checkpoint(job_id, conn);
+                (JobStatus::Broken, json!(JobResults::new(FailedJobResult { error_message: e.to_string(), committed_refs })))
+            }
+        };
+
+    if let Some(log_file) = job_log_file(job_id).filter(|path| path.exists()) {
+        if let Some(results) = new_results.as_object_mut() {
+            results.insert("log-file".to_string(), json!(log_file));
+        }
+    }
+
+    let failed = new_status == JobStatus::Broken;
+    /* If our lease ran out the job may have been broken by another node already */
+    let update_res = metrics::time_query("finish_job", ||
+        diesel::update(jobs::table)
+        .filter(jobs::id.eq(job_id))
+        .filter(jobs::status.eq(JobStatus::Started as i16))
+        .set((jobs::status.eq(new_status as i16),
+              jobs::results.eq(new_results.to_string()),
+              jobs::ended_at.eq(diesel::dsl::now)))
+        .get_result::<Job>(conn));
+    match update_res {
+        Ok(job) => {
+            let event_res = webhooks::queue_event(conn, &executor.config, "job-finished", json!({
+                "job": job.id,
+                "kind": job.kind,
+                "status": job.status,
+                "repo": job.repo,
+            }));
+            if let Err(e) = event_res {
+                error!("handle_job: Error queueing webhook event {}", e);
+            }
+            if let (Some(kind), Some(started_at), Some(ended_at)) = (JobKind::from_db(job.kind), job.started_at, job.ended_at) {
+                let result = if failed { "failed" } else { "ended" };
+                let repo = job_metrics_repo(&job, build_id, conn);
+                metrics::observe(metrics::JOB_DURATION,
+                                 &[("kind", kind.name()), ("repo", &repo), ("result", result)],
+                                 ended_at.duration_since(started_at).unwrap_or_default());
+            }
+            if failed {
+                notify_job_failure(executor, conn, job_id);
+                if let Err(e) = check_job_failure_rate(&executor.config, conn, &job) {
+                    error!("#{}: Failed to check the job failure rate: {}", job_id, e);
+                }
+            }
+        },
+        Err(e) => {
+            error!("handle_job: Error updating job {}", e);
+            errorreporting::report_error(&format!("Error updating job: {}", e),
+                                         &[("job", job_id.to_string())]);
+        },
     }
 }
 
+/* Continues the trace of the request that created the job */
+fn start_job_span(conn: &PgConnection, job_id: i32, kind: JobKind) -> tracing::Span {
+    let trace_parent = jobs::table
+        .filter(jobs::id.eq(job_id))
+        .select(jobs::trace_parent)
+        .get_result::<Option<String>>(conn)
+        .ok()
+        .and_then(|trace_parent| trace_parent)
+        .and_then(|trace_parent| TraceContext::parse(&trace_parent));
+    let mut span = tracing::start_span_with_parent(&format!("job {}", kind.name()), trace_parent);
+    span.tag("job.id", &job_id.to_string());
+    span
+}
 
 fn process_one_job (executor: &mut JobExecutor, conn: &PgConnection) -> bool {
-    let new_instance = pick_next_job(executor, conn);
+    COMMAND_LOG_DIR.with(|dir| *dir.borrow_mut() = Some(executor.config.job_log_dir.clone()));
+    RUNNING_COMMANDS.with(|running| *running.borrow_mut() = Some(executor.running_commands.clone()));
+    COMMAND_BACKEND.with(|backend| *backend.borrow_mut() = Some(executor.commands.clone()));
+
+    let new_instance = metrics::time_query("pick_next_job", || pick_next_job(executor, conn));
 
     match new_instance {
         Ok(mut instance) => {
-            let (new_status, new_results) =
-                match instance.handle_job(executor, conn) {
-                    Ok(json) =>  {
-                        info!("#{}: Job succeeded", instance.get_job_id());
-                        (JobStatus::Ended, json.to_string())
-                    },
-                    Err(e) => {
-                        job_log_and_error(instance.get_job_id(), conn,
-                                          &format!("Job failed: {}", e.to_string()));
-                        (JobStatus::Broken, json!({"error-message": e.to_string()}).to_string())
-                    }
-                };
+            let cgroup = instance.get_kind().and_then(|kind| cgroups::cgroup_for(&executor.config, kind.name()));
+            COMMAND_CGROUP.with(|c| *c.borrow_mut() = cgroup);
 
-            let update_res =
-                diesel::update(jobs::table)
-                .filter(jobs::id.eq(instance.get_job_id()))
-                .set((jobs::status.eq(new_status as i16),
-                      jobs::results.eq(new_results)))
-                .execute(conn);
-            if let Err(e) = update_res {
-                error!("handle_job: Error updating job {}", e);
-            }
+            let _context = logging::job_context(instance.get_job_id(), instance.get_build_id());
+            let _span = instance.get_kind().map(|kind| start_job_span(conn, instance.get_job_id(), kind));
+            let res = instance.handle_job(executor, conn);
+            finish_job(executor, conn, instance.get_job_id(), instance.get_build_id(), res);
             true /* We handled a job */
         },
         Err(diesel::NotFound) => {
@@ -1057,6 +4188,8 @@ fn process_one_job (executor: &mut JobExecutor, conn: &PgConnection) -> bool {
         },
         Err(e) => {
             error!("Unexpected db error processing job: {}", e);
+            errorreporting::report_error(&format!("Unexpected db error processing job: {}", e),
+                                         &[("repo", executor.repo.clone().unwrap_or("builds".to_string()))]);
             false
         },
     }
@@ -1102,18 +4235,28 @@ struct ExecutorInfo {
     addr: Addr<JobExecutor>,
     processing_job: bool,
     job_queued: bool,
+    db_failures: u32,
 }
 
+/* Don't wait longer than this between retries when the db is unreachable */
+const MAX_DB_RETRY_DELAY: time::Duration = time::Duration::from_secs(5 * 60);
+
 pub struct JobQueue {
     executors: HashMap<Option<String>,RefCell<ExecutorInfo>>,
     running: bool,
+    running_commands: RunningCommands,
+    shutdown_deadline: Option<time::Duration>,
+    poll_interval: time::Duration,
 }
 
 impl JobQueue {
     fn kick(&mut self, repo: &Option<String>, ctx: &mut Context<Self>) {
         let mut info = match self.executors.get(repo) {
             None => {
-                error!("Got process jobs for non existing executor");
+                /* In frontend mode there are no executors, the workers pick up the jobs */
+                if !self.executors.is_empty() {
+                    error!("Got process jobs for non existing executor");
+                }
                 return
             },
             Some(executor_info) => executor_info.borrow_mut(),
@@ -1134,32 +4277,57 @@ impl JobQueue {
                     .send (ProcessOneJob())
                     .into_actor(self)
                     .then(|result, queue, ctx| {
-                        let job_queued = {
+                        let poll_interval = queue.poll_interval;
+                        let (job_queued, db_failures) = {
                             let mut info = queue.executors.get(&repo).unwrap().borrow_mut();
                             info.processing_job = false;
-                            info.job_queued
+                            match result {
+                                Ok(Err(())) => {
+                                    /* Only log the first failure, not every retry */
+                                    if info.db_failures == 0 {
+                                        error!("Can't get a database connection for processing jobs, retrying");
+                                    }
+                                    info.db_failures += 1;
+                                },
+                                _ => {
+                                    if info.db_failures > 0 {
+                                        info!("Database connection for processing jobs is back");
+                                    }
+                                    info.db_failures = 0;
+                                },
+                            }
+                            (info.job_queued, info.db_failures)
                         };
 
                         if queue.running {
                             let processed_job = match result {
                                 Ok(Ok(true)) => true,
                                 Ok(Ok(false)) => false,
+                                Ok(Err(())) => false,
                                 res => {
                                     error!("Unexpected ProcessOneJob result {:?}", res);
                                     false
                                 },
                             };
 
-                            // If we ran a job, or a job was queued, kick again
-                            if job_queued || processed_job {
+                            if db_failures > 0 {
+                                // Back off while the db is unreachable
+                                let delay = std::cmp::min(poll_interval * 2u32.pow(std::cmp::min(db_failures, 8)),
+                                                          MAX_DB_RETRY_DELAY);
+                                ctx.run_later(delay, move |queue, ctx| {
+                                    queue.kick(&repo, ctx);
+                                });
+                            } else if job_queued || processed_job {
+                                // If we ran a job, or a job was queued, kick again
                                 queue.kick(&repo, ctx);
                             } else  {
                                 // We send a ProcessJobs message each time we added something to the
-                                // db, but case something external modifes the db we have a 10 sec
-                                // polling loop here.  Ideally this should be using NOTIFY/LISTEN
-                                // postgre, but diesel/pq-sys does not currently support it.
+                                // db, but case something external modifes the db (like a frontend
+                                // in a split setup) we have a polling loop here.  Ideally this should
+                                // be using NOTIFY/LISTEN postgre, but diesel/pq-sys does not currently
+                                // support it.
 
-                                ctx.run_later(time::Duration::new(10, 0), move |queue, ctx| {
+                                ctx.run_later(poll_interval, move |queue, ctx| {
                                     queue.kick(&repo, ctx);
                                 });
                             }
@@ -1208,8 +4376,19 @@ impl Message for StopJobQueue {
 impl Handler<StopJobQueue> for JobQueue {
     type Result = ActorResponse<JobQueue, (), ()>;
 
-    fn handle(&mut self, _msg: StopJobQueue, _ctx: &mut Self::Context) -> Self::Result {
+    fn handle(&mut self, _msg: StopJobQueue, ctx: &mut Self::Context) -> Self::Result {
         self.running = false;
+        self.running_commands.start_shutdown();
+
+        if let Some(deadline) = self.shutdown_deadline {
+            ctx.run_later(deadline, |job_queue, ctx| {
+                info!("Shutdown deadline reached, terminating running commands");
+                job_queue.running_commands.signal_all(libc::SIGTERM);
+                ctx.run_later(SHUTDOWN_KILL_DELAY, |job_queue, _ctx| {
+                    job_queue.running_commands.signal_all(libc::SIGKILL);
+                });
+            });
+        }
 
         let executors : Vec<Addr<JobExecutor>> = self.executors.values().map(|info| info.borrow().addr.clone()).collect();
         ActorResponse::async(
@@ -1230,8 +4409,12 @@ impl Handler<StopJobQueue> for JobQueue {
 fn start_executor(repo: &Option<String>,
                   config: &Arc<Config>,
                   delta_generator: &Addr<DeltaGenerator>,
-                  pool: &Pool) -> RefCell<ExecutorInfo>
+                  pool: &Pool,
+                  running_commands: &RunningCommands,
+                  commands: &Arc<dyn CommandBackend>) -> RefCell<ExecutorInfo>
 {
+    let running_commands_copy = running_commands.clone();
+    let commands_copy = commands.clone();
     let config_copy = config.clone();
     let delta_generator_copy = delta_generator.clone();
     let pool_copy = pool.clone();
@@ -1241,68 +4424,110 @@ fn start_executor(repo: &Option<String>,
             repo: repo_clone.clone(),
             config: config_copy.clone(),
             delta_generator: delta_generator_copy.clone(),
-            pool: pool_copy.clone()
+            pool: pool_copy.clone(),
+            running_commands: running_commands_copy.clone(),
+            commands: commands_copy.clone(),
+            picked_count: 0,
+            last_picked: HashMap::new(),
         }),
         processing_job: false,
         job_queued: false,
+        db_failures: 0,
     })
 }
 
 
 pub fn start_job_executor(config: Arc<Config>,
                           delta_generator: Addr<DeltaGenerator>,
-                          pool: Pool) -> Addr<JobQueue> {
+                          pool: Pool,
+                          commands: Arc<dyn CommandBackend>) -> Addr<JobQueue> {
+    let running_commands = RunningCommands::default();
+    match pool.get() {
+        Ok(conn) => {
+            for repoconfig in config.repos.values() {
+                if let Err(e) = schedule_prune_job(&conn, repoconfig) {
+                    error!("Failed to schedule prune job for repo {}: {}", repoconfig.name, e);
+                }
+                if let Err(e) = schedule_gc_job(&conn, repoconfig) {
+                    error!("Failed to schedule gc job for repo {}: {}", repoconfig.name, e);
+                }
+            }
+        },
+        Err(e) => error!("Failed to schedule prune jobs: {}", e),
+    }
+
     let mut executors = HashMap::new();
     executors.insert(None,
-                     start_executor(&None, &config, &delta_generator, &pool));
+                     start_executor(&None, &config, &delta_generator, &pool, &running_commands, &commands));
 
     for repo in config.repos.keys().cloned() {
         executors.insert(Some(repo.clone()),
-                         start_executor(&Some(repo.clone()), &config, &delta_generator, &pool));
+                         start_executor(&Some(repo.clone()), &config, &delta_generator, &pool, &running_commands, &commands));
     }
+    start_lease_keeper(config.clone(), pool.clone());
+
     JobQueue {
         executors: executors,
         running: true,
+        running_commands,
+        shutdown_deadline: config.shutdown_deadline_secs.map(time::Duration::from_secs),
+        poll_interval: time::Duration::from_secs(config.job_poll_interval_secs),
     }.start()
 }
 
-pub fn cleanup_started_jobs(pool: &Pool) -> Result<(), diesel::result::Error> {
-    let conn = &pool.get().unwrap();
+/* A job queue without executors, for frontend mode */
+pub fn start_frontend_job_queue() -> Addr<JobQueue> {
+    JobQueue {
+        executors: HashMap::new(),
+        running: true,
+        running_commands: RunningCommands::default(),
+        shutdown_deadline: None,
+        poll_interval: time::Duration::from_secs(10),
+    }.start()
+}
+
+/* Marks started jobs as broken, and the builds they were working on as
+ * failed. Used for jobs whose node went away while running them. */
+fn break_started_jobs(conn: &PgConnection, job_ids: &[i32], reason: &str) -> Result<(), DieselError> {
+    if job_ids.is_empty() {
+        return Ok(())
+    }
     {
         use schema::builds::dsl::*;
+        let nullable_job_ids: Vec<Option<i32>> = job_ids.iter().map(|job_id| Some(*job_id)).collect();
         let (verifying, _) = RepoState::Verifying.to_db();
-        let (purging, _) = RepoState::Purging.to_db();
-        let (failed, failed_reason) = RepoState::Failed("Server was restarted during job".to_string()).to_db();
+        let (failed, failed_reason) = RepoState::Failed(format!("{} during job", reason)).to_db();
         let n_updated =
             diesel::update(builds)
-            .filter(repo_state.eq(verifying).or(repo_state.eq(purging)))
+            .filter(repo_state.eq(verifying).and(commit_job_id.eq_any(nullable_job_ids.clone())))
             .set((repo_state.eq(failed),
                   repo_state_reason.eq(failed_reason)))
             .execute(conn)?;
         if n_updated != 0 {
-            error!("Marked {} builds as failed due to in progress jobs on startup", n_updated);
+            error!("Marked {} builds as failed due to in progress jobs ({})", n_updated, reason);
         }
         let (publishing, _) = PublishedState::Publishing.to_db();
-        let (failed_publish, failed_publish_reason) = PublishedState::Failed("Server was restarted during publish".to_string()).to_db();
+        let (failed_publish, failed_publish_reason) = PublishedState::Failed(format!("{} during publish", reason)).to_db();
         let n_updated2 =
             diesel::update(builds)
-            .filter(published_state.eq(publishing))
+            .filter(published_state.eq(publishing).and(publish_job_id.eq_any(nullable_job_ids)))
             .set((published_state.eq(failed_publish),
                   published_state_reason.eq(failed_publish_reason)))
             .execute(conn)?;
         if n_updated2 != 0 {
-            error!("Marked {} builds as failed to publish due to in progress jobs on startup", n_updated2);
+            error!("Marked {} builds as failed to publish due to in progress jobs ({})", n_updated2, reason);
         }
     };
     {
         use schema::jobs::dsl::*;
         let updated =
             diesel::update(jobs)
+            .filter(id.eq_any(job_ids.to_vec()))
             .filter(status.eq(JobStatus::Started as i16))
             .set((status.eq(JobStatus::Broken as i16),))
             .get_results::<Job>(conn)?;
         if !updated.is_empty() {
-            error!("Marked {} jobs as broken due to being started already at startup", updated.len());
+            error!("Marked {} jobs as broken due to being started already ({})", updated.len(), reason);
             /* For any repo that had an update-repo marked broken, queue a new job */
             for job in updated.iter() {
                 let mut queue_update_for_repos = HashSet::new();
@@ -1320,3 +4545,497 @@ pub fn cleanup_started_jobs(pool: &Pool) -> Result<(), diesel::result::Error> {
     };
     Ok(())
 }
+
+/* On startup, break the jobs we were running before the restart, and
+ * those of other nodes whose lease ran out (or that predate leases).
+ * Jobs that were requeued on shutdown will run again, so leave them alone */
+pub fn cleanup_started_jobs(config: &Config, pool: &Pool) -> Result<(), diesel::result::Error> {
+    use diesel::dsl::now;
+
+    let conn = &pool.get().unwrap();
+    conn.transaction(|| {
+        let (purging, _) = RepoState::Purging.to_db();
+        let (failed, failed_reason) = RepoState::Failed("Server was restarted during purge".to_string()).to_db();
+        let n_updated =
+            diesel::update(builds::table)
+            .filter(builds::repo_state.eq(purging))
+            .set((builds::repo_state.eq(failed),
+                  builds::repo_state_reason.eq(failed_reason)))
+            .execute(conn)?;
+        if n_updated != 0 {
+            error!("Marked {} builds as failed due to in progress purges on startup", n_updated);
+        }
+
+        let job_ids = jobs::table
+            .select(jobs::id)
+            .filter(jobs::status.eq(JobStatus::Started as i16))
+            .filter(jobs::lease_owner.is_null()
+                    .or(jobs::lease_owner.eq(&config.node_name))
+                    .or(jobs::lease_expires_at.lt(now)))
+            .for_update()
+            .skip_locked()
+            .get_results::<i32>(conn)?;
+        break_started_jobs(conn, &job_ids, "Server was restarted")
+    })
+}
+
+/* Breaks the jobs of other nodes that stopped renewing their leases */
+fn reclaim_expired_leases(config: &Config, conn: &PgConnection) -> Result<(), DieselError> {
+    use diesel::dsl::now;
+
+    conn.transaction(|| {
+        let expired = jobs::table
+            .select((jobs::id, jobs::lease_owner))
+            .filter(jobs::status.eq(JobStatus::Started as i16))
+            .filter(jobs::lease_owner.ne(&config.node_name))
+            .filter(jobs::lease_expires_at.lt(now))
+            .for_update()
+            .skip_locked()
+            .get_results::<(i32, Option<String>)>(conn)?;
+        for (job_id, owner) in expired.iter() {
+            warn!("Lease of job {} on node {} expired", job_id, owner.as_ref().map(|s| s.as_str()).unwrap_or("unknown"));
+        }
+        let job_ids: Vec<i32> = expired.iter().map(|(job_id, _owner)| *job_id).collect();
+        break_started_jobs(conn, &job_ids, "Node stopped responding")
+    })
+}
+
+fn renew_leases(config: &Config, conn: &PgConnection) -> Result<(), DieselError> {
+    use diesel::dsl::{now, IntervalDsl};
+
+    diesel::update(jobs::table)
+        .filter(jobs::status.eq(JobStatus::Started as i16))
+        .filter(jobs::lease_owner.eq(&config.node_name))
+        .set(jobs::lease_expires_at.eq((now + (config.job_lease_secs as i64).seconds()).nullable()))
+        .execute(conn)?;
+    Ok(())
+}
+
+/* Renews the leases of the jobs this node runs, and reclaims the ones of
+ * dead nodes. This runs on its own, as the executors are busy with the
+ * jobs themselves. */
+pub struct LeaseKeeper {
+    config: Arc<Config>,
+    pool: Pool,
+    running: bool,
+}
+
+impl LeaseKeeper {
+    fn update_leases(&mut self, ctx: &mut Context<Self>) {
+        if self.running {
+            return
+        }
+        self.running = true;
+
+        let config = self.config.clone();
+        let pool = self.pool.clone();
+        ctx.spawn(
+            web::block(move || {
+                let conn = pool.get().map_err(|e| e.to_string())?;
+                metrics::time_query("renew_leases", || renew_leases(&config, &conn)).map_err(|e| e.to_string())?;
+                metrics::time_query("reclaim_expired_leases", || reclaim_expired_leases(&config, &conn)).map_err(|e| e.to_string())
+            })
+                .map_err(|e| error!("Failed to update job leases: {}", e))
+                .into_actor(self)
+                .then(|_r, keeper, _ctx| {
+                    keeper.running = false;
+                    actix::fut::ok(())
+                })
+        );
+    }
+}
+
+impl Actor for LeaseKeeper {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Context<Self>) {
+        /* Renew well before the lease runs out */
+        let interval = time::Duration::from_secs((self.config.job_lease_secs / 3).max(1));
+        ctx.run_interval(interval, |keeper, ctx| keeper.update_leases(ctx));
+    }
+}
+
+fn start_lease_keeper(config: Arc<Config>, pool: Pool) -> Addr<LeaseKeeper> {
+    LeaseKeeper {
+        config,
+        pool,
+        running: false,
+    }.start()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct QueuedJob {
+        job_id: i32,
+        build_id: Option<i32>,
+        kind: i16,
+        order: i32,
+    }
+
+    impl JobInstance for QueuedJob {
+        fn get_job_id (&self) -> i32 {
+            self.job_id
+        }
+        fn get_kind (&self) -> Option<JobKind> {
+            JobKind::from_db(self.kind)
+        }
+        fn get_build_id (&self) -> Option<i32> {
+            self.build_id
+        }
+        fn order (&self) -> i32 {
+            self.order
+        }
+        fn handle_job (&mut self, _executor: &JobExecutor, _conn: &PgConnection) -> JobResult<serde_json::Value> {
+            unreachable!()
+        }
+    }
+
+    fn queued(job_id: i32, build_id: Option<i32>, kind: JobKind, order: i32) -> Box<dyn JobInstance> {
+        Box::new(QueuedJob { job_id, build_id, kind: kind.to_db(), order })
+    }
+
+    /* Picks the queued jobs one by one, like pick_next_job() */
+    fn pick_order(mut instances: Vec<Box<dyn JobInstance>>) -> Vec<i32> {
+        let mut last_picked = HashMap::new();
+        let mut picked = vec![];
+        while !instances.is_empty() {
+            sort_round_robin(&mut instances, &mut last_picked);
+            let instance = instances.remove(0);
+            last_picked.insert(fairness_group(instance.as_ref()), picked.len() as u64 + 1);
+            picked.push(instance.get_job_id());
+        }
+        picked
+    }
+
+    #[test]
+    fn test_round_robin() {
+        /* Build 1 queued a lot of commits before build 2 queued one */
+        let picked = pick_order(vec![
+            queued(1, Some(1), JobKind::Commit, 0),
+            queued(2, Some(1), JobKind::Commit, 0),
+            queued(3, Some(1), JobKind::Commit, 0),
+            queued(4, Some(2), JobKind::Commit, 0),
+            queued(5, None, JobKind::Bundle, 0),
+            queued(6, Some(2), JobKind::Commit, 0),
+        ]);
+        assert_eq!(picked, vec![1, 4, 5, 2, 6, 3]);
+
+        /* The order of the kinds goes first */
+        let picked = pick_order(vec![
+            queued(1, None, JobKind::UpdateRepo, 2),
+            queued(2, Some(1), JobKind::Publish, 1),
+            queued(3, Some(1), JobKind::Commit, 0),
+            queued(4, Some(2), JobKind::Commit, 0),
+        ]);
+        assert_eq!(picked, vec![3, 4, 2, 1]);
+    }
+
+    #[test]
+    fn test_round_robin_forgets_idle_groups() {
+        let mut last_picked = HashMap::new();
+        last_picked.insert("build/1".to_string(), 5);
+        last_picked.insert("build/2".to_string(), 3);
+        let mut instances = vec![queued(7, Some(1), JobKind::Commit, 0), queued(8, Some(3), JobKind::Commit, 0)];
+        sort_round_robin(&mut instances, &mut last_picked);
+        assert_eq!(instances.iter().map(|instance| instance.get_job_id()).collect::<Vec<i32>>(), vec![8, 7]);
+        assert_eq!(last_picked.len(), 1);
+        assert_eq!(last_picked.get("build/1"), Some(&5));
+    }
+
+    fn imported_build() -> ImportedBuild {
+        let mut refs = HashMap::new();
+        refs.insert("app/org.example.App/x86_64/stable".to_string(), "abcd".to_string());
+        ImportedBuild {
+            repo: "stable".to_string(),
+            refs,
+            filtered_refs: vec!["app/org.example.App/aarch64/stable".to_string()],
+            unchanged_refs: vec!["runtime/org.example.App.Locale/x86_64/stable".to_string()],
+        }
+    }
+
+    #[test]
+    fn test_publish_result() {
+        let result = publish_result(Ok(imported_build()), &Ok(42)).unwrap();
+        assert_eq!(result.update_repo_job, 42);
+        assert_eq!(result.refs.get("app/org.example.App/x86_64/stable"), Some(&"abcd".to_string()));
+        assert_eq!(result.filtered_refs, vec!["app/org.example.App/aarch64/stable".to_string()]);
+        assert_eq!(result.unchanged_refs, vec!["runtime/org.example.App.Locale/x86_64/stable".to_string()]);
+
+        /* The import error wins, the update may have been queued for the rest of the batch */
+        let err = publish_result(Err(JobError::new("Import failed")), &Ok(42)).unwrap_err();
+        assert_eq!(err.to_string(), "InternalError: Import failed");
+
+        let err = publish_result(Ok(imported_build()), &Err(JobError::new("Queueing failed"))).unwrap_err();
+        assert_eq!(err.to_string(), "InternalError: Queueing failed");
+    }
+
+    #[test]
+    fn test_resign_job() {
+        let job: ResignJob = serde_json::from_str(r#"{"repo": "stable", "sign-type": "ed25519", "key": "keys", "delete-key": null}"#).unwrap();
+        assert_eq!(job.sign_type, SignType::Ed25519);
+        assert!(serde_json::from_str::<ResignJob>(r#"{"repo": "stable", "sign-type": "rsa", "key": "k", "delete-key": null}"#).is_err());
+        assert_eq!(SignType::from_name("gpg"), Some(SignType::Gpg));
+        assert_eq!(SignType::from_name(SignType::Ed25519.name()), Some(SignType::Ed25519));
+        assert_eq!(SignType::from_name("GPG"), None);
+
+        assert!(is_already_signed(&JobError::new("Command \"ostree\" exited unsuccesfully: error: Commit is already signed with GPG key ABCD")));
+        assert!(!is_already_signed(&JobError::new("Command \"ostree\" exited unsuccesfully: error: No such metadata object")));
+    }
+
+    #[test]
+    fn test_legacy_appstream_ref() {
+        assert_eq!(legacy_appstream_ref("appstream2/x86_64"), "appstream/x86_64");
+        assert_eq!(legacy_appstream_ref("appstream2/aarch64"), "appstream/aarch64");
+    }
+
+    #[test]
+    fn test_preserved_commit_timestamp() {
+        assert_eq!(preserved_commit_timestamp(0), Some("1970-01-01T00:00:00Z".to_string()));
+        assert_eq!(preserved_commit_timestamp(1577836800), Some("2020-01-01T00:00:00Z".to_string()));
+        assert_eq!(preserved_commit_timestamp(253402300799), Some("9999-12-31T23:59:59Z".to_string()));
+        /* Uploaded commits can have any timestamp, these must not panic */
+        assert_eq!(preserved_commit_timestamp(253402300800), None);
+        assert_eq!(preserved_commit_timestamp(i64::MAX as u64), None);
+        assert_eq!(preserved_commit_timestamp(u64::MAX), None);
+    }
+
+    #[test]
+    fn test_repo_lock_order() {
+        assert_eq!(repo_lock_order(&["stable", "beta"]), vec!["beta", "stable"]);
+        assert_eq!(repo_lock_order(&["beta", "stable"]), vec!["beta", "stable"]);
+        assert_eq!(repo_lock_order(&["stable", "stable"]), vec!["stable"]);
+    }
+
+    #[test]
+    fn test_failure_alert_window_start() {
+        let at = |secs| time::UNIX_EPOCH + time::Duration::from_secs(secs);
+        assert_eq!(failure_alert_window_start(at(7200), 3600), at(7200));
+        assert_eq!(failure_alert_window_start(at(7201), 3600), at(7200));
+        assert_eq!(failure_alert_window_start(at(10799), 3600), at(7200));
+        assert_eq!(failure_alert_window_start(at(10800), 3600), at(10800));
+        assert_eq!(failure_alert_window_start(at(10800), 0), at(10800));
+    }
+
+    #[test]
+    fn test_subprocess_output_timeout() {
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg("echo out; echo err >&2; exit 3");
+        let output = subprocess_output(cmd, Some(time::Duration::from_secs(10))).unwrap();
+        assert_eq!(output.status.code(), Some(3));
+        assert_eq!(output.stdout, b"out\n");
+        assert_eq!(output.stderr, b"err\n");
+
+        let mut cmd = Command::new("sleep");
+        cmd.arg("10");
+        let started = time::Instant::now();
+        let err = subprocess_output(cmd, Some(time::Duration::from_millis(100))).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(started.elapsed() < time::Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_published_ref_name() {
+        let branches: HashMap<String, String> = vec![("test".to_string(), "stable".to_string())].into_iter().collect();
+        assert_eq!(published_ref_name("app/org.example.App/x86_64/test", &branches), "app/org.example.App/x86_64/stable");
+        assert_eq!(published_ref_name("runtime/org.example.App.Locale/x86_64/test", &branches), "runtime/org.example.App.Locale/x86_64/stable");
+        assert_eq!(published_ref_name("app/org.example.App/x86_64/beta", &branches), "app/org.example.App/x86_64/beta");
+        assert_eq!(published_ref_name("screenshots/x86_64", &branches), "screenshots/x86_64");
+        assert_eq!(published_ref_name("app/org.example.App/x86_64/test", &HashMap::new()), "app/org.example.App/x86_64/test");
+    }
+
+    /* A (a{sv}aya(say)sstayay) commit with no metadata, parent, subject or body */
+    fn commit_object(timestamp: u64, root_tree: u8, root_metadata: u8) -> Vec<u8> {
+        let mut commit = vec![0, 0]; // The subject and body
+        commit.extend_from_slice(&[0; 6]);
+        commit.extend_from_slice(&timestamp.to_be_bytes());
+        commit.extend_from_slice(&[root_tree; 32]);
+        commit.extend_from_slice(&[root_metadata; 32]);
+        /* The framing offsets of the variable size members but the last, in reverse */
+        commit.extend_from_slice(&[48, 2, 1, 0, 0, 0]);
+        commit
+    }
+
+    fn add_commit(repo_path: &PathBuf, ref_name: &str, commit: &[u8]) -> String {
+        let checksum = hex::encode(sha256(commit));
+        let path = ostree::get_object_path(repo_path, &checksum, "commit").unwrap();
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, commit).unwrap();
+        let ref_path = repo_path.join("refs/heads").join(ref_name);
+        fs::create_dir_all(ref_path.parent().unwrap()).unwrap();
+        fs::write(&ref_path, format!("{}\n", checksum)).unwrap();
+        checksum
+    }
+
+    #[test]
+    fn test_same_content_as_published() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo_path = dir.path().join("repo");
+        let build_repo_path = dir.path().join("build");
+        let ref_name = "app/org.example.App/x86_64/stable";
+
+        add_commit(&repo_path, ref_name, &commit_object(1, 1, 2));
+        let commit = add_commit(&build_repo_path, ref_name, &commit_object(2, 1, 2));
+        assert_eq!(ostree::get_commit(&build_repo_path, &commit).unwrap().root_tree, hex::encode([1; 32]));
+        assert!(same_content_as_published(&repo_path, &build_repo_path, ref_name, &commit).unwrap());
+
+        /* Other files or other file metadata */
+        let commit = add_commit(&build_repo_path, ref_name, &commit_object(2, 3, 2));
+        assert!(!same_content_as_published(&repo_path, &build_repo_path, ref_name, &commit).unwrap());
+        let commit = add_commit(&build_repo_path, ref_name, &commit_object(2, 1, 3));
+        assert!(!same_content_as_published(&repo_path, &build_repo_path, ref_name, &commit).unwrap());
+
+        /* Never published */
+        let other_ref = "app/org.example.Other/x86_64/stable";
+        let commit = add_commit(&build_repo_path, other_ref, &commit_object(2, 1, 2));
+        assert!(!same_content_as_published(&repo_path, &build_repo_path, other_ref, &commit).unwrap());
+
+        /* A missing build commit is an error */
+        assert!(same_content_as_published(&repo_path, &build_repo_path, ref_name, &hex::encode([4; 32])).is_err());
+    }
+
+    fn validate_output(code: i32, stdout: &str, stderr: &str) -> Output {
+        Output {
+            status: std::os::unix::process::ExitStatusExt::from_raw(code << 8),
+            stdout: stdout.as_bytes().to_vec(),
+            stderr: stderr.as_bytes().to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_parse_appstream_validation() {
+        let result = parse_appstream_validation(&validate_output(0, "\u{2714} Validation was successful.\n", ""));
+        assert!(result.errors.is_empty() && result.warnings.is_empty());
+
+        let stdout = "E: org.example.App:12: tag-missing name\n\
+                      W: org.example.App:3: description-first-para-too-short\n  \
+                      I: org.example.App:5: url-not-secure\n\n\
+                      \u{2718} Validation failed: errors: 1, warnings: 1\n";
+        let result = parse_appstream_validation(&validate_output(3, stdout, ""));
+        assert_eq!(result.errors, vec!["org.example.App:12: tag-missing name".to_string()]);
+        assert_eq!(result.warnings, vec!["org.example.App:3: description-first-para-too-short".to_string()]);
+
+        /* Warnings alone are not a failure of appstreamcli itself */
+        let result = parse_appstream_validation(&validate_output(0, "W: org.example.App:3: summary-too-long\n", ""));
+        assert!(result.errors.is_empty());
+        assert_eq!(result.warnings.len(), 1);
+
+        let result = parse_appstream_validation(&validate_output(1, "", "Could not parse the file\n"));
+        assert_eq!(result.errors, vec!["appstreamcli validate failed: Could not parse the file".to_string()]);
+        assert!(result.warnings.is_empty());
+    }
+
+    #[test]
+    fn test_log_batch() {
+        let start = time::Instant::now();
+        let mut batch = LogBatch::new();
+        assert_eq!(batch.take(), None);
+
+        assert_eq!(batch.push("one", start), None);
+        assert_eq!(batch.push("two", start + time::Duration::from_millis(500)), None);
+        assert_eq!(batch.push("three", start + LOG_BATCH_INTERVAL), Some("one\ntwo\nthree\n".to_string()));
+        assert_eq!(batch.take(), None);
+
+        /* The interval starts with the first line of the batch */
+        let later = start + time::Duration::from_secs(60);
+        for i in 0..LOG_BATCH_LINES - 1 {
+            assert_eq!(batch.push(&i.to_string(), later), None);
+        }
+        let full = batch.push("last", later).unwrap();
+        assert_eq!(full.lines().count(), LOG_BATCH_LINES);
+        assert!(full.ends_with("last\n"));
+
+        assert_eq!(batch.push("rest", later), None);
+        assert_eq!(batch.take(), Some("rest\n".to_string()));
+    }
+
+    #[test]
+    fn test_sandboxed_command_creates_writable_paths() {
+        use std::ffi::OsStr;
+
+        let config: Config = serde_json::from_value(json!({
+            "database-url": "postgres://localhost/flat-manager",
+            "secret": "c2VjcmV0",
+            "repos": {},
+            "build-repo-base": "build-repo",
+            "sandbox-commands": true,
+        })).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let readonly = dir.path().join("readonly");
+        let writable = dir.path().join("repo/objects");
+
+        let cmd = new_sandboxed_command(&config, Path::new("ostree"), &[writable.as_path()], &[readonly.as_path()], false);
+        assert_eq!(cmd.get_program(), "bwrap");
+        assert!(writable.is_dir());
+        assert!(!readonly.exists());
+
+        let args: Vec<&OsStr> = cmd.get_args().collect();
+        assert!(args.windows(3).any(|w| w == [OsStr::new("--bind"), writable.as_os_str(), writable.as_os_str()]));
+        assert!(args.windows(3).any(|w| w == [OsStr::new("--ro-bind"), readonly.as_os_str(), readonly.as_os_str()]));
+        assert!(!args.contains(&OsStr::new("--share-net")));
+        assert_eq!(args.last(), Some(&OsStr::new("ostree")));
+    }
+
+    #[test]
+    fn test_commit_checkpoint_resume() {
+        let app = "app/org.example.App/x86_64/stable";
+        let locale = "runtime/org.example.App.Locale/x86_64/stable";
+        let debug = "runtime/org.example.App.Debug/x86_64/stable";
+        let build_refs: Vec<models::BuildRef> = [app, locale, debug].iter().enumerate()
+            .map(|(i, ref_name)| models::BuildRef {
+                id: i as i32,
+                build_id: 1,
+                ref_name: ref_name.to_string(),
+                commit: format!("upload{}", i),
+                size: None,
+            })
+            .collect();
+
+        /* The job committed two of the refs before failing on the third */
+        let mut committed_refs = HashMap::new();
+        committed_refs.insert(app.to_string(), "commit0".to_string());
+        committed_refs.insert(locale.to_string(), "commit1".to_string());
+        let running = CommitCheckpoint { committed_refs: committed_refs.clone() }.to_results();
+        let checkpoint = CommitCheckpoint::from_results(&running).unwrap();
+        assert_eq!(checkpoint.committed_refs, committed_refs);
+
+        let failed = FailedJobResult { error_message: "Failed to commit".to_string(), committed_refs: checkpoint.committed_refs };
+        let mut job = Job {
+            id: 1,
+            kind: JobKind::Commit.to_db(),
+            status: JobStatus::Broken as i16,
+            contents: "{}".to_string(),
+            results: Some(json!(JobResults::new(failed)).to_string()),
+            log: String::new(),
+            start_after: None,
+            repo: None,
+            started_at: None,
+            ended_at: None,
+            progress: None,
+            lease_owner: None,
+            lease_expires_at: None,
+            trace_parent: None,
+        };
+
+        /* The retry skips the refs still as committed in the build repo */
+        let retried = CommitCheckpoint::from_results(&job.retry_results().unwrap()).unwrap();
+        assert_eq!(retried.committed_refs, committed_refs);
+        let resumed = resumable_refs(retried.committed_refs, &build_refs, |ref_name| match ref_name {
+            r if r == app => Some("commit0".to_string()),
+            r if r == locale => Some("changed".to_string()),
+            _ => None,
+        });
+        assert_eq!(resumed.keys().collect::<Vec<_>>(), vec![app]);
+
+        /* Nor refs that are no longer part of the build */
+        let resumed = resumable_refs(committed_refs.clone(), &build_refs[1..], |ref_name| committed_refs.get(ref_name).cloned());
+        assert_eq!(resumed.keys().collect::<Vec<_>>(), vec![locale]);
+
+        /* Only failed commit jobs keep their checkpoint, and only of the current version */
+        job.kind = JobKind::Publish.to_db();
+        assert_eq!(job.retry_results(), None);
+        let newer = json!({ "version": models::JOB_RESULTS_VERSION + 1, "committed-refs": committed_refs }).to_string();
+        assert_eq!(CommitCheckpoint::from_results(&newer), None);
+    }
+}
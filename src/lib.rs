@@ -2,11 +2,14 @@
 
 extern crate actix;
 extern crate actix_net;
+extern crate actix_http;
+extern crate actix_server;
 extern crate actix_service;
 extern crate actix_web;
 extern crate actix_web_actors;
 extern crate actix_multipart;
 extern crate actix_files;
+extern crate awc;
 extern crate askama;
 extern crate base64;
 extern crate byteorder;
@@ -26,15 +29,25 @@ extern crate jsonwebtoken as jwt;
 #[macro_use] extern crate log;
 extern crate libc;
 extern crate walkdir;
+extern crate flate2;
 extern crate hex;
 extern crate filetime;
 extern crate num_cpus;
 extern crate time;
 extern crate tokio;
+extern crate tokio_openssl;
 extern crate tokio_process;
 extern crate tokio_signal;
+extern crate tokio_tcp;
 extern crate rand;
+extern crate sentry;
+extern crate openssl;
+extern crate tar;
+#[macro_use] extern crate lazy_static;
+extern crate zstd;
+extern crate url;
 
+pub mod admin;
 mod api;
 mod app;
 mod db;
@@ -42,21 +55,43 @@ pub mod errors;
 mod models;
 mod schema;
 mod tokens;
+mod ratelimit;
+mod tokenusage;
+mod cors;
 mod jobs;
 pub mod ostree;
 mod deltas;
 mod delayed;
 mod logger;
+mod logging;
+mod tracing;
+mod errorreporting;
+mod cgroups;
+mod mail;
+mod webhooks;
+mod purger;
+mod health;
+mod metadata;
+mod screenshots;
+mod oidc;
+mod summarycache;
+mod diskspace;
+mod throttle;
+mod objectpool;
+mod tools;
+mod metrics;
 
 use actix::prelude::*;
 use actix_web::dev::Server;
 use diesel::prelude::*;
+use futures::future;
 use diesel::r2d2::{ConnectionManager, ManageConnection};
 use std::path;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 use tokio_signal::unix::Signal;
 use app::Config;
+pub use app::RunMode;
 use deltas::{DeltaGenerator,StopDeltaGenerator};
 use jobs::{JobQueue, StopJobQueue};
 
@@ -70,17 +105,57 @@ pub fn load_config(path: &path::Path) -> Arc<Config> {
     Arc::new(config_data)
 }
 
+/* Call this once, after loading the config, before logging anything */
+pub fn init_logging(config: &Arc<Config>) {
+    logging::init(config.log_format)
+}
+
+/* Keep the returned guard alive for as long as errors should be reported */
+pub fn init_error_reporting(config: &Arc<Config>) -> Option<sentry::internals::ClientInitGuard> {
+    errorreporting::init(config)
+}
+
 embed_migrations!();
 
+/* The database may not be up yet when we start (e.g. when both are
+ * started at boot), so keep trying for a while */
+fn connect_with_retry(manager: &ConnectionManager<PgConnection>, retry_secs: u64) -> PgConnection {
+    let deadline = Instant::now() + Duration::from_secs(retry_secs);
+    let mut delay = Duration::from_secs(1);
+    loop {
+        match manager.connect() {
+            Ok(conn) => return conn,
+            Err(e) => {
+                if Instant::now() + delay > deadline {
+                    panic!("Failed to connect to database: {}", e);
+                }
+                warn!("Failed to connect to database, retrying in {} seconds: {}", delay.as_secs(), e);
+                std::thread::sleep(delay);
+                delay = std::cmp::min(delay * 2, Duration::from_secs(10));
+            }
+        }
+    }
+}
+
 fn connect_to_db(config: &Arc<Config>) -> r2d2::Pool<ConnectionManager<PgConnection>> {
     let manager = ConnectionManager::<PgConnection>::new(config.database_url.clone());
+    let pool_config = &config.database_pool;
 
     {
-        let conn = manager.connect().unwrap();
+        let conn = connect_with_retry(&manager, pool_config.startup_retry_secs);
         embedded_migrations::run_with_output(&conn, &mut std::io::stdout()).unwrap();
     }
 
-    r2d2::Pool::builder()
+    let mut builder = r2d2::Pool::builder()
+        .min_idle(pool_config.min_idle)
+        .idle_timeout(pool_config.idle_timeout_secs.map(Duration::from_secs));
+    if let Some(max_size) = pool_config.max_size {
+        builder = builder.max_size(max_size);
+    }
+    if let Some(connection_timeout_secs) = pool_config.connection_timeout_secs {
+        builder = builder.connection_timeout(Duration::from_secs(connection_timeout_secs));
+    }
+    builder
         .build(manager)
         .expect("Failed to create pool.")
 }
@@ -92,11 +167,11 @@ fn start_delta_generator(config: &Arc<Config>) -> Addr<DeltaGenerator> {
 fn start_job_queue(config: &Arc<Config>,
                    pool: &Pool,
                    delta_generator: &Addr<DeltaGenerator>) -> Addr<JobQueue> {
-    jobs::cleanup_started_jobs(&pool).expect("Failed to cleanup started jobs");
-    jobs::start_job_executor(config.clone(), delta_generator.clone(), pool.clone())
+    jobs::cleanup_started_jobs(config, pool).expect("Failed to cleanup started jobs");
+    jobs::start_job_executor(config.clone(), delta_generator.clone(), pool.clone(), Arc::new(jobs::SubprocessBackend))
 }
 
-fn handle_signal(sig: i32, server: &Server, job_queue: Addr<JobQueue>, delta_generator: Addr<DeltaGenerator>) -> impl Future<Item = (), Error = std::io::Error> {
+fn handle_signal(sig: i32, server: &Option<Server>, job_queue: Addr<JobQueue>, delta_generator: Addr<DeltaGenerator>) -> impl Future<Item = (), Error = std::io::Error> {
     let graceful = match sig {
         tokio_signal::unix::SIGINT => {
             info!("SIGINT received, exiting");
@@ -113,9 +188,15 @@ fn handle_signal(sig: i32, server: &Server, job_queue: Addr<JobQueue>, delta_gen
         _ => false,
     };
 
-    info!("Stopping http server");
-    server
-        .stop(graceful)
+    let server_stopped: Box<dyn Future<Item = (), Error = ()>> = match server {
+        Some(server) => {
+            info!("Stopping http server");
+            Box::new(server.stop(graceful))
+        },
+        None => Box::new(future::ok(())),
+    };
+
+    server_stopped
         .then(move |_result| {
             info!("Stopping delta generator");
             delta_generator
@@ -136,7 +217,7 @@ fn handle_signal(sig: i32, server: &Server, job_queue: Addr<JobQueue>, delta_gen
         })
 }
 
-fn handle_signals(server: Server,
+fn handle_signals(server: Option<Server>,
                   job_queue: Addr<JobQueue>,
                   delta_generator: Addr<DeltaGenerator>) {
     let sigint = Signal::new(tokio_signal::unix::SIGINT).flatten_stream();
@@ -151,14 +232,57 @@ fn handle_signals(server: Server,
     actix::spawn(handle_signals);
 }
 
-pub fn start(config: &Arc<Config>) -> Server {
+/* Returns the http server, unless running in worker mode */
+pub fn start(config: &Arc<Config>) -> Option<Server> {
+    let runs_jobs = config.run_mode.runs_jobs();
+
+    if runs_jobs {
+        cgroups::setup_cgroups(config).expect("Failed to set up cgroups");
+    }
+
     let pool = connect_to_db(config);
 
+    tracing::start_span_exporter(&config.tracing);
+    metrics::init(config);
+
+    let gpg_health = health::check_gpg_keys(config);
+    health::log_gpg_health(&gpg_health);
+
+    info!("Using flatpak {} and ostree {}",
+          config.tools.flatpak.as_deref().unwrap_or("(unknown version)"),
+          config.tools.ostree.as_deref().unwrap_or("(unknown version)"));
+
+    /* The tools are only needed where jobs run */
+    let self_check = if runs_jobs && config.startup_self_check {
+        Some(health::run_self_check(config))
+    } else {
+        None
+    };
+
     let delta_generator = start_delta_generator(config);
 
-    let job_queue = start_job_queue(config, &pool, &delta_generator);
+    let job_queue = if runs_jobs {
+        start_job_queue(config, &pool, &delta_generator)
+    } else {
+        info!("Running in frontend mode, jobs are run by the workers");
+        jobs::start_frontend_job_queue()
+    };
+
+    /* These are background work too, so only run them once */
+    if runs_jobs {
+        webhooks::start_webhook_sender(config.clone(), pool.clone());
+        mail::start_mail_sender(config.clone(), pool.clone());
 
-    let app = app::create_app(pool, config, job_queue.clone(), delta_generator.clone());
+        purger::start_build_purger(config.clone(), pool.clone());
+    }
+
+    let app = if config.run_mode.serves_http() {
+        Some(app::create_app(pool, config, job_queue.clone(), delta_generator.clone(),
+                             health::Health::new(gpg_health, self_check)))
+    } else {
+        info!("Running in worker mode, not starting the http server");
+        None
+    };
 
     handle_signals(app.clone(), job_queue, delta_generator);
 
@@ -8,13 +8,26 @@ use std::rc::Rc;
 use futures::{Async, Future, Poll};
 use futures::future::{ok, FutureResult};
 use std::marker::PhantomData;
+use std::time::SystemTime;
 use bytes::Bytes;
+use actix_web::http::header::{HeaderName, HeaderValue};
+use rand::{self, Rng};
 
 use tokens::ClaimsValidator;
+use logging;
+use tracing::{self, TraceContext};
+
+const REQUEST_ID_HEADER: &str = "X-Request-Id";
+const TRACEPARENT_HEADER: &str = "traceparent";
 
 pub struct Logger(Rc<Inner>);
 
 struct RequestData {
+    request_id: String,
+    trace: TraceContext,
+    trace_parent: Option<TraceContext>,
+    span_name: String,
+    start: SystemTime,
     time: time::Tm,
     remote_ip: String,
     request_line: String,
@@ -34,6 +47,7 @@ impl Inner {
     fn log(&self, req: &RequestData, resp: &ResponseData) {
         let rt = ((time::now() - req.time).num_nanoseconds().unwrap_or(0) as f64) / 1_000_000_000.0;
 
+        let _context = logging::request_context(&req.request_id);
         info!("{} \"{}\" {} {} {} {} {:.6}",
               req.remote_ip,
               req.request_line,
@@ -42,9 +56,30 @@ impl Inner {
               resp.size,
               req.user_agent,
               rt);
+
+        tracing::record_span(&req.span_name, &req.trace, &req.trace_parent, Some("SERVER"), req.start,
+                             &[("http.status_code", resp.status.as_u16().to_string()),
+                               ("request.id", req.request_id.clone())]);
+    }
+}
+
+
+/* Use the request id of a proxy in front of us, if it looks sane */
+fn get_request_id(req: &ServiceRequest) -> String {
+    if let Some(id) = req.headers().get(REQUEST_ID_HEADER).and_then(|val| val.to_str().ok()) {
+        if !id.is_empty() && id.len() <= 64 && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+            return id.to_string()
+        }
     }
+    format!("{:016x}", rand::thread_rng().gen::<u64>())
 }
 
+/* Continue the trace of the client if it sent a traceparent */
+fn get_trace_parent(req: &ServiceRequest) -> Option<TraceContext> {
+    req.headers().get(TRACEPARENT_HEADER)
+        .and_then(|val| val.to_str().ok())
+        .and_then(TraceContext::parse)
+}
 
 impl Logger {
     pub fn default() -> Logger {
@@ -95,6 +130,12 @@ where
 
     fn call(&mut self, req: ServiceRequest) -> Self::Future {
         let now = time::now();
+        let request_id = get_request_id(&req);
+        let _context = logging::request_context(&request_id);
+        let trace_parent = get_trace_parent(&req);
+        let trace = TraceContext::child_of(&trace_parent);
+        let _trace = tracing::set_current(Some(trace.clone()));
+        let span_name = format!("{} {}", req.method(), req.path());
 
         let remote_ip = req.connection_info().remote().unwrap_or("-").to_string();
 
@@ -125,6 +166,11 @@ where
             fut: self.service.call(req),
             inner: self.inner.clone(),
             request_data: Some( RequestData {
+                request_id,
+                trace,
+                trace_parent,
+                span_name,
+                start: SystemTime::now(),
                 time: now,
                 remote_ip: remote_ip,
                 request_line: request_line,
@@ -155,7 +201,17 @@ where
     type Error = Error;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        let res = futures::try_ready!(self.fut.poll());
+        /* The handlers mostly run while we poll them, so tag their logging too */
+        let request_id = self.request_data.as_ref().map(|data| data.request_id.clone()).unwrap_or_default();
+        let trace = self.request_data.as_ref().map(|data| data.trace.clone());
+        let mut res = {
+            let _context = logging::request_context(&request_id);
+            let _trace = tracing::set_current(trace);
+            futures::try_ready!(self.fut.poll())
+        };
+        if let Ok(value) = HeaderValue::from_str(&request_id) {
+            res.headers_mut().insert(HeaderName::from_static("x-request-id"), value);
+        }
 
         if let Some(error) = res.response().error() {
             if res.response().head().status != StatusCode::INTERNAL_SERVER_ERROR {
@@ -0,0 +1,102 @@
+use chrono;
+use env_logger;
+use log;
+use std::cell::RefCell;
+use std::io::Write;
+
+use app::LogFormat;
+
+/* Every log line is tagged with the request, build and job it is about,
+ * if any, so that logs from many builds and jobs can be told apart when
+ * aggregated. The context is per thread, which works for the job
+ * executors (each job runs on its own executor thread). For requests
+ * the http workers interleave many requests on one thread, so there the
+ * context is only set while calling into the handlers. */
+
+#[derive(Clone, Default)]
+struct LogContext {
+    request_id: Option<String>,
+    build_id: Option<i32>,
+    job_id: Option<i32>,
+}
+
+thread_local! {
+    static LOG_CONTEXT: RefCell<LogContext> = RefCell::new(LogContext::default());
+}
+
+/* Restores the previous context when dropped */
+pub struct LogContextGuard {
+    previous: LogContext,
+}
+
+impl Drop for LogContextGuard {
+    fn drop(&mut self) {
+        let previous = self.previous.clone();
+        LOG_CONTEXT.with(|context| *context.borrow_mut() = previous);
+    }
+}
+
+fn set_context<F: FnOnce(&mut LogContext)>(f: F) -> LogContextGuard {
+    LOG_CONTEXT.with(|context| {
+        let mut context = context.borrow_mut();
+        let previous = context.clone();
+        f(&mut context);
+        LogContextGuard {
+            previous,
+        }
+    })
+}
+
+pub fn job_context(job_id: i32, build_id: Option<i32>) -> LogContextGuard {
+    set_context(|context| {
+        context.job_id = Some(job_id);
+        context.build_id = build_id;
+    })
+}
+
+pub fn request_context(request_id: &str) -> LogContextGuard {
+    set_context(|context| context.request_id = Some(request_id.to_string()))
+}
+
+fn format_text(buf: &mut env_logger::fmt::Formatter, record: &log::Record) -> std::io::Result<()> {
+    let context = LOG_CONTEXT.with(|context| context.borrow().clone());
+    let mut tags = vec![];
+    if let Some(ref request_id) = context.request_id {
+        tags.push(format!("request={}", request_id));
+    }
+    if let Some(build_id) = context.build_id {
+        tags.push(format!("build={}", build_id));
+    }
+    if let Some(job_id) = context.job_id {
+        tags.push(format!("job={}", job_id));
+    }
+    let tags = if tags.is_empty() { String::new() } else { format!(" [{}]", tags.join(" ")) };
+    writeln!(buf, "{} {:5} {}{}: {}",
+             chrono::Utc::now().format("%Y-%m-%dT%H:%M:%SZ"),
+             record.level(),
+             record.target(),
+             tags,
+             record.args())
+}
+
+fn format_json(buf: &mut env_logger::fmt::Formatter, record: &log::Record) -> std::io::Result<()> {
+    let context = LOG_CONTEXT.with(|context| context.borrow().clone());
+    writeln!(buf, "{}", json!({
+        "time": chrono::Utc::now().to_rfc3339(),
+        "level": record.level().to_string(),
+        "target": record.target(),
+        "message": record.args().to_string(),
+        "request_id": context.request_id,
+        "build_id": context.build_id,
+        "job_id": context.job_id,
+    }))
+}
+
+pub fn init(format: LogFormat) {
+    let mut builder = env_logger::Builder::from_default_env();
+    match format {
+        LogFormat::Text => builder.format(format_text),
+        LogFormat::Json => builder.format(format_json),
+    };
+    builder.init();
+}
@@ -0,0 +1,192 @@
+use actix::prelude::*;
+use actix_web::web;
+use diesel;
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use diesel::result::{Error as DieselError};
+use futures::Future;
+use std::fs::File;
+use std::io::Write;
+use std::sync::Arc;
+use std::time::Duration;
+use tempfile;
+use chrono::Utc;
+
+use app::{Config, SmtpConfig};
+use errors::{JobError, JobResult};
+use jobs;
+use models::{NewQueuedMail, QueuedMail};
+use schema::queued_mails;
+use Pool;
+
+/* Mails are queued in the queued_mails table, so that whoever wants one
+ * sent doesn't wait for the smtp server, and sent by the MailSender
+ * actor, which retries failed mails on the next poll until they have
+ * been attempted MAX_MAIL_ATTEMPTS times. */
+
+const POLL_INTERVAL: Duration = Duration::from_secs(30);
+const MAX_MAIL_ATTEMPTS: i32 = 5;
+const MAX_MAILS_PER_POLL: i64 = 20;
+/* Slightly longer than the --max-time we give curl */
+const SEND_TIMEOUT: Duration = Duration::from_secs(90);
+
+/* Quotes a value for a curl config file, where backslash escapes apply
+ * inside double quotes */
+fn curl_config_quote(value: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' | '\\' => {
+                quoted.push('\\');
+                quoted.push(c);
+            },
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            _ => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/* We use curl for talking SMTP, the same way we use it for the other
+ * outgoing requests from jobs, sandboxed like the other commands. The
+ * credentials are passed in a curl config file rather than the
+ * arguments so they don't show up in ps. */
+fn send_mail(config: &Config, smtp: &SmtpConfig, subject: &str, body: &str) -> JobResult<()> {
+    let dir = tempfile::tempdir()?;
+    let message_path = dir.path().join("message");
+    let mut message = File::create(&message_path)?;
+    write!(message, "From: {}\r\n", smtp.from)?;
+    write!(message, "To: {}\r\n", smtp.to.join(", "))?;
+    write!(message, "Subject: {}\r\n", subject)?;
+    write!(message, "Date: {}\r\n", Utc::now().to_rfc2822())?;
+    write!(message, "Content-Type: text/plain; charset=utf-8\r\n")?;
+    write!(message, "\r\n")?;
+    for line in body.lines() {
+        write!(message, "{}\r\n", line)?;
+    }
+    message.flush()?;
+
+    let curl_config_path = dir.path().join("curl-config");
+    let mut curl_config = File::create(&curl_config_path)?;
+    if let Some(ref username) = smtp.username {
+        let user = format!("{}:{}", username, smtp.password.as_deref().unwrap_or(""));
+        writeln!(curl_config, "user = {}", curl_config_quote(&user))?;
+    }
+    curl_config.flush()?;
+
+    let mut cmd = jobs::new_network_command(config, "curl", &[], &[dir.path()]);
+    cmd
+        .arg("--silent")
+        .arg("--show-error")
+        .arg("--ssl")
+        .arg("--connect-timeout").arg("10")
+        .arg("--max-time").arg("60")
+        .arg("--config").arg(&curl_config_path)
+        .arg("--url").arg(&smtp.url)
+        .arg("--mail-from").arg(&smtp.from)
+        .arg("--upload-file").arg(&message_path);
+    for to in smtp.to.iter() {
+        cmd.arg("--mail-rcpt").arg(to);
+    }
+
+    let output = jobs::command_output(cmd, Some(SEND_TIMEOUT))
+        .map_err(|e| JobError::new(&format!("Failed to run curl: {}", e)))?;
+    if !output.status.success() {
+        return Err(JobError::new(&format!("Sending mail failed: {}", String::from_utf8_lossy(&output.stderr).trim())));
+    }
+    Ok(())
+}
+
+pub fn queue_mail(conn: &PgConnection, subject: &str, body: &str) -> Result<(), DieselError> {
+    diesel::insert_into(queued_mails::table)
+        .values(NewQueuedMail {
+            subject: subject.to_string(),
+            body: body.to_string(),
+        })
+        .execute(conn)?;
+    Ok(())
+}
+
+fn send_queued_mail(config: &Config, smtp: &SmtpConfig, conn: &PgConnection, mail: &QueuedMail) -> Result<(), DieselError> {
+    let error = match send_mail(config, smtp, &mail.subject, &mail.body) {
+        Ok(()) => None,
+        Err(e) => {
+            warn!("Failed to send mail {}: {}", mail.id, e);
+            Some(e.to_string())
+        },
+    };
+    diesel::update(queued_mails::table)
+        .filter(queued_mails::id.eq(mail.id))
+        .set((queued_mails::attempts.eq(queued_mails::attempts + 1),
+              queued_mails::sent.eq(error.is_none()),
+              queued_mails::last_error.eq(error)))
+        .execute(conn)?;
+    Ok(())
+}
+
+pub struct MailSender {
+    config: Arc<Config>,
+    pool: Pool,
+    sending: bool,
+}
+
+impl MailSender {
+    fn send_pending(&mut self, ctx: &mut Context<Self>) {
+        if self.sending {
+            return
+        }
+        let smtp = match self.config.smtp {
+            Some(ref smtp) => smtp.clone(),
+            None => return,
+        };
+        self.sending = true;
+
+        let config = self.config.clone();
+        let pool = self.pool.clone();
+        ctx.spawn(
+            web::block(move || {
+                let conn = pool.get().map_err(|e| e.to_string())?;
+                let mails = queued_mails::table
+                    .filter(queued_mails::sent.eq(false))
+                    .filter(queued_mails::attempts.lt(MAX_MAIL_ATTEMPTS))
+                    .order(queued_mails::id)
+                    .limit(MAX_MAILS_PER_POLL)
+                    .get_results::<QueuedMail>(&conn)
+                    .map_err(|e| e.to_string())?;
+                for mail in mails.iter() {
+                    send_queued_mail(&config, &smtp, &conn, mail).map_err(|e| e.to_string())?;
+                }
+                Ok::<_, String>(())
+            })
+                .map_err(|e| error!("Failed to send queued mails: {}", e))
+                .into_actor(self)
+                .then(|_r, sender, _ctx| {
+                    sender.sending = false;
+                    actix::fut::ok(())
+                })
+        );
+    }
+}
+
+impl Actor for MailSender {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Context<Self>) {
+        if self.config.smtp.is_none() {
+            return
+        }
+        self.send_pending(ctx);
+        ctx.run_interval(POLL_INTERVAL, |sender, ctx| sender.send_pending(ctx));
+    }
+}
+
+pub fn start_mail_sender(config: Arc<Config>, pool: Pool) -> Addr<MailSender> {
+    MailSender {
+        config,
+        pool,
+        sending: false,
+    }.start()
+}
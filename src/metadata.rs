@@ -0,0 +1,166 @@
+use std::collections::BTreeMap;
+
+use ostree::OstreeCommit;
+
+/* Validation of the flatpak metadata of committed refs, so that broken
+ * builds fail at commit time, rather than when users try to install or
+ * run them. The metadata is a GKeyFile, which flatpak also stores in
+ * the xa.metadata key of the commit metadata. */
+
+pub type KeyFile = BTreeMap<String, BTreeMap<String, String>>;
+
+pub fn parse_keyfile(contents: &str) -> Result<KeyFile, String> {
+    let mut keyfile = KeyFile::new();
+    let mut group: Option<String> = None;
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if line.starts_with('[') && line.ends_with(']') {
+            let name = line[1..line.len() - 1].to_string();
+            keyfile.entry(name.clone()).or_default();
+            group = Some(name);
+            continue;
+        }
+        let (key, value) = match line.find('=') {
+            Some(pos) => (line[..pos].trim(), line[pos + 1..].trim()),
+            None => return Err(format!("Invalid line {} in metadata: {}", i + 1, line)),
+        };
+        match group {
+            Some(ref group) => keyfile.get_mut(group).unwrap().insert(key.to_string(), value.to_string()),
+            None => return Err(format!("Key {} outside of a group in metadata", key)),
+        };
+    }
+    Ok(keyfile)
+}
+
+/* Lists like "network;ipc;" */
+fn split_list(value: &str) -> Vec<&str> {
+    value.split(';').map(|v| v.trim()).filter(|v| !v.is_empty()).collect()
+}
+
+/* id/arch/branch, as used for runtime and sdk */
+fn check_partial_ref(key: &str, value: &str, arch: &str) -> Result<(), String> {
+    let parts: Vec<&str> = value.split('/').collect();
+    if parts.len() != 3 || parts.iter().any(|part| part.is_empty()) {
+        return Err(format!("Invalid {} {}, should be id/arch/branch", key, value));
+    }
+    if parts[1] != arch {
+        return Err(format!("The {} {} is not for arch {}", key, value, arch));
+    }
+    Ok(())
+}
+
+pub struct RefMetadata {
+    pub runtime: Option<String>,
+    pub permissions: Vec<String>,
+}
+
+/* Checks the metadata of an app or runtime ref. The returned runtime
+ * (for apps) is for the caller to check that it is available */
+pub fn validate_metadata(ref_name: &str, commit: &OstreeCommit) -> Result<RefMetadata, String> {
+    let contents = commit.metadata.get("xa.metadata")
+        .ok_or_else(|| format!("No metadata in commit for {}", ref_name))?
+        .as_string()
+        .map_err(|e| format!("Invalid metadata for {}: {}", ref_name, e))?;
+    validate_metadata_contents(ref_name, &contents)
+}
+
+fn validate_metadata_contents(ref_name: &str, contents: &str) -> Result<RefMetadata, String> {
+    let ref_parts: Vec<&str> = ref_name.split('/').collect();
+    if ref_parts.len() != 4 {
+        return Err(format!("Invalid ref {}", ref_name));
+    }
+    let (kind, id, arch) = (ref_parts[0], ref_parts[1], ref_parts[2]);
+    if ref_parts[3].is_empty() {
+        return Err(format!("Ref {} has no branch", ref_name));
+    }
+
+    let keyfile = parse_keyfile(contents).map_err(|e| format!("{}: {}", ref_name, e))?;
+
+    let group_name = if kind == "app" { "Application" } else { "Runtime" };
+    let group = keyfile.get(group_name)
+        .ok_or_else(|| format!("No [{}] group in metadata for {}", group_name, ref_name))?;
+
+    match group.get("name") {
+        Some(name) if name == id => (),
+        Some(name) => return Err(format!("Name {} in metadata doesn't match ref {}", name, ref_name)),
+        None => return Err(format!("No name in metadata for {}", ref_name)),
+    }
+
+    let mut runtime = None;
+    if kind == "app" {
+        match group.get("command") {
+            Some(command) if !command.is_empty() => (),
+            _ => return Err(format!("No command in metadata for {}", ref_name)),
+        }
+        let app_runtime = group.get("runtime")
+            .ok_or_else(|| format!("No runtime in metadata for {}", ref_name))?;
+        check_partial_ref("runtime", app_runtime, arch).map_err(|e| format!("{}: {}", ref_name, e))?;
+        runtime = Some(format!("runtime/{}", app_runtime));
+    }
+    if let Some(sdk) = group.get("sdk") {
+        check_partial_ref("sdk", sdk, arch).map_err(|e| format!("{}: {}", ref_name, e))?;
+    }
+
+    let mut permissions = vec![];
+    if let Some(context) = keyfile.get("Context") {
+        for (key, value) in context.iter() {
+            for item in split_list(value) {
+                permissions.push(format!("{}={}", key, item));
+            }
+        }
+    }
+
+    Ok(RefMetadata {
+        runtime,
+        permissions,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_keyfile() {
+        let keyfile = parse_keyfile("# comment\n[Application]\nname = org.example.App\n\n[Context]\nshared=network;ipc;\n").unwrap();
+        assert_eq!(keyfile.len(), 2);
+        assert_eq!(keyfile["Application"]["name"], "org.example.App");
+        assert_eq!(keyfile["Context"]["shared"], "network;ipc;");
+
+        assert!(parse_keyfile("name=org.example.App\n").is_err());
+        assert!(parse_keyfile("[Application]\nname\n").is_err());
+    }
+
+    const APP_METADATA: &str = "[Application]\nname=org.example.App\nruntime=org.example.Platform/x86_64/1.0\nsdk=org.example.Sdk/x86_64/1.0\ncommand=app\n\n[Context]\nshared=network;ipc;\nsockets=x11;\n";
+
+    #[test]
+    fn test_validate_app_metadata() {
+        let metadata = validate_metadata_contents("app/org.example.App/x86_64/stable", APP_METADATA).unwrap();
+        assert_eq!(metadata.runtime, Some("runtime/org.example.Platform/x86_64/1.0".to_string()));
+        assert_eq!(metadata.permissions, vec!["shared=network", "shared=ipc", "sockets=x11"]);
+
+        assert!(validate_metadata_contents("app/org.example.Other/x86_64/stable", APP_METADATA).is_err());
+        assert!(validate_metadata_contents("app/org.example.App/aarch64/stable", APP_METADATA).is_err());
+        assert!(validate_metadata_contents("app/org.example.App/x86_64/", APP_METADATA).is_err());
+        assert!(validate_metadata_contents("app/org.example.App/x86_64", APP_METADATA).is_err());
+        assert!(validate_metadata_contents("app/org.example.App/x86_64/stable",
+                                           &APP_METADATA.replace("command=app\n", "")).is_err());
+        assert!(validate_metadata_contents("app/org.example.App/x86_64/stable",
+                                           &APP_METADATA.replace("org.example.Platform/x86_64/1.0", "org.example.Platform")).is_err());
+        assert!(validate_metadata_contents("app/org.example.App/x86_64/stable",
+                                           &APP_METADATA.replace("[Application]", "[Runtime]")).is_err());
+    }
+
+    #[test]
+    fn test_validate_runtime_metadata() {
+        let contents = "[Runtime]\nname=org.example.Platform\nruntime=org.example.Platform/x86_64/1.0\n";
+        let metadata = validate_metadata_contents("runtime/org.example.Platform/x86_64/1.0", contents).unwrap();
+        assert_eq!(metadata.runtime, None);
+        assert!(metadata.permissions.is_empty());
+
+        assert!(validate_metadata_contents("runtime/org.example.Platform/x86_64/1.0", "[Runtime]\n").is_err());
+    }
+}
@@ -0,0 +1,182 @@
+use std::any::type_name;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use app::Config;
+
+/**************************************************************************
+ * Metrics, served in the Prometheus text format on /metrics.
+ *
+ * Only histograms are kept, in memory in each process, so in worker and
+ * frontend mode each one has to be scraped. A histogram is identified by
+ * its name and label values, and created when first observed.
+ *
+ * Database queries are timed with time_query(), by the Db methods used by
+ * the api handlers and around the queries of the job queue. Queries that
+ * take longer than slow-query-ms are logged.
+ ***************************************************************************/
+
+pub const UPLOAD_REQUEST_DURATION: &str = "flat_manager_upload_request_duration_seconds";
+pub const JOB_DURATION: &str = "flat_manager_job_duration_seconds";
+pub const DB_QUERY_DURATION: &str = "flat_manager_db_query_duration_seconds";
+
+/* In seconds, from quick uploads up to publishes of large repos */
+const BUCKETS: [f64; 14] = [0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 300.0, 900.0, 1800.0, 3600.0];
+const DB_BUCKETS: [f64; 12] = [0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 10.0];
+
+/* 0 disables the logging of slow queries */
+static SLOW_QUERY_MS: AtomicUsize = AtomicUsize::new(0);
+
+fn help(name: &str) -> &'static str {
+    match name {
+        UPLOAD_REQUEST_DURATION => "Time taken by upload requests, including the transfer",
+        JOB_DURATION => "Time from the start of a job until it ended or failed",
+        DB_QUERY_DURATION => "Time taken by database queries, including waiting for a connection",
+        _ => "",
+    }
+}
+
+fn buckets(name: &str) -> &'static [f64] {
+    match name {
+        DB_QUERY_DURATION => &DB_BUCKETS,
+        _ => &BUCKETS,
+    }
+}
+
+#[derive(Default)]
+struct Histogram {
+    buckets: Vec<u64>, // Not cumulative, one per bucket
+    sum: f64,
+    count: u64,
+}
+
+type Key = (&'static str, Vec<(&'static str, String)>);
+
+lazy_static! {
+    static ref HISTOGRAMS: Mutex<BTreeMap<Key, Histogram>> = Mutex::new(BTreeMap::new());
+}
+
+fn as_secs(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + duration.subsec_nanos() as f64 * 1e-9
+}
+
+pub fn observe(name: &'static str, labels: &[(&'static str, &str)], duration: Duration) {
+    let value = as_secs(duration);
+    let key = (name, labels.iter().map(|(label, value)| (*label, value.to_string())).collect());
+    let mut histograms = HISTOGRAMS.lock().unwrap();
+    let histogram = histograms.entry(key).or_default();
+    if histogram.buckets.is_empty() {
+        histogram.buckets = vec![0; buckets(name).len()];
+    }
+    if let Some(i) = buckets(name).iter().position(|le| value <= *le) {
+        histogram.buckets[i] += 1;
+    }
+    histogram.sum += value;
+    histogram.count += 1;
+}
+
+pub fn init(config: &Config) {
+    SLOW_QUERY_MS.store(config.slow_query_ms.unwrap_or(0) as usize, Ordering::Relaxed);
+}
+
+/* The name of the function a closure is defined in, e.g. lookup_build
+ * for the closures passed to Db::run() by Db::lookup_build() */
+pub fn query_name<F>() -> String {
+    type_name::<F>()
+        .split("::")
+        .filter(|part| !part.starts_with("{{"))
+        .last()
+        .unwrap_or("unknown")
+        .to_string()
+}
+
+pub fn time_query<T, F: FnOnce() -> T>(name: &str, f: F) -> T {
+    let start = Instant::now();
+    let res = f();
+    let elapsed = start.elapsed();
+    observe(DB_QUERY_DURATION, &[("query", name)], elapsed);
+    let slow_query_ms = SLOW_QUERY_MS.load(Ordering::Relaxed);
+    if slow_query_ms > 0 && elapsed >= Duration::from_millis(slow_query_ms as u64) {
+        warn!("Slow database query {}: {:.0} ms", name, as_secs(elapsed) * 1000.0);
+    }
+    res
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn format_labels(labels: &[(&'static str, String)], le: Option<&str>) -> String {
+    let mut parts: Vec<String> = labels.iter()
+        .map(|(label, value)| format!("{}=\"{}\"", label, escape_label(value)))
+        .collect();
+    if let Some(le) = le {
+        parts.push(format!("le=\"{}\"", le));
+    }
+    if parts.is_empty() {
+        "".to_string()
+    } else {
+        format!("{{{}}}", parts.join(","))
+    }
+}
+
+pub fn render() -> String {
+    let histograms = HISTOGRAMS.lock().unwrap();
+    let mut out = String::new();
+    let mut last_name = "";
+    for ((name, labels), histogram) in histograms.iter() {
+        if *name != last_name {
+            let _ = writeln!(out, "# HELP {} {}", name, help(name));
+            let _ = writeln!(out, "# TYPE {} histogram", name);
+            last_name = name;
+        }
+        let mut cumulative = 0;
+        for (le, count) in buckets(name).iter().zip(histogram.buckets.iter()) {
+            cumulative += count;
+            let _ = writeln!(out, "{}_bucket{} {}", name, format_labels(labels, Some(&le.to_string())), cumulative);
+        }
+        let _ = writeln!(out, "{}_bucket{} {}", name, format_labels(labels, Some("+Inf")), histogram.count);
+        let _ = writeln!(out, "{}_sum{} {}", name, format_labels(labels, None), histogram.sum);
+        let _ = writeln!(out, "{}_count{} {}", name, format_labels(labels, None), histogram.count);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_escape_label() {
+        assert_eq!(escape_label("stable"), "stable");
+        assert_eq!(escape_label("a\"b"), "a\\\"b");
+        assert_eq!(escape_label("a\\b"), "a\\\\b");
+        assert_eq!(escape_label("a\nb"), "a\\nb");
+    }
+
+    #[test]
+    fn test_render() {
+        const NAME: &str = "flat_manager_test_render_seconds";
+        observe(NAME, &[("repo", "sta\"ble"), ("result", "ok")], Duration::from_millis(300));
+        observe(NAME, &[("repo", "sta\"ble"), ("result", "ok")], Duration::from_secs(7200));
+        observe(NAME, &[("repo", "beta"), ("result", "ok")], Duration::from_millis(20));
+
+        let out = render();
+        let lines: Vec<&str> = out.lines().filter(|line| line.contains(NAME)).collect();
+        assert_eq!(lines.iter().filter(|line| line.starts_with("# TYPE")).count(), 1);
+        assert!(lines.contains(&"# TYPE flat_manager_test_render_seconds histogram"));
+
+        /* The buckets are cumulative, and +Inf counts everything */
+        assert!(lines.contains(&"flat_manager_test_render_seconds_bucket{repo=\"sta\\\"ble\",result=\"ok\",le=\"0.25\"} 0"));
+        assert!(lines.contains(&"flat_manager_test_render_seconds_bucket{repo=\"sta\\\"ble\",result=\"ok\",le=\"0.5\"} 1"));
+        assert!(lines.contains(&"flat_manager_test_render_seconds_bucket{repo=\"sta\\\"ble\",result=\"ok\",le=\"3600\"} 1"));
+        assert!(lines.contains(&"flat_manager_test_render_seconds_bucket{repo=\"sta\\\"ble\",result=\"ok\",le=\"+Inf\"} 2"));
+        assert!(lines.contains(&"flat_manager_test_render_seconds_sum{repo=\"sta\\\"ble\",result=\"ok\"} 7200.3"));
+        assert!(lines.contains(&"flat_manager_test_render_seconds_count{repo=\"sta\\\"ble\",result=\"ok\"} 2"));
+        assert!(lines.contains(&"flat_manager_test_render_seconds_bucket{repo=\"beta\",result=\"ok\",le=\"0.05\"} 1"));
+        assert!(lines.contains(&"flat_manager_test_render_seconds_count{repo=\"beta\",result=\"ok\"} 1"));
+    }
+}
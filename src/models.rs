@@ -1,12 +1,18 @@
 use std::{mem,time};
+use std::collections::HashMap;
 
 use chrono;
-use schema::{ builds, build_refs, jobs, job_dependencies };
+use serde_json;
+use schema::{ acl_group_members, app_acl, audit_log, builds, build_comments, build_refs, jobs, job_dependencies, organizations, organization_members, published_refs, queued_mails, ref_pins, ref_tombstones, upload_sessions, webhook_events };
 
 #[derive(Deserialize, Insertable, Debug)]
 #[table_name = "builds"]
 pub struct NewBuild {
     pub repo: String,
+    pub uploader: Option<String>,
+    pub github_repository: Option<String>,
+    pub github_sha: Option<String>,
+    pub derived_from: Option<i32>,
 }
 
 #[derive(Identifiable, Serialize, Queryable, Debug, PartialEq)]
@@ -25,6 +31,21 @@ pub struct Build {
     pub publish_job_id: Option<i32>,
     pub repo: String,
     pub extra_ids: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub app_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uploader: Option<String>,
+    pub metadata: serde_json::Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub github_repository: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub github_sha: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub deleted_at: Option<chrono::NaiveDateTime>,
+    pub uploaded_objects: i64,
+    pub uploaded_bytes: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub derived_from: Option<i32>,
 }
 
 #[derive(Deserialize, Debug,PartialEq)]
@@ -49,6 +70,15 @@ impl PublishedState {
         }
     }
 
+    pub fn name(&self) -> &'static str {
+        match self {
+            PublishedState::Unpublished => "unpublished",
+            PublishedState::Publishing => "publishing",
+            PublishedState::Published => "published",
+            PublishedState::Failed(_) => "failed",
+        }
+    }
+
     pub fn from_db(val: i16, reason: &Option<String>) -> Self {
         match val {
             0 => PublishedState::Unpublished,
@@ -68,6 +98,7 @@ pub enum RepoState {
     Failed(String),
     Purging,
     Purged,
+    Aborted,
 }
 
 impl RepoState {
@@ -83,6 +114,19 @@ impl RepoState {
             RepoState::Failed(s) => (3, Some(s.to_string())),
             RepoState::Purging => (4, None),
             RepoState::Purged => (5, None),
+            RepoState::Aborted => (6, None),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            RepoState::Uploading => "uploading",
+            RepoState::Verifying => "verifying",
+            RepoState::Ready => "ready",
+            RepoState::Failed(_) => "failed",
+            RepoState::Purging => "purging",
+            RepoState::Purged => "purged",
+            RepoState::Aborted => "aborted",
         }
     }
 
@@ -94,6 +138,7 @@ impl RepoState {
             3 => RepoState::Failed(reason.as_ref().unwrap_or(&"Unknown reason".to_string()).to_string()),
             4 => RepoState::Purging,
             5 => RepoState::Purged,
+            6 => RepoState::Aborted,
             _ => RepoState::Failed("Unknown state".to_string()),
         }
     }
@@ -105,6 +150,7 @@ pub struct NewBuildRef {
     pub build_id: i32,
     pub ref_name: String,
     pub commit: String,
+    pub size: Option<i64>,
 }
 
 #[derive(Identifiable, Associations, Serialize, Queryable, PartialEq, Debug)]
@@ -114,6 +160,209 @@ pub struct BuildRef {
     pub build_id: i32,
     pub ref_name: String,
     pub commit: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<i64>,
+}
+
+#[derive(Deserialize, Insertable, Debug)]
+#[table_name = "build_comments"]
+pub struct NewBuildComment {
+    pub build_id: i32,
+    pub author: String,
+    pub comment: String,
+}
+
+#[derive(Identifiable, Associations, Serialize, Queryable, PartialEq, Debug)]
+#[belongs_to(Build)]
+pub struct BuildComment {
+    pub id: i32,
+    pub build_id: i32,
+    pub created_at: chrono::NaiveDateTime,
+    pub author: String,
+    pub comment: String,
+}
+
+/* What was done with the tokens of a subject, for spotting abandoned
+ * or misused tokens. Organizations are counted here too, as org:NAME */
+#[derive(Serialize, Queryable, Debug)]
+pub struct TokenUsage {
+    pub subject: String,
+    pub uploaded_bytes: i64,
+    pub updated_at: chrono::NaiveDateTime,
+    pub request_count: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_used_at: Option<chrono::NaiveDateTime>,
+    pub remote_addrs: Vec<String>,
+}
+
+/* Uploads to a build that are idle for longer than the configured
+ * timeout get the build aborted */
+#[derive(Identifiable, Associations, Serialize, Queryable, PartialEq, Debug)]
+#[belongs_to(Build)]
+pub struct UploadSession {
+    pub id: i32,
+    pub build_id: i32,
+    pub created_at: chrono::NaiveDateTime,
+    pub last_activity_at: chrono::NaiveDateTime,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expired_at: Option<chrono::NaiveDateTime>,
+}
+
+#[derive(Deserialize, Insertable, Debug)]
+#[table_name = "app_acl"]
+pub struct NewAppAclEntry {
+    pub app_id: String,
+    pub principal: String,
+    pub author: String,
+}
+
+/* Once an app id has entries, only the listed principals may commit or publish it */
+#[derive(Identifiable, Serialize, Queryable, PartialEq, Debug)]
+#[table_name = "app_acl"]
+pub struct AppAclEntry {
+    pub id: i32,
+    pub app_id: String,
+    pub principal: String,
+    pub created_at: chrono::NaiveDateTime,
+    pub author: String,
+}
+
+#[derive(Deserialize, Insertable, Debug)]
+#[table_name = "acl_group_members"]
+pub struct NewAclGroupMember {
+    pub group_name: String,
+    pub member: String,
+    pub author: String,
+}
+
+#[derive(Identifiable, Serialize, Queryable, PartialEq, Debug)]
+pub struct AclGroupMember {
+    pub id: i32,
+    pub group_name: String,
+    pub member: String,
+    pub created_at: chrono::NaiveDateTime,
+    pub author: String,
+}
+
+#[derive(Deserialize, Insertable, Debug)]
+#[table_name = "organizations"]
+pub struct NewOrganization {
+    pub name: String,
+    pub upload_quota: Option<i64>,
+    pub author: String,
+}
+
+/* A team of token names, sharing ACLs and an upload quota */
+#[derive(Identifiable, Serialize, Queryable, PartialEq, Debug)]
+pub struct Organization {
+    pub id: i32,
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub upload_quota: Option<i64>,
+    pub created_at: chrono::NaiveDateTime,
+    pub author: String,
+}
+
+#[derive(Deserialize, Insertable, Debug)]
+#[table_name = "organization_members"]
+pub struct NewOrganizationMember {
+    pub organization_id: i32,
+    pub member: String,
+    pub author: String,
+}
+
+#[derive(Identifiable, Associations, Serialize, Queryable, PartialEq, Debug)]
+#[belongs_to(Organization)]
+pub struct OrganizationMember {
+    pub id: i32,
+    pub organization_id: i32,
+    pub member: String,
+    pub created_at: chrono::NaiveDateTime,
+    pub author: String,
+}
+
+#[derive(Deserialize, Insertable, Debug)]
+#[table_name = "ref_pins"]
+pub struct NewRefPin {
+    pub repo: String,
+    pub ref_name: String,
+    pub commit: Option<String>,
+    pub author: String,
+    pub reason: Option<String>,
+}
+
+/* Keeps a commit, or without one the whole history of a ref, from being pruned */
+#[derive(Identifiable, Serialize, Queryable, PartialEq, Debug)]
+pub struct RefPin {
+    pub id: i32,
+    pub repo: String,
+    #[serde(rename = "ref")]
+    pub ref_name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub commit: Option<String>,
+    pub created_at: chrono::NaiveDateTime,
+    pub author: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Insertable, Clone, Debug)]
+#[table_name = "ref_tombstones"]
+pub struct NewRefTombstone {
+    pub repo: String,
+    pub ref_name: String,
+    pub reason: String,
+    pub rebase: Option<String>,
+    pub author: String,
+    pub job_id: Option<i32>,
+}
+
+/* A ref that was marked end-of-life, new builds can't upload it */
+#[derive(Identifiable, Serialize, Queryable, PartialEq, Debug)]
+pub struct RefTombstone {
+    pub id: i32,
+    pub repo: String,
+    #[serde(rename = "ref")]
+    pub ref_name: String,
+    pub reason: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rebase: Option<String>,
+    pub created_at: chrono::NaiveDateTime,
+    pub author: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub job_id: Option<i32>,
+}
+
+#[derive(Deserialize, Insertable, Debug)]
+#[table_name = "published_refs"]
+pub struct NewPublishedRef {
+    pub build_id: i32,
+    pub ref_name: String,
+    pub commit: String,
+    pub job_id: Option<i32>,
+    pub repo: String,
+    pub previous_commit: Option<String>,
+    pub note: Option<String>,
+    pub published_by: Option<String>, // The token subject that requested the publish
+}
+
+#[derive(Identifiable, Associations, Serialize, Queryable, PartialEq, Debug)]
+#[belongs_to(Build)]
+pub struct PublishedRef {
+    pub id: i32,
+    pub build_id: i32,
+    pub ref_name: String,
+    pub commit: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub job_id: Option<i32>,
+    pub repo: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub previous_commit: Option<String>,
+    pub published_at: chrono::NaiveDateTime,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub note: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub published_by: Option<String>,
 }
 
 table! {
@@ -147,6 +396,15 @@ impl JobStatus {
             _ => None,
         }
     }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            JobStatus::New => "new",
+            JobStatus::Started => "started",
+            JobStatus::Ended => "ended",
+            JobStatus::Broken => "broken",
+        }
+    }
 }
 
 #[derive(Debug,PartialEq)]
@@ -154,6 +412,16 @@ pub enum JobKind {
     Commit,
     Publish,
     UpdateRepo,
+    OciExport,
+    Bundle,
+    Prune,
+    Resign,
+    Gc,
+    Promote,
+    Rollback,
+    Deprecate,
+    Fsck,
+    RegenerateDeltas,
 }
 
 impl JobKind {
@@ -162,14 +430,58 @@ impl JobKind {
             JobKind::Commit => 0,
             JobKind::Publish => 1,
             JobKind::UpdateRepo => 2,
+            JobKind::OciExport => 3,
+            JobKind::Bundle => 4,
+            JobKind::Prune => 5,
+            JobKind::Resign => 6,
+            JobKind::Gc => 7,
+            JobKind::Promote => 8,
+            JobKind::Rollback => 9,
+            JobKind::Deprecate => 10,
+            JobKind::Fsck => 11,
+            JobKind::RegenerateDeltas => 12,
         }
     }
 
+    /* Used for naming things per kind in the configuration */
+    pub fn name(&self) -> &'static str {
+        match self {
+            JobKind::Commit => "commit",
+            JobKind::Publish => "publish",
+            JobKind::UpdateRepo => "update-repo",
+            JobKind::OciExport => "oci-export",
+            JobKind::Bundle => "bundle",
+            JobKind::Prune => "prune",
+            JobKind::Resign => "resign",
+            JobKind::Gc => "gc",
+            JobKind::Promote => "promote",
+            JobKind::Rollback => "rollback",
+            JobKind::Deprecate => "deprecate",
+            JobKind::Fsck => "fsck",
+            JobKind::RegenerateDeltas => "regenerate-deltas",
+        }
+    }
+
+    /* These only run in the maintenance windows, if any are configured */
+    pub fn is_heavy(&self) -> bool {
+        matches!(self, JobKind::Prune | JobKind::Resign | JobKind::Gc | JobKind::Fsck | JobKind::RegenerateDeltas)
+    }
+
     pub fn from_db(val: i16) -> Option<Self> {
         match val {
             0 => Some(JobKind::Commit),
             1 => Some(JobKind::Publish),
             2 => Some(JobKind::UpdateRepo),
+            3 => Some(JobKind::OciExport),
+            4 => Some(JobKind::Bundle),
+            5 => Some(JobKind::Prune),
+            6 => Some(JobKind::Resign),
+            7 => Some(JobKind::Gc),
+            8 => Some(JobKind::Promote),
+            9 => Some(JobKind::Rollback),
+            10 => Some(JobKind::Deprecate),
+            11 => Some(JobKind::Fsck),
+            12 => Some(JobKind::RegenerateDeltas),
             _ => None,
         }
     }
@@ -182,6 +494,7 @@ pub struct NewJob {
     pub contents: String,
     pub start_after: Option<time::SystemTime>,
     pub repo: Option<String>,
+    pub trace_parent: Option<String>,
 }
 
 #[derive(Identifiable, Serialize, Queryable, Debug, PartialEq)]
@@ -195,9 +508,26 @@ pub struct Job {
     pub log: String,
     pub start_after: Option<time::SystemTime>,
     pub repo: Option<String>,
+    pub started_at: Option<time::SystemTime>,
+    pub ended_at: Option<time::SystemTime>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub progress: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lease_owner: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lease_expires_at: Option<time::SystemTime>,
+    #[serde(skip_serializing)]
+    pub trace_parent: Option<String>,
 }
 
 impl Job {
+    /* For unfinished jobs this is the time spent so far */
+    pub fn duration(&self) -> Option<time::Duration> {
+        let started_at = self.started_at?;
+        let ended_at = self.ended_at.unwrap_or_else(time::SystemTime::now);
+        ended_at.duration_since(started_at).ok()
+    }
+
     // Ideally we'd do this via a SUBSTRING query, but at least do it behind the API
     pub fn apply_log_offset(mut self: Self, log_offset: Option<usize>) -> Self {
         if let Some(log_offset) = log_offset {
@@ -205,9 +535,43 @@ impl Job {
         }
         self
     }
+
+    /* The results a retried job starts with: a failed commit job keeps
+     * the refs it committed, so the retry skips them */
+    pub fn retry_results(&self) -> Option<String> {
+        match self.typed_results() {
+            Some(TypedJobResults::Failed(failed)) if self.kind == JobKind::Commit.to_db() && !failed.result.committed_refs.is_empty() =>
+                Some(CommitCheckpoint { committed_refs: failed.result.committed_refs }.to_results()),
+            _ => None,
+        }
+    }
+
+    /* Parses the stored results into the typed struct for the job kind,
+     * results we can't parse (e.g. from a newer schema) are left out */
+    pub fn typed_results(&self) -> Option<TypedJobResults> {
+        let results = self.results.as_ref()?;
+        if JobStatus::from_db(self.status) == Some(JobStatus::Broken) {
+            return serde_json::from_str(results).ok().map(TypedJobResults::Failed);
+        }
+        match JobKind::from_db(self.kind)? {
+            JobKind::Commit => serde_json::from_str(results).ok().map(TypedJobResults::Commit),
+            JobKind::Publish => serde_json::from_str(results).ok().map(TypedJobResults::Publish),
+            JobKind::UpdateRepo => serde_json::from_str(results).ok().map(TypedJobResults::UpdateRepo),
+            JobKind::OciExport => serde_json::from_str(results).ok().map(TypedJobResults::OciExport),
+            JobKind::Bundle => serde_json::from_str(results).ok().map(TypedJobResults::Bundle),
+            JobKind::Prune => serde_json::from_str(results).ok().map(TypedJobResults::Prune),
+            JobKind::Resign => serde_json::from_str(results).ok().map(TypedJobResults::Resign),
+            JobKind::Gc => serde_json::from_str(results).ok().map(TypedJobResults::Gc),
+            JobKind::Promote => serde_json::from_str(results).ok().map(TypedJobResults::Promote),
+            JobKind::Rollback => serde_json::from_str(results).ok().map(TypedJobResults::Rollback),
+            JobKind::Deprecate => serde_json::from_str(results).ok().map(TypedJobResults::Deprecate),
+            JobKind::Fsck => serde_json::from_str(results).ok().map(TypedJobResults::Fsck),
+            JobKind::RegenerateDeltas => serde_json::from_str(results).ok().map(TypedJobResults::UpdateRepo),
+        }
+    }
 }
 
-#[derive(Insertable, Debug, Queryable, Identifiable, Associations)]
+#[derive(Insertable, Serialize, Debug, PartialEq, Queryable, Identifiable, Associations)]
 #[table_name = "job_dependencies"]
 #[primary_key(job_id, depends_on)]
 #[belongs_to(Job, foreign_key = "job_id")]
@@ -216,6 +580,21 @@ pub struct JobDependency {
     pub depends_on: i32,
 }
 
+/* The jobs connected to a build through the builds table or through
+ * job dependencies, as returned by the api */
+#[derive(Serialize, Debug)]
+pub struct JobGraph {
+    pub jobs: Vec<JobGraphNode>,
+    pub dependencies: Vec<JobDependency>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct JobGraphNode {
+    pub id: i32,
+    pub kind: &'static str,
+    pub status: i16,
+}
+
 #[derive(Debug, Queryable, Identifiable, Associations)]
 #[table_name = "job_dependencies_with_status"]
 #[primary_key(job_id, depends_on)]
@@ -233,15 +612,388 @@ pub struct CommitJob {
     pub endoflife: Option<String>,
     pub endoflife_rebase: Option<String>,
     pub token_type: Option<i32>,
+    /* Only used with the supplied commit-timestamp policy, as RFC 3339 */
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<String>,
 }
 
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct PublishJob {
     pub build: i32,
+    /* Why the build is published, passed on to the published refs and webhooks */
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub note: Option<String>,
+    /* Only publish the refs for these arches, on top of the publish-arches of the repo */
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub arches: Option<Vec<String>>,
+    /* Branches to publish the app and runtime refs under instead, e.g. test to stable */
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub branches: HashMap<String, String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub published_by: Option<String>,
 }
 
+/* Also used for regenerate-deltas jobs */
 #[derive(Serialize, Deserialize, Debug)]
 pub struct UpdateRepoJob {
     pub repo: String,
 }
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct OciExportJob {
+    pub repo: String,
+    pub refs: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BundleJob {
+    #[serde(rename = "ref")]
+    pub ref_name: String,
+    pub build: Option<i32>, // None means the published repo
+    pub repo: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub struct PruneJob {
+    pub repo: String,
+    pub depth: i32,
+    pub dry_run: bool,
+    #[serde(default)]
+    pub scheduled: bool, // Queued from the repo's prune config, queues the next one
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub struct GcJob {
+    pub repo: String,
+    pub dry_run: bool,
+    #[serde(default)]
+    pub scheduled: bool, // Queued from the repo's gc config, queues the next one
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FsckJob {
+    pub repo: String,
+}
+
+/* The kinds of signatures the resign job can make, gpg signatures with
+ * ostree gpg-sign and the others with ostree sign --sign-type */
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SignType {
+    Gpg,
+    Ed25519,
+}
+
+impl SignType {
+    pub fn name(&self) -> &'static str {
+        match self {
+            SignType::Gpg => "gpg",
+            SignType::Ed25519 => "ed25519",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "gpg" => Some(SignType::Gpg),
+            "ed25519" => Some(SignType::Ed25519),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub struct ResignJob {
+    pub repo: String,
+    pub sign_type: SignType,
+    pub key: String, // The gpg key id, or the ed25519 secret keys file
+    pub delete_key: Option<String>, // Old gpg key whose signatures are removed
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub struct PromoteJob {
+    pub from_repo: String,
+    pub repo: String,
+    pub refs: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub note: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub struct RollbackJob {
+    pub repo: String,
+    #[serde(rename = "ref")]
+    pub ref_name: String,
+    pub commit: String, // Previously published commit of the ref
+    pub build_id: i32, // The build that published it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub note: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub struct DeprecateJob {
+    pub repo: String,
+    #[serde(rename = "ref")]
+    pub ref_name: String,
+    pub reason: String,
+    pub rebase: Option<String>,
+    pub author: String,
+}
+
+/* Bump this when changing the job result structs in an incompatible way.
+ * Results stored before the version was added are version 1. */
+pub const JOB_RESULTS_VERSION: i32 = 1;
+
+fn default_results_version() -> i32 {
+    1
+}
+
+/* The fields common to all job results, wrapping the per-kind result */
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub struct JobResults<T> {
+    #[serde(default = "default_results_version")]
+    pub version: i32,
+    #[serde(flatten)]
+    pub result: T,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub log_file: Option<String>,
+}
+
+impl<T> JobResults<T> {
+    pub fn new(result: T) -> JobResults<T> {
+        JobResults {
+            version: JOB_RESULTS_VERSION,
+            result,
+            log_file: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct AppstreamValidationResult {
+    pub errors: Vec<String>,
+    pub warnings: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CommitJobResult {
+    pub refs: HashMap<String, String>, // ref name -> commit id
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub appstream: HashMap<String, AppstreamValidationResult>, // app ref name -> problems
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub unchanged_refs: Vec<String>, // Same content as the published commit, with reproducible commits
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub struct PublishJobResult {
+    pub refs: HashMap<String, String>,
+    pub update_repo_job: i32,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub filtered_refs: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub unchanged_refs: Vec<String>, // Same commit as already published, with reproducible commits
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(rename_all = "kebab-case")]
+pub struct UpdateRepoJobResult {
+    #[serde(default)]
+    pub deltas_generated: usize,
+    #[serde(default)]
+    pub deltas_failed: usize,
+    #[serde(default)]
+    pub deltas_retired: usize,
+    #[serde(default)]
+    pub subsummaries: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct OciExportJobResult {
+    pub images: HashMap<String, String>, // ref name -> image reference
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BundleJobResult {
+    pub bundle: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub struct PruneJobResult {
+    pub dry_run: bool,
+    pub depth: i32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub summary: Option<String>, // What ostree reported it would delete
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FsckJobResult {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub summary: Option<String>, // The last line of the ostree fsck output
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub struct ResignJobResult {
+    pub sign_type: SignType,
+    pub refs: HashMap<String, String>, // ref name -> signed commit id
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub struct PromoteJobResult {
+    pub from_repo: String,
+    pub refs: HashMap<String, String>,
+    pub update_repo_job: i32,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub struct RollbackJobResult {
+    #[serde(rename = "ref")]
+    pub ref_name: String,
+    pub commit: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub previous_commit: Option<String>,
+    pub update_repo_job: i32,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub struct DeprecateJobResult {
+    #[serde(rename = "ref")]
+    pub ref_name: String,
+    pub commit: String,
+    pub update_repo_job: i32,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub struct GcJobResult {
+    pub dry_run: bool,
+    pub grace_secs: u64,
+    pub unreachable_objects: usize,
+    pub unreachable_bytes: u64,
+    pub deleted_objects: usize,
+    pub reclaimed_bytes: u64,
+}
+
+/* Stored as the results of a commit job while it runs, with the refs
+ * committed into the build repo so far */
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub struct CommitCheckpoint {
+    pub committed_refs: HashMap<String, String>, // ref name -> commit id
+}
+
+impl CommitCheckpoint {
+    pub fn to_results(&self) -> String {
+        json!(JobResults::new(self)).to_string()
+    }
+
+    /* Checkpoints of other results versions are not resumed from */
+    pub fn from_results(results: &str) -> Option<CommitCheckpoint> {
+        serde_json::from_str::<JobResults<CommitCheckpoint>>(results).ok()
+            .filter(|results| results.version == JOB_RESULTS_VERSION)
+            .map(|results| results.result)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub struct FailedJobResult {
+    pub error_message: String,
+    /* The refs a commit job got into the build repo before failing, which
+     * are not committed again when the job is retried */
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub committed_refs: HashMap<String, String>, // ref name -> commit id
+}
+
+#[derive(Serialize, Debug)]
+#[serde(untagged)]
+pub enum TypedJobResults {
+    Commit(JobResults<CommitJobResult>),
+    Publish(JobResults<PublishJobResult>),
+    UpdateRepo(JobResults<UpdateRepoJobResult>),
+    OciExport(JobResults<OciExportJobResult>),
+    Bundle(JobResults<BundleJobResult>),
+    Prune(JobResults<PruneJobResult>),
+    Resign(JobResults<ResignJobResult>),
+    Gc(JobResults<GcJobResult>),
+    Promote(JobResults<PromoteJobResult>),
+    Rollback(JobResults<RollbackJobResult>),
+    Deprecate(JobResults<DeprecateJobResult>),
+    Fsck(JobResults<FsckJobResult>),
+    Failed(JobResults<FailedJobResult>),
+}
+
+#[derive(Deserialize, Insertable, Debug)]
+#[table_name = "audit_log"]
+pub struct NewAuditLogEntry {
+    pub action: String,
+    pub token_sub: Option<String>,
+    pub token_name: Option<String>,
+    pub token_claims: Option<String>,
+    pub params: String,
+}
+
+#[derive(Identifiable, Serialize, Queryable, Debug, PartialEq)]
+#[table_name = "audit_log"]
+pub struct AuditLogEntry {
+    pub id: i32,
+    pub created_at: chrono::NaiveDateTime,
+    pub action: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token_sub: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token_claims: Option<String>,
+    pub params: String,
+}
+
+#[derive(Insertable, Debug)]
+#[table_name = "queued_mails"]
+pub struct NewQueuedMail {
+    pub subject: String,
+    pub body: String,
+}
+
+#[derive(Identifiable, Queryable, Debug)]
+#[table_name = "queued_mails"]
+pub struct QueuedMail {
+    pub id: i32,
+    pub created_at: chrono::NaiveDateTime,
+    pub subject: String,
+    pub body: String,
+    pub attempts: i32,
+    pub sent: bool,
+    pub last_error: Option<String>,
+}
+
+#[derive(Insertable, Debug)]
+#[table_name = "webhook_events"]
+pub struct NewWebhookEvent {
+    pub event: String,
+    pub payload: String,
+}
+
+#[derive(Identifiable, Queryable, Debug)]
+#[table_name = "webhook_events"]
+pub struct WebhookEvent {
+    pub id: i32,
+    pub created_at: chrono::NaiveDateTime,
+    pub event: String,
+    pub payload: String,
+    pub attempts: i32,
+    pub delivered: bool,
+    pub last_error: Option<String>,
+}
@@ -0,0 +1,109 @@
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+use app::Config;
+use ostree;
+
+/**************************************************************************
+ * Consecutive builds of an app mostly upload the same objects. Objects
+ * that are already published are found in the parent repo of the build,
+ * but with object-pool enabled the objects of builds that haven't been
+ * published (yet) are shared too. Once a commit job has committed a
+ * build, the objects reachable from its refs are hardlinked from the build
+ * repo into a content addressed pool
+ * next to the build repos. When a later build asks for missing objects,
+ * the ones in the pool are hardlinked into its upload repo instead of
+ * being uploaded again. Pool entries that nothing else links to anymore
+ * are removed by the purger.
+ ***************************************************************************/
+
+pub fn get_pool_path(config: &Config) -> Option<PathBuf> {
+    if config.object_pool {
+        Some(config.build_repo_base.join("object-pool"))
+    } else {
+        None
+    }
+}
+
+fn link(from: &Path, to: &Path) -> io::Result<()> {
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    match fs::hard_link(from, to) {
+        Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => Ok(()),
+        r => r,
+    }
+}
+
+/* Adds the objects of the refs a commit job just committed to the pool.
+ * Only these were verified by build-commit-from, other uploaded objects
+ * are not, so nothing is taken from the upload repo. Objects that are
+ * only in the parent repo are published already and left out. */
+pub fn add_build_objects(config: &Config, build_id: i32, parent_repo_path: &Path, ref_names: &[String]) {
+    let pool_path = match get_pool_path(config) {
+        Some(pool_path) => pool_path,
+        None => return,
+    };
+    let build_repo_path = config.build_repo_base.join(build_id.to_string());
+    let repo_paths = [build_repo_path.clone(), parent_repo_path.to_path_buf()];
+    let mut reachable = HashSet::new();
+    for ref_name in ref_names {
+        let res = ostree::parse_ref(&build_repo_path, ref_name)
+            .and_then(|commit| ostree::add_reachable_objects(&repo_paths, &commit, false, &mut reachable));
+        if let Err(e) = res {
+            warn!("Not adding build {} to the object pool, can't walk {}: {}", build_id, ref_name, e);
+            return;
+        }
+    }
+    for object in reachable {
+        let subpath = Path::new("objects").join(&object[0..2]).join(&object[2..]);
+        let build_object = build_repo_path.join(&subpath);
+        if !build_object.is_file() {
+            continue;
+        }
+        if let Err(e) = link(&build_object, &pool_path.join(&subpath)) {
+            warn!("Failed to add {:?} to the object pool: {}", subpath, e);
+        }
+    }
+}
+
+/* Links subpath (objects/xx/yyy.type) from the pool into repo_path, if
+ * the pool has it */
+pub fn link_from_pool(config: &Config, subpath: &Path, repo_path: &Path) -> bool {
+    match get_pool_path(config) {
+        Some(pool_path) => {
+            let pool_object = pool_path.join(subpath);
+            pool_object.is_file() && link(&pool_object, &repo_path.join(subpath)).is_ok()
+        },
+        None => false,
+    }
+}
+
+/* Removes the objects only the pool links to, returns their number and size */
+pub fn remove_unused(config: &Config) -> (u64, u64) {
+    let (mut n_files, mut n_bytes) = (0, 0);
+    let pool_path = match get_pool_path(config) {
+        Some(pool_path) => pool_path,
+        None => return (n_files, n_bytes),
+    };
+    let unused = WalkDir::new(pool_path.join("objects"))
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_file())
+        .filter_map(|e| e.metadata().ok().map(|metadata| (e, metadata)))
+        .filter(|(_e, metadata)| metadata.nlink() == 1);
+    for (entry, metadata) in unused {
+        match fs::remove_file(entry.path()) {
+            Ok(()) => {
+                n_files += 1;
+                n_bytes += metadata.len();
+            },
+            Err(e) => warn!("Failed to remove {:?} from the object pool: {}", entry.path(), e),
+        }
+    }
+    (n_files, n_bytes)
+}
@@ -0,0 +1,346 @@
+use actix_web::cookie::SameSite;
+use actix_web::http::{self, Cookie, Uri};
+use actix_web::web::{Data, Query};
+use actix_web::{HttpMessage, HttpRequest, HttpResponse};
+use awc::Client;
+use base64;
+use chrono::Utc;
+use futures::future::{self, Either};
+use futures::Future;
+use hex;
+use jwt;
+use openssl::sha::sha256;
+use rand::{self, Rng};
+use serde_json;
+use std::time::Duration;
+use url::Url;
+
+use app::{Config, OidcConfig};
+use errors::ApiError;
+
+/**************************************************************************
+ * OpenID Connect login for the pages meant for humans (the status
+ * pages), using the authorization code flow. After logging in, the user
+ * gets a session cookie with a jwt signed by us. This is signed with a
+ * key derived from the secret, so a session can't be used as an api
+ * token, and api tokens can't be used as sessions.
+ ***************************************************************************/
+
+const SESSION_COOKIE: &str = "flat-manager-session";
+const STATE_COOKIE: &str = "flat-manager-login-state";
+
+#[derive(Debug, Serialize, Deserialize)]
+struct WebSession {
+    sub: String,
+    exp: i64,
+}
+
+fn session_key(config: &Config) -> Vec<u8> {
+    let mut data = b"flat-manager-web-session:".to_vec();
+    data.extend_from_slice(&config.secret);
+    sha256(&data).to_vec()
+}
+
+fn redirect_url(config: &Config) -> String {
+    format!("{}/login/callback", config.base_url.trim_end_matches('/'))
+}
+
+/* Only redirect back to our own pages. Browsers treat backslashes like
+ * slashes and drop tabs and newlines, so "/\evil.com" or "/<tab>/evil.com"
+ * would go to another host just like "//evil.com" */
+fn safe_next(next: &Option<String>) -> String {
+    match next {
+        Some(ref next) if next.starts_with('/') && !next.starts_with("//") &&
+            !next.chars().any(|c| c == '\\' || c.is_control()) => next.clone(),
+        _ => "/status".to_string(),
+    }
+}
+
+pub fn has_session<R: HttpMessage>(req: &R, config: &Config) -> bool {
+    let cookie = match req.cookie(SESSION_COOKIE) {
+        Some(cookie) => cookie,
+        None => return false,
+    };
+    jwt::decode::<WebSession>(cookie.value(), &session_key(config), &jwt::Validation::default()).is_ok()
+}
+
+pub fn login_redirect(uri: &Uri) -> HttpResponse {
+    let next = uri.path_and_query().map(|p| p.as_str()).unwrap_or("/status");
+    let location = Url::parse_with_params("http://localhost/login", &[("next", next)])
+        .map(|url| format!("/login?{}", url.query().unwrap_or("")))
+        .unwrap_or("/login".to_string());
+    HttpResponse::Found()
+        .header(http::header::LOCATION, location)
+        .finish()
+}
+
+fn cookie(config: &Config, name: &'static str, value: String, max_age_secs: i64) -> Cookie<'static> {
+    Cookie::build(name, value)
+        .path("/")
+        .http_only(true)
+        .secure(config.base_url.starts_with("https:"))
+        .same_site(SameSite::Lax)
+        .max_age(max_age_secs)
+        .finish()
+}
+
+#[derive(Deserialize)]
+pub struct LoginParams {
+    next: Option<String>,
+}
+
+pub fn login(
+    params: Query<LoginParams>,
+    config: Data<Config>,
+) -> Result<HttpResponse, ApiError> {
+    let oidc = config.oidc.as_ref().ok_or_else(|| ApiError::BadRequest("Login is not configured".to_string()))?;
+
+    let state = hex::encode(rand::thread_rng().gen::<[u8; 16]>());
+    let redirect_uri = redirect_url(&config);
+    let scope = oidc.scopes.join(" ");
+    let url = Url::parse_with_params(&oidc.authorization_endpoint,
+                                     &[("response_type", "code"),
+                                       ("client_id", oidc.client_id.as_str()),
+                                       ("redirect_uri", redirect_uri.as_str()),
+                                       ("scope", scope.as_str()),
+                                       ("state", state.as_str())])
+        .map_err(|e| ApiError::InternalServerError(format!("Invalid authorization-endpoint: {}", e)))?;
+
+    /* The state is checked on the callback, against login csrf */
+    let state_value = format!("{}|{}", state, safe_next(&params.next));
+    Ok(HttpResponse::Found()
+       .header(http::header::LOCATION, url.to_string())
+       .cookie(cookie(&config, STATE_COOKIE, state_value, 10 * 60))
+       .finish())
+}
+
+#[derive(Deserialize)]
+pub struct CallbackParams {
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+/* The id token comes directly from the token endpoint over tls, so as
+ * allowed by the spec we rely on that instead of checking its signature */
+fn check_id_token(oidc: &OidcConfig, id_token: &str) -> Result<String, ApiError> {
+    let payload = id_token.split('.').nth(1)
+        .ok_or_else(|| ApiError::BadRequest("Invalid id token".to_string()))?;
+    let payload = base64::decode_config(payload, base64::URL_SAFE_NO_PAD)
+        .map_err(|_e| ApiError::BadRequest("Invalid id token".to_string()))?;
+    let claims: serde_json::Value = serde_json::from_slice(&payload)
+        .map_err(|_e| ApiError::BadRequest("Invalid id token".to_string()))?;
+
+    if claims["iss"].as_str() != Some(oidc.issuer.as_str()) {
+        return Err(ApiError::BadRequest("Wrong issuer in id token".to_string()));
+    }
+    let audience_ok = match claims["aud"] {
+        serde_json::Value::String(ref aud) => aud == &oidc.client_id,
+        serde_json::Value::Array(ref auds) => auds.iter().any(|aud| aud.as_str() == Some(oidc.client_id.as_str())),
+        _ => false,
+    };
+    if !audience_ok {
+        return Err(ApiError::BadRequest("Wrong audience in id token".to_string()));
+    }
+    if claims["exp"].as_i64().unwrap_or(0) < Utc::now().timestamp() {
+        return Err(ApiError::BadRequest("Expired id token".to_string()));
+    }
+
+    /* Anyone can put any email in their account with some providers, so
+     * it is only used if the provider has verified it */
+    let email_verified = match claims["email_verified"] {
+        serde_json::Value::Bool(verified) => verified,
+        serde_json::Value::String(ref verified) => verified == "true",
+        _ => false,
+    };
+    let email = if email_verified { claims["email"].as_str() } else { None };
+    let user = email.or(claims["sub"].as_str())
+        .ok_or_else(|| ApiError::BadRequest("No user in id token".to_string()))?
+        .to_string();
+    if !is_allowed_user(&oidc.allowed_users, &user, email) {
+        return Err(ApiError::NotEnoughPermissions(format!("User {} is not allowed", user)));
+    }
+    Ok(user)
+}
+
+/* "@example.com" entries allow the verified emails of that domain, which
+ * is case insensitive, but not a sub that happens to end like that */
+fn is_allowed_user(allowed_users: &[String], user: &str, email: Option<&str>) -> bool {
+    let domain = email
+        .and_then(|email| email.rfind('@').map(|at| email[at..].to_lowercase()));
+    allowed_users.is_empty() ||
+        allowed_users.iter().any(|allowed| {
+            if allowed.starts_with('@') {
+                domain.as_ref() == Some(&allowed.to_lowercase())
+            } else {
+                allowed == user
+            }
+        })
+}
+
+pub fn login_callback(
+    params: Query<CallbackParams>,
+    config: Data<Config>,
+    req: HttpRequest,
+) -> impl Future<Item = HttpResponse, Error = ApiError> {
+    let oidc = match config.oidc {
+        Some(ref oidc) => oidc.clone(),
+        None => return Either::A(future::err(ApiError::BadRequest("Login is not configured".to_string()))),
+    };
+    if let Some(ref error) = params.error {
+        return Either::A(future::err(ApiError::BadRequest(format!("Login failed: {}", error))));
+    }
+
+    let state_cookie = req.cookie(STATE_COOKIE).map(|c| c.value().to_string()).unwrap_or_default();
+    let mut state_parts = state_cookie.splitn(2, '|');
+    let (state, next) = (state_parts.next().unwrap_or(""), safe_next(&state_parts.next().map(|next| next.to_string())));
+    let code = match (&params.code, &params.state) {
+        (Some(code), Some(param_state)) if !state.is_empty() && param_state == state => code.clone(),
+        _ => return Either::A(future::err(ApiError::BadRequest("Invalid login state".to_string()))),
+    };
+
+    let token_endpoint = oidc.token_endpoint.clone();
+    let redirect_uri = redirect_url(&config);
+    let client_id = oidc.client_id.clone();
+    let client_secret = oidc.client_secret.clone();
+    Either::B(
+        Client::new()
+            .post(&token_endpoint)
+            .timeout(Duration::from_secs(30))
+            .send_form(&[("grant_type", "authorization_code"),
+                         ("code", code.as_str()),
+                         ("redirect_uri", redirect_uri.as_str()),
+                         ("client_id", client_id.as_str()),
+                         ("client_secret", client_secret.as_str())])
+            .map_err(|e| ApiError::InternalServerError(format!("Failed to contact the token endpoint: {}", e)))
+            .and_then(|mut response| {
+                let status = response.status();
+                response.json::<TokenResponse>()
+                    .map_err(move |e| ApiError::InternalServerError(format!("Invalid token endpoint response ({}): {}", status, e)))
+            })
+            .and_then(move |token_response| {
+                let user = check_id_token(&oidc, &token_response.id_token)?;
+                info!("User {} logged in", user);
+                let session = WebSession {
+                    sub: user,
+                    exp: Utc::now().timestamp() + oidc.session_secs as i64,
+                };
+                let session_token = jwt::encode(&jwt::Header::default(), &session, &session_key(&config))
+                    .map_err(|e| ApiError::InternalServerError(e.to_string()))?;
+                Ok(HttpResponse::Found()
+                   .header(http::header::LOCATION, next)
+                   .cookie(cookie(&config, SESSION_COOKIE, session_token, oidc.session_secs as i64))
+                   .del_cookie(&Cookie::build(STATE_COOKIE, "").path("/").finish())
+                   .finish())
+            })
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn oidc_config(allowed_users: &[&str]) -> OidcConfig {
+        OidcConfig {
+            issuer: "https://sso.example.com".to_string(),
+            authorization_endpoint: "https://sso.example.com/auth".to_string(),
+            token_endpoint: "https://sso.example.com/token".to_string(),
+            client_id: "flat-manager".to_string(),
+            client_secret: "secret".to_string(),
+            scopes: vec!["openid".to_string()],
+            allowed_users: allowed_users.iter().map(|user| user.to_string()).collect(),
+            session_secs: 3600,
+        }
+    }
+
+    /* Only the payload is looked at, the signature isn't checked */
+    fn id_token(claims: serde_json::Value) -> String {
+        format!("eyJhbGciOiJSUzI1NiJ9.{}.c2lnbmF0dXJl",
+                base64::encode_config(claims.to_string().as_bytes(), base64::URL_SAFE_NO_PAD))
+    }
+
+    fn claims(aud: serde_json::Value, exp: i64) -> serde_json::Value {
+        json!({
+            "iss": "https://sso.example.com",
+            "aud": aud,
+            "exp": exp,
+            "sub": "1234",
+            "email": "admin@example.com",
+            "email_verified": true,
+        })
+    }
+
+    #[test]
+    fn test_safe_next() {
+        let next = |next: &str| safe_next(&Some(next.to_string()));
+        assert_eq!(next("/status/build/1"), "/status/build/1");
+        assert_eq!(next("/status?page=2"), "/status?page=2");
+        assert_eq!(safe_next(&None), "/status");
+        assert_eq!(next(""), "/status");
+        assert_eq!(next("https://evil.com/"), "/status");
+        assert_eq!(next("//evil.com/"), "/status");
+        assert_eq!(next("/\\evil.com/"), "/status");
+        assert_eq!(next("/\t/evil.com/"), "/status");
+        assert_eq!(next("/\n/evil.com/"), "/status");
+        assert_eq!(next("status"), "/status");
+        assert_eq!(next("javascript:alert(1)"), "/status");
+    }
+
+    #[test]
+    fn test_check_id_token() {
+        let oidc = oidc_config(&[]);
+        let now = Utc::now().timestamp();
+
+        assert_eq!(check_id_token(&oidc, &id_token(claims(json!("flat-manager"), now + 60))).unwrap(), "admin@example.com");
+        assert_eq!(check_id_token(&oidc, &id_token(claims(json!(["other", "flat-manager"]), now + 60))).unwrap(), "admin@example.com");
+
+        let err = |token: &str| check_id_token(&oidc, token).unwrap_err().to_string();
+        assert!(err(&id_token(claims(json!("flat-manager"), now - 60))).contains("Expired"));
+        assert!(err(&id_token(claims(json!("other"), now + 60))).contains("Wrong audience"));
+        assert!(err(&id_token(claims(json!(["other"]), now + 60))).contains("Wrong audience"));
+        assert!(err(&id_token(claims(json!(null), now + 60))).contains("Wrong audience"));
+
+        let mut no_exp = claims(json!("flat-manager"), 0);
+        no_exp.as_object_mut().unwrap().remove("exp");
+        assert!(err(&id_token(no_exp)).contains("Expired"));
+
+        let mut wrong_issuer = claims(json!("flat-manager"), now + 60);
+        wrong_issuer["iss"] = json!("https://evil.example.com");
+        assert!(err(&id_token(wrong_issuer)).contains("Wrong issuer"));
+
+        assert!(err("garbage").contains("Invalid id token"));
+        assert!(err("a.!!!.c").contains("Invalid id token"));
+
+        /* Without a verified email the user is the sub */
+        let mut unverified = claims(json!("flat-manager"), now + 60);
+        unverified["email_verified"] = json!(false);
+        assert_eq!(check_id_token(&oidc, &id_token(unverified)).unwrap(), "1234");
+    }
+
+    #[test]
+    fn test_allowed_users() {
+        let allowed: Vec<String> = vec!["admin@example.com".to_string(), "@release.example.com".to_string()];
+        assert!(is_allowed_user(&[], "anyone", None));
+        assert!(is_allowed_user(&allowed, "admin@example.com", Some("admin@example.com")));
+        assert!(is_allowed_user(&allowed, "dev@release.example.com", Some("dev@release.example.com")));
+        assert!(is_allowed_user(&allowed, "Dev@Release.Example.com", Some("Dev@Release.Example.com")));
+        assert!(!is_allowed_user(&allowed, "dev@example.com", Some("dev@example.com")));
+        assert!(!is_allowed_user(&allowed, "dev@evilrelease.example.com", Some("dev@evilrelease.example.com")));
+        assert!(!is_allowed_user(&allowed, "dev@release.example.com.evil.com", Some("dev@release.example.com.evil.com")));
+        /* A sub that looks like an email of the domain doesn't count */
+        assert!(!is_allowed_user(&allowed, "dev@release.example.com", None));
+
+        let oidc = oidc_config(&["@release.example.com"]);
+        let mut claims = claims(json!("flat-manager"), Utc::now().timestamp() + 60);
+        claims["email"] = json!("dev@release.example.com");
+        assert_eq!(check_id_token(&oidc, &id_token(claims.clone())).unwrap(), "dev@release.example.com");
+        claims["email_verified"] = json!(false);
+        claims["sub"] = json!("dev@release.example.com");
+        assert!(check_id_token(&oidc, &id_token(claims)).is_err());
+    }
+}
@@ -1,5 +1,6 @@
 use base64;
-use byteorder::{NativeEndian,LittleEndian, ByteOrder};
+use byteorder::{NativeEndian,LittleEndian,BigEndian, ByteOrder};
+use flate2::read::DeflateDecoder;
 use std::fs;
 use std::io::Read;
 use std::num::NonZeroUsize;
@@ -7,15 +8,18 @@ use std::path;
 use std::str;
 use walkdir::WalkDir;
 use hex;
+use openssl::sha::{sha256, Sha256};
 use std::process::Command;
 use tokio_process::CommandExt;
 use std::os::unix::process::CommandExt as UnixCommandExt;
 use futures::Future;
 use futures::future::Either;
 use std::path::{PathBuf};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use futures::future;
 
+use cgroups;
+
 #[derive(Fail, Debug, Clone, PartialEq)]
 pub enum OstreeError {
     #[fail(display = "No such ref: {}", _0)]
@@ -47,6 +51,25 @@ pub struct OstreeCommit {
     pub root_metadata: String,
 }
 
+#[derive(Debug)]
+pub struct OstreeDirTreeFile {
+    pub name: String,
+    pub checksum: String,
+}
+
+#[derive(Debug)]
+pub struct OstreeDirTreeDir {
+    pub name: String,
+    pub tree_checksum: String,
+    pub meta_checksum: String,
+}
+
+#[derive(Debug)]
+pub struct OstreeDirTree {
+    pub files: Vec<OstreeDirTreeFile>,
+    pub dirs: Vec<OstreeDirTreeDir>,
+}
+
 #[derive(Debug)]
 pub struct OstreeDeltaSuperblock {
     pub metadata: HashMap<String,Variant>,
@@ -198,6 +221,13 @@ impl Variant {
     pub fn as_bytes<'a>(&'a self) ->  &'a [u8] {
         return self.root().parse_as_bytes();
     }
+
+    pub fn as_bytes_vec(&self) -> OstreeResult<Vec<Vec<u8>>> {
+        Ok(self.root().parse_as_variable_width_array(0)?
+           .iter()
+           .map(|element| element.parse_as_bytes().to_vec())
+           .collect())
+    }
 }
 
 impl<'a> SubVariant<'a> {
@@ -479,13 +509,20 @@ fn get_tmp_deltas_path(repo_path: &path::PathBuf) -> path::PathBuf {
     ref_dir
 }
 
-fn get_object_path(repo_path: &path::PathBuf, object: &str, object_type: &str) -> path::PathBuf {
+pub fn is_valid_checksum(object: &str) -> bool {
+    object.len() == 64 && object.chars().all(|c| c.is_ascii_hexdigit() && !c.is_uppercase())
+}
+
+pub fn get_object_path(repo_path: &path::PathBuf, object: &str, object_type: &str) -> OstreeResult<path::PathBuf> {
+    if !is_valid_checksum(object) {
+        return Err(OstreeError::InternalError(format!("Invalid object checksum '{}'", object)));
+    }
     let mut path = std::env::current_dir().unwrap_or_else(|_e| path::PathBuf::new());
     path.push(repo_path);
     path.push("objects");
     path.push(object[0..2].to_string());
     path.push(format!("{}.{}", &object[2..], object_type));
-    path
+    Ok(path)
 }
 
 fn parse_commit (variant: &SubVariant) ->OstreeResult<OstreeCommit> {
@@ -522,6 +559,55 @@ fn parse_commit (variant: &SubVariant) ->OstreeResult<OstreeCommit> {
     })
 }
 
+fn parse_dirtree (variant: &SubVariant) ->OstreeResult<OstreeDirTree> {
+    let ostree_dirtree_fields = vec![
+        // 0 - a(say) - array of (filename, checksum) for files
+        VariantFieldInfo { size: VariantSize::Variable, alignment: 0 },
+        // 1 - a(sayay) - array of (dirname, tree_checksum, meta_checksum) for directories
+        VariantFieldInfo { size: VariantSize::Variable, alignment: 0 },
+    ];
+    let file_fields = vec![
+        // 0 - s - filename
+        VariantFieldInfo { size: VariantSize::Variable, alignment: 0 },
+        // 1 - ay - checksum
+        VariantFieldInfo { size: VariantSize::Variable, alignment: 0 },
+    ];
+    let dir_fields = vec![
+        // 0 - s - dirname
+        VariantFieldInfo { size: VariantSize::Variable, alignment: 0 },
+        // 1 - ay - tree checksum
+        VariantFieldInfo { size: VariantSize::Variable, alignment: 0 },
+        // 2 - ay - meta checksum
+        VariantFieldInfo { size: VariantSize::Variable, alignment: 0 },
+    ];
+
+    let dirtree = variant.parse_as_tuple(&ostree_dirtree_fields)?;
+
+    let mut files = Vec::new();
+    for file in dirtree[0].parse_as_variable_width_array(0)? {
+        let file = file.parse_as_tuple(&file_fields)?;
+        files.push(OstreeDirTreeFile {
+            name: file[0].parse_as_string()?,
+            checksum: bytes_to_object (file[1].parse_as_bytes()),
+        });
+    }
+
+    let mut dirs = Vec::new();
+    for dir in dirtree[1].parse_as_variable_width_array(0)? {
+        let dir = dir.parse_as_tuple(&dir_fields)?;
+        dirs.push(OstreeDirTreeDir {
+            name: dir[0].parse_as_string()?,
+            tree_checksum: bytes_to_object (dir[1].parse_as_bytes()),
+            meta_checksum: bytes_to_object (dir[2].parse_as_bytes()),
+        });
+    }
+
+    Ok(OstreeDirTree {
+        files,
+        dirs,
+    })
+}
+
 /* This is like basename, but also includes the parent dir because in
  * ostree object and delta part filenames that is the first to letters
  * of the ID, which we don't want to miss.
@@ -555,18 +641,433 @@ pub fn load_commit_file (path: &path::PathBuf) ->OstreeResult<OstreeCommit> {
 }
 
 pub fn get_commit (repo_path: &path::PathBuf, commit: &String) ->OstreeResult<OstreeCommit> {
-    let path = get_object_path(repo_path, commit, "commit");
+    let path = get_object_path(repo_path, commit, "commit")?;
     return load_commit_file(&path);
 }
 
-pub fn load_delta_superblock_file (path: &path::PathBuf) ->OstreeResult<OstreeDeltaSuperblock> {
+fn ref_without_branch (ref_name: &str) -> &str {
+    match ref_name.rfind('/') {
+        Some(pos) if ref_name.split('/').count() == 4 => &ref_name[..pos],
+        _ => ref_name,
+    }
+}
+
+/* Commits can be bound to the refs (and collection) they are meant for,
+ * so they can't be passed off as another ref. A missing binding is only
+ * an error if required, as older builders don't add them. The commits
+ * of derived builds come from the build repo of the source build, so
+ * they can be bound to another branch and to its build collection. */
+pub fn check_ref_bindings (commit: &OstreeCommit, ref_name: &str, collection_id: &Option<String>, required: bool, derived: bool) -> Result<(), String> {
+    match commit.metadata.get("ostree.ref-binding") {
+        Some(binding) => {
+            let refs = binding.as_string_vec().map_err(|e| format!("Invalid ref binding: {}", e))?;
+            if !refs.iter().any(|r| r == ref_name || (derived && ref_without_branch(r) == ref_without_branch(ref_name))) {
+                return Err(format!("Commit is bound to {}, not {}", refs.join(", "), ref_name));
+            }
+        },
+        None if required => return Err(format!("Commit for {} has no ref binding", ref_name)),
+        None => (),
+    }
+
+    if let Some(collection_id) = collection_id {
+        match commit.metadata.get("ostree.collection-binding") {
+            Some(binding) => {
+                let bound = binding.as_string().map_err(|e| format!("Invalid collection binding: {}", e))?;
+                let build_collection = derived && bound.starts_with(&format!("{}.Build", collection_id));
+                if &bound != collection_id && !build_collection {
+                    return Err(format!("Commit for {} is bound to collection {}, not {}", ref_name, bound, collection_id));
+                }
+            },
+            None if required => return Err(format!("Commit for {} has no collection binding", ref_name)),
+            None => (),
+        }
+    }
+    Ok(())
+}
+
+pub fn load_dirtree_file (path: &path::PathBuf) ->OstreeResult<OstreeDirTree> {
     let mut fp = fs::File::open(path)
         .map_err(|_e| OstreeError::NoSuchObject(get_dir_and_basename(path)))?;
 
     let mut contents = vec![];
     fp.read_to_end(&mut contents)
-        .map_err(|_e| OstreeError::InternalError(format!("Invalid delta superblock {}", get_dir_and_basename(path))))?;
+        .map_err(|_e| OstreeError::InternalError(format!("Invalid dirtree {}", get_dir_and_basename(path))))?;
+
+    let variant = Variant::new("(a(say)a(sayay))".to_string(), contents)?;
+
+    parse_dirtree (&variant.root())
+}
+
+/* The gpg signatures in the detached metadata of a commit, each over the commit object */
+pub fn get_commit_gpg_signatures (repo_path: &path::PathBuf, commit: &str) ->OstreeResult<Vec<Vec<u8>>> {
+    let path = get_object_path(repo_path, commit, "commitmeta")?;
+    let contents = match fs::read(&path) {
+        Ok(contents) => contents,
+        Err(_e) => return Ok(vec![]), /* Not signed at all */
+    };
+    let variant = Variant::new("a{sv}".to_string(), contents)?;
+    match variant.root().parse_as_asv()?.get("ostree.gpgsigs") {
+        Some(signatures) => signatures.as_bytes_vec(),
+        None => Ok(vec![]),
+    }
+}
+
+pub fn get_dirtree (repo_path: &path::PathBuf, dirtree: &str) ->OstreeResult<OstreeDirTree> {
+    let path = get_object_path(repo_path, dirtree, "dirtree")?;
+    load_dirtree_file(&path)
+}
+
+/* File objects in archive repos start with the size of a (tuuuusa(ayay))
+ * header of size, uid, gid, mode, rdev, symlink target and xattrs (big
+ * endian) and padding, followed by the raw deflated content. */
+struct FilezObject<'a> {
+    header: &'a [u8],
+    mode: u32,
+    compressed: &'a [u8],
+}
+
+impl<'a> FilezObject<'a> {
+    fn is_regular(&self) -> bool {
+        self.mode & 0o170000 == 0o100000
+    }
+}
+
+fn parse_filez (contents: &[u8]) -> Option<FilezObject<'_>> {
+    if contents.len() < 8 {
+        return None;
+    }
+    let header_size = BigEndian::read_u32(&contents[0..4]) as usize;
+    let header = contents.get(8..8 + header_size)?;
+    if header.len() < 24 {
+        return None;
+    }
+    Some(FilezObject {
+        header,
+        mode: BigEndian::read_u32(&header[16..20]),
+        compressed: &contents[8 + header_size..],
+    })
+}
 
+/* The size of the framing offsets of a gvariant container of this size */
+fn gvariant_offset_size(size: usize) -> usize {
+    if size > 0xffff_ffff {
+        8
+    } else if size > 0xffff {
+        4
+    } else if size > 0xff {
+        2
+    } else if size > 0 {
+        1
+    } else {
+        0
+    }
+}
+
+/* Appends the framing offsets of a container, these take the smallest
+ * size that can address the whole container, including the offsets */
+fn append_framing_offsets(data: &mut Vec<u8>, offsets: &[usize]) {
+    if offsets.is_empty() {
+        return;
+    }
+    let mut offset_size = 1;
+    while gvariant_offset_size(data.len() + offsets.len() * offset_size) > offset_size {
+        offset_size *= 2;
+    }
+    for offset in offsets {
+        let mut buf = [0u8; 8];
+        LittleEndian::write_uint(&mut buf, *offset as u64, offset_size);
+        data.extend_from_slice(&buf[..offset_size]);
+    }
+}
+
+fn append_padding(data: &mut Vec<u8>, alignment: usize) {
+    while !data.len().is_multiple_of(alignment) {
+        data.push(0);
+    }
+}
+
+/* Serializes a tuple from its serialized members, as (alignment,
+ * variable size, data). Tuples with only fixed size members are not
+ * supported, they would need padding at the end. */
+fn serialize_tuple(members: &[(usize, bool, &[u8])]) -> Vec<u8> {
+    let mut data = vec![];
+    let mut offsets = vec![];
+    for (i, &(alignment, variable, member)) in members.iter().enumerate() {
+        append_padding(&mut data, alignment);
+        data.extend_from_slice(member);
+        if variable && i != members.len() - 1 {
+            offsets.push(data.len());
+        }
+    }
+    /* The offset of the first member is last */
+    offsets.reverse();
+    append_framing_offsets(&mut data, &offsets);
+    data
+}
+
+/* Serializes an array of variable size elements from its serialized elements */
+fn serialize_variable_width_array(alignment: usize, elements: &[&[u8]]) -> Vec<u8> {
+    let mut data = vec![];
+    let mut offsets = vec![];
+    for element in elements {
+        append_padding(&mut data, alignment);
+        data.extend_from_slice(element);
+        offsets.push(data.len());
+    }
+    append_framing_offsets(&mut data, &offsets);
+    data
+}
+
+/* Serializes a {sv} dict entry from the serialized value and its type */
+fn serialize_asv_element(key: &str, value_type: &str, value: &[u8]) -> Vec<u8> {
+    let mut key_data = key.as_bytes().to_vec();
+    key_data.push(0);
+    let mut variant_data = value.to_vec();
+    variant_data.push(0);
+    variant_data.extend_from_slice(value_type.as_bytes());
+    serialize_tuple(&[(1, true, &key_data), (8, true, &variant_data)])
+}
+
+/* The checksum of a file object covers a (uuuusa(ayay)) header, which is
+ * the archive header without the size. The symlink target and xattrs are
+ * unaligned, so only the framing offset of the symlink target changes. */
+fn filez_checksum_header (header: &[u8]) -> Option<Vec<u8>> {
+    let offset_size = gvariant_offset_size(header.len());
+    let body_end = header.len().checked_sub(offset_size)?;
+    let target_end = LittleEndian::read_uint(&header[body_end..], offset_size) as usize;
+    if target_end <= 24 || target_end > body_end {
+        return None;
+    }
+
+    let mut new_header = header[8..body_end].to_vec();
+    append_framing_offsets(&mut new_header, &[target_end - 8]);
+    Some(new_header)
+}
+
+/* The content of a regular file object in an archive repo */
+pub fn load_filez_content (path: &path::PathBuf) ->OstreeResult<Vec<u8>> {
+    let contents = fs::read(path)
+        .map_err(|_e| OstreeError::NoSuchObject(get_dir_and_basename(path)))?;
+    let invalid = || OstreeError::InternalError(format!("Invalid file object {}", get_dir_and_basename(path)));
+
+    let object = parse_filez(&contents).ok_or_else(invalid)?;
+    if !object.is_regular() {
+        return Err(OstreeError::InternalError(format!("{} is not a regular file", get_dir_and_basename(path))));
+    }
+
+    let mut data = vec![];
+    DeflateDecoder::new(object.compressed).read_to_end(&mut data)
+        .map_err(|_e| invalid())?;
+    Ok(data)
+}
+
+/* The checksum of a file object in an archive repo, as in its name */
+pub fn get_filez_checksum (path: &path::PathBuf) ->OstreeResult<String> {
+    let contents = fs::read(path)
+        .map_err(|_e| OstreeError::NoSuchObject(get_dir_and_basename(path)))?;
+    let invalid = || OstreeError::InternalError(format!("Invalid file object {}", get_dir_and_basename(path)));
+
+    let object = parse_filez(&contents).ok_or_else(invalid)?;
+    let header = filez_checksum_header(object.header).ok_or_else(invalid)?;
+    let mut size = [0u8; 8]; // The size is followed by padding to 8 bytes
+    BigEndian::write_u32(&mut size[0..4], header.len() as u32);
+
+    let mut hasher = Sha256::new();
+    hasher.update(&size);
+    hasher.update(&header);
+    if object.is_regular() {
+        let mut decoder = DeflateDecoder::new(object.compressed);
+        let mut buf = [0u8; 64 * 1024];
+        loop {
+            let n = decoder.read(&mut buf).map_err(|_e| invalid())?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[..n]);
+        }
+    }
+    Ok(hex::encode(hasher.finish()))
+}
+
+/* Reads a file in a commit, like ostree cat, but without spawning ostree */
+pub fn read_commit_file (repo_paths: &[path::PathBuf], commit: &str, file_path: &str) ->OstreeResult<Vec<u8>> {
+    let no_such_file = || OstreeError::NoSuchObject(format!("{}:{}", commit, file_path));
+    let ostree_commit = load_commit_file(&find_object_path(repo_paths, commit, "commit")?)?;
+    let mut tree = load_dirtree_file(&find_object_path(repo_paths, &ostree_commit.root_tree, "dirtree")?)?;
+
+    let components: Vec<&str> = file_path.split('/').filter(|c| !c.is_empty()).collect();
+    let (file_name, dir_names) = components.split_last().ok_or_else(no_such_file)?;
+    for dir_name in dir_names {
+        let tree_checksum = tree.dirs.iter()
+            .find(|dir| &dir.name == dir_name)
+            .map(|dir| dir.tree_checksum.clone())
+            .ok_or_else(no_such_file)?;
+        tree = load_dirtree_file(&find_object_path(repo_paths, &tree_checksum, "dirtree")?)?;
+    }
+    let file = tree.files.iter()
+        .find(|file| &file.name == file_name)
+        .ok_or_else(no_such_file)?;
+    load_filez_content(&find_object_path(repo_paths, &file.checksum, "filez")?)
+}
+
+/* Look for the object in each of the repos in order, for repos with a parent */
+fn find_object_path(repo_paths: &[path::PathBuf], object: &str, object_type: &str) -> OstreeResult<path::PathBuf> {
+    for repo_path in repo_paths {
+        let path = get_object_path(repo_path, object, object_type)?;
+        if path.exists() {
+            return Ok(path);
+        }
+    }
+    Err(OstreeError::NoSuchObject(format!("{}.{}", object, object_type)))
+}
+
+/* The size on disk of all the objects in a commit, counting each object
+ * once. Objects shared with other commits are counted for each of them. */
+pub fn get_commit_size (repo_paths: &[path::PathBuf], commit: &str) -> OstreeResult<u64> {
+    let mut seen = HashSet::new();
+    let mut size = 0;
+    let mut add_object = |object: &str, object_type: &str| -> OstreeResult<Option<path::PathBuf>> {
+        if !seen.insert(format!("{}.{}", object, object_type)) {
+            return Ok(None)
+        }
+        let path = find_object_path(repo_paths, object, object_type)?;
+        size += fs::metadata(&path)
+            .map_err(|e| OstreeError::InternalError(format!("Can't stat {}: {}", get_dir_and_basename(&path), e)))?
+            .len();
+        Ok(Some(path))
+    };
+
+    let commit_path = add_object(commit, "commit")?.unwrap();
+    let ostree_commit = load_commit_file(&commit_path)?;
+    add_object(&ostree_commit.root_metadata, "dirmeta")?;
+
+    let mut dirtrees = vec![ostree_commit.root_tree];
+    while let Some(dirtree) = dirtrees.pop() {
+        let dirtree_path = match add_object(&dirtree, "dirtree")? {
+            Some(path) => path,
+            None => continue,
+        };
+        let tree = load_dirtree_file(&dirtree_path)?;
+        for file in tree.files {
+            add_object(&file.checksum, "filez")?;
+        }
+        for dir in tree.dirs {
+            add_object(&dir.meta_checksum, "dirmeta")?;
+            dirtrees.push(dir.tree_checksum);
+        }
+    }
+
+    Ok(size)
+}
+
+/* Adds the objects of a commit (and of its parents that are still in
+ * the repos, if asked for) to reachable, as "$checksum.$type". Objects
+ * already in reachable are not walked again. */
+pub fn add_reachable_objects (repo_paths: &[path::PathBuf], commit: &str, with_parents: bool,
+                              reachable: &mut HashSet<String>) -> OstreeResult<()> {
+    let mut next_commit = Some(commit.to_string());
+    let mut first = true;
+    while let Some(commit) = next_commit.take() {
+        if !reachable.insert(format!("{}.commit", commit)) {
+            break;
+        }
+        let commit_path = match find_object_path(repo_paths, &commit, "commit") {
+            Ok(path) => path,
+            /* Only the first commit has to exist, history may be pruned */
+            Err(_) if !first => break,
+            Err(e) => return Err(e),
+        };
+        first = false;
+        reachable.insert(format!("{}.commitmeta", commit));
+        let ostree_commit = load_commit_file(&commit_path)?;
+        reachable.insert(format!("{}.dirmeta", ostree_commit.root_metadata));
+
+        let mut dirtrees = vec![ostree_commit.root_tree.clone()];
+        while let Some(dirtree) = dirtrees.pop() {
+            if !reachable.insert(format!("{}.dirtree", dirtree)) {
+                continue;
+            }
+            let tree = load_dirtree_file(&find_object_path(repo_paths, &dirtree, "dirtree")?)?;
+            for file in tree.files {
+                reachable.insert(format!("{}.filez", file.checksum));
+            }
+            for dir in tree.dirs {
+                reachable.insert(format!("{}.dirmeta", dir.meta_checksum));
+                dirtrees.push(dir.tree_checksum);
+            }
+        }
+
+        if with_parents {
+            next_commit = ostree_commit.parent;
+        }
+    }
+    Ok(())
+}
+
+/* Checks that the objects of a commit that are stored in one of
+ * checked_paths match their checksum, like ostree fsck but only for this
+ * commit. Objects that are only in the parent repos are not checked.
+ * Returns the number of objects checked. */
+pub fn verify_commit_objects (checked_paths: &[path::PathBuf], parent_paths: &[path::PathBuf], commit: &str) -> OstreeResult<usize> {
+    let repo_paths: Vec<path::PathBuf> = checked_paths.iter().chain(parent_paths.iter()).cloned().collect();
+    let mut reachable = HashSet::new();
+    add_reachable_objects(&repo_paths, commit, false, &mut reachable)?;
+
+    let mut n_checked = 0;
+    for object in reachable.iter() {
+        let parts: Vec<&str> = object.splitn(2, '.').collect();
+        let (checksum, object_type) = (parts[0], parts[1]);
+        /* Commit metadata is not content addressed */
+        if object_type == "commitmeta" {
+            continue;
+        }
+        let path = match find_object_path(checked_paths, checksum, object_type) {
+            Ok(path) => path,
+            Err(_) => continue,
+        };
+        let actual = if object_type == "filez" {
+            get_filez_checksum(&path)?
+        } else {
+            let contents = fs::read(&path)
+                .map_err(|e| OstreeError::InternalError(format!("Can't read {}: {}", get_dir_and_basename(&path), e)))?;
+            hex::encode(sha256(&contents))
+        };
+        if actual != checksum {
+            return Err(OstreeError::InternalError(format!("Object {} has the wrong checksum {}", object, actual)));
+        }
+        n_checked += 1;
+    }
+    Ok(n_checked)
+}
+
+/* All the objects of the kinds referenced from commits in the repo, as
+ * ("$checksum.$type", path). Other files, like tombstone commits, are left out */
+pub fn list_objects (repo_path: &path::PathBuf) -> Vec<(String, path::PathBuf)> {
+    let mut objects_dir = std::env::current_dir().unwrap_or_else(|_e| path::PathBuf::new());
+    objects_dir.push(repo_path);
+    objects_dir.push("objects");
+
+    WalkDir::new(&objects_dir)
+        .min_depth(2)
+        .max_depth(2)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_file())
+        .filter_map(|e| {
+            let prefix = e.path().parent()?.file_name()?.to_str()?.to_string();
+            let name = e.file_name().to_str()?.to_string();
+            let object_type = name.rsplit('.').next()?;
+            match object_type {
+                "commit" | "commitmeta" | "dirtree" | "dirmeta" | "filez" => Some((format!("{}{}", prefix, name), e.path().to_path_buf())),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+const DELTA_SUPERBLOCK_TYPE: &str = "(a{sv}tayay(a{sv}aya(say)sstayay)aya(uayttay)a(yaytt))";
+
+fn parse_delta_superblock<'a> (variant: &'a Variant) ->OstreeResult<Vec<SubVariant<'a>>> {
     let ostree_superblock_fields = vec![
         // 0 - "a{sv}", - Metadata
         VariantFieldInfo { size: VariantSize::Variable, alignment: 8 },
@@ -586,10 +1087,23 @@ pub fn load_delta_superblock_file (path: &path::PathBuf) ->OstreeResult<OstreeDe
         VariantFieldInfo { size: VariantSize::Variable, alignment: 8 },
     ];
 
+    variant.root().parse_as_tuple(&ostree_superblock_fields)
+}
 
-    let variant = Variant::new("(a{sv}tayay(a{sv}aya(say)sstayay)aya(uayttay)a(yaytt))".to_string(), contents)?;
-    let container = variant.root();
-    let superblock = container.parse_as_tuple(&ostree_superblock_fields)?;
+fn read_delta_superblock (path: &path::PathBuf) ->OstreeResult<Variant> {
+    let mut fp = fs::File::open(path)
+        .map_err(|_e| OstreeError::NoSuchObject(get_dir_and_basename(path)))?;
+
+    let mut contents = vec![];
+    fp.read_to_end(&mut contents)
+        .map_err(|_e| OstreeError::InternalError(format!("Invalid delta superblock {}", get_dir_and_basename(path))))?;
+
+    Variant::new(DELTA_SUPERBLOCK_TYPE.to_string(), contents)
+}
+
+pub fn load_delta_superblock_file (path: &path::PathBuf) ->OstreeResult<OstreeDeltaSuperblock> {
+    let variant = read_delta_superblock(path)?;
+    let superblock = parse_delta_superblock(&variant)?;
 
     let metadata = superblock[0].parse_as_asv()?;
     let commit = parse_commit(&superblock[4])?;
@@ -609,12 +1123,194 @@ pub fn get_delta_superblock (repo_path: &path::PathBuf, delta: &String) ->Ostree
     return load_delta_superblock_file(&path);
 }
 
+/* Inline parts are stored in the superblock metadata, under their path
+ * relative to the repo */
+fn delta_relpath (delta: &Delta) ->OstreeResult<String> {
+    let name = delta.to_name()?;
+    Ok(format!("deltas/{}/{}", &name[0..2], &name[2..]))
+}
+
+fn get_delta_part_checksums (superblock: &[SubVariant]) ->OstreeResult<Vec<String>> {
+    let part_fields = vec![
+        // 0 - "u" - Version
+        VariantFieldInfo { size: VariantSize::Fixed(std::num::NonZeroUsize::new(4).unwrap()), alignment: 4 },
+        // 1 - "ay" - Checksum
+        VariantFieldInfo { size: VariantSize::Variable, alignment: 0 },
+        // 2 - "t" - Size
+        VariantFieldInfo { size: VariantSize::Fixed(std::num::NonZeroUsize::new(8).unwrap()), alignment: 8 },
+        // 3 - "t" - Uncompressed size
+        VariantFieldInfo { size: VariantSize::Fixed(std::num::NonZeroUsize::new(8).unwrap()), alignment: 8 },
+        // 4 - "ay" - Objects
+        VariantFieldInfo { size: VariantSize::Variable, alignment: 0 },
+    ];
+
+    superblock[6].parse_as_variable_width_array(8)?
+        .iter()
+        .map(|part| Ok(bytes_to_object(part.parse_as_tuple(&part_fields)?[1].parse_as_bytes())))
+        .collect()
+}
+
+/* Checks that a delta is complete and not corrupt: that the commit in its
+ * superblock is the one it is named for, and that its parts (in files next
+ * to it, or inline in the superblock) match their checksum */
+pub fn verify_delta (repo_path: &path::PathBuf, delta: &Delta) ->OstreeResult<()> {
+    let delta_path = delta.delta_path(repo_path)?;
+    let variant = read_delta_superblock(&delta_path.join("superblock"))?;
+    let superblock = parse_delta_superblock(&variant)?;
+
+    let from = maybe_bytes_to_object(superblock[2].parse_as_bytes());
+    let to = bytes_to_object(superblock[3].parse_as_bytes());
+    if from != delta.from || to != delta.to {
+        return Err(OstreeError::InternalError(format!("Superblock is for delta {}", Delta { from, to }.to_string())));
+    }
+    let commit_checksum = hex::encode(sha256(superblock[4].parse_as_bytes()));
+    if commit_checksum != delta.to {
+        return Err(OstreeError::InternalError(format!("Superblock has commit {}", commit_checksum)));
+    }
+
+    let metadata = superblock[0].parse_as_asv()?;
+    let relpath = delta_relpath(delta)?;
+    for (i, checksum) in get_delta_part_checksums(&superblock)?.iter().enumerate() {
+        let part_path = delta_path.join(i.to_string());
+        let part_checksum = if part_path.exists() {
+            let part = fs::read(&part_path)
+                .map_err(|e| OstreeError::InternalError(format!("Can't read part {}: {}", i, e)))?;
+            hex::encode(sha256(&part))
+        } else if let Some(part) = metadata.get(&format!("{}/{}", relpath, i)) {
+            hex::encode(sha256(part.as_bytes()))
+        } else {
+            return Err(OstreeError::InternalError(format!("Part {} is missing", i)));
+        };
+        if &part_checksum != checksum {
+            return Err(OstreeError::InternalError(format!("Part {} has the wrong checksum {}", i, part_checksum)));
+        }
+    }
+    Ok(())
+}
+
+fn link_or_copy (src: &path::Path, dst: &path::Path) -> std::io::Result<()> {
+    if dst.exists() {
+        fs::remove_file(dst)?;
+    }
+    fs::hard_link(src, dst).or_else(|_e| fs::copy(src, dst).map(|_size| ()))
+}
+
+/* Installs a delta of src_repo_path into repo_path as delta to another
+ * commit with the same content, like flatpak build-commit-from makes when
+ * importing a commit. The parts only have content objects and are used
+ * as is, while the superblock gets the new commit (which has to be in
+ * repo_path already) and its detached metadata. Returns the new delta. */
+pub fn rebase_delta (src_repo_path: &path::PathBuf, delta: &Delta, repo_path: &path::PathBuf, to: &str) ->OstreeResult<Delta> {
+    let internal_error = |e: std::io::Error| OstreeError::InternalError(e.to_string());
+    let src_path = delta.delta_path(src_repo_path)?;
+    let variant = read_delta_superblock(&src_path.join("superblock"))?;
+    let superblock = parse_delta_superblock(&variant)?;
+
+    let new_delta = Delta::new(delta.from.as_deref(), to);
+    let relpath = format!("{}/", delta_relpath(delta)?);
+    let new_relpath = format!("{}/", delta_relpath(&new_delta)?);
+
+    /* The detached metadata of the old commit is replaced, and inline parts renamed */
+    let mut metadata_elements = vec![];
+    for element in superblock[0].parse_as_variable_width_array(8)? {
+        let (key, value) = element.parse_as_asv_element()?;
+        if key == "ostree.commitmeta" {
+            continue;
+        } else if key.starts_with(&relpath) {
+            let value = value.parse_as_variant()?;
+            let new_key = format!("{}{}", new_relpath, &key[relpath.len()..]);
+            metadata_elements.push(serialize_asv_element(&new_key, value.type_string, value.data));
+        } else {
+            metadata_elements.push(element.data.to_vec());
+        }
+    }
+    if let Ok(commitmeta) = fs::read(get_object_path(repo_path, to, "commitmeta")?) {
+        metadata_elements.push(serialize_asv_element("ostree.commitmeta", "a{sv}", &commitmeta));
+    }
+    let metadata_refs: Vec<&[u8]> = metadata_elements.iter().map(|element| element.as_slice()).collect();
+    let metadata = serialize_variable_width_array(8, &metadata_refs);
+
+    let to_bytes = object_to_bytes(to)?;
+    let commit = fs::read(get_object_path(repo_path, to, "commit")?)
+        .map_err(|_e| OstreeError::NoSuchCommit(to.to_string()))?;
+    let new_superblock = serialize_tuple(&[
+        (8, true, &metadata),
+        (8, false, superblock[1].data),
+        (1, true, superblock[2].data),
+        (1, true, &to_bytes),
+        (8, true, &commit),
+        (1, true, superblock[5].data),
+        (8, true, superblock[6].data),
+        (8, true, superblock[7].data),
+    ]);
+
+    let dst_path = new_delta.delta_path(repo_path)?;
+    fs::create_dir_all(&dst_path).map_err(internal_error)?;
+    for entry in fs::read_dir(&src_path).map_err(internal_error)? {
+        let entry = entry.map_err(internal_error)?;
+        if entry.file_name() != "superblock" {
+            link_or_copy(&entry.path(), &dst_path.join(entry.file_name())).map_err(internal_error)?;
+        }
+    }
+    /* The superblock goes last, so the delta is only visible when complete */
+    fs::write(dst_path.join("superblock.tmp"), &new_superblock).map_err(internal_error)?;
+    fs::rename(dst_path.join("superblock.tmp"), dst_path.join("superblock")).map_err(internal_error)?;
+    Ok(new_delta)
+}
+
+/* Lists the subsummaries in the summary.idx written by flatpak, with
+ * the checksum of each. Returns NoSuchObject if there is no index. */
+pub fn list_subsummaries (repo_path: &path::Path) -> OstreeResult<HashMap<String, String>> {
+    let path = repo_path.join("summary.idx");
+    let mut fp = fs::File::open(&path)
+        .map_err(|_e| OstreeError::NoSuchObject("summary.idx".to_string()))?;
+
+    let mut contents = vec![];
+    fp.read_to_end(&mut contents)
+        .map_err(|_e| OstreeError::InternalError("Invalid summary.idx".to_string()))?;
+
+    let index_fields = vec![
+        // 0 - a{s(ayaaya{sv})} - Subsummaries
+        VariantFieldInfo { size: VariantSize::Variable, alignment: 8 },
+        // 1 - a{sv} - Metadata
+        VariantFieldInfo { size: VariantSize::Variable, alignment: 8 },
+    ];
+    let entry_fields = vec![
+        // 0 - s - Name
+        VariantFieldInfo { size: VariantSize::Variable, alignment: 0 },
+        // 1 - (ayaaya{sv}) - Checksum, history and metadata
+        VariantFieldInfo { size: VariantSize::Variable, alignment: 8 },
+    ];
+    let subsummary_fields = vec![
+        // 0 - ay - Checksum
+        VariantFieldInfo { size: VariantSize::Variable, alignment: 0 },
+        // 1 - aay - Previous checksums
+        VariantFieldInfo { size: VariantSize::Variable, alignment: 0 },
+        // 2 - a{sv} - Metadata
+        VariantFieldInfo { size: VariantSize::Variable, alignment: 8 },
+    ];
+
+    let variant = Variant::new("(a{s(ayaaya{sv})}a{sv})".to_string(), contents)?;
+    let index = variant.root().parse_as_tuple(&index_fields)?;
+
+    let mut res = HashMap::new();
+    for entry in index[0].parse_as_variable_width_array(8)? {
+        let kv = entry.parse_as_tuple(&entry_fields)?;
+        let subsummary = kv[1].parse_as_tuple(&subsummary_fields)?;
+        res.insert(kv[0].parse_as_string()?, bytes_to_object(subsummary[0].parse_as_bytes()));
+    }
+    Ok(res)
+}
+
 pub fn parse_ref (repo_path: &path::PathBuf, ref_name: &str) ->OstreeResult<String> {
     let mut ref_dir = get_ref_path(repo_path);
     ref_dir.push(ref_name);
     let commit =
         fs::read_to_string(ref_dir)
-        .map_err(|_e| OstreeError::NoSuchRef(ref_name.to_string()))?
+        .map_err(|e| match e.kind() {
+            std::io::ErrorKind::NotFound => OstreeError::NoSuchRef(ref_name.to_string()),
+            _ => OstreeError::InternalError(format!("Can't read ref {}: {}", ref_name, e)),
+        })?
         .trim_end().to_string();
     Ok(commit)
 }
@@ -634,6 +1330,57 @@ pub fn list_refs (repo_path: &path::PathBuf, prefix: &str) -> Vec<String> {
         .collect();
 }
 
+fn get_remote_ref_path(repo_path: &path::PathBuf, remote: &str) -> path::PathBuf {
+    let mut ref_dir = std::env::current_dir().unwrap_or_else(|_e| path::PathBuf::new());
+    ref_dir.push(repo_path);
+    ref_dir.push("refs/remotes");
+    ref_dir.push(remote);
+    ref_dir
+}
+
+/* The refs of a remote, as name -> commit. Like the other refs these
+ * keep their commits from being pruned, but they are not listed in the
+ * summary. Only refs without slashes are supported. */
+pub fn list_remote_refs (repo_path: &path::PathBuf, remote: &str) -> HashMap<String, String> {
+    let entries = match fs::read_dir(get_remote_ref_path(repo_path, remote)) {
+        Ok(entries) => entries,
+        Err(_) => return HashMap::new(),
+    };
+    entries
+        .filter_map(|e| e.ok())
+        .filter_map(|e| {
+            let name = e.file_name().into_string().ok().filter(|name| !name.starts_with('.'))?;
+            let commit = fs::read_to_string(e.path()).ok()?.trim_end().to_string();
+            Some((name, commit))
+        })
+        .collect()
+}
+
+/* Sets a remote ref to commit, or deletes it without a commit */
+pub fn set_remote_ref (repo_path: &path::PathBuf, remote: &str, ref_name: &str, commit: Option<&str>) -> OstreeResult<()> {
+    if ref_name.is_empty() || ref_name.contains('/') || ref_name.starts_with('.') {
+        return Err(OstreeError::InternalError(format!("Invalid ref name {}", ref_name)));
+    }
+    let ref_dir = get_remote_ref_path(repo_path, remote);
+    let path = ref_dir.join(ref_name);
+    let res = match commit {
+        Some(commit) => {
+            if !is_valid_checksum(commit) {
+                return Err(OstreeError::InternalError(format!("Invalid commit {}", commit)));
+            }
+            let tmp_path = ref_dir.join(format!(".{}.tmp", ref_name));
+            fs::create_dir_all(&ref_dir)
+                .and_then(|_| fs::write(&tmp_path, format!("{}\n", commit)))
+                .and_then(|_| fs::rename(&tmp_path, &path))
+        },
+        None => match fs::remove_file(&path) {
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            res => res,
+        },
+    };
+    res.map_err(|e| OstreeError::InternalError(format!("Can't update ref {}:{}: {}", remote, ref_name, e)))
+}
+
 #[derive(Serialize, Deserialize,Debug,PartialEq,Eq,Hash,Clone)]
 pub struct Delta {
     pub from: Option<String>,
@@ -701,6 +1448,22 @@ impl Delta {
         Ok(path)
     }
 
+    /* The total size of the superblock and parts */
+    pub fn size(&self, repo_path: &path::PathBuf) -> OstreeResult<u64> {
+        let path = self.delta_path(repo_path)?;
+        let entries = fs::read_dir(&path)
+            .map_err(|_e| OstreeError::NoSuchObject(self.to_string()))?;
+        let mut size = 0;
+        for entry in entries {
+            let metadata = entry.and_then(|entry| entry.metadata())
+                .map_err(|e| OstreeError::InternalError(format!("Can't read delta {}: {}", self.to_string(), e)))?;
+            if metadata.is_file() {
+                size += metadata.len();
+            }
+        }
+        Ok(size)
+    }
+
     pub fn to_string(&self) -> String {
         format!("{}-{}",
                 self.from.as_ref().unwrap_or(&"nothing".to_string()),
@@ -727,6 +1490,7 @@ mod tests {
         assert_eq!(type_string_split("(ssas)ii"), Some(("(ssas)", "ii")));
         assert_eq!(type_string_split("a{sv}as"), Some(("a{sv}", "as")));
         assert_eq!(type_string_split("a{vv}as"), None);
+        assert_eq!(type_string_element_len("(a{s(ayaaya{sv})}a{sv})"), Some(23));
     }
 
     #[test]
@@ -740,6 +1504,286 @@ mod tests {
         assert_eq!(Delta::from_name("OkiocD9GLq_Nt660BvWyrH8G62dAvtLv7RPqngWqf5c-3dpOrJG4MNyKHDDGXHpH_zd9NXugnexr5jpvSFQ77S4"),
                    Ok(Delta { from: Some("3a48a8703f462eafcdb7aeb406f5b2ac7f06eb6740bed2efed13ea9e05aa7f97".to_string()), to: "ddda4eac91b830dc8a1c30c65c7a47ff377d357ba09dec6be63a6f48543bed2e".to_string() }));
     }
+
+    #[test]
+    fn test_get_object_path() {
+        let repo = path::PathBuf::from("/repo");
+        let checksum = "3a48a8703f462eafcdb7aeb406f5b2ac7f06eb6740bed2efed13ea9e05aa7f97";
+        assert_eq!(get_object_path(&repo, checksum, "commit").unwrap(),
+                   path::PathBuf::from("/repo/objects/3a/48a8703f462eafcdb7aeb406f5b2ac7f06eb6740bed2efed13ea9e05aa7f97.commit"));
+        assert!(get_object_path(&repo, "3a", "commit").is_err());
+        assert!(get_object_path(&repo, &checksum.to_uppercase(), "commit").is_err());
+        assert!(get_object_path(&repo, &format!("{}etc/", "../".repeat(20)), "commit").is_err());
+        assert!(get_object_path(&repo, &format!("{}0", checksum), "commit").is_err());
+    }
+
+    /* A (tuuuusa(ayay)) archive header with uid and gid 0, no xattrs */
+    fn filez_header(size: u64, mode: u32, symlink_target: &str) -> Vec<u8> {
+        let mut header = vec![0u8; 24];
+        BigEndian::write_u64(&mut header[0..8], size);
+        BigEndian::write_u32(&mut header[16..20], mode);
+        header.extend_from_slice(symlink_target.as_bytes());
+        header.push(0);
+        let target_end = header.len();
+        if target_end < 0xff {
+            header.push(target_end as u8);
+        } else {
+            header.extend_from_slice(&[target_end as u8, (target_end >> 8) as u8]);
+        }
+        header
+    }
+
+    #[test]
+    fn test_parse_filez() {
+        let header = filez_header(5, 0o100644, "");
+        let mut contents = vec![0, 0, 0, header.len() as u8, 0, 0, 0, 0];
+        contents.extend_from_slice(&header);
+        contents.extend_from_slice(b"data");
+
+        let object = parse_filez(&contents).unwrap();
+        assert_eq!(object.header, &header[..]);
+        assert_eq!(object.mode, 0o100644);
+        assert_eq!(object.compressed, b"data");
+        assert!(object.is_regular());
+
+        /* Truncated in the size, the header, or a header too short for the mode */
+        assert!(parse_filez(&contents[..6]).is_none());
+        assert!(parse_filez(&contents[..20]).is_none());
+        assert!(parse_filez(&[0, 0, 0, 4, 0, 0, 0, 0, 1, 2, 3, 4]).is_none());
+
+        let header = filez_header(0, 0o120777, "target");
+        let mut contents = vec![0, 0, 0, header.len() as u8, 0, 0, 0, 0];
+        contents.extend_from_slice(&header);
+        let object = parse_filez(&contents).unwrap();
+        assert!(!object.is_regular());
+        assert!(object.compressed.is_empty());
+
+        /* Only regular files have content to read */
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("symlink.filez");
+        fs::write(&path, &contents).unwrap();
+        assert!(load_filez_content(&path).is_err());
+        let path = dir.path().join("truncated.filez");
+        fs::write(&path, &contents[..10]).unwrap();
+        assert!(load_filez_content(&path).is_err());
+    }
+
+    #[test]
+    fn test_filez_checksum_header() {
+        let header = filez_header(5, 0o100644, "");
+        assert_eq!(filez_checksum_header(&header),
+                   Some(vec![0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x81, 0xa4, 0, 0, 0, 0, 0, 17]));
+
+        /* The framing offset shrinks to one byte without the size */
+        let target = "x".repeat(235);
+        let header = filez_header(0, 0o120777, &target);
+        assert_eq!(header.len(), 262);
+        let converted = filez_checksum_header(&header).unwrap();
+        assert_eq!(converted.len(), 253);
+        assert_eq!(&converted[..252], &header[8..260]);
+        assert_eq!(converted[252], 252);
+
+        assert_eq!(filez_checksum_header(&[0u8; 10]), None);
+    }
+
+    #[test]
+    fn test_filez_checksum() {
+        use flate2::Compression;
+        use flate2::write::DeflateEncoder;
+        use std::io::Write;
+
+        let content = b"hello";
+        let header = filez_header(content.len() as u64, 0o100644, "");
+        let mut object = vec![0, 0, 0, header.len() as u8, 0, 0, 0, 0];
+        object.extend_from_slice(&header);
+        let mut encoder = DeflateEncoder::new(object, Compression::default());
+        encoder.write_all(content).unwrap();
+        let object = encoder.finish().unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("object.filez");
+        fs::write(&path, &object).unwrap();
+
+        assert_eq!(load_filez_content(&path), Ok(content.to_vec()));
+
+        let mut checksummed = vec![0, 0, 0, 18, 0, 0, 0, 0];
+        checksummed.extend_from_slice(&filez_checksum_header(&header).unwrap());
+        checksummed.extend_from_slice(content);
+        assert_eq!(get_filez_checksum(&path), Ok(hex::encode(sha256(&checksummed))));
+    }
+
+    #[test]
+    fn test_serialize_asv() {
+        let mut long_value = "y".repeat(300).into_bytes();
+        long_value.push(0);
+        let elements = [serialize_asv_element("short", "s", b"x\0"),
+            serialize_asv_element("long", "s", &long_value),
+            serialize_asv_element("time", "t", &[1, 0, 0, 0, 0, 0, 0, 0])];
+        let element_refs: Vec<&[u8]> = elements.iter().map(|e| e.as_slice()).collect();
+        let variant = Variant::new("a{sv}".to_string(), serialize_variable_width_array(8, &element_refs)).unwrap();
+
+        let asv = variant.root().parse_as_asv().unwrap();
+        assert_eq!(asv.len(), 3);
+        assert_eq!(asv["short"].as_string(), Ok("x".to_string()));
+        assert_eq!(asv["long"].as_string(), Ok("y".repeat(300)));
+        assert_eq!(asv["time"].as_u64(), Ok(1));
+    }
+
+    fn bound_commit(ref_name: &str, collection_id: &str) -> OstreeCommit {
+        let mut metadata = HashMap::new();
+        let ref_data = format!("{}\0", ref_name);
+        metadata.insert("ostree.ref-binding".to_string(),
+                        Variant::new("as".to_string(), serialize_variable_width_array(1, &[ref_data.as_bytes()])).unwrap());
+        metadata.insert("ostree.collection-binding".to_string(),
+                        Variant::new("s".to_string(), format!("{}\0", collection_id).into_bytes()).unwrap());
+        OstreeCommit {
+            metadata,
+            parent: None,
+            subject: "".to_string(),
+            body: "".to_string(),
+            timestamp: 0,
+            root_tree: "".to_string(),
+            root_metadata: "".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_check_ref_bindings() {
+        let collection_id = Some("org.test.Stable".to_string());
+        let commit = bound_commit("app/org.test.App/x86_64/stable", "org.test.Stable");
+        assert!(check_ref_bindings(&commit, "app/org.test.App/x86_64/stable", &collection_id, true, false).is_ok());
+        assert!(check_ref_bindings(&commit, "app/org.test.App/x86_64/beta", &collection_id, true, false).is_err());
+        assert!(check_ref_bindings(&commit, "app/org.test.Other/x86_64/stable", &collection_id, true, false).is_err());
+        assert!(check_ref_bindings(&commit, "app/org.test.App/x86_64/stable", &Some("org.test.Beta".to_string()), true, false).is_err());
+
+        let unbound = bound_commit("", "");
+        assert!(check_ref_bindings(&OstreeCommit { metadata: HashMap::new(), ..unbound }, "app/org.test.App/x86_64/stable", &collection_id, false, false).is_ok());
+
+        /* Derived builds can change the branch, and their commits come from the source build repo */
+        let derived = bound_commit("app/org.test.App/x86_64/stable", "org.test.Stable.Build12");
+        assert!(check_ref_bindings(&derived, "app/org.test.App/x86_64/stable", &collection_id, true, false).is_err());
+        assert!(check_ref_bindings(&derived, "app/org.test.App/x86_64/beta", &collection_id, true, true).is_ok());
+        assert!(check_ref_bindings(&derived, "app/org.test.App/aarch64/beta", &collection_id, true, true).is_err());
+        assert!(check_ref_bindings(&derived, "app/org.test.Other/x86_64/stable", &collection_id, true, true).is_err());
+        assert!(check_ref_bindings(&derived, "app/org.test.App/x86_64/stable", &Some("org.test.Beta".to_string()), true, true).is_err());
+    }
+
+    #[test]
+    fn test_serialize_tuple() {
+        /* Fixed size members get no framing offset, nor does the last one */
+        let tuple = serialize_tuple(&[(1, true, b"ab"), (8, false, &[1, 0, 0, 0, 0, 0, 0, 0]), (1, true, b"c")]);
+        assert_eq!(tuple, vec![b'a', b'b', 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, b'c', 2]);
+
+        /* The framing offsets grow once the container no longer fits in 255 bytes */
+        let long = vec![b'x'; 254];
+        let array = serialize_variable_width_array(1, &[&long, b"y"]);
+        assert_eq!(array.len(), 255 + 2 * 2);
+        assert_eq!(&array[255..], &[254, 0, 255, 0]);
+        let variant = Variant::new("aay".to_string(), array).unwrap();
+        let elements: Vec<&[u8]> = variant.root().parse_as_variable_width_array(1).unwrap().iter().map(|element| element.parse_as_bytes()).collect();
+        assert_eq!(elements, vec![long.as_slice(), b"y"]);
+    }
+
+    #[test]
+    fn test_remote_refs() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = dir.path().to_path_buf();
+        let commit = hex::encode(sha256(b"commit"));
+        let other_commit = hex::encode(sha256(b"other commit"));
+        fs::create_dir_all(repo.join("refs/heads/app/org.test.App/x86_64")).unwrap();
+        fs::write(repo.join("refs/heads/app/org.test.App/x86_64/stable"), format!("{}\n", commit)).unwrap();
+        assert!(list_remote_refs(&repo, "pins").is_empty());
+
+        set_remote_ref(&repo, "pins", "1", Some(&commit)).unwrap();
+        set_remote_ref(&repo, "pins", "2", Some(&commit)).unwrap();
+        set_remote_ref(&repo, "pins", "2", Some(&other_commit)).unwrap();
+        let refs = list_remote_refs(&repo, "pins");
+        assert_eq!(refs.len(), 2);
+        assert_eq!(refs["1"], commit);
+        assert_eq!(refs["2"], other_commit);
+        assert_eq!(fs::read_to_string(repo.join("refs/remotes/pins/1")).unwrap(), format!("{}\n", commit));
+
+        /* They are not refs of the repo itself, so not in the summary */
+        assert_eq!(list_refs(&repo, ""), vec!["app/org.test.App/x86_64/stable".to_string()]);
+
+        set_remote_ref(&repo, "pins", "1", None).unwrap();
+        set_remote_ref(&repo, "pins", "3", None).unwrap();
+        assert_eq!(list_remote_refs(&repo, "pins").keys().collect::<Vec<_>>(), vec!["2"]);
+
+        assert!(set_remote_ref(&repo, "pins", "../heads", Some(&commit)).is_err());
+        assert!(set_remote_ref(&repo, "pins", "", Some(&commit)).is_err());
+        assert!(set_remote_ref(&repo, "pins", "4", Some("HEAD")).is_err());
+    }
+
+    /* A superblock for a delta from scratch with the given parts */
+    fn delta_superblock(to: &str, commit: &[u8], metadata: &[Vec<u8>], parts: &[&[u8]]) -> Vec<u8> {
+        let metadata_refs: Vec<&[u8]> = metadata.iter().map(|element| element.as_slice()).collect();
+        let part_infos: Vec<Vec<u8>> = parts.iter().map(|part| {
+            let size = (part.len() as u64).to_le_bytes();
+            serialize_tuple(&[(4, false, &[0, 0, 0, 0]), (1, true, &sha256(part)), (8, false, &size), (8, false, &size), (1, true, &[])])
+        }).collect();
+        let part_info_refs: Vec<&[u8]> = part_infos.iter().map(|info| info.as_slice()).collect();
+        serialize_tuple(&[
+            (8, true, &serialize_variable_width_array(8, &metadata_refs)),
+            (8, false, &[0; 8]),
+            (1, true, &[]),
+            (1, true, &object_to_bytes(to).unwrap()),
+            (8, true, commit),
+            (1, true, &[]),
+            (8, true, &serialize_variable_width_array(8, &part_info_refs)),
+            (8, true, &[]),
+        ])
+    }
+
+    #[test]
+    fn test_rebase_delta() {
+        let src_dir = tempfile::tempdir().unwrap();
+        let src_repo = src_dir.path().to_path_buf();
+        let dst_dir = tempfile::tempdir().unwrap();
+        let dst_repo = dst_dir.path().to_path_buf();
+
+        let old_commit = b"old commit".to_vec();
+        let old_delta = Delta::new(None, &hex::encode(sha256(&old_commit)));
+        let old_relpath = delta_relpath(&old_delta).unwrap();
+        let inline_part = b"inline part".to_vec();
+        let file_part = b"file part".to_vec();
+        let metadata = vec![
+            serialize_asv_element("ostree.commitmeta", "a{sv}", &[]),
+            serialize_asv_element(&format!("{}/0", old_relpath), "ay", &inline_part),
+            serialize_asv_element("other", "s", b"kept\0"),
+        ];
+        let src_path = old_delta.delta_path(&src_repo).unwrap();
+        fs::create_dir_all(&src_path).unwrap();
+        fs::write(src_path.join("superblock"), delta_superblock(&old_delta.to, &old_commit, &metadata, &[&inline_part, &file_part])).unwrap();
+        fs::write(src_path.join("1"), &file_part).unwrap();
+        assert_eq!(verify_delta(&src_repo, &old_delta), Ok(()));
+
+        /* The new commit, with its detached metadata */
+        let new_commit = b"new commit".to_vec();
+        let new_to = hex::encode(sha256(&new_commit));
+        let commitmeta = serialize_variable_width_array(8, &[&serialize_asv_element("signed", "s", b"yes\0")]);
+        let commit_path = get_object_path(&dst_repo, &new_to, "commit").unwrap();
+        fs::create_dir_all(commit_path.parent().unwrap()).unwrap();
+        fs::write(&commit_path, &new_commit).unwrap();
+        fs::write(get_object_path(&dst_repo, &new_to, "commitmeta").unwrap(), &commitmeta).unwrap();
+
+        let new_delta = rebase_delta(&src_repo, &old_delta, &dst_repo, &new_to).unwrap();
+        assert_eq!(new_delta, Delta::new(None, &new_to));
+        assert_eq!(verify_delta(&dst_repo, &new_delta), Ok(()));
+
+        let variant = read_delta_superblock(&new_delta.delta_path(&dst_repo).unwrap().join("superblock")).unwrap();
+        let metadata = parse_delta_superblock(&variant).unwrap()[0].parse_as_asv().unwrap();
+        assert_eq!(metadata.len(), 3);
+        assert_eq!(metadata["ostree.commitmeta"].data, commitmeta);
+        assert_eq!(metadata["other"].as_string(), Ok("kept".to_string()));
+        let new_relpath = delta_relpath(&new_delta).unwrap();
+        assert_eq!(metadata[&format!("{}/0", new_relpath)].as_bytes(), inline_part.as_slice());
+        assert!(!metadata.contains_key(&format!("{}/0", old_relpath)));
+
+        /* A delta to a commit that isn't there can't be rebased */
+        let missing = hex::encode(sha256(b"missing"));
+        assert!(rebase_delta(&src_repo, &old_delta, &dst_repo, &missing).is_err());
+    }
 }
 
 pub fn list_deltas (repo_path: &path::PathBuf) -> Vec<Delta> {
@@ -856,9 +1900,17 @@ pub fn pull_delta_async(n_retries: i32,
     )
 }
 
-pub fn generate_delta_async(repo_path: &PathBuf,
-                            delta: &Delta) -> Box<dyn Future<Item=(), Error=OstreeError>> {
-    let mut cmd = Command::new("flatpak");
+pub fn generate_delta_async(flatpak_path: &path::Path,
+                            repo_path: &PathBuf,
+                            delta: &Delta,
+                            cgroup: Option<PathBuf>) -> Box<dyn Future<Item=(), Error=OstreeError>> {
+    let mut cmd = Command::new(flatpak_path);
+
+    if let Some(cgroup) = cgroup {
+        if let Err(e) = cgroups::run_in_cgroup(&mut cmd, &cgroup) {
+            return Box::new(future::err(OstreeError::ExecFailed("flatpak build-update-repo".to_string(), e.to_string())));
+        }
+    }
 
     unsafe {
         cmd
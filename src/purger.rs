@@ -0,0 +1,229 @@
+use actix::prelude::*;
+use actix_web::web;
+use chrono;
+use diesel::prelude::*;
+use futures::Future;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use walkdir::WalkDir;
+
+use app::Config;
+use objectpool;
+use db::{init_purge_build, finish_purge_build, expire_upload_session};
+use models::RepoState;
+use schema::{builds, upload_sessions};
+use Pool;
+
+/**************************************************************************
+ * Deleted builds are kept for delete-grace-secs, during which they can be
+ * undeleted. The BuildPurger actor regularly looks for deleted builds
+ * whose grace period has ended and purges their build repos, the same
+ * way as the purge api call. The build rows themselves are kept.
+ *
+ * Interrupted uploads leave partial files in the tmp directories
+ * (including tmp/cache) of the build repos, which are otherwise only
+ * cleaned when the build is purged. Every TMP_CLEANUP_INTERVAL it also
+ * removes the files there that haven't been modified for stale-tmp-secs,
+ * the objects in the object pool that no build or repo uses anymore, and
+ * the bundles older than bundle-expiry-secs.
+ *
+ * With upload-session-idle-secs set, builds that are still uploading but
+ * haven't had an upload for that long are aborted on the same poll, and
+ * their tmp directories removed right away.
+ ***************************************************************************/
+
+const POLL_INTERVAL: Duration = Duration::from_secs(10 * 60);
+const TMP_CLEANUP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+fn purge_expired_builds(config: &Config, pool: &Pool) -> Result<(), String> {
+    let conn = pool.get().map_err(|e| e.to_string())?;
+    let cutoff = chrono::Utc::now().naive_utc() - chrono::Duration::seconds(config.delete_grace_secs as i64);
+    let (purged, _) = RepoState::Purged.to_db();
+    let (purging, _) = RepoState::Purging.to_db();
+    let expired = builds::table
+        .filter(builds::deleted_at.lt(cutoff))
+        .filter(builds::repo_state.ne(purged))
+        .filter(builds::repo_state.ne(purging))
+        .select(builds::id)
+        .get_results::<i32>(&conn)
+        .map_err(|e| e.to_string())?;
+
+    for build_id in expired {
+        /* This fails if the build is in use, then we retry on the next poll */
+        if let Err(e) = conn.transaction(|| init_purge_build(&conn, config, build_id)) {
+            warn!("Not purging deleted build {}: {}", build_id, e);
+            continue;
+        }
+        let res = fs::remove_dir_all(config.build_repo_base.join(build_id.to_string()));
+        let error = res.err().map(|e| e.to_string());
+        match conn.transaction(|| finish_purge_build(&conn, config, build_id, error)) {
+            Ok(_) => info!("Purged deleted build {}", build_id),
+            Err(e) => error!("Failed to purge deleted build {}: {}", build_id, e),
+        }
+    }
+    Ok(())
+}
+
+fn expire_idle_upload_sessions(config: &Config, pool: &Pool) -> Result<(), String> {
+    let idle_secs = match config.upload_session_idle_secs {
+        Some(idle_secs) => idle_secs,
+        None => return Ok(()),
+    };
+    let conn = pool.get().map_err(|e| e.to_string())?;
+    let cutoff = chrono::Utc::now().naive_utc() - chrono::Duration::seconds(idle_secs as i64);
+    let (uploading, _) = RepoState::Uploading.to_db();
+    let idle = upload_sessions::table
+        .inner_join(builds::table)
+        .filter(upload_sessions::expired_at.is_null())
+        .filter(upload_sessions::last_activity_at.lt(cutoff))
+        .filter(builds::repo_state.eq(uploading))
+        .select(upload_sessions::build_id)
+        .get_results::<i32>(&conn)
+        .map_err(|e| e.to_string())?;
+
+    for build_id in idle {
+        match conn.transaction(|| expire_upload_session(&conn, config, build_id, cutoff)) {
+            Ok(Some(_)) => (),
+            Ok(None) => continue,
+            Err(e) => {
+                warn!("Failed to expire the upload session of build {}: {}", build_id, e);
+                continue;
+            },
+        }
+        info!("Aborted build {}, no uploads for {} seconds", build_id, idle_secs);
+        let build_dir = config.build_repo_base.join(build_id.to_string());
+        for tmp_dir in &[build_dir.join("tmp"), build_dir.join("upload/tmp")] {
+            if tmp_dir.is_dir() {
+                if let Err(e) = fs::remove_dir_all(tmp_dir) {
+                    warn!("Failed to remove {:?}: {}", tmp_dir, e);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn remove_stale_files(tmp_dir: &Path, cutoff: SystemTime) -> (u64, u64) {
+    let (mut n_files, mut n_bytes) = (0, 0);
+    let stale = WalkDir::new(tmp_dir)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_file())
+        .filter_map(|e| e.metadata().ok().map(|metadata| (e, metadata)))
+        .filter(|(_e, metadata)| metadata.modified().map(|modified| modified < cutoff).unwrap_or(false));
+    for (entry, metadata) in stale {
+        match fs::remove_file(entry.path()) {
+            Ok(()) => {
+                n_files += 1;
+                n_bytes += metadata.len();
+            },
+            Err(e) => warn!("Failed to remove stale file {:?}: {}", entry.path(), e),
+        }
+    }
+    (n_files, n_bytes)
+}
+
+fn clean_stale_tmp_files(config: &Config) -> Result<(), String> {
+    let cutoff = SystemTime::now() - Duration::from_secs(config.stale_tmp_secs);
+    let (mut n_files, mut n_bytes) = (0, 0);
+    for build_dir in fs::read_dir(&config.build_repo_base).map_err(|e| e.to_string())? {
+        let build_dir = build_dir.map_err(|e| e.to_string())?.path();
+        for tmp_dir in &[build_dir.join("tmp"), build_dir.join("upload/tmp")] {
+            if tmp_dir.is_dir() {
+                let (files, bytes) = remove_stale_files(tmp_dir, cutoff);
+                n_files += files;
+                n_bytes += bytes;
+            }
+        }
+    }
+    if n_files > 0 {
+        info!("Removed {} stale tmp files ({} bytes) from build repos", n_files, n_bytes);
+    }
+
+    let (n_objects, n_object_bytes) = objectpool::remove_unused(config);
+    if n_objects > 0 {
+        info!("Removed {} unused objects ({} bytes) from the object pool", n_objects, n_object_bytes);
+    }
+
+    if config.bundle_dir.is_dir() {
+        let cutoff = SystemTime::now() - Duration::from_secs(config.bundle_expiry_secs);
+        let (n_bundles, n_bundle_bytes) = remove_stale_files(&config.bundle_dir, cutoff);
+        if n_bundles > 0 {
+            info!("Removed {} expired bundles ({} bytes)", n_bundles, n_bundle_bytes);
+        }
+    }
+    Ok(())
+}
+
+pub struct BuildPurger {
+    config: Arc<Config>,
+    pool: Pool,
+    purging: bool,
+    cleaning: bool,
+}
+
+impl BuildPurger {
+    fn purge_expired(&mut self, ctx: &mut Context<Self>) {
+        if self.purging {
+            return
+        }
+        self.purging = true;
+
+        let config = self.config.clone();
+        let pool = self.pool.clone();
+        ctx.spawn(
+            web::block(move || {
+                if let Err(e) = expire_idle_upload_sessions(&config, &pool) {
+                    error!("Failed to expire idle upload sessions: {}", e);
+                }
+                purge_expired_builds(&config, &pool)
+            })
+                .map_err(|e| error!("Failed to purge deleted builds: {}", e))
+                .into_actor(self)
+                .then(|_r, purger, _ctx| {
+                    purger.purging = false;
+                    actix::fut::ok(())
+                })
+        );
+    }
+
+    fn clean_tmp(&mut self, ctx: &mut Context<Self>) {
+        if self.cleaning {
+            return
+        }
+        self.cleaning = true;
+
+        let config = self.config.clone();
+        ctx.spawn(
+            web::block(move || clean_stale_tmp_files(&config))
+                .map_err(|e| error!("Failed to clean tmp files of build repos: {}", e))
+                .into_actor(self)
+                .then(|_r, purger, _ctx| {
+                    purger.cleaning = false;
+                    actix::fut::ok(())
+                })
+        );
+    }
+}
+
+impl Actor for BuildPurger {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Context<Self>) {
+        self.purge_expired(ctx);
+        ctx.run_interval(POLL_INTERVAL, |purger, ctx| purger.purge_expired(ctx));
+        self.clean_tmp(ctx);
+        ctx.run_interval(TMP_CLEANUP_INTERVAL, |purger, ctx| purger.clean_tmp(ctx));
+    }
+}
+
+pub fn start_build_purger(config: Arc<Config>, pool: Pool) -> Addr<BuildPurger> {
+    BuildPurger {
+        config,
+        pool,
+        purging: false,
+        cleaning: false,
+    }.start()
+}
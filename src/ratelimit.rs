@@ -0,0 +1,324 @@
+use actix_service::{Service, Transform};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::error::Error;
+use actix_web::HttpMessage;
+use futures::{Future, Poll};
+use futures::future::{ok, Either, FutureResult};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use app::{Claims, RateLimitConfig};
+use errors::ApiError;
+
+/* Per token subject rate limiting for the api. Requests are limited with
+ * a token bucket that refills at requests-per-second up to burst, and the
+ * number of uploads running at the same time can be capped, both per
+ * subject and per build. Each of the limits is optional. This has to run inside the TokenParser, as it
+ * keys on the claims. */
+
+/* Don't let the buckets of old subjects pile up forever */
+const MAX_TRACKED_SUBJECTS: usize = 1000;
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+#[derive(Default)]
+struct State {
+    buckets: HashMap<String, Bucket>,
+    uploads: HashMap<String, u32>, // Keyed by "sub:$sub" or "build:$id"
+}
+
+struct Inner {
+    config: Option<RateLimitConfig>,
+    state: Mutex<State>,
+}
+
+impl Inner {
+    fn burst(config: &RateLimitConfig, rate: f64) -> f64 {
+        config.burst.map(|burst| burst as f64).unwrap_or(rate.max(1.0))
+    }
+
+    fn take_token(&self, config: &RateLimitConfig, sub: &str, now: Instant) -> Result<(), ApiError> {
+        let rate = match config.requests_per_second {
+            Some(rate) => rate,
+            None => return Ok(()),
+        };
+        let burst = Inner::burst(config, rate);
+        let mut state = self.state.lock().unwrap();
+
+        if state.buckets.len() > MAX_TRACKED_SUBJECTS {
+            state.buckets.retain(|_sub, bucket| {
+                let elapsed = now.duration_since(bucket.updated);
+                bucket.tokens + rate * (elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 * 1e-9) < burst
+            });
+        }
+
+        let bucket = state.buckets.entry(sub.to_string()).or_insert(Bucket { tokens: burst, updated: now });
+        let elapsed = now.duration_since(bucket.updated);
+        let elapsed_secs = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 * 1e-9;
+        bucket.tokens = (bucket.tokens + elapsed_secs * rate).min(burst);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            let retry_after = ((1.0 - bucket.tokens) / rate).ceil() as u64;
+            Err(ApiError::TooManyRequests("Too many requests".to_string(), retry_after.max(1)))
+        }
+    }
+
+    fn start_upload(inner: &Arc<Inner>, config: &RateLimitConfig, sub: &str, build_id: Option<&str>) -> Result<RunningUpload, ApiError> {
+        let mut limits = vec![];
+        if let Some(max) = config.max_concurrent_uploads {
+            limits.push((format!("sub:{}", sub), max, format!("Too many concurrent uploads, at most {} allowed", max)));
+        }
+        if let (Some(max), Some(build_id)) = (config.max_concurrent_uploads_per_build, build_id) {
+            limits.push((format!("build:{}", build_id), max, format!("Too many concurrent uploads to build {}, at most {} allowed", build_id, max)));
+        }
+
+        let mut state = inner.state.lock().unwrap();
+        for (key, max, message) in &limits {
+            if state.uploads.get(key).cloned().unwrap_or(0) >= *max {
+                return Err(ApiError::TooManyRequests(message.clone(), 1));
+            }
+        }
+        let keys: Vec<String> = limits.into_iter().map(|(key, _, _)| key).collect();
+        for key in &keys {
+            *state.uploads.entry(key.clone()).or_insert(0) += 1;
+        }
+        Ok(RunningUpload {
+            inner: inner.clone(),
+            keys,
+        })
+    }
+}
+
+/* Counts as a running upload until dropped */
+struct RunningUpload {
+    inner: Arc<Inner>,
+    keys: Vec<String>,
+}
+
+impl Drop for RunningUpload {
+    fn drop(&mut self) {
+        let mut state = self.inner.state.lock().unwrap();
+        for key in &self.keys {
+            let done = match state.uploads.get_mut(key) {
+                Some(uploads) => {
+                    *uploads -= 1;
+                    *uploads == 0
+                },
+                None => false,
+            };
+            if done {
+                state.uploads.remove(key);
+            }
+        }
+    }
+}
+
+/* The build id of $api/build/$id/upload and upload_tar */
+fn upload_build_id(path: &str) -> Option<&str> {
+    let mut segments = path.rsplit('/');
+    match segments.next() {
+        Some("upload") | Some("upload_tar") => (),
+        _ => return None,
+    }
+    let build_id = segments.next()?;
+    match segments.next() {
+        Some("build") => Some(build_id),
+        _ => None,
+    }
+}
+
+/* This is shared between all the http workers, so clone it into each */
+#[derive(Clone)]
+pub struct RateLimiter(Arc<Inner>);
+
+impl RateLimiter {
+    pub fn new(config: &Option<RateLimitConfig>) -> RateLimiter {
+        RateLimiter(Arc::new(Inner {
+            config: config.clone(),
+            state: Mutex::new(State::default()),
+        }))
+    }
+}
+
+impl<S: 'static, B> Transform<S> for RateLimiter
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RateLimiterMiddleware<S>;
+    type Future = FutureResult<Self::Transform, Self::InitError>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(RateLimiterMiddleware {
+            service,
+            inner: self.0.clone(),
+        })
+    }
+}
+
+/// RateLimiter middleware
+pub struct RateLimiterMiddleware<S> {
+    service: S,
+    inner: Arc<Inner>,
+}
+
+impl<S> RateLimiterMiddleware<S> {
+    fn check_limits(&self, req: &ServiceRequest) -> Result<Option<RunningUpload>, ApiError> {
+        let config = match self.inner.config {
+            Some(ref config) => config,
+            None => return Ok(None),
+        };
+        let sub = match req.extensions().get::<Claims>() {
+            Some(claims) => claims.usage_subject().to_string(),
+            None => return Ok(None),
+        };
+
+        self.inner.take_token(config, &sub, Instant::now())?;
+        if req.path().ends_with("/upload") || req.path().ends_with("/upload_tar") {
+            Inner::start_upload(&self.inner, config, &sub, upload_build_id(req.path())).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+impl<S, B> Service for RateLimiterMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Either<Box<dyn Future<Item = Self::Response, Error = Self::Error>>,
+                         FutureResult<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        self.service.poll_ready()
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let running_upload = match self.check_limits(&req) {
+            Err(e) => return Either::B(ok(req.error_response(e))),
+            Ok(r) => r,
+        };
+
+        Either::A(Box::new(self.service.call(req)
+                           .then(move |resp| {
+                               drop(running_upload);
+                               resp
+                           })))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn rate_limit_config(requests_per_second: f64, burst: Option<u32>) -> RateLimitConfig {
+        RateLimitConfig {
+            requests_per_second: Some(requests_per_second),
+            burst,
+            max_concurrent_uploads: Some(1),
+            max_concurrent_uploads_per_build: None,
+        }
+    }
+
+    fn retry_after(res: Result<(), ApiError>) -> Option<u64> {
+        match res {
+            Err(ApiError::TooManyRequests(_, retry_after)) => Some(retry_after),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_token_bucket() {
+        let config = rate_limit_config(2.0, Some(3));
+        let limiter = RateLimiter::new(&Some(config.clone()));
+        let inner = &limiter.0;
+        let start = Instant::now();
+
+        /* A new subject starts with a full bucket */
+        for _ in 0..3 {
+            assert!(inner.take_token(&config, "ci", start).is_ok());
+        }
+        assert_eq!(retry_after(inner.take_token(&config, "ci", start)), Some(1));
+        /* Other subjects have their own bucket */
+        assert!(inner.take_token(&config, "other", start).is_ok());
+
+        /* Refills at requests-per-second */
+        let later = start + Duration::from_millis(500);
+        assert!(inner.take_token(&config, "ci", later).is_ok());
+        assert!(inner.take_token(&config, "ci", later).is_err());
+
+        /* But only up to burst */
+        let much_later = later + Duration::from_secs(60);
+        for _ in 0..3 {
+            assert!(inner.take_token(&config, "ci", much_later).is_ok());
+        }
+        assert!(inner.take_token(&config, "ci", much_later).is_err());
+    }
+
+    #[test]
+    fn test_default_burst() {
+        assert_eq!(Inner::burst(&rate_limit_config(10.0, None), 10.0), 10.0);
+        assert_eq!(Inner::burst(&rate_limit_config(0.1, None), 0.1), 1.0);
+
+        /* With less than a request per second, the wait is longer */
+        let config = rate_limit_config(0.1, None);
+        let limiter = RateLimiter::new(&Some(config.clone()));
+        let now = Instant::now();
+        assert!(limiter.0.take_token(&config, "ci", now).is_ok());
+        assert_eq!(retry_after(limiter.0.take_token(&config, "ci", now)), Some(10));
+    }
+
+    #[test]
+    fn test_concurrent_uploads() {
+        let config = rate_limit_config(1.0, None);
+        let limiter = RateLimiter::new(&Some(config.clone()));
+        let upload = Inner::start_upload(&limiter.0, &config, "ci", Some("12")).unwrap();
+        assert!(Inner::start_upload(&limiter.0, &config, "ci", Some("13")).is_err());
+        drop(upload);
+        assert!(Inner::start_upload(&limiter.0, &config, "ci", Some("13")).is_ok());
+
+        assert_eq!(upload_build_id("/api/v1/build/12/upload"), Some("12"));
+        assert_eq!(upload_build_id("/api/v1/build/12/upload_tar"), Some("12"));
+        assert_eq!(upload_build_id("/api/v1/upload"), None);
+    }
+
+    #[test]
+    fn test_uploads_per_build_without_request_rate() {
+        let config = RateLimitConfig {
+            requests_per_second: None,
+            burst: None,
+            max_concurrent_uploads: None,
+            max_concurrent_uploads_per_build: Some(1),
+        };
+        let limiter = RateLimiter::new(&Some(config.clone()));
+        let now = Instant::now();
+        for _ in 0..100 {
+            assert!(limiter.0.take_token(&config, "ci", now).is_ok());
+        }
+
+        let upload = Inner::start_upload(&limiter.0, &config, "ci", Some("12")).unwrap();
+        assert!(Inner::start_upload(&limiter.0, &config, "other", Some("12")).is_err());
+        assert!(Inner::start_upload(&limiter.0, &config, "ci", Some("13")).is_ok());
+        drop(upload);
+        assert!(Inner::start_upload(&limiter.0, &config, "other", Some("12")).is_ok());
+    }
+}
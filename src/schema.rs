@@ -1,9 +1,52 @@
+table! {
+    acl_group_members (id) {
+        id -> Int4,
+        group_name -> Text,
+        member -> Text,
+        created_at -> Timestamp,
+        author -> Text,
+    }
+}
+
+table! {
+    app_acl (id) {
+        id -> Int4,
+        app_id -> Text,
+        principal -> Text,
+        created_at -> Timestamp,
+        author -> Text,
+    }
+}
+
+table! {
+    audit_log (id) {
+        id -> Int4,
+        created_at -> Timestamp,
+        action -> Text,
+        token_sub -> Nullable<Text>,
+        token_name -> Nullable<Text>,
+        token_claims -> Nullable<Text>,
+        params -> Text,
+    }
+}
+
+table! {
+    build_comments (id) {
+        id -> Int4,
+        build_id -> Int4,
+        created_at -> Timestamp,
+        author -> Text,
+        comment -> Text,
+    }
+}
+
 table! {
     build_refs (id) {
         id -> Int4,
         build_id -> Int4,
         ref_name -> Text,
         commit -> Text,
+        size -> Nullable<Int8>,
     }
 }
 
@@ -19,6 +62,27 @@ table! {
         publish_job_id -> Nullable<Int4>,
         repo -> Text,
         extra_ids -> Array<Text>,
+        app_id -> Nullable<Text>,
+        uploader -> Nullable<Text>,
+        metadata -> Jsonb,
+        github_repository -> Nullable<Text>,
+        github_sha -> Nullable<Text>,
+        deleted_at -> Nullable<Timestamp>,
+        uploaded_objects -> Int8,
+        uploaded_bytes -> Int8,
+        derived_from -> Nullable<Int4>,
+    }
+}
+
+table! {
+    job_failure_alerts (id) {
+        id -> Int4,
+        kind -> Int2,
+        created_at -> Timestamp,
+        failed -> Int4,
+        total -> Int4,
+        job_id -> Nullable<Int4>,
+        window_start -> Timestamp,
     }
 }
 
@@ -39,6 +103,32 @@ table! {
         log -> Text,
         start_after -> Nullable<Timestamp>,
         repo -> Nullable<Text>,
+        started_at -> Nullable<Timestamp>,
+        ended_at -> Nullable<Timestamp>,
+        progress -> Nullable<Text>,
+        lease_owner -> Nullable<Text>,
+        lease_expires_at -> Nullable<Timestamp>,
+        trace_parent -> Nullable<Text>,
+    }
+}
+
+table! {
+    organization_members (id) {
+        id -> Int4,
+        organization_id -> Int4,
+        member -> Text,
+        created_at -> Timestamp,
+        author -> Text,
+    }
+}
+
+table! {
+    organizations (id) {
+        id -> Int4,
+        name -> Text,
+        upload_quota -> Nullable<Int8>,
+        created_at -> Timestamp,
+        author -> Text,
     }
 }
 
@@ -48,16 +138,108 @@ table! {
         build_id -> Int4,
         ref_name -> Text,
         commit -> Text,
+        job_id -> Nullable<Int4>,
+        repo -> Text,
+        previous_commit -> Nullable<Text>,
+        published_at -> Timestamp,
+        note -> Nullable<Text>,
+        published_by -> Nullable<Text>,
+    }
+}
+
+table! {
+    queued_mails (id) {
+        id -> Int4,
+        created_at -> Timestamp,
+        subject -> Text,
+        body -> Text,
+        attempts -> Int4,
+        sent -> Bool,
+        last_error -> Nullable<Text>,
+    }
+}
+
+table! {
+    ref_pins (id) {
+        id -> Int4,
+        repo -> Text,
+        ref_name -> Text,
+        commit -> Nullable<Text>,
+        created_at -> Timestamp,
+        author -> Text,
+        reason -> Nullable<Text>,
+    }
+}
+
+table! {
+    ref_tombstones (id) {
+        id -> Int4,
+        repo -> Text,
+        ref_name -> Text,
+        reason -> Text,
+        rebase -> Nullable<Text>,
+        created_at -> Timestamp,
+        author -> Text,
+        job_id -> Nullable<Int4>,
+    }
+}
+
+table! {
+    token_usage (subject) {
+        subject -> Text,
+        uploaded_bytes -> Int8,
+        updated_at -> Timestamp,
+        request_count -> Int8,
+        last_used_at -> Nullable<Timestamp>,
+        remote_addrs -> Array<Text>,
+    }
+}
+
+table! {
+    upload_sessions (id) {
+        id -> Int4,
+        build_id -> Int4,
+        created_at -> Timestamp,
+        last_activity_at -> Timestamp,
+        expired_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    webhook_events (id) {
+        id -> Int4,
+        created_at -> Timestamp,
+        event -> Text,
+        payload -> Text,
+        attempts -> Int4,
+        delivered -> Bool,
+        last_error -> Nullable<Text>,
     }
 }
 
+joinable!(build_comments -> builds (build_id));
 joinable!(build_refs -> builds (build_id));
+joinable!(organization_members -> organizations (organization_id));
 joinable!(published_refs -> builds (build_id));
+joinable!(upload_sessions -> builds (build_id));
 
 allow_tables_to_appear_in_same_query!(
+    acl_group_members,
+    app_acl,
+    audit_log,
+    build_comments,
     build_refs,
     builds,
     job_dependencies,
+    job_failure_alerts,
     jobs,
+    organization_members,
+    organizations,
     published_refs,
+    queued_mails,
+    ref_pins,
+    ref_tombstones,
+    token_usage,
+    upload_sessions,
+    webhook_events,
 );
@@ -0,0 +1,315 @@
+use hex;
+use openssl::sha::sha256;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, ToSocketAddrs};
+use std::path::Path;
+use std::process::Command;
+use url::{Host, Url};
+
+use app::ScreenshotsConfig;
+
+/* Mirroring of the screenshots in the appstream data, so that the
+ * published metadata doesn't depend on the upstream hosting of each
+ * app. The screenshots are stored by the hash of their upstream url in
+ * media-dir, which is expected to be served at media-url. */
+
+const MAX_SCREENSHOT_SIZE: u64 = 20 * 1024 * 1024;
+const MAX_SCREENSHOT_REDIRECTS: usize = 5;
+
+fn xml_unescape(s: &str) -> String {
+    s.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/* The urls of the <image>s in the appstream xml */
+pub fn list_image_urls(xml: &str) -> Vec<String> {
+    let mut urls = vec![];
+    rewrite_image_urls(xml, |url| {
+        urls.push(url.to_string());
+        None
+    });
+    urls
+}
+
+/* Calls mirror for the url of each <image> (these are only used for
+ * screenshots) and replaces it with the returned url, if any. Returns the
+ * new xml and the number of replaced urls. */
+pub fn rewrite_image_urls<F>(xml: &str, mut mirror: F) -> (String, usize)
+    where F: FnMut(&str) -> Option<String>
+{
+    let mut out = String::with_capacity(xml.len());
+    let mut rest = xml;
+    let mut replaced = 0;
+    while let Some(start) = rest.find("<image") {
+        /* Skip e.g. <images> */
+        let after_name = &rest[start + "<image".len()..];
+        if !after_name.starts_with('>') && !after_name.starts_with(' ') {
+            out.push_str(&rest[..start + "<image".len()]);
+            rest = after_name;
+            continue;
+        }
+        let (content_start, content_end) = match (after_name.find('>'), after_name.find("</image>")) {
+            (Some(tag_end), Some(end)) if tag_end < end => (start + "<image".len() + tag_end + 1,
+                                                            start + "<image".len() + end),
+            _ => break,
+        };
+        out.push_str(&rest[..content_start]);
+        let url = xml_unescape(rest[content_start..content_end].trim());
+        match mirror(&url) {
+            Some(new_url) => {
+                out.push_str(&xml_escape(&new_url));
+                replaced += 1;
+            },
+            None => out.push_str(&rest[content_start..content_end]),
+        }
+        rest = &rest[content_end..];
+    }
+    out.push_str(rest);
+    (out, replaced)
+}
+
+/* The name keeps the extension, so the web server serves the right type */
+pub fn mirrored_name(url: &str) -> String {
+    let hash = hex::encode(sha256(url.as_bytes()));
+    let basename = url.split(['?', '#']).next().unwrap_or("").rsplit('/').next().unwrap_or("");
+    match basename.rfind('.') {
+        Some(pos) if basename.len() - pos <= 5 && basename[pos + 1..].chars().all(|c| c.is_ascii_alphanumeric()) =>
+            format!("{}/{}{}", &hash[..2], &hash[2..], basename[pos..].to_lowercase()),
+        _ => format!("{}/{}", &hash[..2], &hash[2..]),
+    }
+}
+
+fn is_public_ipv4(ip: &Ipv4Addr) -> bool {
+    let octets = ip.octets();
+    !(ip.is_private() || ip.is_loopback() || ip.is_link_local() || ip.is_broadcast() ||
+      ip.is_documentation() || ip.is_unspecified() || ip.is_multicast() ||
+      octets[0] == 0 || // This network
+      (octets[0] == 100 && octets[1] & 0xc0 == 64) || // Shared address space
+      (octets[0] == 192 && octets[1] == 0 && octets[2] == 0) || // Protocol assignments
+      (octets[0] == 198 && octets[1] & 0xfe == 18) || // Benchmarking
+      octets[0] >= 240) // Reserved
+}
+
+pub fn is_public_ip(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_ipv4(ip),
+        IpAddr::V6(ip) => {
+            let segments = ip.segments();
+            /* Mapped, compatible and NAT64 addresses lead to an ipv4 address */
+            if let Some(ipv4) = ip.to_ipv4() {
+                return is_public_ipv4(&ipv4);
+            }
+            if segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0] {
+                return is_public_ipv4(&Ipv4Addr::new((segments[6] >> 8) as u8, segments[6] as u8,
+                                                     (segments[7] >> 8) as u8, segments[7] as u8));
+            }
+            !(ip.is_loopback() || ip.is_unspecified() || ip.is_multicast() ||
+              segments[0] & 0xfe00 == 0xfc00 || // Unique local
+              segments[0] & 0xffc0 == 0xfe80 || // Link local
+              segments[0] & 0xffc0 == 0xfec0 || // Site local
+              (segments[0] == 0x2001 && segments[1] == 0x0db8)) // Documentation
+        }
+    }
+}
+
+/* Only http(s) urls of hosts with public addresses are downloaded, so
+ * that the appstream data can't make us fetch from internal services.
+ * Returns the --resolve argument that makes curl connect to the checked
+ * address, so the name can't resolve to another one by then. */
+fn resolve_public_url(url: &str) -> Result<Option<String>, String> {
+    let parsed = Url::parse(url).map_err(|e| format!("Invalid url {}: {}", url, e))?;
+    if parsed.scheme() != "http" && parsed.scheme() != "https" {
+        return Err(format!("Unsupported url {}", url));
+    }
+    let port = parsed.port_or_known_default()
+        .ok_or_else(|| format!("No port for url {}", url))?;
+    let (addresses, domain) = match parsed.host() {
+        Some(Host::Ipv4(ip)) => (vec![IpAddr::V4(ip)], None),
+        Some(Host::Ipv6(ip)) => (vec![IpAddr::V6(ip)], None),
+        Some(Host::Domain(domain)) => {
+            let addresses: Vec<IpAddr> = (domain, port).to_socket_addrs()
+                .map_err(|e| format!("Can't resolve {}: {}", domain, e))?
+                .map(|address| address.ip())
+                .collect();
+            (addresses, Some(domain))
+        },
+        None => return Err(format!("No host in url {}", url)),
+    };
+    if let Some(ip) = addresses.iter().find(|ip| !is_public_ip(ip)) {
+        return Err(format!("{} is not a public address", ip));
+    }
+    match (domain, addresses.first()) {
+        (_, None) => Err(format!("No address for {}", url)),
+        (None, Some(_)) => Ok(None),
+        (Some(domain), Some(IpAddr::V4(ip))) => Ok(Some(format!("{}:{}:{}", domain, port, ip))),
+        (Some(domain), Some(IpAddr::V6(ip))) => Ok(Some(format!("{}:{}:[{}]", domain, port, ip))),
+    }
+}
+
+/* Redirects are followed by hand, so each location is checked like
+ * the original url. new_curl creates the (sandboxed) curl command. */
+fn download<F>(new_curl: &F, url: &str, dest: &Path) -> Result<(), String>
+    where F: Fn() -> Command
+{
+    let mut url = url.to_string();
+    for _ in 0..MAX_SCREENSHOT_REDIRECTS + 1 {
+        let resolve = resolve_public_url(&url)?;
+        let mut cmd = new_curl();
+        cmd
+            .arg("--silent")
+            .arg("--show-error")
+            .arg("--fail")
+            .arg("--proto").arg("=http,https")
+            .arg("--max-time").arg("60")
+            .arg("--max-filesize").arg(MAX_SCREENSHOT_SIZE.to_string())
+            .arg("--write-out").arg("%{http_code} %{redirect_url}");
+        if let Some(resolve) = resolve {
+            cmd.arg("--resolve").arg(resolve);
+        }
+        let output = cmd
+            .arg("--output").arg(dest)
+            .arg(&url)
+            .output()
+            .map_err(|e| format!("Failed to run curl: {}", e))?;
+        if !output.status.success() {
+            return Err(String::from_utf8_lossy(&output.stderr).trim().to_string());
+        }
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let mut parts = stdout.trim().splitn(2, ' ');
+        match (parts.next(), parts.next()) {
+            (Some("200"), _) => return Ok(()),
+            (Some(status), Some(location)) if status.starts_with('3') && !location.is_empty() => url = location.to_string(),
+            (status, _) => return Err(format!("Unexpected http status {}", status.unwrap_or(""))),
+        }
+    }
+    Err("Too many redirects".to_string())
+}
+
+/* The mirrored url, if we have the screenshot already */
+pub fn mirrored_url(config: &ScreenshotsConfig, url: &str) -> Option<String> {
+    let name = mirrored_name(url);
+    if config.media_dir.join(&name).exists() {
+        Some(format!("{}/{}", config.media_url.trim_end_matches('/'), name))
+    } else {
+        None
+    }
+}
+
+/* Downloads the screenshot unless we have it already */
+pub fn download_screenshot<F>(config: &ScreenshotsConfig, new_curl: &F, url: &str) -> Result<(), String>
+    where F: Fn() -> Command
+{
+    let path = config.media_dir.join(mirrored_name(url));
+    if !path.exists() {
+        let dir = path.parent().unwrap();
+        fs::create_dir_all(dir).map_err(|e| format!("Can't create {}: {}", dir.display(), e))?;
+        let tmp_path = dir.join(format!(".tmp-{}", path.file_name().unwrap().to_string_lossy()));
+        let res = download(new_curl, url, &tmp_path)
+            .and_then(|_| fs::rename(&tmp_path, &path).map_err(|e| e.to_string()));
+        if res.is_err() {
+            let _ = fs::remove_file(&tmp_path);
+        }
+        res?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_public(ip: &str) -> bool {
+        is_public_ip(&ip.parse().unwrap())
+    }
+
+    #[test]
+    fn test_is_public_ip() {
+        assert!(is_public("93.184.216.34"));
+        assert!(is_public("2606:2800:220:1:248:1893:25c8:1946"));
+
+        for ip in &["127.0.0.1", "10.1.2.3", "172.16.0.1", "192.168.1.1", "169.254.169.254",
+                    "100.64.0.1", "0.0.0.0", "255.255.255.255", "224.0.0.1", "198.18.0.1",
+                    "::1", "::", "fd00::1", "fe80::1", "::ffff:127.0.0.1", "::ffff:10.0.0.1",
+                    "64:ff9b::a9fe:a9fe", "2001:db8::1"] {
+            assert!(!is_public(ip), "{} is not public", ip);
+        }
+    }
+
+    #[test]
+    fn test_resolve_public_url() {
+        assert_eq!(resolve_public_url("https://93.184.216.34/a.png"), Ok(None));
+        assert!(resolve_public_url("http://127.0.0.1/a.png").is_err());
+        assert!(resolve_public_url("http://[::1]:8080/a.png").is_err());
+        assert!(resolve_public_url("file:///etc/passwd").is_err());
+        assert!(resolve_public_url("gopher://93.184.216.34/").is_err());
+    }
+
+    #[test]
+    fn test_rewrite_image_urls() {
+        let xml = r#"<components>
+  <component>
+    <screenshots>
+      <screenshot type="default">
+        <image type="source">https://example.com/a.png?x=1&amp;y=2</image>
+        <image type="thumbnail" width="224"> https://example.com/b.png </image>
+      </screenshot>
+    </screenshots>
+    <images><image>https://example.com/kept.png</image></images>
+  </component>
+</components>"#;
+
+        assert_eq!(list_image_urls(xml), vec!["https://example.com/a.png?x=1&y=2".to_string(),
+                                              "https://example.com/b.png".to_string(),
+                                              "https://example.com/kept.png".to_string()]);
+
+        let (new_xml, replaced) = rewrite_image_urls(xml, |url| {
+            if url.ends_with("kept.png") {
+                None
+            } else {
+                Some(format!("https://media.example.com/{}?a=<b>&c", url.rsplit('/').next().unwrap()))
+            }
+        });
+        assert_eq!(replaced, 2);
+        assert!(new_xml.contains(r#"<image type="source">https://media.example.com/a.png?x=1&amp;y=2?a=&lt;b&gt;&amp;c</image>"#));
+        assert!(new_xml.contains(r#"<image type="thumbnail" width="224">https://media.example.com/b.png?a=&lt;b&gt;&amp;c</image>"#));
+        assert!(new_xml.contains("<images><image>https://example.com/kept.png</image></images>"));
+        assert!(new_xml.starts_with("<components>\n  <component>"));
+        assert!(new_xml.ends_with("</component>\n</components>"));
+
+        /* Unchanged when nothing is replaced, or the xml is cut off */
+        assert_eq!(rewrite_image_urls(xml, |_url| None), (xml.to_string(), 0));
+        let truncated = "<image>https://example.com/a.png";
+        assert_eq!(rewrite_image_urls(truncated, |_url| Some("x".to_string())), (truncated.to_string(), 0));
+        assert_eq!(rewrite_image_urls("", |_url| Some("x".to_string())), (String::new(), 0));
+    }
+
+    #[test]
+    fn test_mirrored_name() {
+        let name = mirrored_name("https://example.com/shots/Main.PNG");
+        let hash = hex::encode(sha256(b"https://example.com/shots/Main.PNG"));
+        assert_eq!(name, format!("{}/{}.png", &hash[..2], &hash[2..]));
+
+        /* The query and fragment aren't part of the extension, but of the hash */
+        assert!(mirrored_name("https://example.com/a.jpg?size=large").ends_with(".jpg"));
+        assert!(mirrored_name("https://example.com/a.webp#top").ends_with(".webp"));
+        assert_ne!(mirrored_name("https://example.com/a.jpg?size=large"), mirrored_name("https://example.com/a.jpg?size=small"));
+
+        /* Only short alphanumeric extensions are kept */
+        let no_ext = |url: &str| !mirrored_name(url).contains('.');
+        assert!(no_ext("https://example.com/screenshot"));
+        assert!(no_ext("https://example.com/a.toolong"));
+        assert!(no_ext("https://example.com/a.p/g"));
+        assert!(no_ext("https://example.com/a.pn%67"));
+        assert!(no_ext("https://example.com/dir.d/"));
+        assert!(no_ext("https://example.com/a.png/../../x"));
+    }
+}
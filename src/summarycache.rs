@@ -0,0 +1,90 @@
+use actix_web::http::header::{self, HeaderValue, HttpDate};
+use actix_web::{HttpRequest, HttpResponse};
+use bytes::Bytes;
+use hex;
+use openssl::sha::sha256;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+/* Every flatpak client checking for updates downloads the summary of
+ * the repo (and its signature), so we keep these in memory instead of
+ * reading them from disk for each request. An entry is reused as long as
+ * the mtime and size of the file are unchanged, which is the case until
+ * the next update-repo job replaces it. The ETag is the hash of the
+ * contents, so it stays the same if a regenerated summary is identical. */
+
+struct CachedFile {
+    modified: SystemTime,
+    len: u64,
+    etag: String,
+    data: Bytes,
+}
+
+#[derive(Clone)]
+pub struct SummaryCache(Arc<Mutex<HashMap<PathBuf, Arc<CachedFile>>>>);
+
+pub fn is_cached_file(relpath: &Path) -> bool {
+    relpath == Path::new("summary") || relpath == Path::new("summary.sig")
+}
+
+fn etag_matches(req: &HttpRequest, etag: &str) -> bool {
+    req.headers().get_all(header::IF_NONE_MATCH)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|tag| tag.trim().trim_start_matches("W/"))
+        .any(|tag| tag == etag || tag == "*")
+}
+
+impl SummaryCache {
+    pub fn new() -> SummaryCache {
+        SummaryCache(Arc::new(Mutex::new(HashMap::new())))
+    }
+
+    fn get(&self, path: &Path) -> io::Result<Arc<CachedFile>> {
+        let metadata = fs::metadata(path)?;
+        let modified = metadata.modified()?;
+        if let Some(cached) = self.0.lock().unwrap().get(path) {
+            if cached.modified == modified && cached.len == metadata.len() {
+                return Ok(cached.clone());
+            }
+        }
+
+        /* The summary is replaced by a rename, so this reads a complete file */
+        let data = fs::read(path)?;
+        let cached = Arc::new(CachedFile {
+            modified,
+            len: data.len() as u64,
+            etag: format!("\"{}\"", hex::encode(sha256(&data))),
+            data: Bytes::from(data),
+        });
+        self.0.lock().unwrap().insert(path.to_path_buf(), cached.clone());
+        Ok(cached)
+    }
+
+    pub fn respond(&self, path: &Path, req: &HttpRequest) -> io::Result<HttpResponse> {
+        let cached = self.get(path)?;
+        let mut resp = if etag_matches(req, &cached.etag) {
+            HttpResponse::NotModified().finish()
+        } else if req.method() == actix_web::http::Method::HEAD {
+            let mut resp = HttpResponse::Ok().finish();
+            resp.headers_mut().insert(header::CONTENT_LENGTH, HeaderValue::from(cached.len));
+            resp
+        } else {
+            HttpResponse::Ok().body(cached.data.clone())
+        };
+        let headers = resp.headers_mut();
+        headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("application/octet-stream"));
+        headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+        if let Ok(etag) = HeaderValue::from_str(&cached.etag) {
+            headers.insert(header::ETAG, etag);
+        }
+        if let Ok(last_modified) = HeaderValue::from_str(&HttpDate::from(cached.modified).to_string()) {
+            headers.insert(header::LAST_MODIFIED, last_modified);
+        }
+        Ok(resp)
+    }
+}
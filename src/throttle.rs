@@ -0,0 +1,54 @@
+use bytes::Bytes;
+use futures::{Async, Future, Poll, Stream};
+use std::time::{Duration, Instant};
+use tokio::timer::Delay;
+
+/* Limits how fast an upload body is read, so that bulk uploads don't
+ * use up all the bandwidth of the host, which is shared with the
+ * clients pulling from the repos. Not reading makes tcp slow down the
+ * sender, so this only has to wait before handing out the next chunk
+ * whenever the body is ahead of bytes-per-second. */
+pub struct Throttled<S> {
+    stream: S,
+    bytes_per_second: Option<u64>,
+    start: Instant,
+    bytes: u64,
+    delay: Option<Delay>,
+}
+
+impl<S> Throttled<S> {
+    pub fn new(stream: S, bytes_per_second: Option<u64>) -> Throttled<S> {
+        Throttled {
+            stream,
+            bytes_per_second: bytes_per_second.filter(|rate| *rate > 0),
+            start: Instant::now(),
+            bytes: 0,
+            delay: None,
+        }
+    }
+}
+
+impl<S: Stream<Item = Bytes>> Stream for Throttled<S> {
+    type Item = Bytes;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Bytes>, S::Error> {
+        if let Some(ref mut delay) = self.delay {
+            /* A timer error just means we go on without waiting */
+            if let Ok(Async::NotReady) = delay.poll() {
+                return Ok(Async::NotReady);
+            }
+        }
+        self.delay = None;
+
+        let item = futures::try_ready!(self.stream.poll());
+        if let (Some(ref chunk), Some(rate)) = (&item, self.bytes_per_second) {
+            self.bytes += chunk.len() as u64;
+            let due = self.start + Duration::from_millis(self.bytes.saturating_mul(1000) / rate);
+            if due > Instant::now() {
+                self.delay = Some(Delay::new(due));
+            }
+        }
+        Ok(Async::Ready(item))
+    }
+}
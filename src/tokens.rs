@@ -6,9 +6,10 @@ use actix_web::error::Error;
 use futures::{Future, Poll};
 use futures::future::{ok, Either, FutureResult};
 use jwt::{decode, Validation};
+use openssl::ssl::SslRef;
 use std::rc::Rc;
 
-use app::Claims;
+use app::{Claims, ClientCertConfig};
 use errors::ApiError;
 
 pub trait ClaimsValidator {
@@ -87,6 +88,9 @@ impl ClaimsValidator for HttpRequest {
                 if !claims.scope.contains(&required_scope.to_string()) {
                     return Err(ApiError::NotEnoughPermissions(format!("Not matching scope '{}' in token", required_scope)))
                 }
+                if let Some(client_cert) = self.extensions().get::<ClientCert>() {
+                    client_cert.check_scope(required_scope)?;
+                }
                 Ok(())
             })
     }
@@ -116,9 +120,86 @@ impl ClaimsValidator for HttpRequest {
     }
 }
 
+/* The subject of the client certificate verified by the built-in tls
+ * support, stored with each request on the connection */
+#[derive(Clone, Debug)]
+pub struct PeerCertificate {
+    pub subject: Option<String>,
+}
+
+/* Escapes an attribute value of a distinguished name as in RFC 2253, so
+ * that a value can't pass for several attributes */
+fn escape_dn_value(value: &str) -> String {
+    let mut escaped = String::new();
+    let last = value.chars().count().saturating_sub(1);
+    for (i, c) in value.chars().enumerate() {
+        match c {
+            ',' | '+' | '"' | '\\' | '<' | '>' | ';' => {
+                escaped.push('\\');
+                escaped.push(c);
+            },
+            '#' if i == 0 => escaped.push_str("\\#"),
+            ' ' if i == 0 || i == last => escaped.push_str("\\ "),
+            c if c.is_control() => {
+                let mut buf = [0; 4];
+                for b in c.encode_utf8(&mut buf).bytes() {
+                    escaped.push_str(&format!("\\{:02X}", b));
+                }
+            },
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+impl PeerCertificate {
+    /* The subject is formatted like RFC 2253, e.g. "CN=builder1,O=Example",
+     * the same as the $ssl_client_s_dn of nginx */
+    pub fn from_ssl(ssl: &SslRef) -> PeerCertificate {
+        let subject = ssl.peer_certificate().map(|cert| {
+            cert.subject_name().entries()
+                .collect::<Vec<_>>()
+                .iter()
+                .rev()
+                .map(|entry| {
+                    let name = entry.object().nid().short_name().unwrap_or("UNDEF");
+                    let value = entry.data().to_string().unwrap_or_default();
+                    format!("{}={}", name, escape_dn_value(&value))
+                })
+                .collect::<Vec<String>>()
+                .join(",")
+        });
+        PeerCertificate {
+            subject,
+        }
+    }
+}
+
+/* The client certificate requirements of the request, set by the TokenParser */
+pub struct ClientCert {
+    required_scopes: Vec<String>,
+    subject: Option<String>,
+    allowed_scopes: Vec<String>,
+}
+
+impl ClientCert {
+    fn check_scope(&self, scope: &str) -> Result<(), ApiError> {
+        if !self.required_scopes.iter().any(|s| s == scope) {
+            return Ok(())
+        }
+        match self.subject {
+            None => Err(ApiError::NotEnoughPermissions(format!("Scope '{}' requires a client certificate", scope))),
+            Some(ref subject) if !self.allowed_scopes.iter().any(|s| s == scope) =>
+                Err(ApiError::NotEnoughPermissions(format!("Client certificate {} doesn't allow scope '{}'", subject, scope))),
+            Some(_) => Ok(()),
+        }
+    }
+}
+
 pub struct Inner {
     secret: Vec<u8>,
     optional: bool,
+    client_certificates: Option<ClientCertConfig>,
 }
 
 impl Inner {
@@ -157,10 +238,17 @@ pub struct TokenParser(Rc<Inner>);
 
 impl TokenParser {
     pub fn new(secret: &[u8]) -> TokenParser {
-        TokenParser(Rc::new(Inner { secret: secret.to_vec(), optional: false }))
+        TokenParser(Rc::new(Inner { secret: secret.to_vec(), optional: false, client_certificates: None }))
     }
     pub fn optional(secret: &[u8]) -> TokenParser {
-        TokenParser(Rc::new(Inner { secret: secret.to_vec(), optional: true }))
+        TokenParser(Rc::new(Inner { secret: secret.to_vec(), optional: true, client_certificates: None }))
+    }
+    pub fn client_certificates(self, config: &Option<ClientCertConfig>) -> TokenParser {
+        TokenParser(Rc::new(Inner {
+            secret: self.0.secret.clone(),
+            optional: self.0.optional,
+            client_certificates: config.clone(),
+        }))
     }
 }
 
@@ -206,6 +294,34 @@ impl<S> TokenParserMiddleware<S> {
         let claims = self.inner.validate_claims(token)?;
         Ok(Some(claims))
     }
+
+    fn check_client_cert(&self, req: &ServiceRequest) -> Option<ClientCert> {
+        let config = self.inner.client_certificates.as_ref()?;
+        /* Anyone could set the header, unless it comes from the proxy */
+        let from_proxy = req.peer_addr()
+            .map(|addr| config.trusted_proxies.contains(&addr.ip().to_string()))
+            .unwrap_or(false);
+        let peer_subject = req.extensions().get::<PeerCertificate>()
+            .and_then(|peer_cert| peer_cert.subject.clone());
+        let subject = match (peer_subject, &config.subject_header) {
+            (Some(subject), _) => Some(subject),
+            (None, Some(ref subject_header)) if from_proxy =>
+                req.headers().get(subject_header.as_str())
+                .and_then(|value| value.to_str().ok())
+                .map(|value| value.trim().to_string())
+                .filter(|value| !value.is_empty()),
+            _ => None,
+        };
+        let allowed_scopes = subject.as_ref()
+            .and_then(|subject| config.identities.get(subject))
+            .cloned()
+            .unwrap_or_default();
+        Some(ClientCert {
+            required_scopes: config.required_scopes.clone(),
+            subject,
+            allowed_scopes,
+        })
+    }
 }
 
 impl<S, B> Service for TokenParserMiddleware<S>
@@ -237,6 +353,9 @@ where
         if let Some(claims) = maybe_claims {
             req.extensions_mut().insert(claims);
         }
+        if let Some(client_cert) = self.check_client_cert(&req) {
+            req.extensions_mut().insert(client_cert);
+        }
 
         Either::A(Box::new(self.service.call(req)
                            .and_then(move |resp|  {
@@ -250,3 +369,19 @@ where
         ))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_escape_dn_value() {
+        assert_eq!(escape_dn_value("builder1"), "builder1");
+        assert_eq!(escape_dn_value("builder1,O=Example"), "builder1\\,O=Example");
+        assert_eq!(escape_dn_value("a+b;c\"d\\e<f>"), "a\\+b\\;c\\\"d\\\\e\\<f\\>");
+        assert_eq!(escape_dn_value("#1 two "), "\\#1 two\\ ");
+        assert_eq!(escape_dn_value(" "), "\\ ");
+        assert_eq!(escape_dn_value("a\0b\n"), "a\\00b\\0A");
+        assert_eq!(escape_dn_value(""), "");
+    }
+}
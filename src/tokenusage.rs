@@ -0,0 +1,247 @@
+use actix::prelude::*;
+use actix_service::{Service, Transform};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::error::Error;
+use actix_web::{web, HttpMessage};
+use chrono;
+use diesel;
+use diesel::prelude::*;
+use futures::{Future, Poll};
+use futures::future::{ok, FutureResult};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use app::Claims;
+use schema::token_usage;
+use Pool;
+
+/**************************************************************************
+ * Token usage tracking.
+ *
+ * The TokenUsageTracker middleware counts the api requests of each token
+ * subject, and remembers when and from where it was last used. This is
+ * only kept in memory by the http workers, the TokenUsageFlusher actor
+ * adds it to the token_usage table every FLUSH_INTERVAL, so that a
+ * request doesn't cost an extra database write. Like the RateLimiter
+ * this has to run inside the TokenParser, as it keys on the claims.
+ ***************************************************************************/
+
+const FLUSH_INTERVAL: Duration = Duration::from_secs(60);
+/* The most recent addresses are kept, per subject */
+const MAX_REMOTE_ADDRS: usize = 10;
+
+struct PendingUse {
+    requests: i64,
+    last_used: chrono::NaiveDateTime,
+    remote_addrs: Vec<String>, // Most recent first
+}
+
+type Pending = Arc<Mutex<HashMap<String, PendingUse>>>;
+
+fn merge_remote_addrs(recent: Vec<String>, older: Vec<String>) -> Vec<String> {
+    let mut merged: Vec<String> = Vec::new();
+    for addr in recent.into_iter().chain(older) {
+        if !merged.contains(&addr) {
+            merged.push(addr);
+        }
+    }
+    merged.truncate(MAX_REMOTE_ADDRS);
+    merged
+}
+
+fn flush_usage(pool: &Pool, pending: HashMap<String, PendingUse>) -> Result<(), String> {
+    let conn = pool.get().map_err(|e| e.to_string())?;
+    for (subject, usage) in pending {
+        conn.transaction::<_, diesel::result::Error, _>(|| {
+            let older = token_usage::table
+                .filter(token_usage::subject.eq(&subject))
+                .select(token_usage::remote_addrs)
+                .for_update()
+                .first::<Vec<String>>(&conn)
+                .optional()?
+                .unwrap_or_default();
+            let remote_addrs = merge_remote_addrs(usage.remote_addrs, older);
+            diesel::insert_into(token_usage::table)
+                .values((token_usage::subject.eq(&subject),
+                         token_usage::request_count.eq(usage.requests),
+                         token_usage::last_used_at.eq(usage.last_used),
+                         token_usage::remote_addrs.eq(&remote_addrs)))
+                .on_conflict(token_usage::subject)
+                .do_update()
+                .set((token_usage::request_count.eq(token_usage::request_count + usage.requests),
+                      token_usage::last_used_at.eq(usage.last_used),
+                      token_usage::remote_addrs.eq(&remote_addrs)))
+                .execute(&conn)?;
+            Ok(())
+        }).map_err(|e| format!("{}: {}", subject, e))?;
+    }
+    Ok(())
+}
+
+pub struct TokenUsageFlusher {
+    pending: Pending,
+    pool: Pool,
+    flushing: bool,
+}
+
+impl TokenUsageFlusher {
+    fn flush(&mut self, ctx: &mut Context<Self>) {
+        if self.flushing {
+            return
+        }
+        let pending = std::mem::take(&mut *self.pending.lock().unwrap());
+        if pending.is_empty() {
+            return
+        }
+        self.flushing = true;
+
+        let pool = self.pool.clone();
+        ctx.spawn(
+            web::block(move || flush_usage(&pool, pending))
+                .map_err(|e| error!("Failed to record token usage: {}", e))
+                .into_actor(self)
+                .then(|_r, flusher, _ctx| {
+                    flusher.flushing = false;
+                    actix::fut::ok(())
+                })
+        );
+    }
+}
+
+impl Actor for TokenUsageFlusher {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Context<Self>) {
+        ctx.run_interval(FLUSH_INTERVAL, |flusher, ctx| flusher.flush(ctx));
+    }
+}
+
+/* This is shared between all the http workers, so clone it into each */
+#[derive(Clone)]
+pub struct TokenUsageTracker {
+    pending: Pending,
+    trusted_proxies: Arc<Vec<String>>,
+}
+
+pub fn start_token_usage_tracker(pool: Pool, trusted_proxies: &[String]) -> TokenUsageTracker {
+    let pending: Pending = Arc::new(Mutex::new(HashMap::new()));
+    TokenUsageFlusher {
+        pending: pending.clone(),
+        pool,
+        flushing: false,
+    }.start();
+    TokenUsageTracker {
+        pending,
+        trusted_proxies: Arc::new(trusted_proxies.to_vec()),
+    }
+}
+
+/* Anyone can set the Forwarded and X-Forwarded-For headers, so the
+ * address from them (as forwarded_remote) is only used for requests
+ * from a trusted proxy. The port is left out, or each connection
+ * would count as a new address. */
+fn client_addr(peer_addr: Option<SocketAddr>, forwarded_remote: Option<&str>, trusted_proxies: &[String]) -> Option<String> {
+    let peer_ip = peer_addr.map(|addr| addr.ip().to_string());
+    let from_proxy = peer_ip.as_ref().map(|ip| trusted_proxies.contains(ip)).unwrap_or(false);
+    match forwarded_remote {
+        Some(remote) if from_proxy => Some(remote.parse::<SocketAddr>()
+                                           .map(|addr| addr.ip().to_string())
+                                           .unwrap_or(remote.to_string())),
+        _ => peer_ip,
+    }
+}
+
+impl<S: 'static, B> Transform<S> for TokenUsageTracker
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = TokenUsageTrackerMiddleware<S>;
+    type Future = FutureResult<Self::Transform, Self::InitError>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(TokenUsageTrackerMiddleware {
+            service,
+            pending: self.pending.clone(),
+            trusted_proxies: self.trusted_proxies.clone(),
+        })
+    }
+}
+
+/// TokenUsageTracker middleware
+pub struct TokenUsageTrackerMiddleware<S> {
+    service: S,
+    pending: Pending,
+    trusted_proxies: Arc<Vec<String>>,
+}
+
+impl<S> TokenUsageTrackerMiddleware<S> {
+    fn record_use(&self, req: &ServiceRequest) {
+        let sub = match req.extensions().get::<Claims>() {
+            Some(claims) => claims.usage_subject().to_string(),
+            None => return,
+        };
+        let remote_addr = client_addr(req.peer_addr(), req.connection_info().remote(), &self.trusted_proxies);
+
+        let mut pending = self.pending.lock().unwrap();
+        let usage = pending.entry(sub).or_insert(PendingUse {
+            requests: 0,
+            last_used: chrono::Utc::now().naive_utc(),
+            remote_addrs: vec![],
+        });
+        usage.requests += 1;
+        usage.last_used = chrono::Utc::now().naive_utc();
+        if let Some(remote_addr) = remote_addr {
+            usage.remote_addrs = merge_remote_addrs(vec![remote_addr], std::mem::take(&mut usage.remote_addrs));
+        }
+    }
+}
+
+impl<S, B> Service for TokenUsageTrackerMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = S::Future;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        self.service.poll_ready()
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        self.record_use(&req);
+        self.service.call(req)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_client_addr() {
+        let proxies = vec!["10.0.0.1".to_string()];
+        let proxy: SocketAddr = "10.0.0.1:41234".parse().unwrap();
+        let client: SocketAddr = "192.0.2.7:50000".parse().unwrap();
+
+        assert_eq!(client_addr(Some(client), None, &proxies), Some("192.0.2.7".to_string()));
+        /* A forged header is ignored unless the request comes from the proxy */
+        assert_eq!(client_addr(Some(client), Some("198.51.100.1"), &proxies), Some("192.0.2.7".to_string()));
+        assert_eq!(client_addr(Some(client), Some("198.51.100.1"), &[]), Some("192.0.2.7".to_string()));
+        assert_eq!(client_addr(Some(proxy), Some("198.51.100.1"), &proxies), Some("198.51.100.1".to_string()));
+        assert_eq!(client_addr(Some(proxy), Some("[2001:db8::1]:443"), &proxies), Some("2001:db8::1".to_string()));
+        assert_eq!(client_addr(Some(proxy), None, &proxies), Some("10.0.0.1".to_string()));
+        assert_eq!(client_addr(None, Some("198.51.100.1"), &proxies), None);
+    }
+}
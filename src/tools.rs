@@ -0,0 +1,114 @@
+use std::collections::HashSet;
+use std::path::Path;
+use std::process::Command;
+
+/* The versions of flatpak and ostree are detected at startup, together
+ * with the options flatpak build-update-repo supports. The jobs only use
+ * newer options if they are available, rather than failing halfway. */
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct ToolVersions {
+    pub flatpak: Option<String>,
+    pub ostree: Option<String>,
+    #[serde(skip)]
+    build_update_repo_options: HashSet<String>,
+}
+
+fn run(program: &Path, args: &[&str]) -> Option<String> {
+    match Command::new(program).args(args).output() {
+        Ok(ref output) if output.status.success() => Some(String::from_utf8_lossy(&output.stdout).into_owned()),
+        Ok(output) => {
+            warn!("{:?} {} failed: {}", program, args.join(" "), String::from_utf8_lossy(&output.stderr).trim());
+            None
+        },
+        Err(e) => {
+            warn!("Failed to run {:?}: {}", program, e);
+            None
+        },
+    }
+}
+
+/* "Flatpak 1.6.3" */
+fn parse_flatpak_version(output: &str) -> Option<String> {
+    output.split_whitespace().nth(1).map(|version| version.to_string())
+}
+
+/* A yaml document with a line like " Version: '2020.3'" */
+fn parse_ostree_version(output: &str) -> Option<String> {
+    output.lines()
+        .map(|line| line.trim())
+        .find(|line| line.starts_with("Version:"))
+        .map(|line| line["Version:".len()..].trim().trim_matches('\'').to_string())
+}
+
+/* The --long-options listed in --help output */
+fn parse_options(help: &str) -> HashSet<String> {
+    help.split(|c: char| c.is_whitespace() || c == ',' || c == '=' || c == '[')
+        .filter(|word| word.starts_with("--") && word.len() > 2)
+        .map(|word| word.to_string())
+        .collect()
+}
+
+impl ToolVersions {
+    pub fn detect(flatpak_path: &Path, ostree_path: &Path) -> ToolVersions {
+        ToolVersions {
+            flatpak: run(flatpak_path, &["--version"]).and_then(|output| parse_flatpak_version(&output)),
+            ostree: run(ostree_path, &["--version"]).and_then(|output| parse_ostree_version(&output)),
+            build_update_repo_options: run(flatpak_path, &["build-update-repo", "--help"])
+                .map(|output| parse_options(&output))
+                .unwrap_or_default(),
+        }
+    }
+
+    /* If we couldn't tell, we assume it is */
+    pub fn build_update_repo_supports(&self, option: &str) -> bool {
+        self.build_update_repo_options.is_empty() || self.build_update_repo_options.contains(option)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_versions() {
+        assert_eq!(parse_flatpak_version("Flatpak 1.6.3\n"), Some("1.6.3".to_string()));
+        assert_eq!(parse_flatpak_version(""), None);
+
+        let ostree_output = "libostree:\n Version: '2020.3'\n Git: v2020.3\n Features:\n  - libcurl\n";
+        assert_eq!(parse_ostree_version(ostree_output), Some("2020.3".to_string()));
+        assert_eq!(parse_ostree_version("libostree:\n Version: 2019.6\n"), Some("2019.6".to_string()));
+        assert_eq!(parse_ostree_version("libostree:\n Git: v2020.3\n"), None);
+    }
+
+    #[test]
+    fn test_parse_options() {
+        let help = "Usage:
+  flatpak build-update-repo [OPTION…] LOCATION - Update repository metadata
+
+Help Options:
+  -h, --help                     Show help options
+
+Application Options:
+  --redirect-url=URL             Redirect this repo to a new URL
+  --generate-static-deltas       Generate delta files
+  --static-delta-jobs=NUM-JOBS   Max parallel jobs when creating deltas (default: NUMCPUs)
+  --prune-depth=DEPTH            Maximum number of parents to keep when pruning (default: -1)
+  --gpg-sign=KEY-ID              GPG Key ID to sign the summary with
+  --no-summary-index[=BOOL]      Don't generate a summary index
+  -v, --verbose                  Show debug information, -vv for more detail
+";
+        let options = parse_options(help);
+        for option in &["--help", "--redirect-url", "--generate-static-deltas", "--static-delta-jobs",
+                        "--prune-depth", "--gpg-sign", "--no-summary-index", "--verbose"] {
+            assert!(options.contains(*option), "missing {}", option);
+        }
+        assert!(!options.contains("-h"));
+        assert!(!options.contains("--"));
+        assert!(!options.contains("--no-update-appstream"));
+
+        let tools = ToolVersions { build_update_repo_options: options, ..ToolVersions::default() };
+        assert!(tools.build_update_repo_supports("--static-delta-jobs"));
+        assert!(!tools.build_update_repo_supports("--no-update-appstream"));
+        assert!(ToolVersions::default().build_update_repo_supports("--no-update-appstream"));
+    }
+}
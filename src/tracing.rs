@@ -0,0 +1,310 @@
+use actix::prelude::*;
+use actix_web::http::header;
+use awc::Client;
+use futures::Future;
+use hex;
+use rand::{self, Rng};
+use serde_json;
+use std::cell::RefCell;
+use std::mem;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use app::TracingConfig;
+
+/**************************************************************************
+ * Distributed tracing, using W3C trace context (the traceparent header)
+ * for propagation. A trace started by an api request (or continued from
+ * the traceparent of the client) is stored with the jobs the request
+ * creates, and continued by the job when it runs, and by the commands
+ * it spawns (in the TRACEPARENT environment variable). Finished spans
+ * are sent in batches in the zipkin v2 format, which the OpenTelemetry
+ * collector and most tracing backends accept. The opentelemetry crates
+ * need a newer tokio and futures than actix-web 1.0 runs on, so rather
+ * than a second runtime just for them, the spans are exported here.
+ *
+ * The current span is tracked per thread. For requests it is set while
+ * the handlers are polled, and carried over into the db thread pool.
+ ***************************************************************************/
+
+const EXPORT_INTERVAL: Duration = Duration::from_secs(5);
+/* Drop spans rather than use unbounded memory if the collector is down */
+const MAX_BUFFERED_SPANS: usize = 10000;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    static ref FINISHED_SPANS: Mutex<Vec<serde_json::Value>> = Mutex::new(vec![]);
+}
+
+thread_local! {
+    static CURRENT: RefCell<Option<TraceContext>> = const { RefCell::new(None) };
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TraceContext {
+    pub trace_id: String,
+    pub span_id: String,
+}
+
+fn random_id(bytes: usize) -> String {
+    let mut rng = rand::thread_rng();
+    let id: Vec<u8> = (0..bytes).map(|_| rng.gen::<u8>()).collect();
+    hex::encode(id)
+}
+
+fn is_hex(s: &str, len: usize) -> bool {
+    s.len() == len && s.chars().all(|c| c.is_ascii_hexdigit() && !c.is_uppercase())
+}
+
+/* All zero ids are invalid */
+fn is_hex_id(s: &str, len: usize) -> bool {
+    is_hex(s, len) && s.chars().any(|c| c != '0')
+}
+
+impl TraceContext {
+    /* Parses a traceparent like 00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01 */
+    pub fn parse(traceparent: &str) -> Option<TraceContext> {
+        let parts: Vec<&str> = traceparent.trim().split('-').collect();
+        if parts.len() < 4 || !is_hex(parts[0], 2) || parts[0] == "ff" || !is_hex(parts[3], 2) {
+            return None
+        }
+        /* Later versions may add fields, but version 00 has exactly these */
+        if parts[0] == "00" && parts.len() != 4 {
+            return None
+        }
+        if !is_hex_id(parts[1], 32) || !is_hex_id(parts[2], 16) {
+            return None
+        }
+        Some(TraceContext {
+            trace_id: parts[1].to_string(),
+            span_id: parts[2].to_string(),
+        })
+    }
+
+    pub fn to_traceparent(&self) -> String {
+        format!("00-{}-{}-01", self.trace_id, self.span_id)
+    }
+
+    /* A new span in the same trace, or a new trace without a parent */
+    pub fn child_of(parent: &Option<TraceContext>) -> TraceContext {
+        TraceContext {
+            trace_id: parent.as_ref().map(|p| p.trace_id.clone()).unwrap_or_else(|| random_id(16)),
+            span_id: random_id(8),
+        }
+    }
+}
+
+pub fn current() -> Option<TraceContext> {
+    CURRENT.with(|current| current.borrow().clone())
+}
+
+pub fn current_traceparent() -> Option<String> {
+    current().map(|context| context.to_traceparent())
+}
+
+/* Makes context the current one until the guard is dropped */
+pub struct ContextGuard {
+    previous: Option<TraceContext>,
+}
+
+impl Drop for ContextGuard {
+    fn drop(&mut self) {
+        let previous = self.previous.take();
+        CURRENT.with(|current| *current.borrow_mut() = previous);
+    }
+}
+
+pub fn set_current(context: Option<TraceContext>) -> ContextGuard {
+    CURRENT.with(|current| {
+        ContextGuard {
+            previous: mem::replace(&mut *current.borrow_mut(), context),
+        }
+    })
+}
+
+fn micros_since_epoch(time: SystemTime) -> u64 {
+    let d = time.duration_since(UNIX_EPOCH).unwrap_or(Duration::from_secs(0));
+    d.as_secs() * 1_000_000 + d.subsec_micros() as u64
+}
+
+/* Records a finished span, for spans not tracked with start_span() */
+pub fn record_span(name: &str,
+                   context: &TraceContext,
+                   parent: &Option<TraceContext>,
+                   kind: Option<&str>,
+                   start: SystemTime,
+                   tags: &[(&str, String)]) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return
+    }
+    let end = SystemTime::now();
+    let mut span = json!({
+        "traceId": context.trace_id,
+        "id": context.span_id,
+        "name": name,
+        "timestamp": micros_since_epoch(start),
+        "duration": end.duration_since(start).map(|d| d.as_secs() * 1_000_000 + d.subsec_micros() as u64).unwrap_or(0).max(1),
+        "localEndpoint": { "serviceName": "flat-manager" },
+        "tags": tags.iter().map(|(k, v)| (k.to_string(), json!(v))).collect::<serde_json::Map<String, serde_json::Value>>(),
+    });
+    if let Some(parent) = parent {
+        span["parentId"] = json!(parent.span_id);
+    }
+    if let Some(kind) = kind {
+        span["kind"] = json!(kind);
+    }
+
+    let mut spans = FINISHED_SPANS.lock().unwrap();
+    if spans.len() < MAX_BUFFERED_SPANS {
+        spans.push(span);
+    }
+}
+
+/* A span that is the current one from creation until dropped */
+pub struct Span {
+    name: String,
+    context: TraceContext,
+    parent: Option<TraceContext>,
+    start: SystemTime,
+    tags: Vec<(&'static str, String)>,
+    _guard: ContextGuard,
+}
+
+impl Span {
+    pub fn tag(&mut self, key: &'static str, value: &str) {
+        self.tags.push((key, value.to_string()));
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        record_span(&self.name, &self.context, &self.parent, None, self.start, &self.tags);
+    }
+}
+
+/* Starts a span under the given parent, e.g. from the traceparent stored with a job */
+pub fn start_span_with_parent(name: &str, parent: Option<TraceContext>) -> Span {
+    let context = TraceContext::child_of(&parent);
+    Span {
+        name: name.to_string(),
+        context: context.clone(),
+        parent,
+        start: SystemTime::now(),
+        tags: vec![],
+        _guard: set_current(Some(context)),
+    }
+}
+
+/* Starts a span under the current one */
+pub fn start_span(name: &str) -> Span {
+    start_span_with_parent(name, current())
+}
+
+pub struct SpanExporter {
+    config: TracingConfig,
+}
+
+impl SpanExporter {
+    fn export(&mut self, ctx: &mut Context<Self>) {
+        let spans = std::mem::take(&mut *FINISHED_SPANS.lock().unwrap());
+        if spans.is_empty() {
+            return
+        }
+        let n_spans = spans.len();
+        ctx.spawn(
+            Client::new()
+                .post(&self.config.zipkin_url)
+                .header(header::CONTENT_TYPE, "application/json")
+                .timeout(Duration::from_secs(10))
+                .send_json(&spans)
+                .then(move |r| {
+                    match r {
+                        Ok(ref response) if response.status().is_success() => (),
+                        Ok(response) => warn!("Failed to export {} spans: {}", n_spans, response.status()),
+                        Err(e) => warn!("Failed to export {} spans: {}", n_spans, e),
+                    };
+                    Ok::<_, ()>(())
+                })
+                .into_actor(self)
+        );
+    }
+}
+
+impl Actor for SpanExporter {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Context<Self>) {
+        ctx.run_interval(EXPORT_INTERVAL, |exporter, ctx| exporter.export(ctx));
+    }
+}
+
+pub fn start_span_exporter(config: &Option<TracingConfig>) -> Option<Addr<SpanExporter>> {
+    config.as_ref().map(|config| {
+        ENABLED.store(true, Ordering::Relaxed);
+        SpanExporter {
+            config: config.clone(),
+        }.start()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRACE_ID: &str = "4bf92f3577b34da6a3ce929d0e0e4736";
+    const SPAN_ID: &str = "00f067aa0ba902b7";
+
+    #[test]
+    fn test_parse() {
+        let context = TraceContext::parse(&format!("00-{}-{}-01", TRACE_ID, SPAN_ID)).unwrap();
+        assert_eq!(context.trace_id, TRACE_ID);
+        assert_eq!(context.span_id, SPAN_ID);
+        assert_eq!(context.to_traceparent(), format!("00-{}-{}-01", TRACE_ID, SPAN_ID));
+        assert_eq!(TraceContext::parse(&format!(" 00-{}-{}-00\n", TRACE_ID, SPAN_ID)), Some(context));
+
+        /* Future versions can have more fields */
+        assert!(TraceContext::parse(&format!("01-{}-{}-01-extra", TRACE_ID, SPAN_ID)).is_some());
+    }
+
+    #[test]
+    fn test_parse_malformed() {
+        let malformed = [
+            "".to_string(),
+            "garbage".to_string(),
+            "00".to_string(),
+            "---".to_string(),
+            format!("00-{}-{}", TRACE_ID, SPAN_ID),
+            format!("00-{}-{}-01-extra", TRACE_ID, SPAN_ID),
+            format!("ff-{}-{}-01", TRACE_ID, SPAN_ID),
+            format!("0-{}-{}-01", TRACE_ID, SPAN_ID),
+            format!("zz-{}-{}-01", TRACE_ID, SPAN_ID),
+            format!("00-{}-{}-1", TRACE_ID, SPAN_ID),
+            format!("00-{}-{}-0g", TRACE_ID, SPAN_ID),
+            format!("00-{}-{}-01", "0".repeat(32), SPAN_ID),
+            format!("00-{}-{}-01", TRACE_ID, "0".repeat(16)),
+            format!("00-{}-{}-01", TRACE_ID.to_uppercase(), SPAN_ID),
+            format!("00-{}-{}-01", &TRACE_ID[1..], SPAN_ID),
+            format!("00-{}-{}0-01", TRACE_ID, SPAN_ID),
+            format!("00-{}-{}-01", TRACE_ID.replace('4', "x"), SPAN_ID),
+            format!("00-{}-{}-01", TRACE_ID, SPAN_ID.replace('0', "é")),
+        ];
+        for traceparent in malformed.iter() {
+            assert_eq!(TraceContext::parse(traceparent), None, "{:?}", traceparent);
+        }
+    }
+
+    #[test]
+    fn test_child_of() {
+        let parent = TraceContext::parse(&format!("00-{}-{}-01", TRACE_ID, SPAN_ID));
+        let child = TraceContext::child_of(&parent);
+        assert_eq!(child.trace_id, TRACE_ID);
+        assert!(is_hex_id(&child.span_id, 16));
+        assert_ne!(child.span_id, SPAN_ID);
+
+        let root = TraceContext::child_of(&None);
+        assert!(is_hex_id(&root.trace_id, 32));
+        assert!(is_hex_id(&root.span_id, 16));
+    }
+}
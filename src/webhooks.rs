@@ -0,0 +1,218 @@
+use actix::prelude::*;
+use actix_web::http::header;
+use actix_web::web;
+use awc::Client;
+use diesel;
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use diesel::result::{Error as DieselError};
+use futures::future;
+use futures::Future;
+use jwt;
+use serde_json;
+use std::sync::Arc;
+use std::time::Duration;
+
+use app::{Config, WebhookConfig};
+use models::{Build, NewWebhookEvent, WebhookEvent};
+use schema::webhook_events;
+use Pool;
+
+/**************************************************************************
+ * Outbound webhooks.
+ *
+ * Events are queued in the webhook_events table by whoever changes the
+ * state (in the same transaction when possible), and the WebhookSender
+ * actor regularly picks up undelivered events and posts them to all the
+ * configured webhooks. An event is marked delivered when all webhooks
+ * accepted it, otherwise it is retried on the next poll until it has
+ * been attempted webhook-max-attempts times. The last error is stored
+ * with the event.
+ *
+ * Each request has the event id in the X-Flat-Manager-Event-Id header,
+ * so receivers can ignore duplicates caused by the retries, and if the
+ * webhook has a secret the body is signed with HMAC-SHA256 and the
+ * base64url encoded signature is sent in X-Flat-Manager-Signature.
+ ***************************************************************************/
+
+const POLL_INTERVAL: Duration = Duration::from_secs(30);
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(30);
+const MAX_EVENTS_PER_POLL: i64 = 50;
+
+pub fn queue_event(conn: &PgConnection,
+                   config: &Config,
+                   event: &str,
+                   payload: serde_json::Value) -> Result<(), DieselError> {
+    if config.webhooks.is_empty() {
+        return Ok(())
+    }
+
+    diesel::insert_into(webhook_events::table)
+        .values(NewWebhookEvent {
+            event: event.to_string(),
+            payload: payload.to_string(),
+        })
+        .execute(conn)?;
+    Ok(())
+}
+
+/* The state changes of builds, made by the api as well as by the jobs */
+
+pub fn queue_build_repo_state(conn: &PgConnection,
+                              config: &Config,
+                              build: &Build) -> Result<(), DieselError> {
+    queue_event(conn, config, "build-repo-state", json!({
+        "build": build.id,
+        "repo": build.repo,
+        "repo_state": build.repo_state,
+        "repo_state_reason": build.repo_state_reason,
+    }))
+}
+
+pub fn queue_build_published_state(conn: &PgConnection,
+                                   config: &Config,
+                                   build: &Build,
+                                   note: &Option<String>) -> Result<(), DieselError> {
+    queue_event(conn, config, "build-published-state", json!({
+        "build": build.id,
+        "repo": build.repo,
+        "published_state": build.published_state,
+        "published_state_reason": build.published_state_reason,
+        "note": note,
+    }))
+}
+
+pub fn queue_build_deleted(conn: &PgConnection,
+                           config: &Config,
+                           build: &Build) -> Result<(), DieselError> {
+    let event = if build.deleted_at.is_some() { "build-deleted" } else { "build-undeleted" };
+    queue_event(conn, config, event, json!({
+        "build": build.id,
+        "repo": build.repo,
+        "deleted_at": build.deleted_at,
+    }))
+}
+
+fn deliver_to_webhook(webhook: &WebhookConfig,
+                      event_id: i32,
+                      body: &str) -> Box<dyn Future<Item=(), Error=String>> {
+    let mut request = Client::new()
+        .post(&webhook.url)
+        .header(header::CONTENT_TYPE, "application/json")
+        .header("X-Flat-Manager-Event-Id", event_id.to_string())
+        .timeout(DELIVERY_TIMEOUT);
+
+    if let Some(ref secret) = webhook.secret {
+        match jwt::sign(body, secret.as_bytes(), jwt::Algorithm::HS256) {
+            Ok(signature) => request = request.header("X-Flat-Manager-Signature", format!("sha256={}", signature)),
+            Err(e) => return Box::new(future::err(format!("Failed to sign payload: {}", e))),
+        }
+    }
+
+    let url = webhook.url.clone();
+    Box::new(
+        request
+            .send_body(body.to_string())
+            .then(move |r| {
+                match r {
+                    Ok(ref response) if response.status().is_success() => Ok(()),
+                    Ok(response) => Err(format!("{}: Unexpected status {}", url, response.status())),
+                    Err(e) => Err(format!("{}: {}", url, e)),
+                }
+            }))
+}
+
+pub struct WebhookSender {
+    config: Arc<Config>,
+    pool: Pool,
+    delivering: bool,
+}
+
+impl WebhookSender {
+    fn deliver_event(&self, event: WebhookEvent) -> impl Future<Item=(), Error=()> {
+        let body = json!({
+            "id": event.id,
+            "event": event.event,
+            "created_at": event.created_at,
+            "data": serde_json::from_str::<serde_json::Value>(&event.payload).unwrap_or(serde_json::Value::Null),
+        }).to_string();
+
+        let deliveries: Vec<_> = self.config.webhooks.iter()
+            .map(|webhook| deliver_to_webhook(webhook, event.id, &body).then(|r| Ok::<_, ()>(r.err())))
+            .collect();
+
+        let pool = self.pool.clone();
+        let event_id = event.id;
+        future::join_all(deliveries)
+            .and_then(move |results| {
+                let errors: Vec<String> = results.into_iter().flatten().collect();
+                if !errors.is_empty() {
+                    warn!("Failed to deliver webhook event {}: {}", event_id, errors.join(", "));
+                }
+                web::block(move || {
+                    let conn = pool.get().map_err(|e| e.to_string())?;
+                    diesel::update(webhook_events::table)
+                        .filter(webhook_events::id.eq(event_id))
+                        .set((webhook_events::attempts.eq(webhook_events::attempts + 1),
+                              webhook_events::delivered.eq(errors.is_empty()),
+                              webhook_events::last_error.eq(if errors.is_empty() { None } else { Some(errors.join(", ")) })))
+                        .execute(&conn)
+                        .map_err(|e| e.to_string())
+                })
+                    .map_err(move |e| error!("Failed to update webhook event {}: {}", event_id, e))
+                    .map(|_| ())
+            })
+    }
+
+    fn deliver_pending(&mut self, ctx: &mut Context<Self>) {
+        if self.delivering {
+            return
+        }
+        self.delivering = true;
+
+        let pool = self.pool.clone();
+        let max_attempts = self.config.webhook_max_attempts;
+        ctx.spawn(
+            web::block(move || {
+                let conn = pool.get().map_err(|e| e.to_string())?;
+                webhook_events::table
+                    .filter(webhook_events::delivered.eq(false))
+                    .filter(webhook_events::attempts.lt(max_attempts))
+                    .order(webhook_events::id)
+                    .limit(MAX_EVENTS_PER_POLL)
+                    .get_results::<WebhookEvent>(&conn)
+                    .map_err(|e| e.to_string())
+            })
+                .map_err(|e| error!("Failed to load webhook events: {}", e))
+                .into_actor(self)
+                .and_then(|events, sender, _ctx| {
+                    let deliveries: Vec<_> = events.into_iter().map(|event| sender.deliver_event(event)).collect();
+                    future::join_all(deliveries).map(|_| ()).into_actor(sender)
+                })
+                .then(|_r, sender, _ctx| {
+                    sender.delivering = false;
+                    actix::fut::ok(())
+                })
+        );
+    }
+}
+
+impl Actor for WebhookSender {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Context<Self>) {
+        if self.config.webhooks.is_empty() {
+            return
+        }
+        self.deliver_pending(ctx);
+        ctx.run_interval(POLL_INTERVAL, |sender, ctx| sender.deliver_pending(ctx));
+    }
+}
+
+pub fn start_webhook_sender(config: Arc<Config>, pool: Pool) -> Addr<WebhookSender> {
+    WebhookSender {
+        config,
+        pool,
+        delivering: false,
+    }.start()
+}